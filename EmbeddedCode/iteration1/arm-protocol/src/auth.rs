@@ -0,0 +1,277 @@
+/*
+William Albertini
+
+Packet authentication. A signed packet has FLAG_AUTHENTICATED
+set and a trailer after the payload:
+
+    counter     8 bytes, big endian
+    tag         TAG_SIZE bytes, HMAC-SHA256 (truncated)
+
+The tag covers the header, the payload and the counter,
+keyed with a key shared by the controller and the pi.
+The counter must go up with every packet, including
+across controller reboots (the controller keeps a boot
+count in flash for the top half), so the receiver can
+throw out anything it has already seen.
+
+The header payload length does not include the trailer,
+once verify_packet() has checked it the rest of the
+packet decodes like any other.
+
+Keys are given as hex (decode_hex_key()): the controller
+takes it at build time, the pi from its --key-file. There is
+no default key, check_key() refuses short keys, keys that
+are one byte over and over, and the placeholder that was
+once in the repo (WEAK_KEYS), for signing and verifying.
+
+*/
+
+// external imports
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+// internal imports
+use crate::packet::{FLAG_AUTHENTICATED, HEADER_SIZE};
+use crate::protocol_error::ProtocolError;
+
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const COUNTER_SIZE: usize = 8;
+// first 16 bytes of the HMAC
+pub const TAG_SIZE: usize = 16;
+pub const AUTH_TRAILER_SIZE: usize = COUNTER_SIZE + TAG_SIZE;
+// shorter keys are too easy to guess
+pub const MIN_KEY_SIZE: usize = 16;
+// keys that have been public, never sign or accept with these
+pub const WEAK_KEYS: [&[u8]; 1] = [b"change me: 32 bytes of secret!!!"];
+
+
+pub fn check_key(key: &[u8]) -> Result<(), ProtocolError>
+{
+    if key.len() < MIN_KEY_SIZE
+    {
+        return Err(ProtocolError::KeyTooShort(key.len()));
+    }
+    if key.iter().all(|byte| *byte == key[0]) || WEAK_KEYS.contains(&key)
+    {
+        return Err(ProtocolError::WeakKey);
+    }
+    Ok(())
+}
+
+pub fn decode_hex_key<'a>(hex: &str, buffer: &'a mut [u8]) -> Result<&'a [u8], ProtocolError>
+{
+    // whitespace is ignored, the key is checked before it is returned
+    let nibble = |digit: u8| (digit as char).to_digit(16).map(|value| value as u8).ok_or(ProtocolError::InvalidKeyHex(digit));
+    let mut digits = hex.bytes().filter(|byte| !byte.is_ascii_whitespace());
+    let available = buffer.len();
+    let mut len = 0;
+    while let Some(high) = digits.next()
+    {
+        let low = digits.next().ok_or(ProtocolError::InvalidKeyHex(high))?;
+        let slot = buffer.get_mut(len).ok_or(ProtocolError::BufferTooSmall { needed: len + 1, available })?;
+        *slot = (nibble(high)? << 4) | nibble(low)?;
+        len += 1;
+    }
+    check_key(&buffer[..len])?;
+    Ok(&buffer[..len])
+}
+
+
+fn mac(key: &[u8]) -> Result<HmacSha256, ProtocolError>
+{
+    check_key(key)?;
+    HmacSha256::new_from_slice(key).map_err(|_| ProtocolError::KeyTooShort(key.len()))
+}
+
+
+pub fn sign_packet(key: &[u8], packet: &[u8], counter: u64, buffer: &mut [u8]) -> Result<usize, ProtocolError>
+{
+    // copies packet into buffer with the auth flag set and the trailer
+    // added, returns how much of the buffer was used
+    if packet.len() < HEADER_SIZE
+    {
+        return Err(ProtocolError::TooShort(packet.len()));
+    }
+    let needed = packet.len() + AUTH_TRAILER_SIZE;
+    if buffer.len() < needed
+    {
+        return Err(ProtocolError::BufferTooSmall { needed, available: buffer.len() });
+    }
+
+    let signed_len = packet.len() + COUNTER_SIZE;
+    buffer[..packet.len()].copy_from_slice(packet);
+    buffer[3] |= FLAG_AUTHENTICATED;
+    buffer[packet.len()..signed_len].copy_from_slice(&counter.to_be_bytes());
+
+    let mut mac = mac(key)?;
+    mac.update(&buffer[..signed_len]);
+    let tag = mac.finalize().into_bytes();
+    buffer[signed_len..needed].copy_from_slice(&tag[..TAG_SIZE]);
+    Ok(needed)
+}
+
+pub fn strip_signature(datagram: &[u8]) -> Result<(u64, &[u8]), ProtocolError>
+{
+    // splits off the trailer without checking it, for when there is no key
+    if datagram.len() < HEADER_SIZE + AUTH_TRAILER_SIZE
+    {
+        return Err(ProtocolError::TooShort(datagram.len()));
+    }
+    if datagram[3] & FLAG_AUTHENTICATED == 0
+    {
+        return Err(ProtocolError::NotAuthenticated);
+    }
+
+    let signed_len = datagram.len() - TAG_SIZE;
+    let packet_len = signed_len - COUNTER_SIZE;
+    let mut counter = [0; COUNTER_SIZE];
+    counter.copy_from_slice(&datagram[packet_len..signed_len]);
+    Ok((u64::from_be_bytes(counter), &datagram[..packet_len]))
+}
+
+pub fn verify_packet<'a>(key: &[u8], datagram: &'a [u8]) -> Result<(u64, &'a [u8]), ProtocolError>
+{
+    // returns the counter and the packet without its trailer
+    let (counter, packet) = strip_signature(datagram)?;
+    let signed_len = datagram.len() - TAG_SIZE;
+
+    let mut mac = mac(key)?;
+    mac.update(&datagram[..signed_len]);
+    // constant time compare
+    mac.verify_truncated_left(&datagram[signed_len..]).map_err(|_| ProtocolError::BadSignature)?;
+    Ok((counter, packet))
+}
+
+
+pub struct PacketSigner<'k>
+{
+    key: &'k [u8],
+    counter: u64,
+}
+
+impl<'k> PacketSigner<'k>
+{
+    pub fn new(key: &'k [u8], boot_count: u32) -> PacketSigner<'k>
+    {
+        // top half of the counter is the boot count, so a reboot never
+        // reuses a counter as long as the boot count is kept
+        PacketSigner { key, counter: (boot_count as u64) << 32 }
+    }
+
+    pub fn sign(&mut self, packet: &[u8], buffer: &mut [u8]) -> Result<usize, ProtocolError>
+    {
+        self.counter += 1;
+        sign_packet(self.key, packet, self.counter, buffer)
+    }
+
+    pub fn get_counter(&self) -> u64
+    {
+        self.counter
+    }
+}
+
+
+
+
+// ------------------------------- unit tests -------------------------------------
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::joystick::JoystickPayload;
+    use crate::packet::{decode_joystick_packet, PacketSequencer};
+
+    const KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    fn signed_packet(signer: &mut PacketSigner, buffer: &mut [u8; 64]) -> usize
+    {
+        let payload = JoystickPayload { x: 3, ..JoystickPayload::default() };
+        let packet = PacketSequencer::new().joystick_packet(&payload);
+        signer.sign(&packet, buffer).unwrap()
+    }
+
+    #[test]
+    fn test_sign_and_verify()
+    {
+        let mut signer = PacketSigner::new(KEY, 7);
+        let mut buffer = [0; 64];
+        let len = signed_packet(&mut signer, &mut buffer);
+        let (counter, packet) = verify_packet(KEY, &buffer[..len]).unwrap();
+        let (header, payload) = decode_joystick_packet(packet).unwrap();
+
+        assert_eq!(counter, (7 << 32) + 1);
+        assert!(header.has_flag(FLAG_AUTHENTICATED));
+        assert_eq!(payload.x, 3);
+    }
+
+    #[test]
+    fn test_tampering_detected()
+    {
+        let mut signer = PacketSigner::new(KEY, 0);
+        let mut buffer = [0; 64];
+        let len = signed_packet(&mut signer, &mut buffer);
+
+        // flipping any bit of the signed packet breaks the tag
+        for i in 0..len
+        {
+            let mut tampered = buffer;
+            tampered[i] ^= 0x01;
+            assert!(verify_packet(KEY, &tampered[..len]).is_err(), "byte {i}");
+        }
+        // and so does the wrong key
+        assert_eq!(verify_packet(b"fedcba9876543210fedcba9876543210", &buffer[..len]), Err(ProtocolError::BadSignature));
+    }
+
+    #[test]
+    fn test_unsigned_and_short_keys_rejected()
+    {
+        let packet = PacketSequencer::new().joystick_packet(&JoystickPayload::default());
+        let mut buffer = [0; 64];
+
+        assert_eq!(verify_packet(KEY, &packet), Err(ProtocolError::TooShort(13)));
+        assert_eq!(sign_packet(b"short", &packet, 1, &mut buffer), Err(ProtocolError::KeyTooShort(5)));
+    }
+
+    #[test]
+    fn test_weak_keys_rejected()
+    {
+        // the old placeholder and all zeros can not sign or verify
+        let packet = PacketSequencer::new().joystick_packet(&JoystickPayload::default());
+        let mut buffer = [0; 64];
+        assert_eq!(sign_packet(WEAK_KEYS[0], &packet, 1, &mut buffer), Err(ProtocolError::WeakKey));
+        assert_eq!(sign_packet(&[0; 32], &packet, 1, &mut buffer), Err(ProtocolError::WeakKey));
+
+        let len = sign_packet(KEY, &packet, 1, &mut buffer).unwrap();
+        assert_eq!(verify_packet(WEAK_KEYS[0], &buffer[..len]), Err(ProtocolError::WeakKey));
+    }
+
+    #[test]
+    fn test_decode_hex_key()
+    {
+        let mut buffer = [0; 64];
+        let key = decode_hex_key("3031323334353637 38396162636465660a", &mut buffer).unwrap();
+        assert_eq!(key, b"0123456789abcdef\n");
+
+        assert_eq!(decode_hex_key("30313", &mut [0; 64]), Err(ProtocolError::InvalidKeyHex(b'3')));
+        assert_eq!(decode_hex_key("zz", &mut [0; 64]), Err(ProtocolError::InvalidKeyHex(b'z')));
+        assert_eq!(decode_hex_key("3031", &mut [0; 64]), Err(ProtocolError::KeyTooShort(2)));
+        assert_eq!(decode_hex_key(&"00".repeat(32), &mut [0; 64]), Err(ProtocolError::WeakKey));
+        assert!(matches!(decode_hex_key(&"01".repeat(8), &mut [0; 4]), Err(ProtocolError::BufferTooSmall { .. })));
+    }
+
+    #[test]
+    fn test_counter_increases()
+    {
+        let mut signer = PacketSigner::new(KEY, 1);
+        let mut buffer = [0; 64];
+        let len = signed_packet(&mut signer, &mut buffer);
+        let first = strip_signature(&buffer[..len]).unwrap().0;
+        let len = signed_packet(&mut signer, &mut buffer);
+        let second = strip_signature(&buffer[..len]).unwrap().0;
+
+        assert!(second > first);
+        assert_eq!(signer.get_counter(), second);
+    }
+}
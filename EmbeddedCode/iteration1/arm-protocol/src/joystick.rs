@@ -0,0 +1,272 @@
+/*
+William Albertini
+
+Joystick encoding. Each axis is a signed value from
+-AXIS_MAX to AXIS_MAX packed in one byte: positive
+values sit in the low nibble, negative values put their
+magnitude in the high nibble.
+
+    3    -> 0x03
+    -3   -> 0x30
+    8    -> 0x08
+    -8   -> 0x80
+
+A byte with both nibbles set, or a magnitude above
+AXIS_MAX, is not something the encoder can produce and
+is rejected. (The old decoder on the pi treated anything
+above 8 as negative, so 0x09-0x0F came out as 0 instead
+of being caught.)
+
+Buttons are sent as the pin level, the pins are pulled
+up so 1 is released and 0 is pressed.
+
+The payload is [x, y, roll, pitch, button1, button2].
+
+*/
+
+// internal imports
+use crate::protocol_error::ProtocolError;
+
+
+pub const AXIS_MAX: i8 = 8;
+pub const JOYSTICK_PAYLOAD_SIZE: usize = 6;
+
+pub const BUTTON_PRESSED: u8 = 0;
+pub const BUTTON_RELEASED: u8 = 1;
+
+// ADC calibration of the joysticks (12 bit readings)
+// readings inside the deadband are centered
+pub const ADC_DEADBAND_LOW: u16 = 1550;
+pub const ADC_DEADBAND_HIGH: u16 = 1750;
+// the sticks top out around here instead of 4095
+pub const ADC_HIGH: u16 = 2765;
+
+
+pub fn encode_axis(value: i8) -> u8
+{
+    // out of range values are clamped, not wrapped
+    let value = value.clamp(-AXIS_MAX, AXIS_MAX);
+    if value < 0
+    {
+        value.unsigned_abs() << 4
+    } else {
+        value as u8
+    }
+}
+
+pub fn decode_axis(byte: u8) -> Result<i8, ProtocolError>
+{
+    let low = byte & 0x0F;
+    let high = byte >> 4;
+
+    match (high, low)
+    {
+        (0, low) if low as i8 <= AXIS_MAX => Ok(low as i8),
+        (high, 0) if high as i8 <= AXIS_MAX => Ok(-(high as i8)),
+        _ => Err(ProtocolError::InvalidAxis(byte)),
+    }
+}
+
+pub fn encode_button(pressed: bool) -> u8
+{
+    if pressed { BUTTON_PRESSED } else { BUTTON_RELEASED }
+}
+
+pub fn decode_button(byte: u8) -> Result<bool, ProtocolError>
+{
+    match byte
+    {
+        BUTTON_PRESSED => Ok(true),
+        BUTTON_RELEASED => Ok(false),
+        _ => Err(ProtocolError::InvalidButton(byte)),
+    }
+}
+
+pub fn scale_adc(value: u16) -> i8
+{
+    // a stick pushed left (low readings) is positive, right is negative
+    if value < ADC_DEADBAND_LOW
+    {
+        // 0 -> 8, just under the deadband -> 1
+        (AXIS_MAX as u16 - value * AXIS_MAX as u16 / ADC_DEADBAND_LOW) as i8
+    }
+    else if value <= ADC_DEADBAND_HIGH
+    {
+        0
+    } else {
+        // same scaling the controller always used, saturating so readings
+        // just past the deadband can not underflow
+        let range = ADC_HIGH - ADC_DEADBAND_HIGH;
+        let magnitude = (value as u32 * AXIS_MAX as u32 / range as u32).saturating_sub(13).min(AXIS_MAX as u32);
+        -(magnitude as i8)
+    }
+}
+
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct JoystickPayload
+{
+    pub x: i8,
+    pub y: i8,
+    pub roll: i8,
+    pub pitch: i8,
+    pub button1_pressed: bool,
+    pub button2_pressed: bool,
+}
+
+impl JoystickPayload
+{
+    pub fn to_bytes(&self) -> [u8; JOYSTICK_PAYLOAD_SIZE]
+    {
+        [encode_axis(self.x),
+         encode_axis(self.y),
+         encode_axis(self.roll),
+         encode_axis(self.pitch),
+         encode_button(self.button1_pressed),
+         encode_button(self.button2_pressed)]
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<JoystickPayload, ProtocolError>
+    {
+        if bytes.len() != JOYSTICK_PAYLOAD_SIZE
+        {
+            return Err(ProtocolError::LengthMismatch { expected: JOYSTICK_PAYLOAD_SIZE, actual: bytes.len() });
+        }
+
+        Ok(JoystickPayload {
+            x: decode_axis(bytes[0])?,
+            y: decode_axis(bytes[1])?,
+            roll: decode_axis(bytes[2])?,
+            pitch: decode_axis(bytes[3])?,
+            button1_pressed: decode_button(bytes[4])?,
+            button2_pressed: decode_button(bytes[5])?,
+        })
+    }
+}
+
+
+
+
+// ------------------------------- unit tests -------------------------------------
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // small deterministic generator so the property tests need no extra crates
+    struct XorShift(u32);
+
+    impl XorShift
+    {
+        fn next(&mut self) -> u32
+        {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+
+        fn axis(&mut self) -> i8
+        {
+            (self.next() % (2 * AXIS_MAX as u32 + 1)) as i8 - AXIS_MAX
+        }
+    }
+
+    #[test]
+    fn test_axis_round_trip_every_value()
+    {
+        // the whole domain is small enough to check every value
+        for value in -AXIS_MAX..=AXIS_MAX
+        {
+            assert_eq!(decode_axis(encode_axis(value)), Ok(value), "value {value}");
+        }
+    }
+
+    #[test]
+    fn test_axis_edges()
+    {
+        // 8 and -8 are the values the two old copies disagreed on
+        assert_eq!(encode_axis(8), 0x08);
+        assert_eq!(encode_axis(-8), 0x80);
+        assert_eq!(decode_axis(0x08), Ok(8));
+        assert_eq!(decode_axis(0x80), Ok(-8));
+        // clamped, not wrapped
+        assert_eq!(encode_axis(i8::MAX), 0x08);
+        assert_eq!(encode_axis(i8::MIN), 0x80);
+    }
+
+    #[test]
+    fn test_every_byte_decodes_or_is_rejected()
+    {
+        // anything that decodes must encode back to the same byte
+        let mut valid = 0;
+        for byte in 0..=u8::MAX
+        {
+            match decode_axis(byte)
+            {
+                Ok(value) => {
+                    assert_eq!(encode_axis(value), byte);
+                    valid += 1;
+                },
+                Err(e) => assert_eq!(e, ProtocolError::InvalidAxis(byte)),
+            }
+        }
+        assert_eq!(valid, 2 * AXIS_MAX as usize + 1);
+    }
+
+    #[test]
+    fn test_buttons_round_trip()
+    {
+        for pressed in [true, false]
+        {
+            assert_eq!(decode_button(encode_button(pressed)), Ok(pressed));
+        }
+        assert_eq!(decode_button(2), Err(ProtocolError::InvalidButton(2)));
+    }
+
+    #[test]
+    fn test_payload_round_trip()
+    {
+        // random payloads encode and decode without loss
+        let mut rng = XorShift(0x1234_5678);
+        for _ in 0..10_000
+        {
+            let payload = JoystickPayload {
+                x: rng.axis(),
+                y: rng.axis(),
+                roll: rng.axis(),
+                pitch: rng.axis(),
+                button1_pressed: rng.next() & 1 == 0,
+                button2_pressed: rng.next() & 1 == 0,
+            };
+            assert_eq!(JoystickPayload::from_bytes(&payload.to_bytes()), Ok(payload));
+        }
+    }
+
+    #[test]
+    fn test_payload_length_checked()
+    {
+        assert_eq!(JoystickPayload::from_bytes(&[0; 5]),
+                   Err(ProtocolError::LengthMismatch { expected: 6, actual: 5 }));
+    }
+
+    #[test]
+    fn test_scale_adc_every_reading()
+    {
+        // every 12 bit reading lands in range, including the deadband edges
+        // that used to underflow, and the scale never changes direction
+        let mut previous = AXIS_MAX;
+        for reading in 0..4096
+        {
+            let value = scale_adc(reading);
+            assert!((-AXIS_MAX..=AXIS_MAX).contains(&value), "reading {reading} gave {value}");
+            assert!(value <= previous, "reading {reading} went from {previous} to {value}");
+            assert_eq!(decode_axis(encode_axis(value)), Ok(value));
+            previous = value;
+        }
+        assert_eq!(scale_adc(0), AXIS_MAX);
+        assert_eq!(scale_adc(ADC_DEADBAND_LOW), 0);
+        assert_eq!(scale_adc(ADC_DEADBAND_HIGH), 0);
+        assert_eq!(scale_adc(4095), -AXIS_MAX);
+    }
+}
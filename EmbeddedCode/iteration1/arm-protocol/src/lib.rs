@@ -0,0 +1,30 @@
+/*
+William Albertini
+
+Wire protocol shared by the controller (ESP32) and the
+robot-arm (raspberry pi). Both sides used to carry their
+own copy of the joystick encoding and the packet header,
+this crate is now the only definition of either.
+
+joystick: signed axis values, buttons and the six byte
+joystick payload, plus the ADC scaling the controller
+does before encoding.
+packet: the versioned packet header every datagram starts
+with.
+status: what the pi reports back to the controller.
+auth: HMAC signing of packets with a pre-shared key and
+a counter against replays.
+
+no_std and no allocation so it builds for any target.
+
+*/
+
+#![no_std]
+
+pub mod auth;
+pub mod joystick;
+pub mod packet;
+pub mod protocol_error;
+pub mod status;
+
+pub use protocol_error::ProtocolError;
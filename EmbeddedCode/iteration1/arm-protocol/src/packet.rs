@@ -0,0 +1,314 @@
+/*
+William Albertini
+
+Packet header every datagram starts with:
+
+    byte 0-1   magic 0xCA 0x5A
+    byte 2     version (PACKET_VERSION)
+    byte 3     flags
+    byte 4-5   sequence number (big endian, wraps)
+    byte 6     payload length
+    byte 7-    payload
+
+Anything with the wrong magic, an unknown version, or a
+length that does not match the datagram is rejected.
+What to do about sequence numbers is up to the receiver.
+
+A controller sends either joystick input or, when the
+sticks are idle, a heartbeat (FLAG_HEARTBEAT, no payload)
+so the receiver can tell an idle operator from a dead link.
+
+PacketSequencer is the sending side, it numbers packets
+and flags the first one of a session so the receiver
+knows the counter was reset.
+
+Joystick packets with FLAG_JOINT_JOG set move the arm joint
+by joint (x shoulder, y elbow, pitch wrist) instead of moving
+the end effector. The controller sets it on every packet
+while the operator has joint jog toggled on, so a lost packet
+can not leave the two ends disagreeing.
+
+Signed packets (FLAG_AUTHENTICATED) carry a trailer after
+the payload that is not counted in the payload length,
+auth.rs checks and removes it before decoding.
+
+*/
+
+// internal imports
+use crate::joystick::{JoystickPayload, JOYSTICK_PAYLOAD_SIZE};
+use crate::protocol_error::ProtocolError;
+
+
+pub const PACKET_MAGIC: [u8; 2] = [0xCA, 0x5A];
+pub const PACKET_VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 7;
+pub const JOYSTICK_PACKET_SIZE: usize = HEADER_SIZE + JOYSTICK_PAYLOAD_SIZE;
+
+// first packet since the sender booted, resets sequence tracking
+pub const FLAG_SESSION_START: u8 = 0x01;
+// no payload, just says the sender is still there
+pub const FLAG_HEARTBEAT: u8 = 0x02;
+// status going back to the controller (status.rs)
+pub const FLAG_STATUS: u8 = 0x04;
+// followed by a counter and HMAC tag (auth.rs)
+pub const FLAG_AUTHENTICATED: u8 = 0x08;
+// joystick axes are joint increments, not end effector moves
+pub const FLAG_JOINT_JOG: u8 = 0x10;
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PacketHeader
+{
+    pub version: u8,
+    pub flags: u8,
+    pub sequence: u16,
+    pub payload_length: u8,
+}
+
+impl PacketHeader
+{
+    pub fn new(flags: u8, sequence: u16, payload_length: u8) -> PacketHeader
+    {
+        PacketHeader { version: PACKET_VERSION, flags, sequence, payload_length }
+    }
+
+    pub fn has_flag(&self, flag: u8) -> bool
+    {
+        self.flags & flag != 0
+    }
+}
+
+
+pub fn encode_packet(header: &PacketHeader, payload: &[u8], buffer: &mut [u8]) -> Result<usize, ProtocolError>
+{
+    // returns how much of the buffer the packet used
+    let needed = HEADER_SIZE + payload.len();
+    if buffer.len() < needed
+    {
+        return Err(ProtocolError::BufferTooSmall { needed, available: buffer.len() });
+    }
+
+    buffer[0..2].copy_from_slice(&PACKET_MAGIC);
+    buffer[2] = header.version;
+    buffer[3] = header.flags;
+    buffer[4..6].copy_from_slice(&header.sequence.to_be_bytes());
+    buffer[6] = payload.len() as u8;
+    buffer[HEADER_SIZE..needed].copy_from_slice(payload);
+    Ok(needed)
+}
+
+pub fn decode_packet(datagram: &[u8]) -> Result<(PacketHeader, &[u8]), ProtocolError>
+{
+    if datagram.len() < HEADER_SIZE
+    {
+        return Err(ProtocolError::TooShort(datagram.len()));
+    }
+    if datagram[0..2] != PACKET_MAGIC
+    {
+        return Err(ProtocolError::BadMagic([datagram[0], datagram[1]]));
+    }
+
+    let header = PacketHeader {
+        version: datagram[2],
+        flags: datagram[3],
+        sequence: u16::from_be_bytes([datagram[4], datagram[5]]),
+        payload_length: datagram[6],
+    };
+    if header.version != PACKET_VERSION
+    {
+        return Err(ProtocolError::UnsupportedVersion(header.version));
+    }
+
+    let payload = &datagram[HEADER_SIZE..];
+    if payload.len() != header.payload_length as usize
+    {
+        return Err(ProtocolError::LengthMismatch { expected: header.payload_length as usize, actual: payload.len() });
+    }
+    Ok((header, payload))
+}
+
+pub fn decode_joystick_packet(datagram: &[u8]) -> Result<(PacketHeader, JoystickPayload), ProtocolError>
+{
+    let (header, payload) = decode_packet(datagram)?;
+    Ok((header, JoystickPayload::from_bytes(payload)?))
+}
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ControllerPacket
+{
+    Joystick(JoystickPayload),
+    Heartbeat,
+}
+
+pub fn decode_controller_packet(datagram: &[u8]) -> Result<(PacketHeader, ControllerPacket), ProtocolError>
+{
+    let (header, payload) = decode_packet(datagram)?;
+    if !header.has_flag(FLAG_HEARTBEAT)
+    {
+        return Ok((header, ControllerPacket::Joystick(JoystickPayload::from_bytes(payload)?)));
+    }
+
+    // heartbeats carry nothing
+    if !payload.is_empty()
+    {
+        return Err(ProtocolError::LengthMismatch { expected: 0, actual: payload.len() });
+    }
+    Ok((header, ControllerPacket::Heartbeat))
+}
+
+
+pub struct PacketSequencer
+{
+    sequence: u16,
+    session_started: bool,
+}
+
+impl PacketSequencer
+{
+    pub fn new() -> PacketSequencer
+    {
+        PacketSequencer { sequence: 0, session_started: false }
+    }
+
+    pub fn next_header(&mut self, payload_length: u8) -> PacketHeader
+    {
+        // only the first packet of the session is flagged
+        let flags = if self.session_started { 0 } else { FLAG_SESSION_START };
+        self.session_started = true;
+
+        let header = PacketHeader::new(flags, self.sequence, payload_length);
+        self.sequence = self.sequence.wrapping_add(1);
+        header
+    }
+
+    pub fn joystick_packet(&mut self, payload: &JoystickPayload) -> [u8; JOYSTICK_PACKET_SIZE]
+    {
+        let header = self.next_header(JOYSTICK_PAYLOAD_SIZE as u8);
+        let mut packet = [0; JOYSTICK_PACKET_SIZE];
+        // the array is always big enough
+        let _ = encode_packet(&header, &payload.to_bytes(), &mut packet);
+        packet
+    }
+
+    pub fn joint_jog_packet(&mut self, payload: &JoystickPayload) -> [u8; JOYSTICK_PACKET_SIZE]
+    {
+        let mut header = self.next_header(JOYSTICK_PAYLOAD_SIZE as u8);
+        header.flags |= FLAG_JOINT_JOG;
+        let mut packet = [0; JOYSTICK_PACKET_SIZE];
+        let _ = encode_packet(&header, &payload.to_bytes(), &mut packet);
+        packet
+    }
+
+    pub fn heartbeat_packet(&mut self) -> [u8; HEADER_SIZE]
+    {
+        let mut header = self.next_header(0);
+        header.flags |= FLAG_HEARTBEAT;
+        let mut packet = [0; HEADER_SIZE];
+        let _ = encode_packet(&header, &[], &mut packet);
+        packet
+    }
+}
+
+impl Default for PacketSequencer
+{
+    fn default() -> PacketSequencer
+    {
+        PacketSequencer::new()
+    }
+}
+
+
+
+
+// ------------------------------- unit tests -------------------------------------
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_packet_round_trip()
+    {
+        let header = PacketHeader::new(FLAG_SESSION_START, 0xBEEF, 6);
+        let mut packet = [0; 32];
+        let len = encode_packet(&header, &[4, 0, 0x20, 0, 1, 1], &mut packet).unwrap();
+        let (decoded, payload) = decode_packet(&packet[..len]).unwrap();
+
+        assert_eq!(len, JOYSTICK_PACKET_SIZE);
+        assert_eq!(&packet[..7], &[0xCA, 0x5A, 1, 1, 0xBE, 0xEF, 6]);
+        assert_eq!(decoded, header);
+        assert_eq!(payload, &[4, 0, 0x20, 0, 1, 1]);
+    }
+
+    #[test]
+    fn test_malformed_rejected()
+    {
+        let good = PacketSequencer::new().joystick_packet(&JoystickPayload::default());
+        let mut bad_magic = good;
+        bad_magic[0] = 0;
+        let mut bad_version = good;
+        bad_version[2] = 9;
+        let mut bad_length = good;
+        bad_length[6] = 5;
+
+        assert_eq!(decode_packet(&good[..3]), Err(ProtocolError::TooShort(3)));
+        assert_eq!(decode_packet(&bad_magic), Err(ProtocolError::BadMagic([0, 0x5A])));
+        assert_eq!(decode_packet(&bad_version), Err(ProtocolError::UnsupportedVersion(9)));
+        assert_eq!(decode_packet(&bad_length), Err(ProtocolError::LengthMismatch { expected: 5, actual: 6 }));
+        assert_eq!(decode_packet(&good[..12]), Err(ProtocolError::LengthMismatch { expected: 6, actual: 5 }));
+        // the bare six bytes the controller used to send
+        assert_eq!(decode_packet(&[4, 0, 0, 0, 1, 1]), Err(ProtocolError::TooShort(6)));
+    }
+
+    #[test]
+    fn test_buffer_too_small()
+    {
+        let mut packet = [0; 8];
+        assert_eq!(encode_packet(&PacketHeader::new(0, 0, 6), &[0; 6], &mut packet),
+                   Err(ProtocolError::BufferTooSmall { needed: 13, available: 8 }));
+    }
+
+    #[test]
+    fn test_sequencer()
+    {
+        let mut sequencer = PacketSequencer::new();
+        let payload = JoystickPayload { x: -3, pitch: 8, button1_pressed: true, ..JoystickPayload::default() };
+        let first = decode_joystick_packet(&sequencer.joystick_packet(&payload)).unwrap();
+        let second = decode_joystick_packet(&sequencer.joystick_packet(&payload)).unwrap();
+
+        assert_eq!(first.0, PacketHeader::new(FLAG_SESSION_START, 0, 6));
+        assert_eq!(second.0, PacketHeader::new(0, 1, 6));
+        assert_eq!(first.1, payload);
+    }
+
+    #[test]
+    fn test_joint_jog()
+    {
+        // same payload, flagged
+        let mut sequencer = PacketSequencer::new();
+        let payload = JoystickPayload { x: 2, ..JoystickPayload::default() };
+        let (header, packet) = decode_controller_packet(&sequencer.joint_jog_packet(&payload)).unwrap();
+
+        assert!(header.has_flag(FLAG_JOINT_JOG));
+        assert_eq!(packet, ControllerPacket::Joystick(payload));
+        assert!(!decode_packet(&sequencer.joystick_packet(&payload)).unwrap().0.has_flag(FLAG_JOINT_JOG));
+    }
+
+    #[test]
+    fn test_heartbeat()
+    {
+        // heartbeats share the sequence numbers with joystick packets
+        let mut sequencer = PacketSequencer::new();
+        let first = decode_controller_packet(&sequencer.heartbeat_packet()).unwrap();
+        let second = decode_controller_packet(&sequencer.joystick_packet(&JoystickPayload::default())).unwrap();
+
+        assert_eq!(first, (PacketHeader::new(FLAG_SESSION_START | FLAG_HEARTBEAT, 0, 0), ControllerPacket::Heartbeat));
+        assert_eq!(second, (PacketHeader::new(0, 1, 6), ControllerPacket::Joystick(JoystickPayload::default())));
+
+        // a heartbeat with a payload is malformed
+        let mut packet = [0; 16];
+        let len = encode_packet(&PacketHeader::new(FLAG_HEARTBEAT, 2, 1), &[0], &mut packet).unwrap();
+        assert_eq!(decode_controller_packet(&packet[..len]), Err(ProtocolError::LengthMismatch { expected: 0, actual: 1 }));
+    }
+}
@@ -0,0 +1,70 @@
+/*
+William Albertini
+
+Errors from decoding the wire protocol. No strings here
+(no_std, no alloc), the receiving side turns these into
+its own error type.
+
+*/
+
+use core::fmt;
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ProtocolError
+{
+    // datagram shorter than a header
+    TooShort(usize),
+    BadMagic([u8; 2]),
+    UnsupportedVersion(u8),
+    // payload length in the header vs what actually arrived
+    LengthMismatch { expected: usize, actual: usize },
+    // both nibbles set, or a magnitude past AXIS_MAX
+    InvalidAxis(u8),
+    // buttons are 0 (pressed) or 1 (released)
+    InvalidButton(u8),
+    // mode byte in a status packet that is not an ArmMode
+    InvalidMode(u8),
+    // packet flags do not match what was expected (flags given)
+    UnexpectedPacket(u8),
+    // output buffer can not hold the packet
+    BufferTooSmall { needed: usize, available: usize },
+    // pre-shared key shorter than MIN_KEY_SIZE
+    KeyTooShort(usize),
+    // pre-shared key that is public or trivial (auth::check_key)
+    WeakKey,
+    // key text has a character that is not a hex digit, or an odd one out
+    InvalidKeyHex(u8),
+    // packet has no FLAG_AUTHENTICATED trailer
+    NotAuthenticated,
+    // HMAC tag does not match, tampered or signed with another key
+    BadSignature,
+}
+
+
+impl fmt::Display for ProtocolError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            ProtocolError::TooShort(len) => write!(f, "Datagram too short for a header ({len} bytes)"),
+            ProtocolError::BadMagic(magic) => write!(f, "Bad magic {:02x}{:02x}", magic[0], magic[1]),
+            ProtocolError::UnsupportedVersion(version) => write!(f, "Unsupported packet version {version}"),
+            ProtocolError::LengthMismatch { expected, actual } => write!(f, "Payload length {actual} does not match header ({expected})"),
+            ProtocolError::InvalidAxis(byte) => write!(f, "Invalid axis byte {byte:#04x}"),
+            ProtocolError::InvalidButton(byte) => write!(f, "Invalid button byte {byte:#04x}"),
+            ProtocolError::InvalidMode(byte) => write!(f, "Invalid arm mode {byte}"),
+            ProtocolError::UnexpectedPacket(flags) => write!(f, "Unexpected packet (flags {flags:#04x})"),
+            ProtocolError::BufferTooSmall { needed, available } => write!(f, "Packet needs {needed} bytes, buffer holds {available}"),
+            ProtocolError::KeyTooShort(len) => write!(f, "Key too short ({len} bytes)"),
+            ProtocolError::WeakKey => write!(f, "Key is a known or trivial key"),
+            ProtocolError::InvalidKeyHex(digit) => write!(f, "Key is not hex (at {:?})", *digit as char),
+            ProtocolError::NotAuthenticated => write!(f, "Packet is not signed"),
+            ProtocolError::BadSignature => write!(f, "Packet signature does not match"),
+        }
+    }
+}
+
+// so std callers can keep it as the source of their own errors
+impl core::error::Error for ProtocolError {}
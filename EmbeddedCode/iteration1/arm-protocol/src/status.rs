@@ -0,0 +1,222 @@
+/*
+William Albertini
+
+Status the pi sends back to the controller, so the
+operator can see what the arm is doing without a screen.
+Status packets use the same header as everything else
+with FLAG_STATUS set. The payload (STATUS_PAYLOAD_SIZE
+bytes, all big endian) is:
+
+    byte 0-1   end effector x (mm, i16)
+    byte 2-3   end effector y (mm, i16)
+    byte 4-5   end effector si (milliradians, i16)
+    byte 6     IK flags (IK_*)
+    byte 7     mode (ArmMode)
+    byte 8     faults (FAULT_*)
+    byte 9-10  battery (mV, BATTERY_UNKNOWN if not measured)
+
+*/
+
+// internal imports
+use crate::packet::{decode_packet, encode_packet, PacketHeader, PacketSequencer, FLAG_STATUS, HEADER_SIZE};
+use crate::protocol_error::ProtocolError;
+
+
+pub const STATUS_PAYLOAD_SIZE: usize = 11;
+pub const STATUS_PACKET_SIZE: usize = HEADER_SIZE + STATUS_PAYLOAD_SIZE;
+
+// why the last target was rejected by the IK solver
+pub const IK_SINGULARITY: u8 = 0x01;
+pub const IK_OUT_OF_WORKSPACE: u8 = 0x02;
+pub const IK_JOINT_LIMIT: u8 = 0x04;
+
+// faults on the pi side
+pub const FAULT_LINK_LOST: u8 = 0x01;
+
+pub const BATTERY_UNKNOWN: u16 = 0xFFFF;
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ArmMode
+{
+    // following joystick input
+    Teleop = 0,
+    // motors holding, waiting to be re-armed
+    Hold = 1,
+    // starting up
+    Boot = 2,
+    // position not trusted until the arm is homed
+    Unhomed = 3,
+    Homing = 4,
+    // homed, waiting for a mode to be picked
+    Idle = 5,
+    // following commands from a script or ground station
+    Script = 6,
+    // something went wrong, holding until re-armed
+    Fault = 7,
+    // too many faults, holding until homed again
+    SafeMode = 8,
+}
+
+impl ArmMode
+{
+    pub fn from_u8(byte: u8) -> Result<ArmMode, ProtocolError>
+    {
+        match byte
+        {
+            0 => Ok(ArmMode::Teleop),
+            1 => Ok(ArmMode::Hold),
+            2 => Ok(ArmMode::Boot),
+            3 => Ok(ArmMode::Unhomed),
+            4 => Ok(ArmMode::Homing),
+            5 => Ok(ArmMode::Idle),
+            6 => Ok(ArmMode::Script),
+            7 => Ok(ArmMode::Fault),
+            8 => Ok(ArmMode::SafeMode),
+            _ => Err(ProtocolError::InvalidMode(byte)),
+        }
+    }
+
+    pub fn accepts_motion(&self) -> bool
+    {
+        // the arm is following commands, anything else is some kind of hold
+        matches!(self, ArmMode::Teleop | ArmMode::Script | ArmMode::Homing)
+    }
+}
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct StatusPayload
+{
+    // [x mm, y mm, si milliradians]
+    pub pose: [i16; 3],
+    pub ik_flags: u8,
+    pub mode: ArmMode,
+    pub faults: u8,
+    pub battery_mv: u16,
+}
+
+impl StatusPayload
+{
+    pub fn ik_rejected(&self) -> bool
+    {
+        self.ik_flags != 0
+    }
+
+    pub fn has_fault(&self, fault: u8) -> bool
+    {
+        self.faults & fault != 0
+    }
+
+    pub fn to_bytes(&self) -> [u8; STATUS_PAYLOAD_SIZE]
+    {
+        let mut bytes = [0; STATUS_PAYLOAD_SIZE];
+        for (i, value) in self.pose.iter().enumerate()
+        {
+            bytes[2 * i..2 * i + 2].copy_from_slice(&value.to_be_bytes());
+        }
+        bytes[6] = self.ik_flags;
+        bytes[7] = self.mode as u8;
+        bytes[8] = self.faults;
+        bytes[9..11].copy_from_slice(&self.battery_mv.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<StatusPayload, ProtocolError>
+    {
+        if bytes.len() != STATUS_PAYLOAD_SIZE
+        {
+            return Err(ProtocolError::LengthMismatch { expected: STATUS_PAYLOAD_SIZE, actual: bytes.len() });
+        }
+
+        Ok(StatusPayload {
+            pose: [i16::from_be_bytes([bytes[0], bytes[1]]),
+                   i16::from_be_bytes([bytes[2], bytes[3]]),
+                   i16::from_be_bytes([bytes[4], bytes[5]])],
+            ik_flags: bytes[6],
+            mode: ArmMode::from_u8(bytes[7])?,
+            faults: bytes[8],
+            battery_mv: u16::from_be_bytes([bytes[9], bytes[10]]),
+        })
+    }
+}
+
+
+pub fn status_packet(sequencer: &mut PacketSequencer, status: &StatusPayload) -> [u8; STATUS_PACKET_SIZE]
+{
+    let mut header = sequencer.next_header(STATUS_PAYLOAD_SIZE as u8);
+    header.flags |= FLAG_STATUS;
+    let mut packet = [0; STATUS_PACKET_SIZE];
+    // the array is always big enough
+    let _ = encode_packet(&header, &status.to_bytes(), &mut packet);
+    packet
+}
+
+pub fn decode_status_packet(datagram: &[u8]) -> Result<(PacketHeader, StatusPayload), ProtocolError>
+{
+    let (header, payload) = decode_packet(datagram)?;
+    if !header.has_flag(FLAG_STATUS)
+    {
+        return Err(ProtocolError::UnexpectedPacket(header.flags));
+    }
+    Ok((header, StatusPayload::from_bytes(payload)?))
+}
+
+
+
+
+// ------------------------------- unit tests -------------------------------------
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::joystick::JoystickPayload;
+
+    fn test_status() -> StatusPayload
+    {
+        StatusPayload {
+            pose: [1800, -250, -1571],
+            ik_flags: IK_OUT_OF_WORKSPACE,
+            mode: ArmMode::Hold,
+            faults: FAULT_LINK_LOST,
+            battery_mv: 7400,
+        }
+    }
+
+    #[test]
+    fn test_status_round_trip()
+    {
+        let mut sequencer = PacketSequencer::new();
+        let (header, status) = decode_status_packet(&status_packet(&mut sequencer, &test_status())).unwrap();
+
+        assert!(header.has_flag(FLAG_STATUS));
+        assert_eq!(status, test_status());
+        assert!(status.ik_rejected());
+        assert!(status.has_fault(FAULT_LINK_LOST));
+        assert!(!status.has_fault(0x02));
+    }
+
+    #[test]
+    fn test_every_mode_round_trips()
+    {
+        for byte in 0..=8
+        {
+            assert_eq!(ArmMode::from_u8(byte).unwrap() as u8, byte);
+        }
+        assert!(ArmMode::Script.accepts_motion());
+        assert!(!ArmMode::Fault.accepts_motion());
+    }
+
+    #[test]
+    fn test_status_rejects_other_packets()
+    {
+        // a joystick packet is not a status packet, and unknown modes are caught
+        let mut sequencer = PacketSequencer::new();
+        let joystick = sequencer.joystick_packet(&JoystickPayload::default());
+        let mut bad_mode = status_packet(&mut sequencer, &test_status());
+        bad_mode[HEADER_SIZE + 7] = 42;
+
+        assert!(matches!(decode_status_packet(&joystick), Err(ProtocolError::UnexpectedPacket(_))));
+        assert_eq!(decode_status_packet(&bad_mode), Err(ProtocolError::InvalidMode(42)));
+    }
+}
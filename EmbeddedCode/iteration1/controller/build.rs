@@ -1,3 +1,5 @@
 fn main() {
     embuild::espidf::sysenv::output();
+    // the pre-shared key is built in (env! in main.rs)
+    println!("cargo:rerun-if-env-changed=ARM_AUTH_KEY");
 }
@@ -4,18 +4,16 @@ William Albertini
 This module is built to make sending the data easier.
 It provides a single struct that contains all user
 input. This can be sent via UDP/IP to the
-raspberry pi.
+raspberry pi. The encoding itself lives in the shared
+arm_protocol crate.
 */
 
+use arm_protocol::joystick::JoystickPayload;
+
 
 pub struct DataHandler
 {
-    x: u8,
-    y: u8,
-    pitch: u8,
-    roll: u8,
-    button1: u8,
-    button2: u8,
+    payload: JoystickPayload,
 }
 
 impl DataHandler 
@@ -24,48 +22,31 @@ impl DataHandler
     pub fn new() -> DataHandler 
     {
         DataHandler{
-            x: 0,
-            y: 0,
-            roll: 0,
-            pitch: 0,
-            button1: 1,
-            button2: 1,
+            payload: JoystickPayload::default(),
         }
     }
 
-    pub fn update(&mut self, joystick1: [u8; 3], joystick2: [u8; 3]) 
+    pub fn update(&mut self, joystick1: ([i8; 2], bool), joystick2: ([i8; 2], bool)) 
     {
         // unpack joystick 1
-        self.x = joystick1[0];
-        self.y = joystick1[1];
-        self.button1 = joystick1[2];
+        self.payload.x = joystick1.0[0];
+        self.payload.y = joystick1.0[1];
+        self.payload.button1_pressed = joystick1.1;
 
         // unpack joystick 2
-        self.roll = joystick2[0];
-        self.pitch = joystick2[1];
-        self.button2 = joystick2[2]
+        self.payload.roll = joystick2.0[0];
+        self.payload.pitch = joystick2.0[1];
+        self.payload.button2_pressed = joystick2.1;
     }
 
     pub fn input_detected(&self) -> bool 
     {
         // checks to see if user input has been given
-        if  self.x != 0 || 
-            self.y != 0 || 
-            self.button1 == 0 ||
-            self.pitch != 0 ||
-            self.roll != 0 ||
-            self.button2 == 0
-        {
-            return true
-        }
-        false
+        self.payload != JoystickPayload::default()
     }
 
-    pub fn data_as_bytes(&self) -> [u8; 6]
+    pub fn get_payload(&self) -> &JoystickPayload
     {
-        // input values are sent over udp and always in known order
-        // for each individual datagram
-        [self.x, self.y, self.roll, self.pitch,
-        self.button1, self.button2]
+        &self.payload
     }
-}
\ No newline at end of file
+}
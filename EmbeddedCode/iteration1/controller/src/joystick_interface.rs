@@ -5,6 +5,9 @@ This module handles the hardware interface. It acts as a high
 level driver for the joystick interface. Joystick pins
 utilize the ADC and must use ADC1 as the ADC2 and Wifi
 should not be used together as recomended by espressif
+
+Readings are scaled to axis values with the shared
+arm_protocol crate so the pi decodes exactly what is sent.
 */
 use esp_idf_svc::hal;
 use hal::gpio::{
@@ -20,6 +23,7 @@ use hal::adc::{
     ADC1,
 };
 use esp_idf_svc::hal::sys::EspError;
+use arm_protocol::joystick::scale_adc;
 
 // create joystick object to handle interfacing
 pub struct JoyStick<'a, T, U, V>
@@ -49,40 +53,15 @@ where
         JoyStick{x, y, button}
     }
 
-    pub fn sample(&mut self, adc_access: &mut AdcDriver<ADC1>) -> anyhow::Result<[u8; 3], EspError>
+    pub fn sample(&mut self, adc_access: &mut AdcDriver<ADC1>) -> anyhow::Result<([i8; 2], bool), EspError>
     {
         // read values from all sensors
         let x_val: u16 = adc_access.read(&mut self.x)?;
         let y_val: u16 = adc_access.read(&mut self.y)?;
-        let button_val: u8 = self.button.is_high() as u8;
-
-        // return values in array
-        Ok([self.scale_value(x_val), 
-            self.scale_value(y_val), 
-            button_val])
-    }
-
-    fn scale_value(&self, value: u16) -> u8 
-    {
-        // scale values of sensor readings
-        // this range marks no change
-        if value > 1550 && value < 1750 
-        {
-            return 0 as u8
-        } 
-        else if value < 1550  // this indicates a left movement
-        {
-            return (8 - (value * 8 / 1550) ) as u8
-        }
-
-        else // this indicates a right movement (will be bit shifter back later to 0-8 range)
-        {
-            // perform scaling (values dont reach 2814 only 2765 for some reason)
-            let mut value = (value * 8 / (2765 - 1750) - 13);
-            if value > 8 {value = 8};
+        // pin is pulled up, low means pressed
+        let pressed: bool = self.button.is_low();
 
-            return (value << 4) as u8
-        }
-            
+        // return scaled axis values and the button
+        Ok(([scale_adc(x_val), scale_adc(y_val)], pressed))
     }
 }
\ No newline at end of file
@@ -7,7 +7,34 @@ connection with a pre-defined IPV4 address
 and port. Once connection is established, 
 joysticks are sampled at a given rate. When 
 an input is detected, this data is sent over
-the network to the main computer.
+the network to the main computer, wrapped in a
+numbered packet (arm_protocol). When the sticks
+are idle a heartbeat is sent instead, so the pi
+knows the controller is still there (it holds
+the arm if both stop arriving).
+
+Every packet is signed with the pre-shared key
+(arm_protocol::auth), the pi is given the same
+key and drops anything that is not signed with
+it. The key is never in the repo: it is given
+in hex when building,
+
+    ARM_AUTH_KEY=$(cat arm.key) cargo build --release
+
+and the build fails without it. A short,
+trivial or known key is refused at start up
+before anything is signed. The signature
+counter starts at the boot count kept in
+flash, so packets from an earlier boot can
+not be replayed at the pi.
+
+The jog button toggles joint jog: while it is on
+the sticks move the shoulder, elbow and wrist
+directly (FLAG_JOINT_JOG) instead of the end
+effector, for getting out of singularities.
+
+Status sent back by the pi is shown on an LED
+and buzzer (status_display.rs).
 */
 
 
@@ -29,7 +56,7 @@ use hal::adc::{
 // wifi imports
 use hal::modem::Modem;
 use esp_idf_svc::eventloop::EspSystemEventLoop;
-use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
 use esp_idf_svc::wifi::{
     AuthMethod,
     Configuration,
@@ -45,19 +72,31 @@ use std::io::Write;
 // internal imports
 mod joystick_interface;
 mod data_handler;
+mod status_display;
 use joystick_interface::JoyStick;
 use data_handler::DataHandler;
+use status_display::StatusDisplay;
+use arm_protocol::auth::{decode_hex_key, PacketSigner, AUTH_TRAILER_SIZE};
+use arm_protocol::packet::{PacketSequencer, JOYSTICK_PACKET_SIZE};
+use arm_protocol::status::{decode_status_packet, StatusPayload};
 
 // wifi credentials
 const SSID: &str = "will_ipad";
 const PASSWORD: &str = "ubuntu707";
 const IPADDR: &str = "172.20.10.3:8001";
+// pre-shared key in hex, the pi reads the same hex from its --key-file
+const AUTH_KEY_HEX: &str = env!("ARM_AUTH_KEY", "set ARM_AUTH_KEY to the pre-shared key (hex) when building");
 
 
 fn main() -> anyhow::Result<()> {
     // necessary to call this function once
     esp_idf_svc::sys::link_patches();
 
+    // no point starting up with a key the pi should not trust
+    let mut key_buffer = [0u8; 64];
+    let auth_key = decode_hex_key(AUTH_KEY_HEX, &mut key_buffer)
+        .map_err(|e| anyhow::anyhow!("ARM_AUTH_KEY can not be used: {e}"))?;
+
     // make peripherals accessible
     let dp = Peripherals::take()?;
     // set up delays
@@ -85,9 +124,26 @@ fn main() -> anyhow::Result<()> {
         button2,
     );
 
+    // joint jog toggle
+    let mut jog_button = PinDriver::input(dp.pins.gpio6)?;
+    jog_button.set_pull(Pull::Up)?;
+    let mut joint_jog = false;
+    let mut jog_button_was_pressed = false;
+
+    // status LED and buzzer
+    let mut display = StatusDisplay::new(
+        PinDriver::output(dp.pins.gpio4)?,
+        PinDriver::output(dp.pins.gpio5)?,
+    );
+
     // ------------------------------ WIFI Pin Setup -------------------------------------------
+    // flash is shared by the wifi driver and the boot counter
+    let nvs = EspDefaultNvsPartition::take()?;
+    let boot_count = next_boot_count(nvs.clone())?;
+    println!("boot {}", boot_count);
+
     // setup network connection
-    let wifi = match setup_wifi(dp.modem) {
+    let wifi = match setup_wifi(dp.modem, nvs) {
         Ok(wifi) => wifi,
         Err(_e) => panic!("Could not connect to wifi")
     };
@@ -100,6 +156,11 @@ fn main() -> anyhow::Result<()> {
 
     // create data handler
     let mut input = DataHandler::new();
+    // numbers every packet sent
+    let mut sequencer = PacketSequencer::new();
+    // and signs it
+    let mut signer = PacketSigner::new(auth_key, boot_count);
+    let mut signed = [0u8; JOYSTICK_PACKET_SIZE + AUTH_TRAILER_SIZE];
 
     loop {
         // sample the joysticks
@@ -107,28 +168,52 @@ fn main() -> anyhow::Result<()> {
         let ang_mov = joystick2.sample(&mut adc1)?;
         input.update(trans_mov, ang_mov);
 
-        // if theres input, send it to server
-        if input.input_detected()
+        // each press of the jog button switches between end effector and joint jog (pulled up, low is pressed)
+        let jog_button_pressed = jog_button.is_low();
+        if jog_button_pressed && !jog_button_was_pressed
+        {
+            joint_jog = !joint_jog;
+            println!("joint jog {}", if joint_jog { "on" } else { "off" });
+        }
+        jog_button_was_pressed = jog_button_pressed;
+
+        // if theres input, send it to server, otherwise just say we are still here
+        let length = if input.input_detected()
         {
             println!("User Input {:?}, {:?}", trans_mov, ang_mov);
-            // if connection is broken, wait until reconnect
-            if let Err(_) = socket.send(&input.data_as_bytes())
+            let packet = if joint_jog
             {
-                socket = wait_for_udp_connection(delay);
-            }
+                sequencer.joint_jog_packet(input.get_payload())
+            } else {
+                sequencer.joystick_packet(input.get_payload())
+            };
+            signer.sign(&packet, &mut signed)
+        } else {
+            signer.sign(&sequencer.heartbeat_packet(), &mut signed)
+        };
+        // the buffer fits the biggest packet, this only fails on a bad key
+        let length = length.expect("could not sign packet");
+        let sent = socket.send(&signed[..length]);
 
+        // if connection is broken, wait until reconnect
+        if sent.is_err()
+        {
+            println!("send failed, reconnecting");
+            socket = wait_for_udp_connection(delay);
         }
+
+        // show whatever the pi last told us
+        display.show(receive_status(&socket))?;
         delay.delay_ms(100);
     }
 }
 
 
 // -------------------------- wifi --------------------------------------
-fn setup_wifi(modem: Modem) -> anyhow::Result<EspWifi<'static>> 
+fn setup_wifi(modem: Modem, nvs: EspDefaultNvsPartition) -> anyhow::Result<EspWifi<'static>> 
 {
     // create a new event loop (configuring wifi happens here I think)
     let sysloop = EspSystemEventLoop::take()?;
-    let nvs = EspDefaultNvsPartition::take()?;
 
     // create wifi driver
     let mut wifi: EspWifi = EspWifi::new(modem, sysloop, Some(nvs))?;
@@ -158,6 +243,31 @@ fn setup_wifi(modem: Modem) -> anyhow::Result<EspWifi<'static>>
     Ok(wifi)
 }
 
+// count this boot in flash and return it, the signature counter starts from it
+fn next_boot_count(partition: EspDefaultNvsPartition) -> anyhow::Result<u32>
+{
+    let mut nvs: EspNvs<NvsDefault> = EspNvs::new(partition, "arm", true)?;
+    let boot_count = nvs.get_u32("boot_count")?.unwrap_or(0).wrapping_add(1);
+    nvs.set_u32("boot_count", boot_count)?;
+    Ok(boot_count)
+}
+
+// newest status waiting on the socket, if any (socket is non blocking)
+fn receive_status(socket: &UdpSocket) -> Option<StatusPayload>
+{
+    let mut buffer = [0u8; 64];
+    let mut status = None;
+    while let Ok(amount) = socket.recv(&mut buffer)
+    {
+        match decode_status_packet(&buffer[..amount])
+        {
+            Ok((_, payload)) => status = Some(payload),
+            Err(e) => println!("bad status: {}", e),
+        }
+    }
+    status
+}
+
 // wait until UDP connection and return socket
 fn wait_for_udp_connection(delay: Delay) -> UdpSocket
 {
@@ -166,6 +276,8 @@ fn wait_for_udp_connection(delay: Delay) -> UdpSocket
     loop {
         // check to see if connection was established, if not continue loop
         if let Ok(_) = socket.connect(IPADDR) {
+            // status is polled every loop, never wait on it
+            socket.set_nonblocking(true).expect("could not set non blocking");
             return socket;
         }
         delay.delay_ms(250);
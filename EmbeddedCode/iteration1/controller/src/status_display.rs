@@ -0,0 +1,116 @@
+/*
+William Albertini
+
+This module shows the status the raspberry pi sends back
+(arm_protocol::status) to the operator with an LED and a
+buzzer, there is no screen on the controller.
+
+LED:
+    off         arm is following the joysticks
+    solid       last target was rejected (out of workspace,
+                singularity or joint limit)
+    blinking    arm is not following commands (holding,
+                unhomed, faulted), see ArmMode
+Buzzer:
+    one short beep when a target is first rejected
+    three beeps when the battery runs low
+*/
+use esp_idf_svc::hal;
+use hal::gpio::{
+    Output,
+    OutputPin,
+    PinDriver,
+};
+use hal::sys::EspError;
+use arm_protocol::status::{StatusPayload, BATTERY_UNKNOWN};
+
+// below this the battery is reported as low (mV)
+const LOW_BATTERY_MV: u16 = 6800;
+// display updates per blink of the LED
+const BLINK_TICKS: u32 = 5;
+
+
+pub struct StatusDisplay<'a, L, B>
+where
+    L: OutputPin,
+    B: OutputPin,
+{
+    led: PinDriver<'a, L, Output>,
+    buzzer: PinDriver<'a, B, Output>,
+    // what was shown last, so beeps only happen on changes
+    last: Option<StatusPayload>,
+    ticks: u32,
+}
+
+
+impl<'a, L, B> StatusDisplay<'a, L, B>
+where
+    L: OutputPin,
+    B: OutputPin,
+{
+    pub fn new(led: PinDriver<'a, L, Output>, buzzer: PinDriver<'a, B, Output>) -> StatusDisplay<'a, L, B>
+    {
+        StatusDisplay{
+            led,
+            buzzer,
+            last: None,
+            ticks: 0,
+        }
+    }
+
+    pub fn show(&mut self, status: Option<StatusPayload>) -> Result<(), EspError>
+    {
+        // called every loop, with whatever status arrived this time (if any)
+        self.ticks = self.ticks.wrapping_add(1);
+        if let Some(status) = status
+        {
+            self.beep_on_change(&status)?;
+            self.last = Some(status);
+        }
+
+        let Some(status) = self.last else {
+            // nothing heard from the pi yet
+            return self.led.set_low();
+        };
+
+        if !status.mode.accepts_motion()
+        {
+            if (self.ticks / BLINK_TICKS) % 2 == 0 { self.led.set_high() } else { self.led.set_low() }
+        }
+        else if status.ik_rejected()
+        {
+            self.led.set_high()
+        } else {
+            self.led.set_low()
+        }
+    }
+
+    fn beep_on_change(&mut self, status: &StatusPayload) -> Result<(), EspError>
+    {
+        let was_rejected = self.last.map(|last| last.ik_rejected()).unwrap_or(false);
+        if status.ik_rejected() && !was_rejected
+        {
+            self.beep(1)?;
+        }
+
+        let low = |battery_mv: u16| battery_mv != BATTERY_UNKNOWN && battery_mv < LOW_BATTERY_MV;
+        let was_low = self.last.map(|last| low(last.battery_mv)).unwrap_or(false);
+        if low(status.battery_mv) && !was_low
+        {
+            self.beep(3)?;
+        }
+        Ok(())
+    }
+
+    fn beep(&mut self, count: u32) -> Result<(), EspError>
+    {
+        for _ in 0..count
+        {
+            self.buzzer.set_high()?;
+            std::thread::sleep(std::time::Duration::from_millis(60));
+            self.buzzer.set_low()?;
+            std::thread::sleep(std::time::Duration::from_millis(60));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,535 @@
+/*
+William Albertini
+
+ArmConfig holds everything main.rs used to hard code: where the UDP
+server listens, the arm geometry and starting pose, the encoder
+maps, which motor controller each joint is wired to, the SPI bus,
+the jog gains, velocity teleop, the gripper, backlash and sag
+compensation and the safety limits. It is read from a TOML file
+(robot-arm.toml next to Cargo.toml lists every key with its
+default). Any section or key left out of the file keeps its
+default, but [encoders] and [wiring] have to be given whole.
+
+Keys can be overridden from the command line with
+--set <section.key>=<value>, the value written as it would be
+in the file, e.g.
+
+    --set network.bind="127.0.0.1:8001"
+    --set arm.link_lengths=[900.0,500.0,300.0]
+    --set limits.joints.elbow=[-2.0,2.0]
+
+The whole config is checked before anything starts and every
+problem found is reported at once, instead of the first one
+turning up as a panic halfway through startup.
+
+*/
+
+// external imports
+use std::f64::consts::PI;
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::Path;
+use rppal::spi::Bus;
+use serde::{Deserialize, Serialize};
+
+// internal imports
+use crate::arm_errors::RoboticArmError;
+use crate::robotics::arm_state::{AngleToEncoderMap, Joint, JogGains, JointLimits, RoboticArmSolver};
+use crate::robotics::gripper::GripperConfig;
+use crate::robotics::joint_compensation::CompensationConfig;
+use crate::robotics::robot_driver::JointWiring;
+use crate::robotics::velocity_teleop::TeleopConfig;
+
+// motor controllers take 13 bit positions
+pub const MAX_ENCODER_TICKS: u16 = 1 << 13;
+// motor controllers on the bus, two motors each
+pub const MAC_COUNT: u8 = 3;
+pub const MOTORS_PER_MAC: u8 = 2;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig
+{
+    // the UDP server the controller sends to
+    pub bind: SocketAddrV4,
+}
+
+impl Default for NetworkConfig
+{
+    fn default() -> NetworkConfig
+    {
+        NetworkConfig { bind: SocketAddrV4::new(Ipv4Addr::new(172, 20, 10, 3), 8001) }
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GeometryConfig
+{
+    // shoulder to elbow, elbow to wrist, wrist to end effector (mm)
+    pub link_lengths: [f64; 3],
+    // [x, y, si] the arm is in when the program starts, home() goes back here
+    pub start: [f64; 3],
+}
+
+impl Default for GeometryConfig
+{
+    fn default() -> GeometryConfig
+    {
+        GeometryConfig { link_lengths: [1000.0, 500.0, 300.0], start: [1800.0, 0.0, 0.0] }
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpiConfig
+{
+    // SPI0 to SPI6, the motor controllers are on slave selects 0 to 2
+    pub bus: u8,
+    pub clock_speed: u32,
+}
+
+impl SpiConfig
+{
+    pub fn get_bus(&self) -> Result<Bus, RoboticArmError>
+    {
+        match self.bus
+        {
+            0 => Ok(Bus::Spi0),
+            1 => Ok(Bus::Spi1),
+            2 => Ok(Bus::Spi2),
+            3 => Ok(Bus::Spi3),
+            4 => Ok(Bus::Spi4),
+            5 => Ok(Bus::Spi5),
+            6 => Ok(Bus::Spi6),
+            bus => Err(RoboticArmError::ConfigError { context: format!("spi.bus is {bus}, there is no SPI{bus} (0 to 6)"), source: None }),
+        }
+    }
+}
+
+impl Default for SpiConfig
+{
+    fn default() -> SpiConfig
+    {
+        SpiConfig { bus: 1, clock_speed: 8_000_000 }
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SafetyLimits
+{
+    // time without packets before the arm holds
+    pub watchdog_ms: u64,
+    pub joints: JointLimits,
+}
+
+impl Default for SafetyLimits
+{
+    fn default() -> SafetyLimits
+    {
+        SafetyLimits { watchdog_ms: 500, joints: JointLimits::default() }
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArmConfig
+{
+    pub network: NetworkConfig,
+    pub arm: GeometryConfig,
+    // encoder ticks per revolution of each joint, or its whole JointEncoder
+    pub encoders: AngleToEncoderMap,
+    pub wiring: JointWiring,
+    pub spi: SpiConfig,
+    pub jog: JogGains,
+    pub teleop: TeleopConfig,
+    pub gripper: GripperConfig,
+    pub compensation: CompensationConfig,
+    pub limits: SafetyLimits,
+}
+
+impl Default for ArmConfig
+{
+    fn default() -> ArmConfig
+    {
+        ArmConfig
+        {
+            network: NetworkConfig::default(),
+            arm: GeometryConfig::default(),
+            encoders: AngleToEncoderMap::new(5000, 5000, 5000, 5000, 5000),
+            wiring: JointWiring::default(),
+            spi: SpiConfig::default(),
+            jog: JogGains::default(),
+            teleop: TeleopConfig::default(),
+            gripper: GripperConfig::default(),
+            compensation: CompensationConfig::default(),
+            limits: SafetyLimits::default(),
+        }
+    }
+}
+
+impl ArmConfig
+{
+    pub fn try_from_file(path: &Path) -> Result<ArmConfig, RoboticArmError>
+    {
+        ArmConfig::load(Some(path), &[])
+    }
+
+    pub fn load(path: Option<&Path>, overrides: &[String]) -> Result<ArmConfig, RoboticArmError>
+    {
+        // the file (or nothing, all defaults), then the overrides on top, then checked
+        let source = path.map_or("config".to_string(), |path| path.display().to_string());
+        let mut table = match path
+        {
+            Some(path) => {
+                let text = fs::read_to_string(path)
+                    .map_err(|e| RoboticArmError::config(format!("Could not read {source}"), e))?;
+                text.parse::<toml::Table>()
+                    .map_err(|e| RoboticArmError::config(source.clone(), e))?
+            },
+            None => toml::Table::new(),
+        };
+        for setting in overrides
+        {
+            apply_override(&mut table, setting)?;
+        }
+
+        let config: ArmConfig = toml::Value::Table(table).try_into()
+            .map_err(|e| RoboticArmError::config(source.clone(), e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), RoboticArmError>
+    {
+        let problems = self.find_problems();
+        if problems.is_empty()
+        {
+            Ok(())
+        } else {
+            Err(RoboticArmError::ConfigError { context: format!("Invalid config:\n    {}", problems.join("\n    ")), source: None })
+        }
+    }
+
+    pub fn build_solver(&self) -> Result<RoboticArmSolver, RoboticArmError>
+    {
+        // the arm at its starting pose, with the configured gains and limits
+        let [link1, link2, link3] = self.arm.link_lengths;
+        let [x, y, si] = self.arm.start;
+        let mut robotic_arm = RoboticArmSolver::try_new_from_ef_position(link1, link2, link3, x, y, si, self.encoders)?;
+        if let Some(joint_angles) = robotic_arm.get_last_joint_angles()
+        {
+            self.limits.joints.check(joint_angles)?;
+        }
+        robotic_arm.set_jog_gains(self.jog);
+        robotic_arm.set_joint_limits(self.limits.joints);
+        robotic_arm.set_gripper_config(self.gripper);
+        Ok(robotic_arm)
+    }
+
+    fn find_problems(&self) -> Vec<String>
+    {
+        let mut problems = Vec::new();
+
+        if self.arm.link_lengths.iter().any(|length| !length.is_finite() || *length <= 0.0)
+        {
+            problems.push(format!("arm.link_lengths {:?} must all be positive", self.arm.link_lengths));
+        }
+        if self.arm.start.iter().any(|value| !value.is_finite())
+        {
+            problems.push(format!("arm.start {:?} must be finite", self.arm.start));
+        }
+
+        for joint in Joint::ALL
+        {
+            let encoder = self.encoders.get_encoder(joint);
+            let key = joint_key(joint);
+            if !encoder.gear_ratio.is_finite() || encoder.gear_ratio <= 0.0
+            {
+                problems.push(format!("encoders.{key}.gear_ratio ({}) must be above 0", encoder.gear_ratio));
+            } else if !(2..=MAX_ENCODER_TICKS).contains(&encoder.get_span())
+            {
+                problems.push(format!("encoders.{key} is {} ticks per turn of the joint, it must be between 2 and {MAX_ENCODER_TICKS}",
+                    encoder.get_span()));
+            }
+            if encoder.direction.abs() != 1
+            {
+                problems.push(format!("encoders.{key}.direction ({}) must be 1 or -1", encoder.direction));
+            }
+            if encoder.zero_offset.is_some_and(|offset| !offset.is_finite())
+            {
+                problems.push(format!("encoders.{key}.zero_offset must be finite"));
+            }
+            if !(0.0..PI).contains(&encoder.backlash)
+            {
+                problems.push(format!("encoders.{key}.backlash ({}) must be from 0 up to pi", encoder.backlash));
+            }
+
+            let (mac_number, motor) = self.wiring.get_joint(joint);
+            if !(1..=MAC_COUNT).contains(&mac_number) || motor >= MOTORS_PER_MAC
+            {
+                problems.push(format!("wiring.{} is ({mac_number}, {motor}), there are motor controllers 1 to {MAC_COUNT} with motors 0 and 1",
+                    joint_key(joint)));
+            } else if let Some(other) = self.wiring.find_joint(mac_number, motor).filter(|other| *other != joint)
+            {
+                problems.push(format!("wiring.{} and wiring.{} are both on ({mac_number}, {motor})", joint_key(other), joint_key(joint)));
+            }
+        }
+
+        if let Err(e) = self.spi.get_bus()
+        {
+            problems.push(e.to_string());
+        }
+        if self.spi.clock_speed == 0
+        {
+            problems.push("spi.clock_speed must be above 0".to_string());
+        }
+
+        let valid_gain = |gain: f64| gain.is_finite() && gain >= 0.0;
+        if !valid_gain(self.jog.linear) || !valid_gain(self.jog.pitch) || !valid_gain(self.jog.joint)
+        {
+            problems.push(format!("jog.linear ({}), jog.pitch ({}) and jog.joint ({}) must be 0 or more",
+                self.jog.linear, self.jog.pitch, self.jog.joint));
+        }
+        // a jog of more than a turn is a typo
+        let roll = self.encoders.ticks_per_revolution(Joint::Roll) as i32;
+        let spool = self.encoders.ticks_per_revolution(Joint::Spool) as i32;
+        if !(0..=roll).contains(&self.jog.roll) || !(0..=spool).contains(&self.jog.spool)
+        {
+            problems.push(format!("jog.roll ({}) and jog.spool ({}) must be 0 up to a turn of encoders.roll ({roll}) and encoders.spool ({spool})",
+                self.jog.roll, self.jog.spool));
+        }
+
+        let valid_rate = |rate: f64| rate.is_finite() && rate > 0.0;
+        let teleop = self.teleop;
+        if !valid_rate(teleop.max_linear_speed) || !valid_rate(teleop.max_angular_speed)
+        {
+            problems.push(format!("teleop.max_linear_speed ({}) and teleop.max_angular_speed ({}) must be above 0",
+                teleop.max_linear_speed, teleop.max_angular_speed));
+        }
+        if !valid_rate(teleop.max_linear_acceleration) || !valid_rate(teleop.max_angular_acceleration)
+        {
+            problems.push(format!("teleop.max_linear_acceleration ({}) and teleop.max_angular_acceleration ({}) must be above 0",
+                teleop.max_linear_acceleration, teleop.max_angular_acceleration));
+        }
+        if !(0.0..=1.0).contains(&teleop.expo)
+        {
+            problems.push(format!("teleop.expo ({}) must be from 0 to 1", teleop.expo));
+        }
+        if teleop.input_timeout_ms == 0
+        {
+            problems.push("teleop.input_timeout_ms must be above 0".to_string());
+        }
+
+        let gripper = self.gripper;
+        let spool = self.encoders.ticks_per_revolution(Joint::Spool);
+        if gripper.open == gripper.closed || gripper.open.max(gripper.closed) >= spool
+        {
+            problems.push(format!("gripper.open ({}) and gripper.closed ({}) must differ and be below encoders.spool ({spool})",
+                gripper.open, gripper.closed));
+        }
+        if gripper.clamp(gripper.pinch as i32) != gripper.pinch
+        {
+            problems.push(format!("gripper.pinch ({}) must be between gripper.closed and gripper.open", gripper.pinch));
+        }
+        if gripper.stall_ms == 0
+        {
+            problems.push("gripper.stall_ms must be above 0".to_string());
+        }
+        if gripper.grasp_current.is_nan() || gripper.grasp_current <= 0.0
+        {
+            problems.push(format!("gripper.grasp_current ({}) must be above 0, inf to only use stalls", gripper.grasp_current));
+        }
+
+        if !(0.0..=1.0).contains(&self.compensation.take_up)
+        {
+            problems.push(format!("compensation.take_up ({}) must be from 0 to 1", self.compensation.take_up));
+        }
+        if let Some(compliance) = self.compensation.compliance
+        {
+            if compliance.link_masses.iter().chain([&compliance.payload]).any(|mass| !mass.is_finite() || *mass < 0.0)
+            {
+                problems.push(format!("compensation.compliance.link_masses {:?} and payload ({}) must be 0 or more",
+                    compliance.link_masses, compliance.payload));
+            }
+            if !compliance.gravity.is_finite() || compliance.gravity < 0.0
+            {
+                problems.push(format!("compensation.compliance.gravity ({}) must be 0 or more", compliance.gravity));
+            }
+            if compliance.stiffness.iter().any(|stiffness| stiffness.is_nan() || *stiffness <= 0.0)
+            {
+                problems.push(format!("compensation.compliance.stiffness {:?} must be above 0, inf for a rigid joint", compliance.stiffness));
+            }
+        }
+
+        if self.limits.watchdog_ms == 0
+        {
+            problems.push("limits.watchdog_ms must be above 0".to_string());
+        }
+        for (name, [min, max]) in ["shoulder", "elbow", "wrist"].iter().zip(self.limits.joints.as_array())
+        {
+            if min.is_nan() || max.is_nan() || min >= max
+            {
+                problems.push(format!("limits.joints.{name} [{min}, {max}] must be [min, max]"));
+            }
+        }
+
+        // only worth trying once the geometry and limits make sense
+        if !problems.iter().any(|problem| problem.starts_with("arm.") || problem.starts_with("limits.joints"))
+        {
+            if let Err(e) = self.build_solver()
+            {
+                problems.push(format!("arm.start {:?} can not be used: {}", self.arm.start, e.report()));
+            }
+        }
+        problems
+    }
+}
+
+fn joint_key(joint: Joint) -> &'static str
+{
+    match joint
+    {
+        Joint::Shoulder => "shoulder",
+        Joint::Elbow => "elbow",
+        Joint::Wrist => "wrist",
+        Joint::Roll => "roll",
+        Joint::Spool => "spool",
+    }
+}
+
+fn apply_override(table: &mut toml::Table, setting: &str) -> Result<(), RoboticArmError>
+{
+    // section.key=value, the value in TOML (a bare word is taken as a string)
+    let (key, value) = setting.split_once('=')
+        .ok_or_else(|| RoboticArmError::ConfigError { context: format!("Override {setting:?} should look like section.key=value"), source: None })?;
+    let value = format!("value = {value}").parse::<toml::Table>()
+        .ok()
+        .and_then(|mut parsed| parsed.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.trim().to_string()));
+
+    let path: Vec<&str> = key.trim().split('.').collect();
+    let (last, sections) = path.split_last().expect("split always returns one item");
+    let mut current = table;
+    for section in sections
+    {
+        current = current.entry(section.to_string())
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+            .as_table_mut()
+            .ok_or_else(|| RoboticArmError::ConfigError { context: format!("Override {setting:?}: {section} is not a section"), source: None })?;
+    }
+    current.insert(last.to_string(), value);
+    Ok(())
+}
+
+
+
+
+// ------------------------------- unit tests -------------------------------------
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::robotics::arm_state::JointEncoder;
+
+    fn temp_config(name: &str, text: &str) -> std::path::PathBuf
+    {
+        let path = std::env::temp_dir().join(format!("robot-arm-{name}-{}.toml", std::process::id()));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_example_file_is_the_defaults()
+    {
+        // robot-arm.toml documents the defaults, keep them in step
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("robot-arm.toml");
+        assert_eq!(ArmConfig::try_from_file(&path).unwrap(), ArmConfig::default());
+        assert_eq!(ArmConfig::load(None, &[]).unwrap(), ArmConfig::default());
+    }
+
+    #[test]
+    fn test_partial_file_and_overrides()
+    {
+        let path = temp_config("partial", "[arm]\nlink_lengths = [10.0, 5.0, 3.0]\nstart = [12.0, 6.0, 0.5]\n[jog]\nlinear = 0.5\n");
+        let overrides = vec!["network.bind=\"127.0.0.1:9000\"".to_string(),
+                             "spi.clock_speed=4000000".to_string(),
+                             "limits.joints.elbow=[-2.0, 2.0]".to_string()];
+        let config = ArmConfig::load(Some(&path), &overrides).unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(config.arm.link_lengths, [10.0, 5.0, 3.0]);
+        assert_eq!(config.jog, JogGains { linear: 0.5, ..JogGains::default() });
+        assert_eq!(config.network.bind, "127.0.0.1:9000".parse().unwrap());
+        assert_eq!(config.spi.clock_speed, 4_000_000);
+        assert_eq!(config.limits.joints.elbow, [-2.0, 2.0]);
+
+        let robotic_arm = config.build_solver().unwrap();
+        assert_eq!(robotic_arm.get_end_effector_position(), [12.0, 6.0, 0.5]);
+        assert_eq!(robotic_arm.get_jog_gains().linear, 0.5);
+    }
+
+    #[test]
+    fn test_every_problem_reported()
+    {
+        let overrides = vec!["arm.link_lengths=[1000.0, -5.0, 300.0]".to_string(),
+                             "spi.bus=9".to_string(),
+                             "wiring={shoulder=[1, 0], elbow=[1, 1], wrist=[2, 0], roll=[1, 0], spool=[3, 0]}".to_string(),
+                             "teleop.expo=2.0".to_string(),
+                             "gripper.pinch=6000".to_string(),
+                             "compensation.compliance.stiffness=[1.0, 0.0, inf]".to_string(),
+                             "limits.watchdog_ms=0".to_string()];
+        let error = ArmConfig::load(None, &overrides).err().unwrap().to_string();
+
+        assert!(error.contains("arm.link_lengths"));
+        assert!(error.contains("SPI9"));
+        assert!(error.contains("wiring.shoulder and wiring.roll"));
+        assert!(error.contains("teleop.expo"));
+        assert!(error.contains("gripper.pinch"));
+        assert!(error.contains("compensation.compliance.stiffness"));
+        assert!(error.contains("limits.watchdog_ms"));
+    }
+
+    #[test]
+    fn test_encoder_tables()
+    {
+        // ticks per revolution alone or the whole encoder, mixed
+        let encoders = "encoders={shoulder=5000, wrist=5000, roll=5000, spool=5000, elbow={ticks_per_revolution=1000, gear_ratio=4.0, direction=-1, zero_offset=0.3}}";
+        let config = ArmConfig::load(None, &[encoders.to_string()]).unwrap();
+        let elbow = config.encoders.get_encoder(Joint::Elbow);
+        assert_eq!((elbow.get_span(), elbow.direction, elbow.zero_offset), (4000, -1, Some(0.3)));
+        assert_eq!(config.encoders.get_encoder(Joint::Shoulder), &JointEncoder::new(5000));
+
+        let error = ArmConfig::load(None, &[encoders.replace("gear_ratio=4.0", "gear_ratio=9.0").replace("direction=-1", "direction=2")])
+            .err().unwrap().report();
+        assert!(error.contains("encoders.elbow is 9000 ticks"));
+        assert!(error.contains("encoders.elbow.direction"));
+        let error = ArmConfig::load(None, &[encoders.replace("gear_ratio", "gear_raito")]).err().unwrap().report();
+        assert!(error.contains("gear_raito"));
+    }
+
+    #[test]
+    fn test_bad_files()
+    {
+        // unknown keys, bad values, unreachable starting poses
+        let path = temp_config("typo", "[network]\nbnid = \"127.0.0.1:9000\"\n");
+        assert!(ArmConfig::try_from_file(&path).err().unwrap().report().contains("bnid"));
+        let _ = fs::remove_file(&path);
+
+        assert!(ArmConfig::load(None, &["network.bind=nowhere".to_string()]).is_err());
+        assert!(ArmConfig::load(None, &["network=1".to_string(), "network.bind=\"127.0.0.1:1\"".to_string()]).is_err());
+        let error = ArmConfig::load(None, &["arm.start=[5000.0, 0.0, 0.0]".to_string()]).err().unwrap();
+        assert!(error.to_string().contains("arm.start"));
+        let error = ArmConfig::load(None, &["limits.joints.shoulder=[0.5, 1.0]".to_string()]).err().unwrap();
+        assert!(error.to_string().contains("Shoulder angle"));
+        let error = ArmConfig::load(None, &["jog.roll=2147483647".to_string()]).err().unwrap();
+        assert!(error.to_string().contains("jog.roll"));
+        assert!(ArmConfig::load(None, &["jog.spool=-1".to_string()]).is_err());
+    }
+}
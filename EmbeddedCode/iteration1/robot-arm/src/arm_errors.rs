@@ -3,36 +3,260 @@ William Albertini
 
 Custom error enum for this project.
 
+Most variants carry a message, the ones a refused command
+is usually down to carry typed fields instead: which joint,
+the pose that was asked for, the limit it went past, the
+mode that would not take it. Errors caused by something
+else keep it as their source() (std::error::Error), so
+nothing is lost on the way up:
+
+    KinematicJointsNotUpdated   the solver or limit error
+                                the move was refused for
+    MissionAborted              the error the step failed on
+    NetworkError                the io::Error, if there was one
+    SpiError                    the rppal SPI error
+    FileError, ConfigError      the io, toml, serde or solver
+                                error, if there was one
+
+Display is only the error's own message. report() follows
+the sources down and joins them with ": ", that is what the
+logs and the telemetry downlink show:
+
+    Joints not updated for pose [21.000, -0.500, 0.000]: Singularity in Elbow reaching [21.000, -0.500, 0.000]
+
+Sources are held in ErrorSource, which compares by message,
+so errors can still be compared in tests (io::Error is not
+PartialEq).
+
 */
 
 
+// external imports
+use std::error::Error;
 use std::fmt;
+use std::io;
+
+// internal imports
+use crate::commands::arm_command::ArmCommand;
+use crate::robotics::arm_controller::OperatingMode;
+use crate::robotics::arm_state::Joint;
+
+
+// any error from a library, for the variants that can be caused by several
+pub type BoxedError = Box<dyn Error + Send + Sync>;
 
 
 #[derive(Debug, PartialEq)]
 pub enum RoboticArmError {
-	Singularity(String),
-	NetworkError(String),
+	Singularity { joint: Joint, pose: [f64; 3] },
+	NetworkError { context: String, source: Option<ErrorSource<io::Error>> },
+	SpiError { mac_number: u8, source: ErrorSource<rppal::spi::Error> },
 	BadPipe(String),
-	KinematicJointsNotUpdated(String)
+	KinematicJointsNotUpdated { pose: [f64; 3], source: Box<RoboticArmError> },
+	InvalidTrajectory(String),
+	ReactionBudgetExceeded(String),
+	FileError { context: String, source: Option<ErrorSource<BoxedError>> },
+	MalformedPacket(String),
+	StalePacket(String),
+	AuthenticationFailed(String),
+	MissionAborted { context: String, source: Box<RoboticArmError> },
+	// radians, limits are [min, max]
+	JointLimitExceeded { joint: Joint, angle: f64, limits: [f64; 2] },
+	ConfigError { context: String, source: Option<ErrorSource<BoxedError>> },
+	CommandRejected { mode: OperatingMode, command: ArmCommand },
+	IllegalTransition { from: OperatingMode, to: OperatingMode },
+	// a mission check that did not hold
+	CheckFailed(String),
+}
+
+
+impl RoboticArmError
+{
+	pub fn network(context: impl Into<String>, source: io::Error) -> RoboticArmError
+	{
+		RoboticArmError::NetworkError { context: context.into(), source: Some(ErrorSource(source)) }
+	}
+
+	pub fn file(context: impl Into<String>, source: impl Into<BoxedError>) -> RoboticArmError
+	{
+		RoboticArmError::FileError { context: context.into(), source: Some(ErrorSource(source.into())) }
+	}
+
+	pub fn config(context: impl Into<String>, source: impl Into<BoxedError>) -> RoboticArmError
+	{
+		RoboticArmError::ConfigError { context: context.into(), source: Some(ErrorSource(source.into())) }
+	}
 
+	pub fn report(&self) -> String
+	{
+		// this error and everything under it
+		let mut report = self.to_string();
+		let mut source = self.source();
+		while let Some(cause) = source
+		{
+			report.push_str(&format!(": {cause}"));
+			source = cause.source();
+		}
+		report
+	}
 }
 
 
-impl fmt::Display for RoboticArmError 
+impl fmt::Display for RoboticArmError
 {
-	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result 
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
 	{
-		match &self 
+		match &self
 		{
-			self::RoboticArmError::Singularity(em) => write!(f,
-				"{em}"),
-			self::RoboticArmError::NetworkError(em) => write!(f,
-				"{}", em),
+			self::RoboticArmError::Singularity { joint, pose } => write!(f,
+				"Singularity in {joint:?} reaching {}", Pose(pose)),
+			self::RoboticArmError::NetworkError { context, .. } => write!(f,
+				"{}", context),
+			self::RoboticArmError::SpiError { mac_number, .. } => write!(f,
+				"SPI to motor controller {mac_number} failed"),
 			self::RoboticArmError::BadPipe(em) => write!(f,
 				"{}", em),
-			self::RoboticArmError::KinematicJointsNotUpdated(em) => write!(f,
+			self::RoboticArmError::KinematicJointsNotUpdated { pose, .. } => write!(f,
+				"Joints not updated for pose {}", Pose(pose)),
+			self::RoboticArmError::InvalidTrajectory(em) => write!(f,
+				"{}", em),
+			self::RoboticArmError::ReactionBudgetExceeded(em) => write!(f,
+				"{}", em),
+			self::RoboticArmError::FileError { context, .. } => write!(f,
+				"{}", context),
+			self::RoboticArmError::MalformedPacket(em) => write!(f,
+				"{}", em),
+			self::RoboticArmError::StalePacket(em) => write!(f,
+				"{}", em),
+			self::RoboticArmError::AuthenticationFailed(em) => write!(f,
+				"{}", em),
+			self::RoboticArmError::MissionAborted { context, .. } => write!(f,
+				"{}", context),
+			self::RoboticArmError::JointLimitExceeded { joint, angle, limits: [min, max] } => write!(f,
+				"{joint:?} angle {angle:.3} is outside its limits [{min:.3}, {max:.3}]"),
+			self::RoboticArmError::ConfigError { context, .. } => write!(f,
+				"{}", context),
+			self::RoboticArmError::CommandRejected { mode, command } => write!(f,
+				"{command:?} is not accepted in {mode:?}"),
+			self::RoboticArmError::IllegalTransition { from, to } => write!(f,
+				"Can not go from {from:?} to {to:?}"),
+			self::RoboticArmError::CheckFailed(em) => write!(f,
 				"{}", em),
 		}
 	}
-}
\ No newline at end of file
+}
+
+impl Error for RoboticArmError
+{
+	fn source(&self) -> Option<&(dyn Error + 'static)>
+	{
+		match self
+		{
+			RoboticArmError::NetworkError { source: Some(ErrorSource(e)), .. } => Some(e),
+			RoboticArmError::SpiError { source: ErrorSource(e), .. } => Some(e),
+			RoboticArmError::FileError { source: Some(ErrorSource(e)), .. }
+			| RoboticArmError::ConfigError { source: Some(ErrorSource(e)), .. } => Some(e.as_ref()),
+			RoboticArmError::KinematicJointsNotUpdated { source, .. } => Some(source.as_ref()),
+			RoboticArmError::MissionAborted { source, .. } => Some(source.as_ref()),
+			_ => None,
+		}
+	}
+}
+
+
+// an error from outside this crate, equal to another if their messages are
+#[derive(Debug)]
+pub struct ErrorSource<E>(pub E);
+
+impl<E: fmt::Display> PartialEq for ErrorSource<E>
+{
+	fn eq(&self, other: &Self) -> bool
+	{
+		self.0.to_string() == other.0.to_string()
+	}
+}
+
+impl<E: fmt::Display> fmt::Display for ErrorSource<E>
+{
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+	{
+		self.0.fmt(f)
+	}
+}
+
+
+struct Pose<'a>(&'a [f64; 3]);
+
+impl fmt::Display for Pose<'_>
+{
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+	{
+		let [x, y, si] = self.0;
+		write!(f, "[{x:.3}, {y:.3}, {si:.3}]")
+	}
+}
+
+
+
+
+// ------------------------------- unit tests -------------------------------------
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn test_report_follows_sources()
+	{
+		// a refused move keeps the singularity it was refused for
+		let singularity = RoboticArmError::Singularity { joint: Joint::Elbow, pose: [21.0, -0.5, 0.0] };
+		let error = RoboticArmError::KinematicJointsNotUpdated { pose: [21.0, -0.5, 0.0], source: Box::new(singularity) };
+		assert_eq!(error.to_string(), "Joints not updated for pose [21.000, -0.500, 0.000]");
+		assert!(matches!(error.source().and_then(|e| e.downcast_ref()), Some(RoboticArmError::Singularity { joint: Joint::Elbow, .. })));
+		assert_eq!(error.report(),
+			"Joints not updated for pose [21.000, -0.500, 0.000]: Singularity in Elbow reaching [21.000, -0.500, 0.000]");
+
+		let aborted = RoboticArmError::MissionAborted { context: "Mission aborted at step 2".into(), source: Box::new(error) };
+		assert!(aborted.report().starts_with("Mission aborted at step 2: Joints not updated"));
+	}
+
+	#[test]
+	fn test_network_source()
+	{
+		// the io error underneath is kept, and compared by its message
+		let error = RoboticArmError::network("Could not send status", io::Error::new(io::ErrorKind::BrokenPipe, "pipe closed"));
+		assert_eq!(error.report(), "Could not send status: pipe closed");
+		assert!(error.source().and_then(|e| e.downcast_ref::<io::Error>()).is_some_and(|e| e.kind() == io::ErrorKind::BrokenPipe));
+		assert_eq!(error, RoboticArmError::network("Could not send status", io::Error::other("pipe closed")));
+
+		let no_source = RoboticArmError::NetworkError { context: "Lock poisoned".into(), source: None };
+		assert!(no_source.source().is_none());
+		assert_eq!(no_source.report(), "Lock poisoned");
+	}
+
+	#[test]
+	fn test_file_and_config_sources()
+	{
+		// parse errors are kept as they are, not flattened into the message
+		let parse = "1.5".parse::<u64>().unwrap_err();
+		let error = RoboticArmError::file("Bad replay counter in counter.txt", parse.clone());
+		assert_eq!(error.to_string(), "Bad replay counter in counter.txt");
+		assert_eq!(error.report(), format!("Bad replay counter in counter.txt: {parse}"));
+		assert!(error.source().and_then(|e| e.downcast_ref::<std::num::ParseIntError>()).is_some());
+
+		// and a solver error under a config error can be followed the same way
+		let singularity = RoboticArmError::Singularity { joint: Joint::Elbow, pose: [40.0, 0.0, 0.0] };
+		let error = RoboticArmError::config("Calibration sample 1 is not reachable", singularity);
+		assert!(error.report().ends_with("Singularity in Elbow reaching [40.000, 0.000, 0.000]"));
+	}
+
+	#[test]
+	fn test_refusals_keep_the_mode()
+	{
+		// the mode and command can be matched on, the message is built from them
+		let rejected = RoboticArmError::CommandRejected { mode: OperatingMode::Unhomed, command: ArmCommand::Stop };
+		assert_eq!(rejected.to_string(), "Stop is not accepted in Unhomed");
+		let illegal = RoboticArmError::IllegalTransition { from: OperatingMode::Fault, to: OperatingMode::Homing };
+		assert_eq!(illegal.report(), "Can not go from Fault to Homing");
+	}
+}
@@ -0,0 +1,63 @@
+/*
+William Albertini
+
+Fits the encoder zero offsets from measured poses (see
+robotics/encoder_calibration.rs for the samples file).
+
+usage:
+    calibrate_encoders <samples.toml> [--config <file>]
+
+The link lengths and the encoders (ticks, gear ratio,
+direction) come from the config file, defaults without one.
+Prints the rms residual of each joint and an [encoders]
+section with the fitted zero offsets to paste into the config.
+
+*/
+
+// external imports
+use std::path::Path;
+
+// internal imports
+use robot_arm::arm_config::ArmConfig;
+use robot_arm::arm_errors::RoboticArmError;
+use robot_arm::robotics::arm_kinematics::InverseKinematicSolver;
+use robot_arm::robotics::encoder_calibration::{fit_zero_offsets, load_samples};
+
+
+fn main()
+{
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some(samples_path) = args.first().filter(|arg| !arg.starts_with("--")) else {
+        eprintln!("usage: calibrate_encoders <samples.toml> [--config <file>]");
+        std::process::exit(1);
+    };
+
+    let config_path = args.windows(2).find(|pair| pair[0] == "--config").map(|pair| pair[1].clone());
+    let config = ArmConfig::load(config_path.as_deref().map(Path::new), &[]).unwrap_or_else(|e| exit_with(e));
+    let samples = load_samples(Path::new(samples_path)).unwrap_or_else(|e| exit_with(e));
+
+    let [link1, link2, link3] = config.arm.link_lengths;
+    let solver = InverseKinematicSolver::new(link1, link2, link3);
+    let fit = fit_zero_offsets(&solver, &config.encoders, &samples).unwrap_or_else(|e| exit_with(e));
+
+    for ((name, offset), residual) in ["shoulder", "elbow", "wrist"].iter().zip(fit.zero_offsets).zip(fit.residuals)
+    {
+        println!("{name}: zero offset {offset:.5} rad, rms residual {residual:.5} rad over {} samples", samples.len());
+    }
+
+    let mut section = toml::Table::new();
+    match toml::Value::try_from(fit.joint_map)
+    {
+        Ok(encoders) => {
+            section.insert("encoders".to_string(), encoders);
+            println!("\n{section}");
+        },
+        Err(e) => exit_with(RoboticArmError::config("Could not write the encoder table", e)),
+    }
+}
+
+fn exit_with(error: RoboticArmError) -> !
+{
+    eprintln!("{}", error.report());
+    std::process::exit(1);
+}
@@ -0,0 +1,140 @@
+/*
+William Albertini
+
+Replays a log of ArmStates (one json ArmState per line)
+so a session can be reviewed without the python sims.
+
+usage:
+    session_viewer <log> [--config <file>] [--svg <directory>] [--fps <rate>] [--target x,y,si]
+
+With --svg every state is written out as an SVG frame,
+otherwise the frames are played back in the terminal.
+The arm is built from the config like main.rs does (defaults
+without one), so logged states, which count from the starting
+pose, are drawn from arm.start with the configured encoders.
+Use the config the session ran with. An unknown option or
+a value that does not parse prints the usage and exits.
+
+*/
+
+// external imports
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+// internal imports
+use robot_arm::arm_config::ArmConfig;
+use robot_arm::visualization::arm_render::{read_state_log, ArmFrame, ArmRenderer};
+
+// terminal view size (columns, rows)
+const ASCII_WIDTH: usize = 80;
+const ASCII_HEIGHT: usize = 40;
+
+
+const USAGE: &str = "usage: session_viewer <log> [--config <file>] [--svg <directory>] [--fps <rate>] [--target x,y,si]";
+
+
+struct ViewerOptions
+{
+    log_path: String,
+    config_path: Option<String>,
+    svg_directory: Option<String>,
+    fps: f64,
+    target: Option<[f64; 3]>,
+}
+
+
+fn main()
+{
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let options = parse_options(&args).unwrap_or_else(|problem| {
+        eprintln!("{problem}");
+        eprintln!("{USAGE}");
+        std::process::exit(1);
+    });
+    let log_path = &options.log_path;
+
+    let contents = fs::read_to_string(log_path).unwrap_or_else(|e| {
+        eprintln!("Could not read {log_path}: {e}");
+        std::process::exit(1);
+    });
+    let states = read_state_log(&contents).unwrap_or_else(|e| {
+        eprintln!("{}", e.report());
+        std::process::exit(1);
+    });
+
+    let target = options.target;
+    let robotic_arm = ArmConfig::load(options.config_path.as_deref().map(Path::new), &[])
+        .and_then(|config| config.build_solver())
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e.report());
+            std::process::exit(1);
+        });
+    let renderer = ArmRenderer::from_arm(&robotic_arm);
+    let frames: Vec<ArmFrame> = states.iter().map(|state| renderer.frame_from_state(state, target)).collect();
+
+    if let Some(directory) = options.svg_directory
+    {
+        match renderer.write_svg_frames(&frames, &PathBuf::from(&directory))
+        {
+            Ok(count) => println!("Wrote {count} frames to {directory}"),
+            Err(e) => {
+                eprintln!("{}", e.report());
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // play back in the terminal
+    let fps = options.fps;
+    for (index, frame) in frames.iter().enumerate()
+    {
+        // clear screen and home the cursor
+        print!("\x1B[2J\x1B[H");
+        println!("frame {}/{}  {:?}", index + 1, frames.len(), states[index]);
+        print!("{}", renderer.render_ascii(frame, ASCII_WIDTH, ASCII_HEIGHT));
+        thread::sleep(Duration::from_secs_f64(1.0 / fps.max(0.1)));
+    }
+}
+
+fn parse_options(args: &[String]) -> Result<ViewerOptions, String>
+{
+    // the log, then any options, each followed by its value
+    let mut args = args.iter();
+    let log_path = args.next().filter(|arg| !arg.starts_with("--")).ok_or("No log given")?.clone();
+    let mut options = ViewerOptions { log_path, config_path: None, svg_directory: None, fps: 10.0, target: None };
+
+    while let Some(arg) = args.next()
+    {
+        let mut value = || args.next().cloned().ok_or(format!("{arg} needs a value"));
+        match arg.as_str()
+        {
+            "--config" => options.config_path = Some(value()?),
+            "--svg" => options.svg_directory = Some(value()?),
+            "--fps" => {
+                let fps = value()?;
+                options.fps = fps.parse().ok().filter(|fps: &f64| fps.is_finite() && *fps > 0.0)
+                    .ok_or(format!("--fps {fps} is not a frame rate above 0"))?;
+            },
+            "--target" => {
+                let target = value()?;
+                options.target = Some(parse_target(&target).ok_or(format!("--target {target} is not x,y,si"))?);
+            },
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {arg}")),
+            _ => return Err(format!("Unexpected argument {arg}")),
+        }
+    }
+    Ok(options)
+}
+
+fn parse_target(value: &str) -> Option<[f64; 3]>
+{
+    let parts: Vec<f64> = value.split(',').map(|part| part.trim().parse().ok()).collect::<Option<_>>()?;
+    match parts.as_slice()
+    {
+        [x, y, si] => Some([*x, *y, *si]),
+        _ => None,
+    }
+}
@@ -0,0 +1,103 @@
+/*
+William Albertini
+
+Queries and exports the telemetry logged by main.rs
+(--telemetry <directory>).
+
+usage:
+    telemetry_reader <directory|file> [--from ms] [--to ms] [--errors] [--csv <out>] [--states <out>]
+
+A directory is read oldest file first. --from and --to
+are milliseconds since the unix epoch, --errors keeps
+only cycles where the IK solver rejected the target.
+With no export option a one line summary of each entry
+is printed. --states writes ArmState json lines that
+session_viewer can play back.
+
+*/
+
+// external imports
+use std::fs;
+use std::path::Path;
+
+// internal imports
+use robot_arm::telemetry::telemetry_recorder::{IkOutcome, TelemetryEntry};
+use robot_arm::telemetry::telemetry_reader::{export_csv, export_states, read_log, read_log_directory, TelemetryQuery};
+
+
+fn main()
+{
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some(log_path) = args.first() else {
+        eprintln!("usage: telemetry_reader <directory|file> [--from ms] [--to ms] [--errors] [--csv <out>] [--states <out>]");
+        std::process::exit(1);
+    };
+
+    let path = Path::new(log_path);
+    let entries = if path.is_dir() { read_log_directory(path, "telemetry") } else { read_log(path) };
+    let entries = entries.unwrap_or_else(|e| {
+        eprintln!("{}", e.report());
+        std::process::exit(1);
+    });
+
+    let query = TelemetryQuery {
+        from_ms: option_value(&args, "--from").and_then(|ms| ms.parse().ok()),
+        to_ms: option_value(&args, "--to").and_then(|ms| ms.parse().ok()),
+        errors_only: args.iter().any(|arg| arg == "--errors"),
+    };
+    let entries = query.apply(entries);
+
+    let csv_path = option_value(&args, "--csv");
+    let states_path = option_value(&args, "--states");
+
+    if let Some(csv_path) = &csv_path
+    {
+        write_or_exit(csv_path, &export_csv(&entries));
+        println!("Wrote {} entries to {csv_path}", entries.len());
+    }
+    if let Some(states_path) = &states_path
+    {
+        let states = export_states(&entries).unwrap_or_else(|e| {
+            eprintln!("{}", e.report());
+            std::process::exit(1);
+        });
+        write_or_exit(states_path, &states);
+        println!("Wrote {} states to {states_path}", entries.len());
+    }
+
+    if csv_path.is_none() && states_path.is_none()
+    {
+        for entry in &entries
+        {
+            println!("{}", summary(entry));
+        }
+        println!("{} entries", entries.len());
+    }
+}
+
+fn summary(entry: &TelemetryEntry) -> String
+{
+    let [x, y, si] = entry.target;
+    let ik = match &entry.ik
+    {
+        Some(IkOutcome::Solved { joint_angles }) => format!("ok {joint_angles:.3?}"),
+        Some(IkOutcome::Rejected { reason }) => format!("rejected ({reason})"),
+        None => "idle".to_string(),
+    };
+    format!("{} target [{x:.1}, {y:.1}, {si:.3}] {ik} commanded {:?}", entry.timestamp_ms, entry.commanded.as_array())
+}
+
+fn write_or_exit(path: &str, contents: &str)
+{
+    if let Err(e) = fs::write(path, contents)
+    {
+        eprintln!("Could not write {path}: {e}");
+        std::process::exit(1);
+    }
+}
+
+fn option_value(args: &[String], name: &str) -> Option<String>
+{
+    let position = args.iter().position(|arg| arg == name)?;
+    args.get(position + 1).cloned()
+}
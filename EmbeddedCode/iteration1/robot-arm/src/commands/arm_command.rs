@@ -0,0 +1,116 @@
+/*
+William Albertini
+
+ArmCommand is what the control loop acts on, whatever it
+came from. The joystick only ever jogs (or says it is
+still there), other sources can send the arm straight to
+a pose or a set of joint angles, set the gripper, home it
+or stop it.
+
+Commands are serde enums so the JSON sources can send
+them as they are, e.g.
+
+    {"move_to_pose": {"x": 1500.0, "y": 200.0, "si": 0.1}}
+    {"move_joints": {"joint_angles": [0.1, -0.2, 0.1]}}
+    {"gripper": {"position": 0.5}}
+    {"gripper": {"preset": "pinch"}}
+    {"set_mode": {"mode": "hold"}}
+    "home"
+    "stop"
+
+*/
+
+// external imports
+use serde::{Deserialize, Serialize};
+
+// internal imports
+use crate::networking::data_handler::DataHandler;
+use crate::robotics::gripper::GripperCommand;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlMode
+{
+    // homed and holding, nothing picked yet
+    Idle,
+    // joystick jogs
+    Teleop,
+    // poses and joint moves from scripts and the ground station
+    Script,
+    // holding, motion is ignored until another mode is picked
+    Hold,
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArmCommand
+{
+    // joystick deltas, including the buttons (spool, re-arm)
+    Jog(DataHandler),
+    // the sender is still there, nothing to do
+    Heartbeat,
+    // end effector [x, y] (mm) and si (radians)
+    MoveToPose { x: f64, y: f64, si: f64 },
+    // shoulder, elbow, wrist (radians)
+    MoveJoints { joint_angles: [f64; 3] },
+    // position (0 closed, 1 open) or preset, see gripper.rs
+    Gripper(GripperCommand),
+    Home,
+    // hold where the arm is now
+    Stop,
+    SetMode { mode: ControlMode },
+}
+
+impl ArmCommand
+{
+    pub fn moves_arm(&self) -> bool
+    {
+        !matches!(self, ArmCommand::Heartbeat | ArmCommand::Stop | ArmCommand::SetMode { .. })
+    }
+}
+
+impl From<DataHandler> for ArmCommand
+{
+    fn from(data: DataHandler) -> ArmCommand
+    {
+        if data.heartbeat
+        {
+            ArmCommand::Heartbeat
+        } else {
+            ArmCommand::Jog(data)
+        }
+    }
+}
+
+
+
+
+// ------------------------------- unit tests -------------------------------------
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_json_format()
+    {
+        // the format documented above
+        let pose: ArmCommand = serde_json::from_str(r#"{"move_to_pose": {"x": 1500.0, "y": 200.0, "si": 0.1}}"#).unwrap();
+        let home: ArmCommand = serde_json::from_str(r#""home""#).unwrap();
+        let jog: ArmCommand = serde_json::from_str(r#"{"jog": {"x": 1, "y": 0, "roll": 0, "pitch": 0, "button1": 1, "button2": 1}}"#).unwrap();
+
+        assert_eq!(pose, ArmCommand::MoveToPose { x: 1500.0, y: 200.0, si: 0.1 });
+        assert_eq!(home, ArmCommand::Home);
+        assert_eq!(jog, ArmCommand::Jog(DataHandler::new(1, 0, 0, 0, 1, 1)));
+        assert_eq!(serde_json::to_string(&ArmCommand::Stop).unwrap(), r#""stop""#);
+    }
+
+    #[test]
+    fn test_from_data_handler()
+    {
+        assert_eq!(ArmCommand::from(DataHandler::heartbeat()), ArmCommand::Heartbeat);
+        assert!(ArmCommand::from(DataHandler::new(0, 0, 0, 0, 0, 0)).moves_arm());
+    }
+}
@@ -0,0 +1,303 @@
+/*
+William Albertini
+
+ArmCommandSource is where the control loop gets its
+commands from. next_command() waits at most the timeout
+it is given, so the loop keeps running (watchdog, status,
+simulator) when nothing comes in, and returns an error
+once the source is finished for good.
+
+    ChannelSource    anything running in its own thread
+                     that sends into an mpsc channel: the
+                     UDP NetworkHandler, a SessionReplay
+                     or the CCSDS bus (DataHandler), or
+                     the JSON readers below (ArmCommand)
+    ScriptSource     a file of JSON commands, one per line,
+                     handed out one per call
+    tcp_source()     JSON commands, one per line, from
+                     whoever connects to a TCP port
+    stdin_source()   JSON commands typed on stdin
+
+JSON commands are checked like the JSON-RPC ones, jog axes
+are at most AXIS_MAX (what a joystick packet can carry).
+tcp_source() clients are not authenticated either, main.rs
+only listens on a loopback address unless --insecure is
+given.
+
+Sources that stream joystick input expect heartbeats, the
+control loop only runs the CommandWatchdog for those. A
+script or a ground station sending a move every so often
+is not a dead link.
+
+*/
+
+// external imports
+use std::fs;
+use std::io::{self, BufRead, BufReader};
+use std::net::{SocketAddr, TcpListener};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+use arm_protocol::joystick::AXIS_MAX;
+
+// internal imports
+use crate::arm_errors::RoboticArmError;
+use super::arm_command::ArmCommand;
+
+
+pub trait ArmCommandSource
+{
+    // Ok(None) if nothing arrived within the timeout
+    fn next_command(&mut self, timeout: Duration) -> Result<Option<ArmCommand>, RoboticArmError>;
+
+    // true for joystick streams, which send heartbeats when idle
+    fn expects_heartbeats(&self) -> bool
+    {
+        false
+    }
+}
+
+
+pub struct ChannelSource<T>
+{
+    receiver: Receiver<T>,
+    heartbeats: bool,
+}
+
+impl<T: Into<ArmCommand>> ChannelSource<T>
+{
+    pub fn new(receiver: Receiver<T>, heartbeats: bool) -> ChannelSource<T>
+    {
+        ChannelSource { receiver, heartbeats }
+    }
+}
+
+impl<T: Into<ArmCommand>> ArmCommandSource for ChannelSource<T>
+{
+    fn next_command(&mut self, timeout: Duration) -> Result<Option<ArmCommand>, RoboticArmError>
+    {
+        match self.receiver.recv_timeout(timeout)
+        {
+            Ok(command) => Ok(Some(command.into())),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(RoboticArmError::BadPipe("Command source closed".into())),
+        }
+    }
+
+    fn expects_heartbeats(&self) -> bool
+    {
+        self.heartbeats
+    }
+}
+
+
+pub struct ScriptSource
+{
+    commands: Vec<ArmCommand>,
+    next: usize,
+}
+
+impl ScriptSource
+{
+    pub fn new(commands: Vec<ArmCommand>) -> ScriptSource
+    {
+        ScriptSource { commands, next: 0 }
+    }
+
+    pub fn try_from_file(path: &Path) -> Result<ScriptSource, RoboticArmError>
+    {
+        // the whole file is checked before anything runs
+        let text = fs::read_to_string(path)
+            .map_err(|e| RoboticArmError::file(format!("Could not read {}", path.display()), e))?;
+        let commands = text.lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(number, line)| parse_command(line)
+                .map_err(|e| RoboticArmError::file(format!("{}:{}", path.display(), number + 1), e)))
+            .collect::<Result<Vec<ArmCommand>, RoboticArmError>>()?;
+        Ok(ScriptSource::new(commands))
+    }
+
+    pub fn remaining(&self) -> usize
+    {
+        self.commands.len() - self.next
+    }
+}
+
+impl ArmCommandSource for ScriptSource
+{
+    fn next_command(&mut self, _timeout: Duration) -> Result<Option<ArmCommand>, RoboticArmError>
+    {
+        let command = self.commands.get(self.next)
+            .ok_or_else(|| RoboticArmError::BadPipe(format!("Script finished after {} commands", self.commands.len())))?;
+        self.next += 1;
+        Ok(Some(*command))
+    }
+}
+
+
+pub fn parse_command(line: &str) -> Result<ArmCommand, RoboticArmError>
+{
+    let command = serde_json::from_str(line.trim())
+        .map_err(|e| RoboticArmError::MalformedPacket(format!("Bad command {:?}: {e}", line.trim())))?;
+    if let ArmCommand::Jog(data) = command
+    {
+        if [data.x, data.y, data.roll, data.pitch].iter().any(|axis| axis.unsigned_abs() > AXIS_MAX as u16)
+        {
+            return Err(RoboticArmError::MalformedPacket(format!("Bad command {:?}: jog axes are limited to {AXIS_MAX}", line.trim())));
+        }
+    }
+    Ok(command)
+}
+
+pub fn read_json_commands<R: BufRead>(reader: R, sender: &Sender<ArmCommand>) -> Result<usize, RoboticArmError>
+{
+    // bad lines are reported and skipped, returns how many commands were sent
+    let mut sent = 0;
+    for line in reader.lines()
+    {
+        let line = line.map_err(|e| RoboticArmError::network("Could not read command", e))?;
+        if line.trim().is_empty()
+        {
+            continue;
+        }
+        match parse_command(&line)
+        {
+            Ok(command) => {
+                if sender.send(command).is_err()
+                {
+                    return Err(RoboticArmError::BadPipe(format!("Bad pipe after {sent} commands")));
+                }
+                sent += 1;
+            },
+            Err(e) => println!("{e}"),
+        }
+    }
+    Ok(sent)
+}
+
+pub fn tcp_source(address: SocketAddr) -> Result<ChannelSource<ArmCommand>, RoboticArmError>
+{
+    // one client at a time, the next is accepted when the last hangs up
+    let listener = TcpListener::bind(address)
+        .map_err(|e| RoboticArmError::network(format!("Could not listen on {address}"), e))?;
+    println!("Taking commands on {:?}", listener.local_addr());
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten()
+        {
+            let peer = stream.peer_addr().ok();
+            match read_json_commands(BufReader::new(stream), &sender)
+            {
+                Ok(sent) => println!("{peer:?} sent {sent} commands"),
+                Err(RoboticArmError::BadPipe(_)) => return,
+                Err(e) => println!("{}", e.report()),
+            }
+        }
+    });
+    Ok(ChannelSource::new(receiver, false))
+}
+
+pub fn stdin_source() -> ChannelSource<ArmCommand>
+{
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        if let Err(e) = read_json_commands(io::stdin().lock(), &sender)
+        {
+            println!("{}", e.report());
+        }
+    });
+    ChannelSource::new(receiver, false)
+}
+
+
+
+
+// ------------------------------- unit tests -------------------------------------
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::io::{Cursor, Write};
+    use std::net::TcpStream;
+    use crate::networking::data_handler::DataHandler;
+    use crate::robotics::gripper::GripperCommand;
+
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    #[test]
+    fn test_joystick_channel()
+    {
+        // what the UDP server sends comes out as commands
+        let (sender, receiver) = mpsc::channel();
+        let mut source = ChannelSource::new(receiver, true);
+        sender.send(DataHandler::new(2, 0, 0, 0, 1, 1)).unwrap();
+        sender.send(DataHandler::heartbeat()).unwrap();
+
+        assert_eq!(source.next_command(TIMEOUT).unwrap(), Some(ArmCommand::Jog(DataHandler::new(2, 0, 0, 0, 1, 1))));
+        assert_eq!(source.next_command(TIMEOUT).unwrap(), Some(ArmCommand::Heartbeat));
+        assert_eq!(source.next_command(Duration::from_millis(1)).unwrap(), None);
+        assert!(source.expects_heartbeats());
+
+        drop(sender);
+        assert!(source.next_command(TIMEOUT).is_err());
+    }
+
+    #[test]
+    fn test_json_lines()
+    {
+        // bad lines are skipped, blank lines ignored
+        let (sender, receiver) = mpsc::channel();
+        let input = Cursor::new("\"home\"\n\nnot json\n{\"gripper\": {\"position\": 1.0}}\n");
+        assert_eq!(read_json_commands(input, &sender).unwrap(), 2);
+
+        let received: Vec<ArmCommand> = receiver.try_iter().collect();
+        assert_eq!(received, vec![ArmCommand::Home, ArmCommand::Gripper(GripperCommand::Position(1.0))]);
+    }
+
+    #[test]
+    fn test_jog_axes_checked()
+    {
+        // a joystick can not send more than AXIS_MAX, neither can a script
+        let jog = |x: i32| format!(r#"{{"jog": {{"x": {x}, "y": 0, "roll": 0, "pitch": 0, "button1": 1, "button2": 1}}}}"#);
+        assert_eq!(parse_command(&jog(AXIS_MAX as i32)).unwrap(), ArmCommand::Jog(DataHandler::new(AXIS_MAX as i16, 0, 0, 0, 1, 1)));
+        assert!(matches!(parse_command(&jog(-(AXIS_MAX as i32) - 1)), Err(RoboticArmError::MalformedPacket(_))));
+        assert!(parse_command(&jog(i16::MAX as i32)).is_err());
+    }
+
+    #[test]
+    fn test_script_file()
+    {
+        let path = std::env::temp_dir().join(format!("robot-arm-commands-{}.jsonl", std::process::id()));
+        fs::write(&path, "{\"move_to_pose\": {\"x\": 1.0, \"y\": 2.0, \"si\": 0.0}}\n\"stop\"\n").unwrap();
+        let mut source = ScriptSource::try_from_file(&path).unwrap();
+
+        assert_eq!(source.remaining(), 2);
+        assert_eq!(source.next_command(TIMEOUT).unwrap(), Some(ArmCommand::MoveToPose { x: 1.0, y: 2.0, si: 0.0 }));
+        assert_eq!(source.next_command(TIMEOUT).unwrap(), Some(ArmCommand::Stop));
+        assert!(source.next_command(TIMEOUT).is_err());
+        assert!(!source.expects_heartbeats());
+
+        // one bad line and the script is refused
+        fs::write(&path, "\"home\"\n\"fly\"\n").unwrap();
+        let error = ScriptSource::try_from_file(&path).err().unwrap();
+        assert!(error.report().contains(":2:"));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_tcp_source()
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        let mut source = tcp_source(address).unwrap();
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"{\"move_joints\": {\"joint_angles\": [0.1, 0.2, 0.3]}}\n").unwrap();
+
+        assert_eq!(source.next_command(TIMEOUT).unwrap(), Some(ArmCommand::MoveJoints { joint_angles: [0.1, 0.2, 0.3] }));
+    }
+}
@@ -0,0 +1,417 @@
+/*
+William Albertini
+
+Mission scripts, a list of steps the arm runs on its own
+instead of being flown by hand. Scripts are TOML (or JSON,
+anything not ending in .toml):
+
+    name = "stow"
+
+    [[steps]]
+    step = "move_to_pose"
+    x = 1500.0
+    y = 200.0
+    si = 0.1
+
+    [[steps]]
+    step = "wait"
+    ms = 2000
+
+    [[steps]]
+    step = "check"
+    condition = { type = "settled", tolerance_ticks = 10 }
+
+    [[steps]]
+    step = "close_gripper"
+
+Steps are move_to_pose, move_joints (joint_angles, radians),
+gripper (position, 0 closed to 1 open), open_gripper,
+close_gripper, wait (ms) and check. A check is one of
+
+    at_pose     the commanded end effector pose is within
+                tolerance (mm, and radians for si)
+    settled     encoder feedback is within tolerance_ticks
+                of what was commanded on every joint
+
+run() always starts with a dry run: every step is played
+on a copy of the solver and each waypoint has to pass IK
+(and every at_pose check has to hold) before a motor moves.
+The steps then go through ArmController like any other
+command: the arm has to be homed, the mission switches it to
+Script and goes back to Idle when it is done. The first step
+that fails aborts the mission, Stop drops the arm into Hold
+and the error says which step it was. With a StateStore the
+state is saved while the mission runs, as the main loop does,
+and once more when it ends.
+dry_run() on its own is the --dry-run mode.
+
+*/
+
+// external imports
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+
+// internal imports
+use crate::arm_errors::{BoxedError, RoboticArmError};
+use crate::robotics::arm_controller::ArmController;
+use crate::robotics::arm_state::{Joint, RoboticArmSolver};
+use crate::robotics::gripper::{GripperCommand, GripperPreset};
+use crate::robotics::robot_driver::MotorDriver;
+use crate::robotics::saved_state::StateStore;
+use super::arm_command::{ArmCommand, ControlMode};
+
+
+// how often the controller is updated while waiting
+const WAIT_STEP: Duration = Duration::from_millis(10);
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Condition
+{
+    AtPose { x: f64, y: f64, si: f64, tolerance: f64 },
+    Settled { tolerance_ticks: u16 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "step", rename_all = "snake_case")]
+pub enum MissionStep
+{
+    MoveToPose { x: f64, y: f64, si: f64 },
+    MoveJoints { joint_angles: [f64; 3] },
+    Gripper { position: f64 },
+    OpenGripper,
+    CloseGripper,
+    Wait { ms: u64 },
+    Check { condition: Condition },
+}
+
+impl MissionStep
+{
+    pub fn to_command(&self) -> Option<ArmCommand>
+    {
+        // the steps that move something
+        match *self
+        {
+            MissionStep::MoveToPose { x, y, si } => Some(ArmCommand::MoveToPose { x, y, si }),
+            MissionStep::MoveJoints { joint_angles } => Some(ArmCommand::MoveJoints { joint_angles }),
+            MissionStep::Gripper { position } => Some(ArmCommand::Gripper(GripperCommand::Position(position))),
+            MissionStep::OpenGripper => Some(ArmCommand::Gripper(GripperCommand::Preset(GripperPreset::Open))),
+            MissionStep::CloseGripper => Some(ArmCommand::Gripper(GripperCommand::Preset(GripperPreset::Closed))),
+            MissionStep::Wait { .. } | MissionStep::Check { .. } => None,
+        }
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Mission
+{
+    #[serde(default)]
+    pub name: String,
+    pub steps: Vec<MissionStep>,
+}
+
+impl Mission
+{
+    pub fn try_from_file(path: &Path) -> Result<Mission, RoboticArmError>
+    {
+        let text = fs::read_to_string(path)
+            .map_err(|e| RoboticArmError::file(format!("Could not read {}", path.display()), e))?;
+        let mission = if path.extension().is_some_and(|extension| extension == "toml")
+        {
+            toml::from_str(&text).map_err(BoxedError::from)
+        } else {
+            serde_json::from_str(&text).map_err(BoxedError::from)
+        };
+        mission.map_err(|e| RoboticArmError::file(format!("Bad mission {}", path.display()), e))
+    }
+
+    pub fn dry_run(&self, arm: &RoboticArmSolver) -> Result<Vec<[f64; 3]>, RoboticArmError>
+    {
+        // the pose after every step, nothing is sent to a driver
+        let mut plan = arm.clone();
+        let mut poses = Vec::with_capacity(self.steps.len());
+        for (number, step) in self.steps.iter().enumerate()
+        {
+            let result = match step
+            {
+                MissionStep::Check { condition: Condition::Settled { .. } } => Ok(()),
+                MissionStep::Check { condition } => check_pose(&plan, condition),
+                _ => apply_step(&mut plan, step),
+            };
+            result.map_err(|e| abort_error("Dry run", number, step, e))?;
+            poses.push(plan.get_end_effector_position());
+        }
+        Ok(poses)
+    }
+
+    pub fn run<D: MotorDriver>(&self, controller: &mut ArmController<D>, mut state_store: Option<&mut StateStore>) -> Result<usize, RoboticArmError>
+    {
+        // returns how many steps ran, which is all of them if it returns Ok
+        self.dry_run(controller.get_arm())?;
+        // refused unless the arm is homed
+        controller.handle(ArmCommand::SetMode { mode: ControlMode::Script }, Instant::now())?;
+
+        for (number, step) in self.steps.iter().enumerate()
+        {
+            println!("Step {}: {:?}", number + 1, step);
+            let result = self.run_step(controller, state_store.as_deref_mut(), step);
+            save_state(controller, state_store.as_deref_mut(), false);
+            if let Err(e) = result
+            {
+                // Script -> Hold holds the motors
+                if let Err(stop) = controller.handle(ArmCommand::Stop, Instant::now())
+                {
+                    println!("{}", stop.report());
+                }
+                save_state(controller, state_store, true);
+                return Err(abort_error("Mission aborted", number, step, e));
+            }
+        }
+        let result = controller.handle(ArmCommand::SetMode { mode: ControlMode::Idle }, Instant::now());
+        save_state(controller, state_store, true);
+        result.map(|_| self.steps.len())
+    }
+
+    fn run_step<D: MotorDriver>(&self, controller: &mut ArmController<D>, state_store: Option<&mut StateStore>, step: &MissionStep) -> Result<(), RoboticArmError>
+    {
+        match step
+        {
+            MissionStep::Wait { ms } => wait(controller, state_store, Duration::from_millis(*ms)),
+            MissionStep::Check { condition: Condition::Settled { tolerance_ticks } } => check_settled(controller, *tolerance_ticks),
+            MissionStep::Check { condition } => check_pose(controller.get_arm(), condition),
+            _ => match step.to_command()
+            {
+                Some(command) => controller.handle(command, Instant::now()).map(|_| ()),
+                None => Ok(()),
+            },
+        }
+    }
+}
+
+
+fn apply_step(arm: &mut RoboticArmSolver, step: &MissionStep) -> Result<(), RoboticArmError>
+{
+    match step.to_command()
+    {
+        Some(command) => arm.apply_command(command),
+        None => Ok(()),
+    }
+}
+
+fn check_pose(arm: &RoboticArmSolver, condition: &Condition) -> Result<(), RoboticArmError>
+{
+    let Condition::AtPose { x, y, si, tolerance } = *condition else {
+        return Ok(());
+    };
+    let [ax, ay, asi] = arm.get_end_effector_position();
+    if (ax - x).hypot(ay - y) > tolerance || (asi - si).abs() > tolerance
+    {
+        return Err(RoboticArmError::CheckFailed(format!("End effector at [{ax:.1}, {ay:.1}, {asi:.3}], expected [{x}, {y}, {si}]")));
+    }
+    Ok(())
+}
+
+fn check_settled<D: MotorDriver>(controller: &mut ArmController<D>, tolerance_ticks: u16) -> Result<(), RoboticArmError>
+{
+    let feedback = controller.get_driver_mut().read_feedback()
+        .ok_or_else(|| RoboticArmError::CheckFailed("No encoder feedback to check".into()))?;
+    let arm = controller.get_arm();
+    let commanded = arm.get_delta_joints();
+    for joint in Joint::ALL
+    {
+        // encoders wrap, take the short way round
+        let error = arm.get_joint_map().tick_distance(joint, feedback.get_joint(joint), commanded.get_joint(joint));
+        if error > tolerance_ticks
+        {
+            return Err(RoboticArmError::CheckFailed(format!("{joint:?} is {error} ticks from where it was sent")));
+        }
+    }
+    Ok(())
+}
+
+fn wait<D: MotorDriver>(controller: &mut ArmController<D>, mut state_store: Option<&mut StateStore>, duration: Duration) -> Result<(), RoboticArmError>
+{
+    // keep the controller (and the simulator) running while we wait
+    let start = Instant::now();
+    while let Some(remaining) = duration.checked_sub(start.elapsed())
+    {
+        controller.update(Instant::now())?;
+        save_state(controller, state_store.as_deref_mut(), false);
+        thread::sleep(remaining.min(WAIT_STEP));
+    }
+    controller.update(Instant::now()).map(|_| ())
+}
+
+fn save_state<D: MotorDriver>(controller: &mut ArmController<D>, state_store: Option<&mut StateStore>, last: bool)
+{
+    // every SAVE_PERIOD while it runs and once at the end, a failing disk does not stop the mission
+    if let Some(store) = state_store
+    {
+        let state = controller.get_saved_state();
+        let result = if last { store.save(&state) } else { store.update(state, Instant::now()).map(|_| ()) };
+        if let Err(e) = result
+        {
+            println!("{}", e.report());
+        }
+    }
+}
+
+fn abort_error(stage: &str, number: usize, step: &MissionStep, e: RoboticArmError) -> RoboticArmError
+{
+    RoboticArmError::MissionAborted { context: format!("{stage} at step {} ({step:?})", number + 1), source: Box::new(e) }
+}
+
+
+
+
+// ------------------------------- unit tests -------------------------------------
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::robotics::arm_controller::OperatingMode;
+    use crate::robotics::robot_driver::JointWiring;
+    use crate::robotics::robot_driver::mock::{test_arm, MockDriver};
+
+    fn homed_controller(driver: MockDriver) -> ArmController<MockDriver>
+    {
+        // homing writes every joint once, the count starts after it
+        let now = Instant::now();
+        let mut controller = ArmController::new(test_arm(), driver, JointWiring::default());
+        controller.start(now).unwrap();
+        controller.handle(ArmCommand::Home, now).unwrap();
+        controller.update(now).unwrap();
+        assert_eq!(controller.get_mode(), OperatingMode::Idle);
+        controller.get_driver_mut().written.clear();
+        controller.get_driver_mut().holds = 0;
+        controller
+    }
+
+    const TOML_MISSION: &str = r#"
+        name = "reach"
+
+        [[steps]]
+        step = "move_to_pose"
+        x = 11.0
+        y = 6.0
+        si = 0.5
+
+        [[steps]]
+        step = "wait"
+        ms = 5
+
+        [[steps]]
+        step = "check"
+        condition = { type = "at_pose", x = 11.0, y = 6.0, si = 0.5, tolerance = 0.01 }
+
+        [[steps]]
+        step = "check"
+        condition = { type = "settled", tolerance_ticks = 2 }
+
+        [[steps]]
+        step = "open_gripper"
+    "#;
+
+    #[test]
+    fn test_toml_and_json_agree()
+    {
+        let from_toml: Mission = toml::from_str(TOML_MISSION).unwrap();
+        let from_json: Mission = serde_json::from_str(&serde_json::to_string(&from_toml).unwrap()).unwrap();
+
+        assert_eq!(from_toml.name, "reach");
+        assert_eq!(from_toml.steps.len(), 5);
+        assert_eq!(from_toml.steps[4], MissionStep::OpenGripper);
+        assert_eq!(from_json, from_toml);
+    }
+
+    #[test]
+    fn test_mission_runs()
+    {
+        let mission: Mission = toml::from_str(TOML_MISSION).unwrap();
+        let mut controller = homed_controller(MockDriver::following());
+
+        assert_eq!(mission.run(&mut controller, None), Ok(5));
+        assert_eq!(controller.get_arm().get_end_effector_position(), [11.0, 6.0, 0.5]);
+        assert_eq!(controller.get_arm().get_updated_state().spool, 4999);
+        // one write per joint for the move and the gripper, then Idle holds where the encoders are
+        assert_eq!(controller.get_driver().writes(), 15);
+        assert_eq!(controller.get_driver().holds, 1);
+        assert_eq!(controller.get_mode(), OperatingMode::Idle);
+    }
+
+    #[test]
+    fn test_mission_needs_homing()
+    {
+        // an unhomed arm does not take script moves, so nothing moves
+        let mission: Mission = toml::from_str(TOML_MISSION).unwrap();
+        let mut controller = ArmController::new(test_arm(), MockDriver::default(), JointWiring::default());
+        controller.start(Instant::now()).unwrap();
+
+        assert!(matches!(mission.run(&mut controller, None), Err(RoboticArmError::CommandRejected { mode: OperatingMode::Unhomed, .. })));
+        assert_eq!(controller.get_mode(), OperatingMode::Unhomed);
+        assert_eq!(controller.get_driver().writes(), 0);
+    }
+
+    #[test]
+    fn test_mission_saves_state()
+    {
+        let path = std::env::temp_dir().join(format!("robot_arm_mission_{}.json", std::process::id()));
+        let mut store = StateStore::new(&path);
+        let mission: Mission = toml::from_str(TOML_MISSION).unwrap();
+        let mut controller = homed_controller(MockDriver::following());
+
+        mission.run(&mut controller, Some(&mut store)).unwrap();
+        let saved = store.load().unwrap().unwrap();
+        assert!(saved.homed);
+        assert_eq!(saved.pose, [11.0, 6.0, 0.5]);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_dry_run_catches_unreachable_waypoint()
+    {
+        // nothing moves when a later step can not be reached
+        let mission = Mission {
+            name: String::new(),
+            steps: vec![
+                MissionStep::MoveToPose { x: 11.0, y: 6.0, si: 0.5 },
+                MissionStep::MoveToPose { x: 40.0, y: 0.0, si: 0.0 },
+            ],
+        };
+        let mut controller = homed_controller(MockDriver::default());
+
+        let error = mission.run(&mut controller, None).unwrap_err();
+        assert!(error.to_string().contains("Dry run at step 2"));
+        assert_eq!(controller.get_driver().writes(), 0);
+        assert_eq!(controller.get_mode(), OperatingMode::Idle);
+        assert_eq!(controller.get_arm().get_end_effector_position(), [12.0, 6.0, 0.5]);
+        assert_eq!(mission.dry_run(controller.get_arm()).map(|poses| poses.len()).ok(), None);
+    }
+
+    #[test]
+    fn test_failed_check_aborts_and_holds()
+    {
+        // without feedback the arm can not be shown to have settled
+        let mission = Mission {
+            name: String::new(),
+            steps: vec![
+                MissionStep::MoveToPose { x: 11.0, y: 6.0, si: 0.5 },
+                MissionStep::Check { condition: Condition::Settled { tolerance_ticks: 2 } },
+                MissionStep::CloseGripper,
+            ],
+        };
+        let mut controller = homed_controller(MockDriver::default());
+
+        let error = mission.run(&mut controller, None).unwrap_err();
+        assert!(matches!(&error, RoboticArmError::MissionAborted { source, .. } if matches!(**source, RoboticArmError::CheckFailed(_))));
+        assert!(error.to_string().contains("step 2"));
+        assert_eq!(controller.get_mode(), OperatingMode::Hold);
+        assert_eq!(controller.get_driver().holds, 1);
+        assert_eq!(controller.get_driver().writes(), 5);
+    }
+}
@@ -0,0 +1,3 @@
+pub mod arm_command;
+pub mod command_source;
+pub mod mission;
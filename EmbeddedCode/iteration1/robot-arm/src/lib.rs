@@ -0,0 +1,17 @@
+/*
+William Albertini
+
+Library side of the robot-arm crate. main.rs (and any
+other binaries) pull the arm, networking and simulation
+modules in from here.
+
+*/
+
+pub mod robotics;
+pub mod commands;
+pub mod arm_errors;
+pub mod arm_config;
+pub mod networking;
+pub mod simulation;
+pub mod visualization;
+pub mod telemetry;
@@ -5,11 +5,26 @@ This program utilizes two threads to control data transfer.
 The first thread hosts a UDP server and waits for data
 to arrive from ESP32. This data is decoded and piped to the
 other thread, which controls the inverse kinematics and motor
-controller interface. 
+controller interface.
 
 Data coming from the controller tells the end-effector how to
-move. If a state is unreachable or a singularity occurs, the 
-end effector position is not updated.
+move. If a state is unreachable or a singularity occurs, the
+end effector position is not updated. Why is logged
+(RoboticArmError::report()), recorded in telemetry and
+reported over RPC and CCSDS. Every command goes through
+ArmController (robotics/arm_controller.rs), which has to be
+homed before the arm takes any moves.
+
+Settings come from --config <file> (see robot-arm.toml) and
+--set <section.key>=<value>. The other options pick where
+commands come from (the UDP server, or --ccsds, --replay,
+--script, --tcp, --stdin, --rpc or --mission), what drives
+the arm (--simulate instead of the SPI motor controllers)
+and what is kept (--state-file, --telemetry, --record).
+The UDP server needs --key-file and --tcp and --rpc only
+listen on loopback, unless --insecure is given. Every
+problem with the options is listed before anything moves.
+How each piece works is in its own module header.
 
 */
 
@@ -17,62 +32,634 @@ end effector position is not updated.
 
 
 // external imports
-use std::net::
-{
-    Ipv4Addr,
-    SocketAddrV4,
-};
+use std::fs::OpenOptions;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::thread;
-use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, Instant};
+use std::sync::mpsc;
 // internal imports
-mod robotics;
-mod arm_errors;
-mod networking;
 // mod hardware_interface;
-use networking::network_interface::NetworkHandler;
-use networking::data_handler::DataHandler;
-use robotics::arm_state::{RoboticArmSolver, ArmState, AngleToEncoderMap};
-use robotics::robot_driver::RobotDriver;
+use robot_arm::arm_config::ArmConfig;
+use robot_arm::arm_errors::RoboticArmError;
+use robot_arm::commands::command_source::{stdin_source, tcp_source, ArmCommandSource, ChannelSource, ScriptSource};
+use robot_arm::commands::arm_command::ArmCommand;
+use robot_arm::commands::mission::Mission;
+use robot_arm::networking::network_interface::NetworkHandler;
+use robot_arm::networking::ccsds_interface::{CcsdsHandler, TelemetryDownlink};
+use robot_arm::networking::data_handler::DataHandler;
+use robot_arm::networking::json_rpc::{rpc_source, ArmSnapshot, SharedSnapshot};
+use robot_arm::networking::packet::read_key_file;
+use robot_arm::networking::session_recording::{SessionRecorder, SessionReplay};
+use robot_arm::networking::status_link::{ik_flags, pose_to_wire, read_battery_millivolts, StatusLink};
+use robot_arm::robotics::arm_state::{RoboticArmSolver, ArmState};
+use robot_arm::robotics::robot_driver::{RobotDriver, MotorDriver, JointWiring};
+use robot_arm::robotics::command_watchdog::CommandWatchdog;
+use robot_arm::robotics::arm_controller::{ArmController, OperatingMode};
+use robot_arm::robotics::joint_compensation::CompensatedDriver;
+use robot_arm::robotics::saved_state::StateStore;
+use robot_arm::robotics::velocity_teleop::{TeleopMode, VelocityTeleop};
+use robot_arm::simulation::arm_dynamics::{LinkProperties, MotorProperties};
+use robot_arm::simulation::simulated_arm::SimulatedArm;
+use robot_arm::simulation::free_floating::SpacecraftProperties;
+use robot_arm::telemetry::telemetry_recorder::{IkOutcome, TelemetryEntry, TelemetryRecorder};
+use arm_protocol::status::{StatusPayload, BATTERY_UNKNOWN, FAULT_LINK_LOST};
+
+// how often the loop runs when no data is coming in
+const CONTROL_PERIOD: Duration = Duration::from_millis(10);
+// telemetry files rotate at 10 MB, ten old files are kept
+const TELEMETRY_MAX_BYTES: u64 = 10_000_000;
+const TELEMETRY_MAX_FILES: usize = 10;
+// how often status goes back to the controller
+const STATUS_PERIOD: Duration = Duration::from_millis(100);
 
 fn main() {
-    
-    // // define server socket connection
-    let socket = SocketAddrV4::new(Ipv4Addr::new(172,20,10, 3), 8001);
-
-    // network connection
-    let mut network = NetworkHandler::new(socket);
-    
-    // define pipe
-    let sender: Sender<DataHandler>;
-    let receiver: Receiver<DataHandler>;
-    (sender, receiver) = mpsc::channel();
-
-    // launch server in a seperate thread
-    let handle = thread::spawn(move || {
-        network.launch_server(sender).unwrap();
-    });
-
-    // create a map (angular encoder ticks per revolution)
-    let joint_map = AngleToEncoderMap::new(5000, 5000, 5000, 5000, 5000);
-    let mut robotic_arm = RoboticArmSolver::try_new_from_ef_position(1000.0, 500.0, 300.0, 1800.0, 0.0, 0.0, joint_map).expect("Failed to construct arm");
+
+    // everything about the arm, where it listens and what it was asked to do, checked before anything starts
+    let (options, config) = load_options();
+    let mission = options.mission.as_deref().map(|path| Mission::try_from_file(path).unwrap_or_else(|e| exit_with(e)));
+    let robotic_arm = config.build_solver().unwrap_or_else(|e| exit_with(e));
+    // which motor controller each joint is plugged into
+    let wiring = config.wiring;
     // create driver for interface
-    let mut driver = RobotDriver::new();
+    let driver: Box<dyn MotorDriver> = match options.simulate
+    {
+        Some(time_scale) => Box::new(build_simulator(&robotic_arm, wiring, time_scale, options.free_floating).unwrap_or_else(|e| exit_with(e))),
+        None => {
+            // the real joints have play and sag, the simulated ones do not
+            let bus = config.spi.get_bus().unwrap_or_else(|e| exit_with(e));
+            let spi = RobotDriver::with_spi(bus, config.spi.clock_speed).unwrap_or_else(|e| exit_with(e));
+            Box::new(CompensatedDriver::new(spi, &robotic_arm, config.compensation))
+        },
+    };
+
+    // every command goes through the operating mode state machine
+    let mut controller = ArmController::new(robotic_arm, driver, wiring);
+    if controller.get_driver_mut().read_feedback().is_none()
+    {
+        println!("No encoder feedback: homing, grasps and the saved state can not be confirmed");
+    }
+    // pick up from the last run, or start over at the home pose
+    let mut state_store = options.state_file.as_deref().map(StateStore::new);
+    restore_or_start(&mut controller, state_store.as_ref());
+    log_events(&mut controller);
+
+    // a mission runs on its own and then the program is done
+    if let Some(mission) = mission
+    {
+        run_mission(&mission, options.dry_run, &mut controller, state_store.as_mut());
+        return;
+    }
+
+    // status goes back to the controller (or down the bus, or to RPC clients), whoever is sending commands
+    let (reporting, mut commands) = build_command_source(&config, &options).unwrap_or_else(|e| exit_with(e));
+
+    // joystick streams have to keep talking
+    if commands.expects_heartbeats()
+    {
+        controller.set_watchdog(CommandWatchdog::new(Duration::from_millis(config.limits.watchdog_ms), Instant::now()));
+        if config.teleop.mode == TeleopMode::Velocity
+        {
+            controller.set_velocity_teleop(VelocityTeleop::new(config.teleop));
+        }
+    }
+    if controller.get_mode() == OperatingMode::Unhomed
+    {
+        println!("Home the arm (both buttons, or home) before anything else");
+    }
+
+    let mut telemetry = build_telemetry(&options).unwrap_or_else(|e| exit_with(e));
+    let mut last_status = Instant::now();
+    let mut last_ik_flags = 0;
+    let mut last_ik_error: Option<String> = None;
+
+    loop
+    {
+        // the last move this cycle, for the status flags and telemetry
+        let mut moved = None;
+        match commands.next_command(CONTROL_PERIOD)
+        {
+            Ok(Some(command)) => {
+                // handle case of singularities or EF out of workspace
+                let result = controller.handle(command, Instant::now());
+                if let Some(ik) = ik_outcome(&reporting, &controller, result)
+                {
+                    moved = Some((command, ik));
+                }
+            },
+            Ok(None) => (),
+            Err(e) => {
+                println!("{}", e.report());
+                break;
+            },
+        }
+
+        // watchdog, homing, velocity teleop, and letting the simulator catch up to the wall clock
+        let result = controller.update(Instant::now());
+        if let Some(ik) = ik_outcome(&reporting, &controller, result)
+        {
+            let input = controller.get_teleop().map(|teleop| teleop.get_input()).unwrap_or_else(DataHandler::heartbeat);
+            moved = Some((ArmCommand::Jog(input), ik));
+        }
+        log_events(&mut controller);
+
+        if let Some((_, ik)) = &moved
+        {
+            let rejected = matches!(ik, IkOutcome::Rejected { .. });
+            last_ik_error = match ik
+            {
+                IkOutcome::Rejected { reason } => Some(reason.clone()),
+                IkOutcome::Solved { .. } => None,
+            };
+            let robotic_arm = controller.get_arm();
+            last_ik_flags = ik_flags(robotic_arm.get_solver(), robotic_arm.get_last_target(), rejected);
+        }
+
+        // every cycle, moved or not, the arm may still be getting somewhere
+        if let Some(recorder) = &mut telemetry
+        {
+            let robotic_arm = controller.get_arm();
+            let (target, delta) = (robotic_arm.get_last_target(), robotic_arm.get_delta_joints());
+            let feedback = controller.get_driver_mut().read_feedback();
+            let entry = match moved
+            {
+                Some((command, ik)) => TelemetryEntry::for_command(command, target, ik, delta, feedback),
+                None => TelemetryEntry::for_cycle(target, delta, feedback),
+            };
+            if let Err(e) = recorder.record(&entry)
+            {
+                println!("{}", e.report());
+            }
+        }
+
+        if let Some(store) = &mut state_store
+        {
+            if let Err(e) = store.update(controller.get_saved_state(), Instant::now())
+            {
+                println!("{}", e.report());
+            }
+        }
+
+        let link_lost = controller.link_lost(Instant::now());
+        if last_status.elapsed() >= STATUS_PERIOD
+        {
+            send_status(&reporting, &controller, link_lost, last_ik_flags);
+            last_status = Instant::now();
+        }
+        if let Some(snapshot) = &reporting.snapshot
+        {
+            let mut faults: Vec<String> = controller.get_fault().map(String::from).into_iter().collect();
+            if link_lost
+            {
+                let silence = controller.get_watchdog().map(|watchdog| watchdog.silence(Instant::now())).unwrap_or_default();
+                faults.push(format!("No commands for {silence:?}"));
+            }
+            if controller.is_homed() && !controller.is_homing_confirmed()
+            {
+                faults.push("Homed without encoder feedback, the position is not confirmed".into());
+            }
+            faults.extend(last_ik_error.iter().map(|reason| format!("Last move rejected: {reason}")));
+            let robotic_arm = controller.get_arm();
+            let (pose, joint_angles) = (robotic_arm.get_end_effector_position(), robotic_arm.get_last_joint_angles());
+            let gripper = robotic_arm.get_gripper().get_state();
+            snapshot.publish(ArmSnapshot {
+                pose,
+                joint_angles,
+                commanded: controller.get_last_commanded(),
+                feedback: controller.get_driver_mut().read_feedback(),
+                mode: controller.get_mode(),
+                gripper,
+                faults,
+            });
+        }
+    }
+
+}
+
+fn restore_or_start<D: MotorDriver>(controller: &mut ArmController<D>, state_store: Option<&StateStore>)
+{
+    let saved = match state_store.map(StateStore::load)
+    {
+        Some(Ok(saved)) => saved,
+        Some(Err(e)) => {
+            println!("{}, starting over", e.report());
+            None
+        },
+        None => None,
+    };
+    if let Some(saved) = saved
+    {
+        match controller.restore(&saved, Instant::now())
+        {
+            Ok(reconciliation) => {
+                println!("Restored saved state: {reconciliation:?}");
+                return;
+            },
+            Err(e) => println!("Could not restore saved state: {}, starting over", e.report()),
+        }
+    }
+    controller.start(Instant::now()).unwrap_or_else(|e| exit_with(e));
+}
+
+
+fn ik_outcome<D: MotorDriver>(reporting: &Reporting, controller: &ArmController<D>, result: Result<Option<ArmState>, RoboticArmError>) -> Option<IkOutcome>
+{
+    // what the solver made of a move, None if nothing was asked of it
+    let e = match result
+    {
+        Ok(None) => return None,
+        Ok(Some(_)) => return Some(IkOutcome::Solved { joint_angles: controller.get_arm().get_last_joint_angles().unwrap_or_default() }),
+        Err(e) => e,
+    };
+    let reason = e.report();
+    println!("Refused: {reason}");
+    if let Some(Err(e)) = reporting.downlink.as_ref().map(|downlink| downlink.send_event(&format!("Refused: {reason}")))
+    {
+        println!("{}", e.report());
+    }
+    match e
+    {
+        // refused by the mode, re-arming without a link, a fault or the bus, not the solver
+        RoboticArmError::CommandRejected { .. } | RoboticArmError::IllegalTransition { .. } | RoboticArmError::NetworkError { .. }
+        | RoboticArmError::SpiError { .. } | RoboticArmError::ConfigError { .. } => None,
+        _ => Some(IkOutcome::Rejected { reason }),
+    }
+}
+
+
+fn run_mission<D: MotorDriver>(mission: &Mission, dry_run: bool, controller: &mut ArmController<D>, state_store: Option<&mut StateStore>)
+{
+    // --mission <file> [--dry-run]
+    let unhomed = controller.get_mode() == OperatingMode::Unhomed;
+    if dry_run
+    {
+        // an unhomed arm would be homed before the first step
+        let mut start = controller.get_arm().clone();
+        let result = if unhomed { start.home() } else { Ok(()) };
+        match result.and_then(|_| mission.dry_run(&start))
+        {
+            Ok(poses) => {
+                for (number, (step, pose)) in mission.steps.iter().zip(poses).enumerate()
+                {
+                    println!("Step {}: {:?} -> {:?}", number + 1, step, pose);
+                }
+                println!("Mission {:?} is reachable", mission.name);
+            },
+            Err(e) => println!("{}", e.report()),
+        }
+        return;
+    }
+
+    if unhomed
+    {
+        if let Err(e) = home(controller)
+        {
+            println!("{}", e.report());
+            return;
+        }
+    }
+    let result = mission.run(controller, state_store);
+    log_events(controller);
+    match result
+    {
+        Ok(steps) => println!("Mission {:?} finished, {steps} steps", mission.name),
+        Err(e) => println!("{}", e.report()),
+    }
+}
+
+fn home<D: MotorDriver>(controller: &mut ArmController<D>) -> Result<(), RoboticArmError>
+{
+    // homing finishes (or times out into SafeMode) in update()
+    println!("Homing before the mission");
+    controller.handle(ArmCommand::Home, Instant::now())?;
+    log_events(controller);
+    while controller.get_mode() == OperatingMode::Homing
+    {
+        thread::sleep(CONTROL_PERIOD);
+        let result = controller.update(Instant::now());
+        log_events(controller);
+        result?;
+    }
+    Ok(())
+}
+
+fn log_events<D: MotorDriver>(controller: &mut ArmController<D>)
+{
+    // mode changes, faults and grasps, the controller leaves printing to us
+    for event in controller.take_events()
+    {
+        println!("{event}");
+    }
+}
+
+// where status goes, depending on where commands come from
+struct Reporting
+{
+    status_link: StatusLink,
+    downlink: Option<TelemetryDownlink>,
+    snapshot: Option<SharedSnapshot>,
+    battery: Option<PathBuf>,
+}
+
+fn build_command_source(config: &ArmConfig, options: &Options) -> Result<(Reporting, Box<dyn ArmCommandSource>), RoboticArmError>
+{
+    // --replay, --ccsds, --rpc, --tcp, --stdin or --script (Options::parse() allows one), the UDP server otherwise
+    let mut network = NetworkHandler::new(config.network.bind);
+    // status goes back out through the server socket
+    let mut reporting = Reporting { status_link: network.get_status_link(), downlink: None, snapshot: None, battery: options.battery.clone() };
+
+    if let Some(path) = &options.script
+    {
+        return Ok((reporting, Box::new(ScriptSource::try_from_file(path)?)));
+    }
+    if let Some(address) = options.rpc
+    {
+        let (source, snapshot) = rpc_source(address)?;
+        reporting.snapshot = Some(snapshot);
+        return Ok((reporting, Box::new(source)));
+    }
+    if let Some(address) = options.tcp
+    {
+        return Ok((reporting, Box::new(tcp_source(address)?)));
+    }
+    if options.stdin
+    {
+        return Ok((reporting, Box::new(stdin_source())));
+    }
+
+    // joystick streams, each in a seperate thread
+    let (sender, receiver) = mpsc::channel::<DataHandler>();
+    if let Some((path, speed)) = &options.replay
+    {
+        let replay = SessionReplay::try_from_file(path)?;
+        let speed = *speed;
+        thread::spawn(move || {
+            match replay.replay(&sender, speed)
+            {
+                Ok(sent) => println!("Replayed {sent} datagrams"),
+                Err(e) => println!("{}", e.report()),
+            }
+        });
+    } else if let Some(device) = &options.ccsds
+    {
+        let context = format!("Could not open CCSDS device {}", device.display());
+        let bus = OpenOptions::new().read(true).write(true).open(device).map_err(|e| RoboticArmError::file(context.clone(), e))?;
+        let downlink = bus.try_clone().map_err(|e| RoboticArmError::file(context, e))?;
+        reporting.downlink = Some(TelemetryDownlink::new(Box::new(downlink)));
+        thread::spawn(move || {
+            match CcsdsHandler::new(bus).launch_server(sender)
+            {
+                Ok(accepted) => println!("Bus closed after {accepted} telecommands"),
+                Err(e) => println!("{}", e.report()),
+            }
+        });
+    } else {
+        match &options.key_file
+        {
+            Some(path) => {
+                network.set_key(read_key_file(path)?);
+                let mut default_counter = path.clone().into_os_string();
+                default_counter.push(".counter");
+                network.set_counter_file(options.counter_file.clone().unwrap_or_else(|| PathBuf::from(default_counter)));
+            },
+            None => {
+                // Options::parse() only lets this through with --insecure
+                network.set_insecure(options.insecure);
+                println!("--insecure given, controller packets are not authenticated");
+            },
+        }
+        if let Some(path) = &options.record
+        {
+            network.set_recorder(SessionRecorder::try_new(path)?);
+        }
+        // the server only returns when it can not go on, which ends the command stream
+        thread::spawn(move || {
+            if let Err(e) = network.launch_server(sender)
+            {
+                println!("{}", e.report());
+            }
+        });
+    }
+    Ok((reporting, Box::new(ChannelSource::new(receiver, true))))
+}
+
+fn build_simulator(robotic_arm: &RoboticArmSolver, wiring: JointWiring, time_scale: f64, free_floating: bool) -> Result<SimulatedArm, RoboticArmError>
+{
+    // link lengths are in mm, so inertia is kg mm^2 and torque kg mm^2/s^2
+    let [link1, link2, link3] = robotic_arm.get_solver().link_lengths();
+    let links = [LinkProperties::uniform_rod(0.30, link1),
+                 LinkProperties::uniform_rod(0.20, link2),
+                 LinkProperties::uniform_rod(0.15, link3)];
+    // 1.0, 0.5 and 0.25 Nm after gearing
+    let motors = [MotorProperties::new(1.0e6, 1.0e3),
+                  MotorProperties::new(0.5e6, 1.0e3),
+                  MotorProperties::new(0.25e6, 1.0e3)];
+
+    // no gravity in orbit
+    let mut simulator = SimulatedArm::try_new(robotic_arm, links, motors, 0.0, 15.0, 2.0, wiring)?;
+    simulator.set_time_scale(time_scale);
+
+    // 6U bus, 12 kg and 0.1 kg m^2, shoulder mounted on the +x face
+    if free_floating
+    {
+        simulator.set_free_floating(SpacecraftProperties::new(12.0, 1.0e5, [170.0, 0.0]));
+    }
+    Ok(simulator)
+}
+
+fn build_telemetry(options: &Options) -> Result<Option<TelemetryRecorder>, RoboticArmError>
+{
+    // --telemetry <directory>
+    let Some(directory) = &options.telemetry else { return Ok(None) };
+
+    TelemetryRecorder::try_new(directory, "telemetry", TELEMETRY_MAX_BYTES, TELEMETRY_MAX_FILES).map(Some)
+}
+
+fn send_status<D: MotorDriver>(reporting: &Reporting, controller: &ArmController<D>, link_lost: bool, ik_flags: u8)
+{
+    let status = StatusPayload {
+        pose: pose_to_wire(controller.get_arm().get_end_effector_position()),
+        ik_flags,
+        mode: controller.get_mode().into(),
+        faults: if link_lost { FAULT_LINK_LOST } else { 0 },
+        battery_mv: reporting.battery.as_deref()
+            .and_then(read_battery_millivolts)
+            .unwrap_or(BATTERY_UNKNOWN),
+    };
+
+    if let Err(e) = reporting.status_link.send(&status)
+    {
+        println!("{}", e.report());
+    }
+    if let Some(Err(e)) = reporting.downlink.as_ref().map(|downlink| downlink.send(&status))
+    {
+        println!("{}", e.report());
+    }
+}
 
-    for data in receiver
+// everything given on the command line, read once at start up
+#[derive(Default)]
+struct Options
+{
+    config: Option<PathBuf>,
+    // --set, --bind and --watchdog-ms, in the order given
+    overrides: Vec<String>,
+    simulate: Option<f64>,
+    free_floating: bool,
+    mission: Option<PathBuf>,
+    dry_run: bool,
+    state_file: Option<PathBuf>,
+    telemetry: Option<PathBuf>,
+    record: Option<PathBuf>,
+    replay: Option<(PathBuf, f64)>,
+    ccsds: Option<PathBuf>,
+    script: Option<PathBuf>,
+    rpc: Option<SocketAddr>,
+    tcp: Option<SocketAddr>,
+    stdin: bool,
+    key_file: Option<PathBuf>,
+    counter_file: Option<PathBuf>,
+    insecure: bool,
+    battery: Option<PathBuf>,
+}
+
+impl Options
+{
+    fn parse(args: &[String]) -> (Options, Vec<String>)
     {
-        println!("{:?}", data.return_joystick_data());
-        
-        // handle case of singularities or EF out of workspace
-        if let Err(e) = robotic_arm.update_from_data_handler(data)
+        // every option that is not understood is a problem, not just the first
+        let mut options = Options::default();
+        let mut problems = Vec::new();
+        let mut args = args.iter().peekable();
+        while let Some(option) = args.next()
         {
-            println!("Requested EF position unavailable");
+            let option = option.as_str();
+            // options that take a value get the next argument, whatever it is
+            let mut value = || {
+                let value = args.next().cloned();
+                if value.is_none()
+                {
+                    problems.push(format!("{option} needs a value"));
+                }
+                value
+            };
+            match option
+            {
+                "--config" => options.config = value().map(PathBuf::from),
+                "--set" => options.overrides.extend(value()),
+                "--bind" => options.overrides.extend(value().map(|address| format!("network.bind=\"{address}\""))),
+                "--watchdog-ms" => options.overrides.extend(value().map(|ms| format!("limits.watchdog_ms={ms}"))),
+                "--mission" => options.mission = value().map(PathBuf::from),
+                "--state-file" => options.state_file = value().map(PathBuf::from),
+                "--telemetry" => options.telemetry = value().map(PathBuf::from),
+                "--record" => options.record = value().map(PathBuf::from),
+                "--ccsds" => options.ccsds = value().map(PathBuf::from),
+                "--script" => options.script = value().map(PathBuf::from),
+                "--key-file" => options.key_file = value().map(PathBuf::from),
+                "--counter-file" => options.counter_file = value().map(PathBuf::from),
+                "--battery" => options.battery = value().map(PathBuf::from),
+                "--rpc" => options.rpc = value().and_then(|address| parse_address(option, &address, &mut problems)),
+                "--tcp" => options.tcp = value().and_then(|address| parse_address(option, &address, &mut problems)),
+                "--free-floating" => options.free_floating = true,
+                "--dry-run" => options.dry_run = true,
+                "--stdin" => options.stdin = true,
+                "--insecure" => options.insecure = true,
+                // --simulate runs in real time, --simulate 10 runs ten times faster
+                "--simulate" => {
+                    let scale: f64 = args.next_if(|scale| scale.parse::<f64>().is_ok()).and_then(|scale| scale.parse().ok()).unwrap_or(1.0);
+                    if !(scale.is_finite() && scale > 0.0)
+                    {
+                        problems.push(format!("--simulate time scale {scale} has to be above 0"));
+                    }
+                    options.simulate = Some(scale);
+                },
+                // --replay <file> [speed], real time unless a speed follows the file
+                "--replay" => {
+                    let path = value().map(PathBuf::from);
+                    let speed: f64 = args.next_if(|speed| speed.parse::<f64>().is_ok()).and_then(|speed| speed.parse().ok()).unwrap_or(1.0);
+                    if !(speed.is_finite() && speed >= 0.0)
+                    {
+                        problems.push(format!("--replay speed {speed} can not be negative"));
+                    }
+                    options.replay = path.map(|path| (path, speed));
+                },
+                _ => problems.push(format!("Unknown option {option:?}")),
+            }
         }
+        // commands come from one place, the UDP server if nothing else is given
+        let sources: Vec<&str> = [
+            ("--mission", options.mission.is_some()),
+            ("--script", options.script.is_some()),
+            ("--rpc", options.rpc.is_some()),
+            ("--tcp", options.tcp.is_some()),
+            ("--stdin", options.stdin),
+            ("--replay", options.replay.is_some()),
+            ("--ccsds", options.ccsds.is_some()),
+        ].into_iter().filter(|(_, given)| *given).map(|(source, _)| source).collect();
+        if sources.len() > 1
+        {
+            problems.push(format!("Pick one command source, not {}", sources.join(" and ")));
+        }
+        if options.record.is_some() && !sources.is_empty()
+        {
+            problems.push(format!("--record only applies to the UDP server, not {}", sources.join(" or ")));
+        }
+        // the UDP server only takes signed packets unless told otherwise
+        let udp_server = sources.is_empty();
+        if udp_server && options.key_file.is_none() && !options.insecure
+        {
+            problems.push("The UDP server needs --key-file <file>, or --insecure to take unsigned packets".to_string());
+        }
+        // JSON-RPC and TCP clients have no key, so they have to be on this machine
+        check_local("--rpc", options.rpc, options.insecure, &mut problems);
+        check_local("--tcp", options.tcp, options.insecure, &mut problems);
+        (options, problems)
+    }
+}
 
-        let delta: ArmState = robotic_arm.get_delta_joints();
-        println!("Delta joints: {:?}", delta);
-        driver.write_mac(delta.roll, 1, 2);
+fn check_local(option: &str, address: Option<SocketAddr>, insecure: bool, problems: &mut Vec<String>)
+{
+    // the clients are not authenticated, anything but loopback has to be asked for
+    if let Some(address) = address.filter(|address| !address.ip().is_loopback() && !insecure)
+    {
+        problems.push(format!("{option} {address} can be reached from other machines, use 127.0.0.1 or add --insecure"));
     }
+}
 
-    handle.join().unwrap();
+fn parse_address(option: &str, address: &str, problems: &mut Vec<String>) -> Option<SocketAddr>
+{
+    match address.parse()
+    {
+        Ok(address) => Some(address),
+        Err(e) => {
+            problems.push(format!("{option} {address:?} is not an address like 127.0.0.1:8003 ({e})"));
+            None
+        },
+    }
+}
 
+fn load_options() -> (Options, ArmConfig)
+{
+    // the command line, then --config <file> with every override in order, all of it reported at once
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (options, problems) = Options::parse(&args);
+    let config = ArmConfig::load(options.config.as_deref(), &options.overrides);
+
+    if !problems.is_empty()
+    {
+        println!("Invalid options:\n    {}", problems.join("\n    "));
+    }
+    match config
+    {
+        Ok(config) if problems.is_empty() => (options, config),
+        Ok(_) => std::process::exit(1),
+        Err(e) => exit_with(e),
+    }
+}
+
+fn exit_with(error: RoboticArmError) -> !
+{
+    // nothing has moved yet, so stop with the whole error chain rather than a panic
+    println!("{}", error.report());
+    std::process::exit(1);
 }
@@ -0,0 +1,527 @@
+/*
+William Albertini
+
+Command and telemetry interface for the spacecraft bus,
+an alternative to the UDP NetworkHandler when commands
+come from the flight computer instead of the joystick.
+Both feed the same Sender<DataHandler>, the control loop
+does not know which one it is listening to.
+
+Telecommands are CCSDS space packets (space_packet.rs) on
+COMMAND_APID. The first byte of the user data is a function
+code:
+
+    FC_NOOP     no arguments, keeps the watchdog fed
+    FC_JOG      x, y, roll, pitch (i8, -AXIS_MAX..AXIS_MAX)
+                and a button byte (BUTTON1, BUTTON2 bits set
+                when pressed)
+    FC_REARM    no arguments, same as holding both buttons
+
+Packets for other APIDs, telemetry packets and repeated
+or old sequence counts are dropped and logged, the same
+way PacketFilter treats UDP datagrams. Telecommands have no
+session start flag, so a flight computer that restarts (its
+count back at 0) is picked up again once RESYNC_AFTER
+telecommands in a row have counted up from each other while
+looking old. Repeats of the same old packet never do that.
+
+Telemetry goes back on TELEMETRY_APID as one packet per
+status (arm_protocol::status payload) through a
+TelemetryDownlink, which can be cloned into the control
+loop like the StatusLink. Events (why a command was
+refused, the whole error chain) go down on EVENT_APID as
+UTF-8 text, at most MAX_EVENT_SIZE bytes, with their own
+sequence count.
+
+TelecommandGenerator builds commands for bench testing.
+
+*/
+
+// external imports
+use std::io::{Read, Write};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use arm_protocol::joystick::{encode_button, AXIS_MAX};
+use arm_protocol::status::StatusPayload;
+
+// internal imports
+use crate::arm_errors::RoboticArmError;
+use super::data_handler::{DataHandler, RawDatagram};
+use super::space_packet::{read_space_packet, CucTime, SpacePacket, PACKET_TYPE_TELECOMMAND, PACKET_TYPE_TELEMETRY, SEQUENCE_COUNT_MODULO};
+
+
+pub const COMMAND_APID: u16 = 0x0A0;
+pub const TELEMETRY_APID: u16 = 0x0A1;
+pub const EVENT_APID: u16 = 0x0A2;
+// longer event text is cut short
+pub const MAX_EVENT_SIZE: usize = 256;
+
+pub const FC_NOOP: u8 = 0x00;
+pub const FC_JOG: u8 = 0x01;
+pub const FC_REARM: u8 = 0x02;
+
+// old looking telecommands in a row, counting up, that mean the sender restarted
+pub const RESYNC_AFTER: usize = 4;
+
+pub const BUTTON1: u8 = 0x01;
+pub const BUTTON2: u8 = 0x02;
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Telecommand
+{
+    NoOp,
+    Jog { x: i8, y: i8, roll: i8, pitch: i8, buttons: u8 },
+    Rearm,
+}
+
+impl Telecommand
+{
+    pub fn to_user_data(&self) -> Vec<u8>
+    {
+        match *self
+        {
+            Telecommand::NoOp => vec![FC_NOOP],
+            Telecommand::Jog { x, y, roll, pitch, buttons } => vec![FC_JOG, x as u8, y as u8, roll as u8, pitch as u8, buttons],
+            Telecommand::Rearm => vec![FC_REARM],
+        }
+    }
+
+    pub fn from_user_data(user_data: &[u8]) -> Result<Telecommand, RoboticArmError>
+    {
+        let Some((&function_code, arguments)) = user_data.split_first() else {
+            return Err(RoboticArmError::MalformedPacket("Telecommand has no function code".into()));
+        };
+        let expected = match function_code
+        {
+            FC_NOOP | FC_REARM => 0,
+            FC_JOG => 5,
+            _ => return Err(RoboticArmError::MalformedPacket(format!("Unknown function code {function_code:#04x}"))),
+        };
+        if arguments.len() != expected
+        {
+            return Err(RoboticArmError::MalformedPacket(format!("Function code {function_code:#04x} takes {expected} bytes, got {}", arguments.len())));
+        }
+
+        match function_code
+        {
+            FC_NOOP => Ok(Telecommand::NoOp),
+            FC_REARM => Ok(Telecommand::Rearm),
+            _ => {
+                let axes = [arguments[0] as i8, arguments[1] as i8, arguments[2] as i8, arguments[3] as i8];
+                if let Some(axis) = axes.iter().find(|axis| axis.unsigned_abs() > AXIS_MAX as u8)
+                {
+                    return Err(RoboticArmError::MalformedPacket(format!("Jog axis {axis} out of range")));
+                }
+                Ok(Telecommand::Jog { x: axes[0], y: axes[1], roll: axes[2], pitch: axes[3], buttons: arguments[4] })
+            },
+        }
+    }
+
+    pub fn to_data_handler(&self) -> DataHandler
+    {
+        // ground commands are already in arm axes, no stick layout to undo
+        match *self
+        {
+            Telecommand::NoOp => DataHandler::heartbeat(),
+            Telecommand::Jog { x, y, roll, pitch, buttons } => DataHandler::new(x as i16, y as i16, roll as i16, pitch as i16,
+                encode_button(buttons & BUTTON1 != 0) as i16, encode_button(buttons & BUTTON2 != 0) as i16),
+            Telecommand::Rearm => DataHandler::new(0, 0, 0, 0, encode_button(true) as i16, encode_button(true) as i16),
+        }
+    }
+}
+
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TelecommandFilter
+{
+    last_count: Option<u16>,
+    // last old looking count and how many have counted up to it in a row
+    restart: Option<(u16, usize)>,
+    accepted: usize,
+    rejected: usize,
+}
+
+impl TelecommandFilter
+{
+    pub fn new() -> TelecommandFilter
+    {
+        TelecommandFilter::default()
+    }
+
+    pub fn accept(&mut self, packet: &SpacePacket) -> Result<DataHandler, RoboticArmError>
+    {
+        let result = self.decode(packet);
+        match result
+        {
+            Ok(_) => self.accepted += 1,
+            Err(_) => self.rejected += 1,
+        }
+        result
+    }
+
+    fn decode(&mut self, packet: &SpacePacket) -> Result<DataHandler, RoboticArmError>
+    {
+        let header = &packet.header;
+        if header.packet_type != PACKET_TYPE_TELECOMMAND || header.apid != COMMAND_APID
+        {
+            return Err(RoboticArmError::MalformedPacket(format!("Not an arm telecommand (type {}, APID {:#05x})", header.packet_type, header.apid)));
+        }
+        let command = Telecommand::from_user_data(&packet.user_data)?;
+
+        // 14 bit counts, anything up to half the range ahead is newer
+        if let Some(last) = self.last_count
+        {
+            let ahead = header.sequence_count.wrapping_sub(last) % SEQUENCE_COUNT_MODULO;
+            if (ahead == 0 || ahead >= SEQUENCE_COUNT_MODULO / 2) && !self.restarted(header.sequence_count)
+            {
+                return Err(RoboticArmError::StalePacket(format!("Dropped telecommand {} (last accepted {last})", header.sequence_count)));
+            }
+        }
+        self.last_count = Some(header.sequence_count);
+        self.restart = None;
+
+        let mut data = command.to_data_handler();
+        data.raw = RawDatagram::new(&packet.to_bytes());
+        Ok(data)
+    }
+
+    fn restarted(&mut self, count: u16) -> bool
+    {
+        // true once enough old looking counts have followed on from each other
+        let run = match self.restart
+        {
+            Some((previous, run)) if (previous + 1) % SEQUENCE_COUNT_MODULO == count => run + 1,
+            _ => 1,
+        };
+        self.restart = Some((count, run));
+        if run < RESYNC_AFTER
+        {
+            return false;
+        }
+        println!("Telecommand counts restarted, following on from {count}");
+        true
+    }
+
+    pub fn get_accepted(&self) -> usize
+    {
+        self.accepted
+    }
+
+    pub fn get_rejected(&self) -> usize
+    {
+        self.rejected
+    }
+}
+
+
+pub struct CcsdsHandler<R: Read>
+{
+    reader: R,
+}
+
+impl<R: Read> CcsdsHandler<R>
+{
+    pub fn new(reader: R) -> CcsdsHandler<R>
+    {
+        CcsdsHandler { reader }
+    }
+
+    pub fn launch_server(&mut self, sender: Sender<DataHandler>) -> Result<usize, RoboticArmError>
+    {
+        // runs until the bus closes, returns how many commands went through
+        let mut filter = TelecommandFilter::new();
+        loop
+        {
+            let packet = match read_space_packet(&mut self.reader)
+            {
+                Ok(Some(packet)) => packet,
+                Ok(None) => return Ok(filter.get_accepted()),
+                // a refused packet has been read to its end, carry on with the next one
+                Err(RoboticArmError::MalformedPacket(e)) => {
+                    println!("{e}");
+                    continue;
+                },
+                Err(e) => return Err(e),
+            };
+
+            let data = match filter.accept(&packet)
+            {
+                Ok(data) => data,
+                Err(e) => {
+                    println!("{e} ({} rejected so far)", filter.get_rejected());
+                    continue;
+                },
+            };
+            if sender.send(data).is_err()
+            {
+                return Err(RoboticArmError::BadPipe("Bad pipe".into()));
+            }
+        }
+    }
+}
+
+
+struct DownlinkState
+{
+    writer: Box<dyn Write + Send>,
+    status_count: u16,
+    event_count: u16,
+}
+
+#[derive(Clone)]
+pub struct TelemetryDownlink
+{
+    state: Arc<Mutex<DownlinkState>>,
+}
+
+impl TelemetryDownlink
+{
+    pub fn new(writer: Box<dyn Write + Send>) -> TelemetryDownlink
+    {
+        TelemetryDownlink { state: Arc::new(Mutex::new(DownlinkState { writer, status_count: 0, event_count: 0 })) }
+    }
+
+    pub fn send(&self, status: &StatusPayload) -> Result<(), RoboticArmError>
+    {
+        self.send_packet(|state| &mut state.status_count, |count| telemetry_packet(status, count, CucTime::now()))
+    }
+
+    pub fn send_event(&self, text: &str) -> Result<(), RoboticArmError>
+    {
+        self.send_packet(|state| &mut state.event_count, |count| event_packet(text, count, CucTime::now()))
+    }
+
+    fn send_packet<C, P>(&self, counter: C, packet: P) -> Result<(), RoboticArmError>
+    where
+        C: FnOnce(&mut DownlinkState) -> &mut u16,
+        P: FnOnce(u16) -> Result<SpacePacket, RoboticArmError>,
+    {
+        let mut state = self.state.lock()
+            .map_err(|_| RoboticArmError::NetworkError { context: "Telemetry downlink lock poisoned".into(), source: None })?;
+        let sequence_count = counter(&mut state);
+        let packet = packet(*sequence_count)?;
+        *sequence_count = (*sequence_count + 1) % SEQUENCE_COUNT_MODULO;
+        let writer = &mut state.writer;
+        writer.write_all(&packet.to_bytes())
+            .and_then(|_| writer.flush())
+            .map_err(|e| RoboticArmError::network("Could not send telemetry", e))
+    }
+}
+
+
+pub fn telemetry_packet(status: &StatusPayload, sequence_count: u16, time: CucTime) -> Result<SpacePacket, RoboticArmError>
+{
+    SpacePacket::try_new(PACKET_TYPE_TELEMETRY, TELEMETRY_APID, sequence_count, time, status.to_bytes().to_vec())
+}
+
+pub fn event_packet(text: &str, sequence_count: u16, time: CucTime) -> Result<SpacePacket, RoboticArmError>
+{
+    // cut at a character boundary so the text stays valid UTF-8
+    let mut end = text.len().min(MAX_EVENT_SIZE);
+    while !text.is_char_boundary(end)
+    {
+        end -= 1;
+    }
+    SpacePacket::try_new(PACKET_TYPE_TELEMETRY, EVENT_APID, sequence_count, time, text.as_bytes()[..end].to_vec())
+}
+
+pub fn decode_event(packet: &SpacePacket) -> Result<String, RoboticArmError>
+{
+    if packet.header.packet_type != PACKET_TYPE_TELEMETRY || packet.header.apid != EVENT_APID
+    {
+        return Err(RoboticArmError::MalformedPacket(format!("Not an arm event (APID {:#05x})", packet.header.apid)));
+    }
+    String::from_utf8(packet.user_data.clone()).map_err(|e| RoboticArmError::MalformedPacket(format!("Event is not UTF-8: {e}")))
+}
+
+pub fn decode_telemetry(packet: &SpacePacket) -> Result<StatusPayload, RoboticArmError>
+{
+    if packet.header.packet_type != PACKET_TYPE_TELEMETRY || packet.header.apid != TELEMETRY_APID
+    {
+        return Err(RoboticArmError::MalformedPacket(format!("Not arm telemetry (APID {:#05x})", packet.header.apid)));
+    }
+    StatusPayload::from_bytes(&packet.user_data).map_err(|e| RoboticArmError::MalformedPacket(e.to_string()))
+}
+
+
+pub struct TelecommandGenerator
+{
+    sequence_count: u16,
+}
+
+impl TelecommandGenerator
+{
+    pub fn new() -> TelecommandGenerator
+    {
+        TelecommandGenerator { sequence_count: 0 }
+    }
+
+    pub fn next_packet(&mut self, command: &Telecommand) -> SpacePacket
+    {
+        // the user data is never more than six bytes, it always fits
+        let packet = SpacePacket::try_new(PACKET_TYPE_TELECOMMAND, COMMAND_APID, self.sequence_count, CucTime::now(), command.to_user_data())
+            .expect("telecommand fits in a space packet");
+        self.sequence_count = (self.sequence_count + 1) % SEQUENCE_COUNT_MODULO;
+        packet
+    }
+}
+
+impl Default for TelecommandGenerator
+{
+    fn default() -> TelecommandGenerator
+    {
+        TelecommandGenerator::new()
+    }
+}
+
+
+
+
+// ------------------------------- unit tests -------------------------------------
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::io::Cursor;
+    use std::sync::mpsc;
+    use arm_protocol::status::{ArmMode, FAULT_LINK_LOST};
+    use crate::networking::space_packet::SpacePacketHeader;
+
+    #[test]
+    fn test_generated_commands_reach_the_channel()
+    {
+        // a short pass from the generator over an in-memory bus
+        let mut generator = TelecommandGenerator::new();
+        let commands = [
+            Telecommand::NoOp,
+            Telecommand::Jog { x: 4, y: -2, roll: 0, pitch: 8, buttons: BUTTON1 },
+            Telecommand::Rearm,
+        ];
+        let bus: Vec<u8> = commands.iter().flat_map(|command| generator.next_packet(command).to_bytes()).collect();
+
+        let (sender, receiver) = mpsc::channel();
+        let accepted = CcsdsHandler::new(Cursor::new(bus)).launch_server(sender).unwrap();
+        let received: Vec<DataHandler> = receiver.iter().collect();
+
+        assert_eq!(accepted, 3);
+        assert!(received[0].heartbeat);
+        assert_eq!(received[1].return_joystick_data(), ([4, -2, 0], [0, 8, 1]));
+        assert!(received[2].both_buttons_pressed());
+        assert!(!received[2].raw.as_slice().is_empty());
+    }
+
+    #[test]
+    fn test_bad_commands_dropped()
+    {
+        let mut generator = TelecommandGenerator::new();
+        let jog = generator.next_packet(&Telecommand::Jog { x: 1, y: 0, roll: 0, pitch: 0, buttons: 0 });
+        let mut wrong_apid = generator.next_packet(&Telecommand::NoOp);
+        wrong_apid.header.apid = 0x0A2;
+        let mut bad_axis = generator.next_packet(&Telecommand::NoOp);
+        bad_axis.user_data = vec![FC_JOG, 9, 0, 0, 0, 0];
+        bad_axis.header.data_field_length += 5;
+        let mut unknown = generator.next_packet(&Telecommand::NoOp);
+        unknown.user_data = vec![0x7F];
+
+        // the jog again is a repeat, then one more good packet
+        let last = generator.next_packet(&Telecommand::Rearm);
+        let bus: Vec<u8> = [&jog, &wrong_apid, &bad_axis, &unknown, &jog, &last].iter().flat_map(|packet| packet.to_bytes()).collect();
+
+        let (sender, receiver) = mpsc::channel();
+        let accepted = CcsdsHandler::new(Cursor::new(bus)).launch_server(sender).unwrap();
+        assert_eq!(accepted, 2);
+        assert_eq!(receiver.iter().count(), 2);
+    }
+
+    #[test]
+    fn test_sequence_count_wraps()
+    {
+        let mut filter = TelecommandFilter::new();
+        let packet = |count| {
+            let mut packet = TelecommandGenerator::new().next_packet(&Telecommand::NoOp);
+            packet.header.sequence_count = count;
+            packet
+        };
+
+        assert!(filter.accept(&packet(SEQUENCE_COUNT_MODULO - 1)).is_ok());
+        assert!(filter.accept(&packet(0)).is_ok());
+        assert!(filter.accept(&packet(SEQUENCE_COUNT_MODULO - 1)).is_err());
+    }
+
+    #[test]
+    fn test_resync_after_sender_restart()
+    {
+        let mut filter = TelecommandFilter::new();
+        let mut generator = TelecommandGenerator::new();
+        for _ in 0..100
+        {
+            assert!(filter.accept(&generator.next_packet(&Telecommand::NoOp)).is_ok());
+        }
+
+        // the same old packet over and over is never taken for a restart
+        let mut old = generator.next_packet(&Telecommand::NoOp);
+        old.header.sequence_count = 50;
+        for _ in 0..2 * RESYNC_AFTER
+        {
+            assert!(filter.accept(&old).is_err());
+        }
+
+        // a restarted generator counts from 0 again and is followed once it has shown it
+        let mut generator = TelecommandGenerator::new();
+        let first = generator.next_packet(&Telecommand::NoOp);
+        assert!(matches!(filter.accept(&first), Err(RoboticArmError::StalePacket(_))));
+        for _ in 1..RESYNC_AFTER - 1
+        {
+            assert!(matches!(filter.accept(&generator.next_packet(&Telecommand::NoOp)), Err(RoboticArmError::StalePacket(_))));
+        }
+        assert!(filter.accept(&generator.next_packet(&Telecommand::NoOp)).is_ok());
+        assert!(filter.accept(&generator.next_packet(&Telecommand::NoOp)).is_ok());
+        // and from then on it is tracked as usual
+        assert!(filter.accept(&first).is_err());
+    }
+
+    #[test]
+    fn test_telemetry_downlink()
+    {
+        #[derive(Clone, Default)]
+        struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+        impl Write for SharedBuffer
+        {
+            fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize>
+            {
+                self.0.lock().unwrap().write(bytes)
+            }
+            fn flush(&mut self) -> std::io::Result<()>
+            {
+                Ok(())
+            }
+        }
+
+        let buffer = SharedBuffer::default();
+        let downlink = TelemetryDownlink::new(Box::new(buffer.clone()));
+        let status = StatusPayload { pose: [1800, 0, 0], ik_flags: 0, mode: ArmMode::Hold, faults: FAULT_LINK_LOST, battery_mv: 7400 };
+        downlink.send(&status).unwrap();
+        downlink.clone().send(&status).unwrap();
+        let reason = "Joints not updated for pose [21.000, -0.500, 0.000]: Singularity in Elbow";
+        downlink.send_event(reason).unwrap();
+        downlink.send_event(&"é".repeat(MAX_EVENT_SIZE)).unwrap();
+
+        let bytes = buffer.0.lock().unwrap().clone();
+        let mut stream = Cursor::new(bytes);
+        let first = read_space_packet(&mut stream).unwrap().unwrap();
+        let second = read_space_packet(&mut stream).unwrap().unwrap();
+
+        assert_eq!(decode_telemetry(&first).unwrap(), status);
+        assert_eq!(first.header, SpacePacketHeader { sequence_count: 0, ..second.header });
+        assert_eq!(second.header.sequence_count, 1);
+
+        // events are counted on their own APID, long ones are cut short
+        let event = read_space_packet(&mut stream).unwrap().unwrap();
+        assert_eq!(event.header.sequence_count, 0);
+        assert_eq!(decode_event(&event).unwrap(), reason);
+        assert!(decode_telemetry(&event).is_err());
+        let long = read_space_packet(&mut stream).unwrap().unwrap();
+        assert_eq!(decode_event(&long).unwrap().len(), MAX_EVENT_SIZE);
+    }
+}
@@ -7,11 +7,70 @@ controller data interact with this struct. It contains
 the data from two joysticks, used to manually control
 the robot
 
+A heartbeat from an idle controller comes through as a
+DataHandler with heartbeat set and no input, it only
+tells the control loop the link is alive.
+
+With joint_jog set (FLAG_JOINT_JOG from the controller) the
+axes are joint increments, x shoulder, y elbow, pitch wrist,
+see RoboticArmSolver::jog_joints.
+
+The raw datagram the data was decoded from travels with
+it (RawDatagram) so telemetry can log exactly what came
+off the wire.
+
 */
 
+use std::fmt;
+use serde::{Deserialize, Serialize};
+
+
+// largest datagram kept alongside the decoded data
+pub const MAX_DATAGRAM: usize = 64;
+
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct RawDatagram
+{
+    bytes: [u8; MAX_DATAGRAM],
+    len: usize,
+}
+
+impl RawDatagram
+{
+    pub fn new(bytes: &[u8]) -> RawDatagram
+    {
+        // anything past MAX_DATAGRAM is dropped
+        let len = bytes.len().min(MAX_DATAGRAM);
+        let mut raw = RawDatagram { bytes: [0; MAX_DATAGRAM], len };
+        raw.bytes[..len].copy_from_slice(&bytes[..len]);
+        raw
+    }
+
+    pub fn as_slice(&self) -> &[u8]
+    {
+        &self.bytes[..self.len]
+    }
+}
+
+impl Default for RawDatagram
+{
+    fn default() -> RawDatagram
+    {
+        RawDatagram::new(&[])
+    }
+}
+
+impl fmt::Debug for RawDatagram
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{:?}", self.as_slice())
+    }
+}
 
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub struct DataHandler
 {
     pub x: i16,
@@ -20,13 +79,30 @@ pub struct DataHandler
     pub pitch: i16,
     pub button1: i16,
     pub button2: i16,
+    // no input, the controller is just saying it is still there
+    #[serde(default)]
+    pub heartbeat: bool,
+    // axes move joints instead of the end effector
+    #[serde(default)]
+    pub joint_jog: bool,
+    // datagram this was decoded from (empty if it did not come off the network)
+    #[serde(skip)]
+    pub raw: RawDatagram,
 }
 
 impl DataHandler 
 {
     pub fn new(x: i16, y: i16, roll: i16, pitch: i16, button1: i16, button2: i16) -> DataHandler
     {
-        DataHandler { x, y, roll, pitch, button1, button2 }
+        DataHandler { x, y, roll, pitch, button1, button2, heartbeat: false, joint_jog: false, raw: RawDatagram::default() }
+    }
+
+    pub fn heartbeat() -> DataHandler
+    {
+        // sticks centered, buttons released
+        let mut data = DataHandler::new(0, 0, 0, 0, 1, 1);
+        data.heartbeat = true;
+        data
     }
 
     pub fn from_buffer(buffer: &[i16; 6]) -> DataHandler 
@@ -38,6 +114,9 @@ impl DataHandler
             pitch: buffer[3],
             button1: buffer[4],
             button2: buffer[5],
+            heartbeat: false,
+            joint_jog: false,
+            raw: RawDatagram::default(),
         }
     }
 
@@ -0,0 +1,372 @@
+/*
+William Albertini
+
+JSON-RPC 2.0 over TCP for ground station software and test
+scripts, one request per line and one response per line:
+
+    --> {"jsonrpc": "2.0", "method": "move_to_pose", "params": {"x": 1500.0, "y": 0.0, "si": 0.0}, "id": 1}
+    <-- {"jsonrpc": "2.0", "result": {"queued": true}, "id": 1}
+
+Methods:
+
+    get_state       everything in the ArmSnapshot
+    get_pose        {"x", "y", "si"} of the end effector
+    get_faults      list of active faults
+    move_to_pose    {"x", "y", "si"}
+    jog             {"x", "y", "roll", "pitch"}, each at most
+                    AXIS_MAX, missing axes are 0. With
+                    "joints": true x, y and pitch move the
+                    shoulder, elbow and wrist instead
+    set_mode        {"mode": "idle" | "teleop" | "script" | "hold"}
+    gripper         {"position": 0 closed to 1 open} or
+                    {"preset": "open" | "closed" | "pinch"}
+    home, stop      no params
+
+Commands are queued for the control loop as ArmCommands
+(rpc_source() is an ArmCommandSource like the others), the
+response only says they were queued. Whether a move was
+reachable shows up in get_state and get_faults afterwards.
+
+The control loop owns the arm, so queries are answered
+from an ArmSnapshot it publishes every cycle through a
+SharedSnapshot, the same way the StatusLink is shared.
+
+Clients are not authenticated, so main.rs only serves it on
+a loopback address unless --insecure is given.
+
+*/
+
+// external imports
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use arm_protocol::joystick::AXIS_MAX;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+// internal imports
+use crate::arm_errors::RoboticArmError;
+use crate::commands::arm_command::{ArmCommand, ControlMode};
+use crate::commands::command_source::ChannelSource;
+use crate::robotics::arm_controller::OperatingMode;
+use crate::robotics::arm_state::ArmState;
+use crate::robotics::gripper::{GripState, GripperCommand};
+use super::data_handler::DataHandler;
+
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+// the control loop has stopped taking commands
+pub const NOT_RUNNING: i64 = -32000;
+
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ArmSnapshot
+{
+    // [x, y, si] of the end effector
+    pub pose: [f64; 3],
+    // shoulder, elbow, wrist (radians) for the pose
+    pub joint_angles: Option<[f64; 3]>,
+    // last state written to the motor controllers
+    pub commanded: Option<ArmState>,
+    // encoder readback, if the driver has it
+    pub feedback: Option<ArmState>,
+    pub mode: OperatingMode,
+    // moving, reached, grasped, stalled or sent (no feedback)
+    pub gripper: GripState,
+    pub faults: Vec<String>,
+}
+
+impl Default for ArmSnapshot
+{
+    fn default() -> ArmSnapshot
+    {
+        ArmSnapshot {
+            pose: [0.0; 3],
+            joint_angles: None,
+            commanded: None,
+            feedback: None,
+            mode: OperatingMode::Boot,
+            gripper: GripState::Reached,
+            faults: Vec::new(),
+        }
+    }
+}
+
+
+#[derive(Clone, Default)]
+pub struct SharedSnapshot
+{
+    snapshot: Arc<Mutex<ArmSnapshot>>,
+}
+
+impl SharedSnapshot
+{
+    pub fn new() -> SharedSnapshot
+    {
+        SharedSnapshot::default()
+    }
+
+    pub fn publish(&self, snapshot: ArmSnapshot)
+    {
+        if let Ok(mut shared) = self.snapshot.lock()
+        {
+            *shared = snapshot;
+        }
+    }
+
+    pub fn get(&self) -> ArmSnapshot
+    {
+        self.snapshot.lock().map(|snapshot| snapshot.clone()).unwrap_or_default()
+    }
+}
+
+
+#[derive(Deserialize)]
+struct PoseParams
+{
+    x: f64,
+    y: f64,
+    si: f64,
+}
+
+#[derive(Deserialize)]
+struct JogParams
+{
+    #[serde(default)]
+    x: i16,
+    #[serde(default)]
+    y: i16,
+    #[serde(default)]
+    roll: i16,
+    #[serde(default)]
+    pitch: i16,
+    #[serde(default)]
+    joints: bool,
+}
+
+#[derive(Deserialize)]
+struct ModeParams
+{
+    mode: ControlMode,
+}
+
+
+fn error_response(id: Value, code: i64, message: String) -> Value
+{
+    json!({ "jsonrpc": "2.0", "error": { "code": code, "message": message }, "id": id })
+}
+
+fn params<T: for<'de> Deserialize<'de>>(request: &Value) -> Result<T, (i64, String)>
+{
+    serde_json::from_value(request.get("params").cloned().unwrap_or(Value::Null))
+        .map_err(|e| (INVALID_PARAMS, format!("Invalid params: {e}")))
+}
+
+fn to_command(method: &str, request: &Value) -> Result<Option<ArmCommand>, (i64, String)>
+{
+    // None for the methods that only read
+    let command = match method
+    {
+        "get_state" | "get_pose" | "get_faults" => return Ok(None),
+        "move_to_pose" => {
+            let PoseParams { x, y, si } = params(request)?;
+            ArmCommand::MoveToPose { x, y, si }
+        },
+        "jog" => {
+            let JogParams { x, y, roll, pitch, joints } = params(request)?;
+            if [x, y, roll, pitch].iter().any(|axis| axis.unsigned_abs() > AXIS_MAX as u16)
+            {
+                return Err((INVALID_PARAMS, format!("Jog axes are limited to {AXIS_MAX}")));
+            }
+            // buttons released
+            let mut data = DataHandler::new(x, y, roll, pitch, 1, 1);
+            data.joint_jog = joints;
+            ArmCommand::Jog(data)
+        },
+        "set_mode" => ArmCommand::SetMode { mode: params::<ModeParams>(request)?.mode },
+        "gripper" => ArmCommand::Gripper(params::<GripperCommand>(request)?),
+        "home" => ArmCommand::Home,
+        "stop" => ArmCommand::Stop,
+        _ => return Err((METHOD_NOT_FOUND, format!("Method {method:?} not found"))),
+    };
+    Ok(Some(command))
+}
+
+pub fn handle_request(line: &str, snapshot: &SharedSnapshot, sender: &Sender<ArmCommand>) -> Option<Value>
+{
+    // None for notifications (no id), which get no response
+    let request: Value = match serde_json::from_str(line)
+    {
+        Ok(request) => request,
+        Err(e) => return Some(error_response(Value::Null, PARSE_ERROR, format!("Parse error: {e}"))),
+    };
+    let id = request.get("id").cloned();
+    let reply_id = id.clone().unwrap_or(Value::Null);
+    let Some(method) = request.get("method").and_then(Value::as_str) else {
+        return Some(error_response(reply_id, INVALID_REQUEST, "Request has no method".into()));
+    };
+
+    let result = to_command(method, &request).and_then(|command| match command
+    {
+        Some(command) => sender.send(command)
+            .map(|_| json!({ "queued": true }))
+            .map_err(|_| (NOT_RUNNING, "Control loop is not running".into())),
+        None => {
+            let state = snapshot.get();
+            Ok(match method
+            {
+                "get_pose" => json!({ "x": state.pose[0], "y": state.pose[1], "si": state.pose[2] }),
+                "get_faults" => json!(state.faults),
+                _ => json!(state),
+            })
+        },
+    });
+
+    id?;
+    Some(match result
+    {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": reply_id }),
+        Err((code, message)) => error_response(reply_id, code, message),
+    })
+}
+
+fn serve_client(stream: TcpStream, snapshot: &SharedSnapshot, sender: &Sender<ArmCommand>) -> Result<(), RoboticArmError>
+{
+    let mut writer = stream.try_clone()
+        .map_err(|e| RoboticArmError::network("Could not clone RPC stream", e))?;
+    for line in BufReader::new(stream).lines()
+    {
+        let line = line.map_err(|e| RoboticArmError::network("Could not read RPC request", e))?;
+        if line.trim().is_empty()
+        {
+            continue;
+        }
+        if let Some(response) = handle_request(&line, snapshot, sender)
+        {
+            writeln!(writer, "{response}")
+                .map_err(|e| RoboticArmError::network("Could not send RPC response", e))?;
+        }
+    }
+    Ok(())
+}
+
+pub fn rpc_source(address: SocketAddr) -> Result<(ChannelSource<ArmCommand>, SharedSnapshot), RoboticArmError>
+{
+    // every client gets its own thread, they all feed the same channel
+    let listener = TcpListener::bind(address)
+        .map_err(|e| RoboticArmError::network(format!("Could not listen on {address}"), e))?;
+    println!("JSON-RPC on {:?}", listener.local_addr());
+
+    let snapshot = SharedSnapshot::new();
+    let (sender, receiver) = mpsc::channel();
+    let shared = snapshot.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten()
+        {
+            let snapshot = shared.clone();
+            let sender = sender.clone();
+            thread::spawn(move || {
+                if let Err(e) = serve_client(stream, &snapshot, &sender)
+                {
+                    println!("{}", e.report());
+                }
+            });
+        }
+    });
+    Ok((ChannelSource::new(receiver, false), snapshot))
+}
+
+
+
+
+// ------------------------------- unit tests -------------------------------------
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::time::Duration;
+    use crate::commands::command_source::ArmCommandSource;
+    use crate::robotics::gripper::GripperPreset;
+
+    fn request(line: &str) -> (Option<Value>, Vec<ArmCommand>)
+    {
+        let snapshot = SharedSnapshot::new();
+        snapshot.publish(ArmSnapshot { pose: [1800.0, 0.0, 0.1], faults: vec!["link lost".into()], ..ArmSnapshot::default() });
+        let (sender, receiver) = mpsc::channel();
+        let response = handle_request(line, &snapshot, &sender);
+        (response, receiver.try_iter().collect())
+    }
+
+    #[test]
+    fn test_queries()
+    {
+        let (pose, _) = request(r#"{"jsonrpc": "2.0", "method": "get_pose", "id": 1}"#);
+        let (faults, _) = request(r#"{"jsonrpc": "2.0", "method": "get_faults", "id": "a"}"#);
+        let (state, _) = request(r#"{"jsonrpc": "2.0", "method": "get_state", "id": 3}"#);
+
+        assert_eq!(pose.unwrap(), json!({ "jsonrpc": "2.0", "result": { "x": 1800.0, "y": 0.0, "si": 0.1 }, "id": 1 }));
+        assert_eq!(faults.unwrap()["result"], json!(["link lost"]));
+        assert_eq!(state.unwrap()["result"]["mode"], json!("boot"));
+    }
+
+    #[test]
+    fn test_commands_queued()
+    {
+        let (response, commands) = request(r#"{"jsonrpc": "2.0", "method": "move_to_pose", "params": {"x": 1.0, "y": 2.0, "si": 0.0}, "id": 7}"#);
+        assert_eq!(response.unwrap()["result"], json!({ "queued": true }));
+        assert_eq!(commands, vec![ArmCommand::MoveToPose { x: 1.0, y: 2.0, si: 0.0 }]);
+
+        let (_, commands) = request(r#"{"jsonrpc": "2.0", "method": "jog", "params": {"x": 3}, "id": 8}"#);
+        assert_eq!(commands, vec![ArmCommand::Jog(DataHandler::new(3, 0, 0, 0, 1, 1))]);
+        let (_, commands) = request(r#"{"jsonrpc": "2.0", "method": "jog", "params": {"y": -2, "joints": true}, "id": 8}"#);
+        assert!(matches!(commands[0], ArmCommand::Jog(data) if data.joint_jog && data.y == -2));
+        let (_, commands) = request(r#"{"jsonrpc": "2.0", "method": "set_mode", "params": {"mode": "hold"}, "id": 9}"#);
+        assert_eq!(commands, vec![ArmCommand::SetMode { mode: ControlMode::Hold }]);
+        let (_, commands) = request(r#"{"jsonrpc": "2.0", "method": "gripper", "params": {"preset": "pinch"}, "id": 10}"#);
+        assert_eq!(commands, vec![ArmCommand::Gripper(GripperCommand::Preset(GripperPreset::Pinch))]);
+
+        // notifications are carried out without a response
+        let (response, commands) = request(r#"{"jsonrpc": "2.0", "method": "stop"}"#);
+        assert_eq!((response, commands), (None, vec![ArmCommand::Stop]));
+    }
+
+    #[test]
+    fn test_errors()
+    {
+        let code = |line: &str| request(line).0.unwrap()["error"]["code"].as_i64().unwrap();
+
+        assert_eq!(code("{not json"), PARSE_ERROR);
+        assert_eq!(code(r#"{"jsonrpc": "2.0", "id": 1}"#), INVALID_REQUEST);
+        assert_eq!(code(r#"{"jsonrpc": "2.0", "method": "fly", "id": 1}"#), METHOD_NOT_FOUND);
+        assert_eq!(code(r#"{"jsonrpc": "2.0", "method": "move_to_pose", "params": {"x": 1.0}, "id": 1}"#), INVALID_PARAMS);
+        assert_eq!(code(r#"{"jsonrpc": "2.0", "method": "jog", "params": {"x": 9}, "id": 1}"#), INVALID_PARAMS);
+        assert_eq!(code(r#"{"jsonrpc": "2.0", "method": "set_mode", "params": {"mode": "fly"}, "id": 1}"#), INVALID_PARAMS);
+    }
+
+    #[test]
+    fn test_over_tcp()
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        let (mut source, snapshot) = rpc_source(address).unwrap();
+        snapshot.publish(ArmSnapshot { mode: OperatingMode::Hold, ..ArmSnapshot::default() });
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        writeln!(client, r#"{{"jsonrpc": "2.0", "method": "home", "id": 1}}"#).unwrap();
+        writeln!(client, r#"{{"jsonrpc": "2.0", "method": "get_state", "id": 2}}"#).unwrap();
+
+        let mut lines = BufReader::new(client).lines();
+        let first: Value = serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+        let second: Value = serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+        assert_eq!(first["result"]["queued"], json!(true));
+        assert_eq!(second["result"]["mode"], json!("hold"));
+        assert_eq!(source.next_command(Duration::from_secs(2)).unwrap(), Some(ArmCommand::Home));
+    }
+}
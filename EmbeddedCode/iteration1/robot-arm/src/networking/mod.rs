@@ -1,2 +1,8 @@
 pub mod network_interface;
-pub mod data_handler;
\ No newline at end of file
+pub mod data_handler;
+pub mod packet;
+pub mod session_recording;
+pub mod status_link;
+pub mod space_packet;
+pub mod ccsds_interface;
+pub mod json_rpc;
@@ -14,22 +14,53 @@ the IPV4 socket that the ESP32 is looking for. In
 launch_server(), a mspc Sender is used to pipe data 
 to a queue that the main thread reads. 
 
+Datagrams are versioned packets (packet.rs), they go
+through a PacketFilter that throws out malformed, duplicate
+and out of order packets before anything reaches the arm.
+Once set_key() is given the pre-shared key, only packets
+signed with it get through, anything else is rejected and
+logged with a running count. Without a key launch_server()
+refuses to run unless set_insecure(true) says unsigned
+packets are wanted. set_counter_file() keeps the
+replay counter across restarts (PacketFilter::keep_counter()).
+launch_server() only returns on an error: the socket failing
+(NetworkError) or the main thread hanging up (BadPipe).
+
+The StatusLink (get_status_link()) is pointed at whoever
+sent the last good packet, so the control loop can send
+status back to the controller over the same socket.
+
+With set_recorder() every datagram received is also
+written to a SessionRecorder so the session can be
+replayed later (SessionReplay).
+
 */
 
 use std::net::{
 	UdpSocket,
 	SocketAddrV4,
 };
+use std::path::PathBuf;
 use std::sync::mpsc::Sender;
+use arm_protocol::joystick::{encode_button, JoystickPayload};
 // internal imports
 use crate::arm_errors::RoboticArmError;
-use super::data_handler::DataHandler;
+use super::data_handler::{DataHandler, MAX_DATAGRAM};
+use super::packet::PacketFilter;
+use super::session_recording::SessionRecorder;
+use super::status_link::StatusLink;
 
 
 // create struct handle network interfacing
 pub struct NetworkHandler
 {
 	socket: SocketAddrV4,
+	recorder: Option<SessionRecorder>,
+	status_link: StatusLink,
+	key: Option<Vec<u8>>,
+	// run without a key, taking unsigned packets
+	insecure: bool,
+	counter_file: Option<PathBuf>,
 }
 
 impl NetworkHandler 
@@ -37,56 +68,117 @@ impl NetworkHandler
 
 	pub fn new(socket: SocketAddrV4) -> NetworkHandler 
 	{
-		NetworkHandler{socket}
+		NetworkHandler{socket, recorder: None, status_link: StatusLink::new(), key: None, insecure: false, counter_file: None}
+	}
+
+	pub fn get_status_link(&self) -> StatusLink
+	{
+		self.status_link.clone()
+	}
+
+	pub fn set_key(&mut self, key: Vec<u8>)
+	{
+		self.key = Some(key);
+	}
+
+	pub fn set_insecure(&mut self, insecure: bool)
+	{
+		self.insecure = insecure;
+	}
+
+	pub fn set_counter_file(&mut self, path: PathBuf)
+	{
+		self.counter_file = Some(path);
+	}
+
+	pub fn set_recorder(&mut self, recorder: SessionRecorder)
+	{
+		self.recorder = Some(recorder);
 	}
 
 	pub fn launch_server(&mut self, sender: Sender<DataHandler>) -> Result<(), RoboticArmError> 
 	{
+		// a key, or being told to do without one
+		if self.key.is_none() && !self.insecure
+		{
+			return Err(RoboticArmError::AuthenticationFailed("No pre-shared key set, unsigned packets are only taken with set_insecure(true)".to_string()));
+		}
+
 		// try to bind ipv4 socket to tcp listener
 		let socket = match UdpSocket::bind(self.socket)
 		{
 			Ok(socket) => socket,
-			Err(_) => return Err(RoboticArmError::NetworkError("Failure to bind to socket".into())),
+			Err(e) => return Err(RoboticArmError::network("Failure to bind to socket", e)),
 		};
 		println!("Running on port: {:?}", self.socket);
+		if let Ok(status_socket) = socket.try_clone()
+		{
+			self.status_link.set_socket(status_socket);
+		}
 
 		// launch server and wait for connections
-		let mut buffer: [u8; 6] = [0; 6];
+		let mut buffer: [u8; MAX_DATAGRAM] = [0; MAX_DATAGRAM];
+		let mut filter = match self.key.take()
+		{
+			Some(key) => PacketFilter::with_key(key),
+			// only with set_insecure(true), checked above
+			None => PacketFilter::new(),
+		};
+		if let Some(path) = &self.counter_file
+		{
+			filter.keep_counter(path)?;
+		}
 		loop 
 		{
-			// (amoumt, source) amount is a fixed size [u8; 0] and no messages go back to source
-			let (_, _) = socket.recv_from(&mut buffer).expect("nothing");
+			// (amount, source) status goes back to the source of good packets
+			let (amount, source) = socket.recv_from(&mut buffer)
+				.map_err(|e| RoboticArmError::network("Could not receive joystick datagram", e))?;
+			let datagram = &buffer[..amount];
+			// a failed recording should not take the arm down with it
+			if let Some(recorder) = &mut self.recorder
+			{
+				if let Err(e) = recorder.record(datagram)
+				{
+					println!("{}", e.report());
+					self.recorder = None;
+				}
+			}
+
+			let data = match filter.accept(datagram)
+			{
+				Ok(data) => data,
+				Err(e) => {
+					println!("{e} from {source} ({} malformed, {} stale, {} rejected so far)",
+						filter.get_malformed(), filter.get_stale(), filter.get_rejected());
+					continue;
+				},
+			};
+			self.status_link.set_controller(source);
+
 			// pipe data back to main thread
-			sender.send(self.process_buffer(&buffer)).expect("Bad pipe");
+			if sender.send(data).is_err()
+			{
+				// receiving thread has hung up, nothing left to serve
+				return Err(RoboticArmError::BadPipe("Bad pipe".into()));
+			}
 		}
-
-		#[allow(unreachable_code)]
-		Ok(())
 	}
 
 	
 
-	fn process_buffer(&self, buffer: &[u8; 6]) -> DataHandler
+	pub fn process_payload(payload: &JoystickPayload) -> DataHandler
 	{
-	// buffer is received as [x, y, roll, pitch, button1, button2]
-		// create empty buffer
-		let mut buffer_i16: [i16; 6] = [0; 6];
-		// data is received in a single packet with known order
-		for i in 0..=5
-		{
-			if buffer[i] > 8
-			{
-				buffer_i16[i] = -((buffer[i] >> 4) as i16);
-			} else {
-				buffer_i16[i] = buffer[i] as i16;
-			}
-			// make values correct for stick layout (left and right stick are opposite directions)
-			if i == 1 || i == 2
-			{
-				buffer_i16[i] *= -1;
-			}
-		}
-		
+		// axis values were already checked when the payload was decoded,
+		// make them correct for stick layout (left and right stick are opposite directions)
+		let buffer_i16: [i16; 6] = [
+			payload.x as i16,
+			-(payload.y as i16),
+			-(payload.roll as i16),
+			payload.pitch as i16,
+			encode_button(payload.button1_pressed) as i16,
+			encode_button(payload.button2_pressed) as i16,
+		];
+
 		DataHandler::from_buffer(&buffer_i16)
 	}
-}
\ No newline at end of file
+}
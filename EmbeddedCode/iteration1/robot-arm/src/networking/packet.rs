@@ -0,0 +1,463 @@
+/*
+William Albertini
+
+Receiving side of the controller packets. The packet
+format itself (header, joystick payload) is defined in the
+shared arm_protocol crate, which the controller uses to
+build them, so there is only one copy of the encoding.
+
+SequenceTracker drops duplicates and packets that arrive
+after a newer one. Sequence numbers are compared with
+wrapping arithmetic, and the controller sets
+FLAG_SESSION_START on its first packet after boot so a
+reset counter is not mistaken for a stale one.
+
+PacketFilter puts the two together, it is what the UDP
+server and session replay run every datagram through.
+Heartbeats go through sequence tracking like any other
+packet and come out as DataHandler::heartbeat().
+
+PacketFilter::with_key() only lets through packets signed
+with the pre-shared key (arm_protocol::auth) whose counter
+is higher than any accepted so far, so captured packets
+can not be sent again. Everything else is counted as
+rejected. Without a key the signature trailer is just
+removed, which is what replaying a recorded session needs
+(the live server only runs keyless when told it is
+insecure, see network_interface.rs).
+With keep_counter(<file>) the highest accepted counter is
+also saved (atomically, at most every SAVE_PERIOD so the SD
+card lasts), and read back when the server starts again.
+Anything up to RESTART_COUNTER_MARGIN past the saved counter
+is refused after a restart as well, which covers the packets
+accepted since the last save (the controller never sends
+that many in one SAVE_PERIOD). A restart quicker than the
+controller can send that many packets drops them until its
+counter gets past the margin. Without a counter file the
+counter is forgotten when the server stops, and captured
+packets can be sent again after a restart.
+
+read_key_file() loads the key, written as hex.
+
+*/
+
+// external imports
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use arm_protocol::auth::{decode_hex_key, strip_signature, verify_packet};
+use arm_protocol::packet::{decode_controller_packet, ControllerPacket, PacketHeader, FLAG_AUTHENTICATED, FLAG_JOINT_JOG, FLAG_SESSION_START};
+
+// internal imports
+use crate::arm_errors::RoboticArmError;
+use crate::robotics::saved_state::{write_atomic, SAVE_PERIOD};
+use super::data_handler::{DataHandler, RawDatagram};
+use super::network_interface::NetworkHandler;
+
+// counters this far past the saved one are refused after a restart
+pub const RESTART_COUNTER_MARGIN: u64 = 256;
+
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SequenceTracker
+{
+    last: Option<u16>,
+}
+
+impl SequenceTracker
+{
+    pub fn accept(&mut self, header: &PacketHeader) -> Result<(), RoboticArmError>
+    {
+        if let Some(last) = self.last
+        {
+            // anything up to half the sequence space ahead counts as newer
+            let ahead = header.sequence.wrapping_sub(last);
+            if !header.has_flag(FLAG_SESSION_START) && (ahead == 0 || ahead >= 0x8000)
+            {
+                return Err(RoboticArmError::StalePacket(format!("Dropped packet {} (last accepted {last})", header.sequence)));
+            }
+        }
+        self.last = Some(header.sequence);
+        Ok(())
+    }
+
+    pub fn get_last(&self) -> Option<u16>
+    {
+        self.last
+    }
+}
+
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PacketFilter
+{
+    tracker: SequenceTracker,
+    key: Option<Vec<u8>>,
+    last_counter: Option<u64>,
+    // where the last counter is kept across restarts, and when it was last written
+    counter_file: Option<PathBuf>,
+    last_save: Option<Instant>,
+    accepted: usize,
+    malformed: usize,
+    stale: usize,
+    rejected: usize,
+}
+
+impl PacketFilter
+{
+    pub fn new() -> PacketFilter
+    {
+        PacketFilter::default()
+    }
+
+    pub fn with_key(key: Vec<u8>) -> PacketFilter
+    {
+        PacketFilter { key: Some(key), ..PacketFilter::default() }
+    }
+
+    pub fn keep_counter(&mut self, path: &Path) -> Result<(), RoboticArmError>
+    {
+        // picks up the counter saved before a restart, if there is one
+        match fs::read_to_string(path)
+        {
+            Ok(text) => {
+                let saved: u64 = text.trim().parse()
+                    .map_err(|e| RoboticArmError::file(format!("Bad replay counter in {}", path.display()), e))?;
+                let floor = saved.saturating_add(RESTART_COUNTER_MARGIN);
+                self.last_counter = self.last_counter.max(Some(floor));
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(RoboticArmError::file(format!("Could not read {}", path.display()), e)),
+        }
+        self.counter_file = Some(path.to_path_buf());
+        Ok(())
+    }
+
+    pub fn accept(&mut self, datagram: &[u8]) -> Result<DataHandler, RoboticArmError>
+    {
+        let result = self.decode(datagram);
+        match result
+        {
+            Ok(_) => self.accepted += 1,
+            Err(RoboticArmError::StalePacket(_)) => self.stale += 1,
+            Err(RoboticArmError::AuthenticationFailed(_)) => self.rejected += 1,
+            Err(_) => self.malformed += 1,
+        }
+        result
+    }
+
+    fn authenticate<'a>(&mut self, datagram: &'a [u8]) -> Result<&'a [u8], RoboticArmError>
+    {
+        // returns the packet without its signature
+        let Some(key) = &self.key else {
+            let signed = datagram.get(3).is_some_and(|flags| flags & FLAG_AUTHENTICATED != 0);
+            if !signed
+            {
+                return Ok(datagram);
+            }
+            return strip_signature(datagram).map(|(_, packet)| packet)
+                .map_err(|e| RoboticArmError::MalformedPacket(e.to_string()));
+        };
+
+        let (counter, packet) = verify_packet(key, datagram)
+            .map_err(|e| RoboticArmError::AuthenticationFailed(e.to_string()))?;
+        if let Some(last) = self.last_counter
+        {
+            if counter <= last
+            {
+                return Err(RoboticArmError::AuthenticationFailed(format!("Replayed packet, counter {counter} (last accepted {last})")));
+            }
+        }
+        self.last_counter = Some(counter);
+        self.save_counter(counter, Instant::now());
+        Ok(packet)
+    }
+
+    fn save_counter(&mut self, counter: u64, now: Instant)
+    {
+        let Some(path) = &self.counter_file else {
+            return;
+        };
+        if self.last_save.is_some_and(|last_save| now.saturating_duration_since(last_save) < SAVE_PERIOD)
+        {
+            return;
+        }
+        // a failing disk is tried again next period, the packet still counts
+        self.last_save = Some(now);
+        if let Err(e) = write_atomic(path, &counter.to_string())
+        {
+            println!("{}", e.report());
+        }
+    }
+
+    fn decode(&mut self, datagram: &[u8]) -> Result<DataHandler, RoboticArmError>
+    {
+        let packet = self.authenticate(datagram)?;
+        let (header, packet) = decode_controller_packet(packet)
+            .map_err(|e| RoboticArmError::MalformedPacket(e.to_string()))?;
+        self.tracker.accept(&header)?;
+
+        // keep the whole datagram with the decoded data for telemetry
+        let mut data = match packet
+        {
+            ControllerPacket::Joystick(payload) => NetworkHandler::process_payload(&payload),
+            ControllerPacket::Heartbeat => DataHandler::heartbeat(),
+        };
+        data.joint_jog = header.has_flag(FLAG_JOINT_JOG);
+        data.raw = RawDatagram::new(datagram);
+        Ok(data)
+    }
+
+    pub fn get_accepted(&self) -> usize
+    {
+        self.accepted
+    }
+
+    pub fn get_malformed(&self) -> usize
+    {
+        self.malformed
+    }
+
+    pub fn get_stale(&self) -> usize
+    {
+        self.stale
+    }
+
+    pub fn get_rejected(&self) -> usize
+    {
+        self.rejected
+    }
+}
+
+
+pub fn read_key_file(path: &Path) -> Result<Vec<u8>, RoboticArmError>
+{
+    // hex, whitespace is ignored
+    let text = fs::read_to_string(path)
+        .map_err(|e| RoboticArmError::file(format!("Could not read key file {}", path.display()), e))?;
+    let mut buffer = vec![0; text.len() / 2];
+    decode_hex_key(&text, &mut buffer)
+        .map(<[u8]>::to_vec)
+        .map_err(|e| RoboticArmError::file(format!("Bad key in {}", path.display()), e))
+}
+
+
+
+
+// ------------------------------- unit tests -------------------------------------
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use arm_protocol::auth::PacketSigner;
+    use arm_protocol::joystick::JoystickPayload;
+    use arm_protocol::packet::{encode_packet, PacketSequencer, JOYSTICK_PACKET_SIZE};
+
+    const KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    fn joystick_packet(flags: u8, sequence: u16, payload: [u8; 6]) -> Vec<u8>
+    {
+        let mut packet = vec![0; JOYSTICK_PACKET_SIZE];
+        encode_packet(&PacketHeader::new(flags, sequence, 6), &payload, &mut packet).unwrap();
+        packet
+    }
+
+    #[test]
+    fn test_sequence_tracking()
+    {
+        let mut tracker = SequenceTracker::default();
+        let header = |flags, sequence| PacketHeader::new(flags, sequence, 6);
+
+        assert!(tracker.accept(&header(0, 10)).is_ok());
+        // duplicate and reordered packets are dropped
+        assert!(tracker.accept(&header(0, 10)).is_err());
+        assert!(tracker.accept(&header(0, 12)).is_ok());
+        assert!(tracker.accept(&header(0, 11)).is_err());
+        // gaps are fine (lost packets)
+        assert!(tracker.accept(&header(0, 400)).is_ok());
+        // a rebooted controller starts again from zero
+        assert!(tracker.accept(&header(0, 0)).is_err());
+        assert!(tracker.accept(&header(FLAG_SESSION_START, 0)).is_ok());
+        assert_eq!(tracker.get_last(), Some(0));
+    }
+
+    #[test]
+    fn test_sequence_wraps()
+    {
+        let mut tracker = SequenceTracker::default();
+        let header = |sequence| PacketHeader::new(0, sequence, 6);
+
+        assert!(tracker.accept(&header(0xFFFE)).is_ok());
+        assert!(tracker.accept(&header(0xFFFF)).is_ok());
+        assert!(tracker.accept(&header(0)).is_ok());
+        assert!(tracker.accept(&header(0xFFFF)).is_err());
+    }
+
+    #[test]
+    fn test_filter_counts()
+    {
+        let mut filter = PacketFilter::new();
+        let data = filter.accept(&joystick_packet(FLAG_SESSION_START, 1, [4, 0, 0, 0, 1, 1])).unwrap();
+        assert!(filter.accept(&joystick_packet(0, 1, [4, 0, 0, 0, 1, 1])).is_err());
+        assert!(filter.accept(&[4, 0, 0, 0, 1, 1]).is_err());
+        // 0x09 used to slip through as 0, it is not a valid axis
+        assert!(matches!(filter.accept(&joystick_packet(0, 2, [9, 0, 0, 0, 1, 1])), Err(RoboticArmError::MalformedPacket(_))));
+
+        assert_eq!(data.x, 4);
+        assert_eq!(data.raw.as_slice().len(), JOYSTICK_PACKET_SIZE);
+        assert_eq!((filter.get_accepted(), filter.get_stale(), filter.get_malformed()), (1, 1, 2));
+    }
+
+    #[test]
+    fn test_controller_packets_decode()
+    {
+        // what the controller sends comes out the other side, with y and roll
+        // flipped for the stick layout
+        let mut sequencer = PacketSequencer::new();
+        let mut filter = PacketFilter::new();
+        let payload = JoystickPayload { x: -8, y: 8, roll: -3, pitch: 5, button1_pressed: true, button2_pressed: false };
+        let data = filter.accept(&sequencer.joystick_packet(&payload)).unwrap();
+
+        assert_eq!(data.return_joystick_data(), ([-8, -8, 0], [3, 5, 1]));
+        assert!(filter.accept(&sequencer.joystick_packet(&payload)).is_ok());
+    }
+
+    #[test]
+    fn test_heartbeat_passes_through()
+    {
+        let mut sequencer = PacketSequencer::new();
+        let mut filter = PacketFilter::new();
+        let data = filter.accept(&sequencer.heartbeat_packet()).unwrap();
+
+        assert!(data.heartbeat);
+        assert_eq!(data.return_joystick_data(), ([0, 0, 1], [0, 0, 1]));
+        // and it counts towards sequence tracking
+        assert!(!filter.accept(&sequencer.joystick_packet(&JoystickPayload::default())).unwrap().heartbeat);
+    }
+
+    #[test]
+    fn test_joint_jog_flag()
+    {
+        let mut sequencer = PacketSequencer::new();
+        let mut filter = PacketFilter::new();
+        let payload = JoystickPayload { y: -1, ..JoystickPayload::default() };
+
+        assert!(filter.accept(&sequencer.joint_jog_packet(&payload)).unwrap().joint_jog);
+        assert!(!filter.accept(&sequencer.joystick_packet(&payload)).unwrap().joint_jog);
+    }
+
+    #[test]
+    fn test_signed_packets_accepted()
+    {
+        let mut sequencer = PacketSequencer::new();
+        let mut signer = PacketSigner::new(KEY, 3);
+        let mut filter = PacketFilter::with_key(KEY.to_vec());
+        let mut buffer = [0; 64];
+        let payload = JoystickPayload { x: 2, ..JoystickPayload::default() };
+
+        let len = signer.sign(&sequencer.joystick_packet(&payload), &mut buffer).unwrap();
+        let data = filter.accept(&buffer[..len]).unwrap();
+        let len = signer.sign(&sequencer.heartbeat_packet(), &mut buffer).unwrap();
+
+        assert_eq!(data.x, 2);
+        assert!(filter.accept(&buffer[..len]).unwrap().heartbeat);
+        assert_eq!(filter.get_accepted(), 2);
+    }
+
+    #[test]
+    fn test_unsigned_forged_and_replayed_rejected()
+    {
+        let mut sequencer = PacketSequencer::new();
+        let mut signer = PacketSigner::new(KEY, 1);
+        let mut forger = PacketSigner::new(b"not the key, not the key, not it", 9);
+        let mut filter = PacketFilter::with_key(KEY.to_vec());
+        let mut buffer = [0; 64];
+
+        let unsigned = sequencer.joystick_packet(&JoystickPayload::default());
+        assert!(matches!(filter.accept(&unsigned), Err(RoboticArmError::AuthenticationFailed(_))));
+
+        let len = forger.sign(&sequencer.joystick_packet(&JoystickPayload::default()), &mut buffer).unwrap();
+        assert!(filter.accept(&buffer[..len]).is_err());
+
+        let len = signer.sign(&sequencer.joystick_packet(&JoystickPayload::default()), &mut buffer).unwrap();
+        let captured = buffer[..len].to_vec();
+        assert!(filter.accept(&captured).is_ok());
+        // the same packet again, even with a fresh sequence number, is a replay
+        assert!(filter.accept(&captured).is_err());
+        let mut resequenced = captured.clone();
+        resequenced[3] |= FLAG_SESSION_START;
+        assert!(filter.accept(&resequenced).is_err());
+
+        assert_eq!((filter.get_accepted(), filter.get_rejected()), (1, 4));
+    }
+
+    #[test]
+    fn test_counter_kept_across_restart()
+    {
+        let path = std::env::temp_dir().join(format!("robot-arm-counter-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut sequencer = PacketSequencer::new();
+        let mut signer = PacketSigner::new(KEY, 1);
+        let mut buffer = [0; 64];
+        let mut captured = Vec::new();
+
+        let mut filter = PacketFilter::with_key(KEY.to_vec());
+        filter.keep_counter(&path).unwrap();
+        for _ in 0..3
+        {
+            let len = signer.sign(&sequencer.joystick_packet(&JoystickPayload::default()), &mut buffer).unwrap();
+            captured.push(buffer[..len].to_vec());
+            assert!(filter.accept(&captured[captured.len() - 1]).is_ok());
+        }
+        // only the first was written, the rest were inside SAVE_PERIOD
+        assert_eq!(fs::read_to_string(&path).unwrap(), ((1u64 << 32) + 1).to_string());
+
+        // after a restart none of them get in again, even the unsaved ones
+        let mut filter = PacketFilter::with_key(KEY.to_vec());
+        filter.keep_counter(&path).unwrap();
+        for packet in &captured
+        {
+            assert!(matches!(filter.accept(packet), Err(RoboticArmError::AuthenticationFailed(_))));
+        }
+        // the controller after its own reboot does
+        let mut signer = PacketSigner::new(KEY, 2);
+        let len = signer.sign(&sequencer.joystick_packet(&JoystickPayload::default()), &mut buffer).unwrap();
+        assert!(filter.accept(&buffer[..len]).is_ok());
+
+        fs::write(&path, "not a counter").unwrap();
+        assert!(PacketFilter::with_key(KEY.to_vec()).keep_counter(&path).is_err());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_keyless_filter_strips_signature()
+    {
+        // replaying a signed session without the key
+        let mut sequencer = PacketSequencer::new();
+        let mut signer = PacketSigner::new(KEY, 1);
+        let mut filter = PacketFilter::new();
+        let mut buffer = [0; 64];
+        let payload = JoystickPayload { pitch: -4, ..JoystickPayload::default() };
+        let len = signer.sign(&sequencer.joystick_packet(&payload), &mut buffer).unwrap();
+
+        assert_eq!(filter.accept(&buffer[..len]).unwrap().pitch, -4);
+    }
+
+    #[test]
+    fn test_read_key_file()
+    {
+        let path = std::env::temp_dir().join(format!("robot-arm-key-{}", std::process::id()));
+        fs::write(&path, "30313233343536373839616263646566\n30313233343536373839616263646566\n").unwrap();
+        assert_eq!(read_key_file(&path).unwrap(), KEY);
+
+        fs::write(&path, "abcd").unwrap();
+        assert!(read_key_file(&path).is_err());
+        fs::write(&path, "zz313233343536373839616263646566").unwrap();
+        assert!(read_key_file(&path).is_err());
+        // the placeholder that used to be built into the controller
+        fs::write(&path, "6368616e6765206d653a203332206279746573206f6620736563726574212121").unwrap();
+        assert!(read_key_file(&path).unwrap_err().report().contains("known or trivial"));
+        let _ = fs::remove_file(&path);
+    }
+}
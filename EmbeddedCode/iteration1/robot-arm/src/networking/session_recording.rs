@@ -0,0 +1,280 @@
+/*
+William Albertini
+
+Record and replay of controller sessions. SessionRecorder
+is handed to the NetworkHandler and writes every datagram
+the server receives, with the time since recording
+started, as one json line (RecordedDatagram).
+
+SessionReplay reads a recording back and pushes it through
+the same Sender<DataHandler> the server uses, through the
+same PacketFilter, so datagrams the server would have thrown
+out are thrown out again. Replay can run
+at the original speed, scaled, or (speed 0) as fast as
+the channel takes it, which is what regression tests want.
+Nothing downstream of the channel can tell a replay from
+the ESP32.
+
+*/
+
+// external imports
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+
+// internal imports
+use crate::arm_errors::RoboticArmError;
+use super::data_handler::DataHandler;
+use super::packet::PacketFilter;
+
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedDatagram
+{
+    // time since the recording started (microseconds)
+    pub offset_us: u64,
+    pub bytes: Vec<u8>,
+}
+
+
+pub struct SessionRecorder
+{
+    writer: BufWriter<File>,
+    start: Instant,
+    count: usize,
+}
+
+impl SessionRecorder
+{
+    pub fn try_new(path: &Path) -> Result<SessionRecorder, RoboticArmError>
+    {
+        let file = File::create(path)
+            .map_err(|e| RoboticArmError::file(format!("Could not create {}", path.display()), e))?;
+
+        Ok(SessionRecorder {
+            writer: BufWriter::new(file),
+            start: Instant::now(),
+            count: 0,
+        })
+    }
+
+    pub fn record(&mut self, bytes: &[u8]) -> Result<(), RoboticArmError>
+    {
+        let datagram = RecordedDatagram {
+            offset_us: self.start.elapsed().as_micros() as u64,
+            bytes: bytes.to_vec(),
+        };
+        self.record_datagram(&datagram)
+    }
+
+    pub fn record_datagram(&mut self, datagram: &RecordedDatagram) -> Result<(), RoboticArmError>
+    {
+        let line = serde_json::to_string(datagram)
+            .map_err(|e| RoboticArmError::file("Could not encode datagram", e))?;
+
+        // flushed every datagram so a crash keeps everything up to it
+        writeln!(self.writer, "{line}")
+            .and_then(|_| self.writer.flush())
+            .map_err(|e| RoboticArmError::file("Could not write recording", e))?;
+        self.count += 1;
+        Ok(())
+    }
+
+    pub fn get_count(&self) -> usize
+    {
+        self.count
+    }
+}
+
+
+pub struct SessionReplay
+{
+    datagrams: Vec<RecordedDatagram>,
+}
+
+impl SessionReplay
+{
+    pub fn new(datagrams: Vec<RecordedDatagram>) -> SessionReplay
+    {
+        SessionReplay { datagrams }
+    }
+
+    pub fn try_from_file(path: &Path) -> Result<SessionReplay, RoboticArmError>
+    {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| RoboticArmError::file(format!("Could not read {}", path.display()), e))?;
+
+        // one json RecordedDatagram per line, blank lines are skipped
+        let datagrams = contents.lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(number, line)| {
+                serde_json::from_str(line)
+                    .map_err(|e| RoboticArmError::file(format!("Bad datagram on line {}", number + 1), e))
+            })
+            .collect::<Result<Vec<RecordedDatagram>, RoboticArmError>>()?;
+
+        Ok(SessionReplay::new(datagrams))
+    }
+
+    pub fn get_datagrams(&self) -> &[RecordedDatagram]
+    {
+        &self.datagrams
+    }
+
+    pub fn duration(&self) -> Duration
+    {
+        Duration::from_micros(self.datagrams.last().map(|datagram| datagram.offset_us).unwrap_or(0))
+    }
+
+    pub fn decoded(&self) -> Vec<DataHandler>
+    {
+        // everything the server would have let through
+        let mut filter = PacketFilter::new();
+        self.datagrams.iter().filter_map(|datagram| filter.accept(&datagram.bytes).ok()).collect()
+    }
+
+    pub fn replay(&self, sender: &Sender<DataHandler>, speed: f64) -> Result<usize, RoboticArmError>
+    {
+        // speed 1.0 is the original timing, 2.0 twice as fast,
+        // 0.0 (or less) sends everything without waiting
+        let start = Instant::now();
+        let mut filter = PacketFilter::new();
+        let mut sent = 0;
+        for datagram in &self.datagrams
+        {
+            if speed > 0.0
+            {
+                let due = Duration::from_secs_f64(datagram.offset_us as f64 / 1.0e6 / speed);
+                if let Some(wait) = due.checked_sub(start.elapsed())
+                {
+                    thread::sleep(wait);
+                }
+            }
+
+            let Ok(data) = filter.accept(&datagram.bytes) else {
+                continue;
+            };
+            if sender.send(data).is_err()
+            {
+                // same as the server, nobody left to listen
+                return Err(RoboticArmError::BadPipe(format!("Bad pipe after {sent} datagrams")));
+            }
+            sent += 1;
+        }
+        Ok(sent)
+    }
+}
+
+
+
+
+// ------------------------------- unit tests -------------------------------------
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::sync::mpsc;
+    use arm_protocol::packet::{encode_packet, PacketHeader, FLAG_SESSION_START, JOYSTICK_PACKET_SIZE};
+    use crate::robotics::arm_state::{AngleToEncoderMap, RoboticArmSolver};
+
+    fn test_path(name: &str) -> std::path::PathBuf
+    {
+        std::env::temp_dir().join(format!("robot-arm-{name}-{}.jsonl", std::process::id()))
+    }
+
+    fn recorded(offset_us: u64, sequence: u16, payload: [u8; 6]) -> RecordedDatagram
+    {
+        let flags = if sequence == 0 { FLAG_SESSION_START } else { 0 };
+        let mut bytes = vec![0; JOYSTICK_PACKET_SIZE];
+        encode_packet(&PacketHeader::new(flags, sequence, 6), &payload, &mut bytes).unwrap();
+        RecordedDatagram { offset_us, bytes }
+    }
+
+    fn test_session() -> SessionReplay
+    {
+        // x right, y stick, a duplicate, x right with button 1, then a bad pitch request
+        SessionReplay::new(vec![
+            recorded(0, 0, [4, 0, 0, 0, 0, 0]),
+            recorded(10_000, 1, [0, 0x40, 0, 0, 0, 0]),
+            recorded(12_000, 1, [0, 0x40, 0, 0, 0, 0]),
+            recorded(20_000, 2, [4, 0, 0, 0, 1, 0]),
+            recorded(30_000, 3, [0, 0, 0, 8, 0, 0]),
+        ])
+    }
+
+    #[test]
+    fn test_recording_round_trip()
+    {
+        let path = test_path("session");
+        let mut recorder = SessionRecorder::try_new(&path).unwrap();
+        for datagram in test_session().get_datagrams()
+        {
+            recorder.record_datagram(datagram).unwrap();
+        }
+        recorder.record(&[1, 2, 3, 4, 5, 6]).unwrap();
+        assert_eq!(recorder.get_count(), 6);
+
+        let replay = SessionReplay::try_from_file(&path).unwrap();
+        assert_eq!(&replay.get_datagrams()[..5], test_session().get_datagrams());
+        assert_eq!(replay.get_datagrams()[5].bytes, vec![1, 2, 3, 4, 5, 6]);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_replay_through_channel()
+    {
+        let session = test_session();
+        let (sender, receiver) = mpsc::channel();
+        // the duplicate is dropped, same as it was live
+        assert_eq!(session.replay(&sender, 0.0), Ok(4));
+
+        let received: Vec<DataHandler> = receiver.try_iter().collect();
+        assert_eq!(received, session.decoded());
+        // 0x40 is -4 on the wire, the y stick is flipped when decoded
+        assert_eq!(received[1].y, 4);
+        assert_eq!(&received[2].raw.as_slice()[7..], &[4, 0, 0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn test_replay_scaled_timing()
+    {
+        // 30 ms of session at double speed takes at least 15 ms
+        let session = test_session();
+        let (sender, _receiver) = mpsc::channel();
+        let start = Instant::now();
+        session.replay(&sender, 2.0).unwrap();
+
+        assert!(start.elapsed() >= Duration::from_millis(15));
+        assert_eq!(session.duration(), Duration::from_millis(30));
+    }
+
+    #[test]
+    fn test_replay_is_deterministic()
+    {
+        // the same session always leaves the solver in the same state
+        let run = || {
+            let map = AngleToEncoderMap::new(5000, 5000, 5000, 5000, 5000);
+            let mut arm = RoboticArmSolver::try_new_from_ef_position(1000.0, 500.0, 300.0, 1200.0, 0.0, 0.0, map).unwrap();
+            let (sender, receiver) = mpsc::channel();
+            test_session().replay(&sender, 0.0).unwrap();
+            let results: Vec<bool> = receiver.try_iter().map(|data| arm.update_from_data_handler(data).is_ok()).collect();
+            (results, arm.get_updated_state(), arm.get_end_effector_position())
+        };
+
+        assert_eq!(run(), run());
+    }
+
+    #[test]
+    fn test_replay_hung_up()
+    {
+        let (sender, receiver) = mpsc::channel();
+        drop(receiver);
+
+        assert!(matches!(test_session().replay(&sender, 0.0), Err(RoboticArmError::BadPipe(_))));
+    }
+}
@@ -0,0 +1,315 @@
+/*
+William Albertini
+
+CCSDS Space Packets (CCSDS 133.0-B), the framing used on
+the spacecraft bus. Primary header, six bytes big endian:
+
+    bits 0-2     version (0)
+    bit  3       type (PACKET_TYPE_TELECOMMAND or _TELEMETRY)
+    bit  4       secondary header present
+    bits 5-15    APID
+    bits 16-17   sequence flags (SEQUENCE_UNSEGMENTED)
+    bits 18-31   sequence count (wraps at 14 bits)
+    bits 32-47   packet data length - 1
+
+Every packet on this bus carries a secondary header, a
+CUC time code: seconds (u32) and 1/65536ths of a second
+(u16) since the unix epoch. What follows it is up to the
+APID, ccsds_interface.rs defines the arm's commands and
+telemetry.
+
+Packets are self delimiting, read_space_packet() pulls one
+at a time off any byte stream. A packet it refuses (a bad
+version, no secondary header) is still read to the end its
+length field gives, so the next one is read in step.
+
+*/
+
+// external imports
+use std::io::{ErrorKind, Read};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// internal imports
+use crate::arm_errors::RoboticArmError;
+
+
+pub const PRIMARY_HEADER_SIZE: usize = 6;
+pub const SECONDARY_HEADER_SIZE: usize = 6;
+pub const PACKET_TYPE_TELEMETRY: u8 = 0;
+pub const PACKET_TYPE_TELECOMMAND: u8 = 1;
+// the whole message in one packet, the only kind used here
+pub const SEQUENCE_UNSEGMENTED: u8 = 0b11;
+pub const MAX_APID: u16 = 0x07FF;
+pub const SEQUENCE_COUNT_MODULO: u16 = 0x4000;
+// the data field holds at most 65536 bytes
+pub const MAX_DATA_FIELD: usize = 0x10000;
+
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CucTime
+{
+    pub seconds: u32,
+    pub fraction: u16,
+}
+
+impl CucTime
+{
+    pub fn now() -> CucTime
+    {
+        CucTime::from_duration(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default())
+    }
+
+    pub fn from_duration(since_epoch: Duration) -> CucTime
+    {
+        CucTime {
+            seconds: since_epoch.as_secs() as u32,
+            fraction: ((since_epoch.subsec_nanos() as u64 * 0x10000) / 1_000_000_000) as u16,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; SECONDARY_HEADER_SIZE]
+    {
+        let mut bytes = [0; SECONDARY_HEADER_SIZE];
+        bytes[0..4].copy_from_slice(&self.seconds.to_be_bytes());
+        bytes[4..6].copy_from_slice(&self.fraction.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; SECONDARY_HEADER_SIZE]) -> CucTime
+    {
+        CucTime {
+            seconds: u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            fraction: u16::from_be_bytes([bytes[4], bytes[5]]),
+        }
+    }
+}
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SpacePacketHeader
+{
+    pub packet_type: u8,
+    pub secondary_header: bool,
+    pub apid: u16,
+    pub sequence_flags: u8,
+    pub sequence_count: u16,
+    // bytes after the primary header (the field on the wire is this - 1)
+    pub data_field_length: usize,
+}
+
+impl SpacePacketHeader
+{
+    pub fn to_bytes(&self) -> [u8; PRIMARY_HEADER_SIZE]
+    {
+        let identification = ((self.packet_type as u16 & 1) << 12)
+            | ((self.secondary_header as u16) << 11)
+            | (self.apid & MAX_APID);
+        let sequence = ((self.sequence_flags as u16 & 0b11) << 14) | (self.sequence_count % SEQUENCE_COUNT_MODULO);
+        let length = (self.data_field_length - 1) as u16;
+
+        let mut bytes = [0; PRIMARY_HEADER_SIZE];
+        bytes[0..2].copy_from_slice(&identification.to_be_bytes());
+        bytes[2..4].copy_from_slice(&sequence.to_be_bytes());
+        bytes[4..6].copy_from_slice(&length.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; PRIMARY_HEADER_SIZE]) -> Result<SpacePacketHeader, RoboticArmError>
+    {
+        let identification = u16::from_be_bytes([bytes[0], bytes[1]]);
+        let sequence = u16::from_be_bytes([bytes[2], bytes[3]]);
+        let version = identification >> 13;
+        if version != 0
+        {
+            return Err(RoboticArmError::MalformedPacket(format!("Unsupported space packet version {version}")));
+        }
+
+        Ok(SpacePacketHeader {
+            packet_type: ((identification >> 12) & 1) as u8,
+            secondary_header: identification & (1 << 11) != 0,
+            apid: identification & MAX_APID,
+            sequence_flags: (sequence >> 14) as u8,
+            sequence_count: sequence & (SEQUENCE_COUNT_MODULO - 1),
+            data_field_length: u16::from_be_bytes([bytes[4], bytes[5]]) as usize + 1,
+        })
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpacePacket
+{
+    pub header: SpacePacketHeader,
+    pub time: CucTime,
+    pub user_data: Vec<u8>,
+}
+
+impl SpacePacket
+{
+    pub fn try_new(packet_type: u8, apid: u16, sequence_count: u16, time: CucTime, user_data: Vec<u8>) -> Result<SpacePacket, RoboticArmError>
+    {
+        if apid > MAX_APID
+        {
+            return Err(RoboticArmError::MalformedPacket(format!("APID {apid} does not fit in 11 bits")));
+        }
+        let data_field_length = SECONDARY_HEADER_SIZE + user_data.len();
+        if data_field_length > MAX_DATA_FIELD
+        {
+            return Err(RoboticArmError::MalformedPacket(format!("{} bytes of user data do not fit in a space packet", user_data.len())));
+        }
+
+        let header = SpacePacketHeader {
+            packet_type,
+            secondary_header: true,
+            apid,
+            sequence_flags: SEQUENCE_UNSEGMENTED,
+            sequence_count: sequence_count % SEQUENCE_COUNT_MODULO,
+            data_field_length,
+        };
+        Ok(SpacePacket { header, time, user_data })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8>
+    {
+        let mut bytes = Vec::with_capacity(PRIMARY_HEADER_SIZE + self.header.data_field_length);
+        bytes.extend_from_slice(&self.header.to_bytes());
+        bytes.extend_from_slice(&self.time.to_bytes());
+        bytes.extend_from_slice(&self.user_data);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<SpacePacket, RoboticArmError>
+    {
+        let Some(primary) = bytes.first_chunk::<PRIMARY_HEADER_SIZE>() else {
+            return Err(RoboticArmError::MalformedPacket(format!("Space packet too short for a header ({} bytes)", bytes.len())));
+        };
+        let header = SpacePacketHeader::from_bytes(primary)?;
+        let data_field = &bytes[PRIMARY_HEADER_SIZE..];
+        if data_field.len() != header.data_field_length
+        {
+            return Err(RoboticArmError::MalformedPacket(format!("Space packet data field is {} bytes, header says {}",
+                data_field.len(), header.data_field_length)));
+        }
+        SpacePacket::from_data_field(header, data_field)
+    }
+
+    fn from_data_field(header: SpacePacketHeader, data_field: &[u8]) -> Result<SpacePacket, RoboticArmError>
+    {
+        if !header.secondary_header
+        {
+            return Err(RoboticArmError::MalformedPacket(format!("Space packet on APID {} has no secondary header", header.apid)));
+        }
+        if header.sequence_flags != SEQUENCE_UNSEGMENTED
+        {
+            return Err(RoboticArmError::MalformedPacket(format!("Segmented space packets are not supported (APID {})", header.apid)));
+        }
+        let Some(time) = data_field.first_chunk::<SECONDARY_HEADER_SIZE>() else {
+            return Err(RoboticArmError::MalformedPacket(format!("Space packet too short for a secondary header ({} bytes)", data_field.len())));
+        };
+
+        Ok(SpacePacket {
+            header,
+            time: CucTime::from_bytes(time),
+            user_data: data_field[SECONDARY_HEADER_SIZE..].to_vec(),
+        })
+    }
+}
+
+
+pub fn read_space_packet<R: Read>(reader: &mut R) -> Result<Option<SpacePacket>, RoboticArmError>
+{
+    // Ok(None) once the stream ends cleanly between packets
+    let mut primary = [0; PRIMARY_HEADER_SIZE];
+    match reader.read_exact(&mut primary)
+    {
+        Ok(()) => (),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(RoboticArmError::network("Could not read space packet", e)),
+    }
+
+    // read the whole data field before checking anything, even the version,
+    // so a refused packet still leaves the stream at the start of the next one
+    let data_field_length = u16::from_be_bytes([primary[4], primary[5]]) as usize + 1;
+    let mut data_field = vec![0; data_field_length];
+    reader.read_exact(&mut data_field)
+        .map_err(|e| RoboticArmError::network("Space packet cut short", e))?;
+    let header = SpacePacketHeader::from_bytes(&primary)?;
+    SpacePacket::from_data_field(header, &data_field).map(Some)
+}
+
+
+
+
+// ------------------------------- unit tests -------------------------------------
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_header_layout()
+    {
+        // APID 0x123 telecommand, count 5, 8 bytes of user data
+        let time = CucTime { seconds: 0x01020304, fraction: 0x8000 };
+        let packet = SpacePacket::try_new(PACKET_TYPE_TELECOMMAND, 0x123, 5, time, vec![0xAA; 8]).unwrap();
+        let bytes = packet.to_bytes();
+
+        assert_eq!(&bytes[..6], &[0x19, 0x23, 0xC0, 0x05, 0x00, 0x0D]);
+        assert_eq!(&bytes[6..12], &[1, 2, 3, 4, 0x80, 0]);
+        assert_eq!(SpacePacket::from_bytes(&bytes).unwrap(), packet);
+    }
+
+    #[test]
+    fn test_sequence_count_wraps()
+    {
+        let packet = SpacePacket::try_new(PACKET_TYPE_TELEMETRY, 1, SEQUENCE_COUNT_MODULO + 2, CucTime::default(), vec![]).unwrap();
+        assert_eq!(SpacePacket::from_bytes(&packet.to_bytes()).unwrap().header.sequence_count, 2);
+    }
+
+    #[test]
+    fn test_malformed_rejected()
+    {
+        let good = SpacePacket::try_new(PACKET_TYPE_TELECOMMAND, 7, 0, CucTime::default(), vec![1, 2]).unwrap().to_bytes();
+        let mut bad_version = good.clone();
+        bad_version[0] |= 0x20;
+        let mut no_secondary = good.clone();
+        no_secondary[0] &= !0x08;
+        let mut segmented = good.clone();
+        segmented[2] &= 0x3F;
+
+        assert!(SpacePacket::from_bytes(&good[..4]).is_err());
+        assert!(SpacePacket::from_bytes(&good[..good.len() - 1]).is_err());
+        assert!(SpacePacket::from_bytes(&bad_version).is_err());
+        assert!(SpacePacket::from_bytes(&no_secondary).is_err());
+        assert!(SpacePacket::from_bytes(&segmented).is_err());
+        assert!(SpacePacket::try_new(PACKET_TYPE_TELECOMMAND, 0x800, 0, CucTime::default(), vec![]).is_err());
+    }
+
+    #[test]
+    fn test_read_stream()
+    {
+        // two packets back to back, then the end of the stream
+        let first = SpacePacket::try_new(PACKET_TYPE_TELECOMMAND, 7, 0, CucTime::default(), vec![1]).unwrap();
+        let second = SpacePacket::try_new(PACKET_TYPE_TELECOMMAND, 7, 1, CucTime::default(), vec![2, 3]).unwrap();
+        let mut stream = Cursor::new([first.to_bytes(), second.to_bytes()].concat());
+
+        assert_eq!(read_space_packet(&mut stream).unwrap(), Some(first.clone()));
+        assert_eq!(read_space_packet(&mut stream).unwrap(), Some(second));
+        assert_eq!(read_space_packet(&mut stream).unwrap(), None);
+
+        // a bad version is refused without losing the packet after it
+        let mut bad_version = first.to_bytes();
+        bad_version[0] |= 0x20;
+        let mut stream = Cursor::new([bad_version, first.to_bytes()].concat());
+        assert!(matches!(read_space_packet(&mut stream), Err(RoboticArmError::MalformedPacket(_))));
+        assert_eq!(read_space_packet(&mut stream).unwrap(), Some(first));
+    }
+
+    #[test]
+    fn test_cuc_time()
+    {
+        let time = CucTime::from_duration(Duration::from_millis(1500));
+        assert_eq!(time, CucTime { seconds: 1, fraction: 0x8000 });
+    }
+}
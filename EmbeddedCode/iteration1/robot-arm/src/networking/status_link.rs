@@ -0,0 +1,201 @@
+/*
+William Albertini
+
+StatusLink sends status datagrams (arm_protocol::status)
+back to the controller: the current end effector pose,
+why the last target was rejected, the arm mode, faults
+and battery voltage.
+
+The link shares the UDP server socket, so the datagrams
+come from the port the controller is already talking to.
+NetworkHandler fills in where to send them, the source of
+the last packet that made it through the PacketFilter (so
+junk from elsewhere can not redirect the status). Until
+the controller has been heard from, send() does nothing.
+
+The link is cloned into the control loop, the server
+thread keeps its own copy.
+
+*/
+
+// external imports
+use std::fs;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use arm_protocol::packet::PacketSequencer;
+use arm_protocol::status::{status_packet, StatusPayload, IK_OUT_OF_WORKSPACE, IK_SINGULARITY};
+
+// internal imports
+use crate::arm_errors::RoboticArmError;
+use crate::robotics::arm_kinematics::InverseKinematicSolver;
+
+
+struct LinkState
+{
+    socket: Option<UdpSocket>,
+    controller: Option<SocketAddr>,
+    sequencer: PacketSequencer,
+}
+
+
+#[derive(Clone)]
+pub struct StatusLink
+{
+    state: Arc<Mutex<LinkState>>,
+}
+
+
+impl StatusLink
+{
+    pub fn new() -> StatusLink
+    {
+        StatusLink {
+            state: Arc::new(Mutex::new(LinkState {
+                socket: None,
+                controller: None,
+                sequencer: PacketSequencer::new(),
+            })),
+        }
+    }
+
+    pub fn set_socket(&self, socket: UdpSocket)
+    {
+        if let Ok(mut state) = self.state.lock()
+        {
+            state.socket = Some(socket);
+        }
+    }
+
+    pub fn set_controller(&self, controller: SocketAddr)
+    {
+        if let Ok(mut state) = self.state.lock()
+        {
+            state.controller = Some(controller);
+        }
+    }
+
+    pub fn get_controller(&self) -> Option<SocketAddr>
+    {
+        self.state.lock().ok().and_then(|state| state.controller)
+    }
+
+    pub fn send(&self, status: &StatusPayload) -> Result<(), RoboticArmError>
+    {
+        let mut state = self.state.lock()
+            .map_err(|_| RoboticArmError::NetworkError { context: "Status link lock poisoned".into(), source: None })?;
+        let (Some(socket), Some(controller)) = (&state.socket, state.controller) else {
+            // nobody to tell yet
+            return Ok(());
+        };
+        let socket = socket.try_clone()
+            .map_err(|e| RoboticArmError::network("Could not clone status socket", e))?;
+
+        let packet = status_packet(&mut state.sequencer, status);
+        socket.send_to(&packet, controller)
+            .map_err(|e| RoboticArmError::network(format!("Could not send status to {controller}"), e))?;
+        Ok(())
+    }
+}
+
+impl Default for StatusLink
+{
+    fn default() -> StatusLink
+    {
+        StatusLink::new()
+    }
+}
+
+
+pub fn pose_to_wire(pose: [f64; 3]) -> [i16; 3]
+{
+    // mm and milliradians, clamped to what fits
+    let to_i16 = |value: f64| value.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16;
+    [to_i16(pose[0]), to_i16(pose[1]), to_i16(pose[2] * 1000.0)]
+}
+
+pub fn ik_flags(solver: &InverseKinematicSolver, target: [f64; 3], rejected: bool) -> u8
+{
+    // the solver only reports a singularity, tell the operator when it is
+    // because the target is past the reach of the arm
+    if !rejected
+    {
+        return 0;
+    }
+    if target[0].hypot(target[1]) > solver.max_end_effector_distance()
+    {
+        IK_SINGULARITY | IK_OUT_OF_WORKSPACE
+    } else {
+        IK_SINGULARITY
+    }
+}
+
+pub fn read_battery_millivolts(path: &Path) -> Option<u16>
+{
+    // sysfs power supplies report voltage_now in microvolts
+    let microvolts: u64 = fs::read_to_string(path).ok()?.trim().parse().ok()?;
+    u16::try_from(microvolts / 1000).ok()
+}
+
+
+
+
+// ------------------------------- unit tests -------------------------------------
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::time::Duration;
+    use arm_protocol::status::{decode_status_packet, ArmMode, BATTERY_UNKNOWN, FAULT_LINK_LOST};
+
+    #[test]
+    fn test_status_reaches_controller()
+    {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let controller = UdpSocket::bind("127.0.0.1:0").unwrap();
+        controller.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+
+        let link = StatusLink::new();
+        let status = StatusPayload {
+            pose: pose_to_wire([1800.0, -20.4, 0.5]),
+            ik_flags: IK_SINGULARITY,
+            mode: ArmMode::Hold,
+            faults: FAULT_LINK_LOST,
+            battery_mv: BATTERY_UNKNOWN,
+        };
+        // no socket or controller yet, nothing is sent
+        assert_eq!(link.send(&status), Ok(()));
+
+        link.set_socket(server.try_clone().unwrap());
+        link.clone().set_controller(controller.local_addr().unwrap());
+        link.send(&status).unwrap();
+
+        let mut buffer = [0; 64];
+        let (amount, source) = controller.recv_from(&mut buffer).unwrap();
+        let (_, received) = decode_status_packet(&buffer[..amount]).unwrap();
+        assert_eq!(source, server.local_addr().unwrap());
+        assert_eq!(received, status);
+        assert_eq!(received.pose, [1800, -20, 500]);
+    }
+
+    #[test]
+    fn test_ik_flags()
+    {
+        let solver = InverseKinematicSolver::new(10.0, 5.0, 3.0);
+
+        assert_eq!(ik_flags(&solver, [30.0, 0.0, 0.0], false), 0);
+        assert_eq!(ik_flags(&solver, [30.0, 0.0, 0.0], true), IK_SINGULARITY | IK_OUT_OF_WORKSPACE);
+        assert_eq!(ik_flags(&solver, [1.0, 0.0, 0.0], true), IK_SINGULARITY);
+    }
+
+    #[test]
+    fn test_read_battery()
+    {
+        let path = std::env::temp_dir().join(format!("robot-arm-battery-{}", std::process::id()));
+        fs::write(&path, "7412000\n").unwrap();
+
+        assert_eq!(read_battery_millivolts(&path), Some(7412));
+        assert_eq!(read_battery_millivolts(Path::new("/nonexistent/voltage_now")), None);
+        let _ = fs::remove_file(&path);
+    }
+}
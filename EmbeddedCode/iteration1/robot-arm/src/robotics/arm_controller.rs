@@ -0,0 +1,1012 @@
+/*
+William Albertini
+
+ArmController is the operating mode state machine between the
+command sources and the arm. It owns the RoboticArmSolver and
+the MotorDriver, and every command goes through it instead of
+straight to IK.
+
+    Boot -> Unhomed -> Homing -> Idle -> Teleop / Script / Hold
+                                              |
+                                            Fault -> SafeMode
+
+    Boot       nothing but stop, start() moves on to Unhomed,
+               restore() to Idle if the saved state was homed
+               and the encoders confirm it (saved_state.rs)
+    Unhomed    the arm position is not trusted, only home
+               (both buttons on the joystick) is accepted
+    Homing     moving to the home pose, done once the encoders
+               settle there (straight away without feedback,
+               see below)
+    Idle       homed and holding, set_mode picks what is next
+               (both buttons means teleop)
+    Teleop     joystick jogs and the gripper
+    Script     poses, joint moves and the gripper, from scripts
+               and the ground station
+    Hold       holding, set_mode, both buttons or home to leave
+    Fault      holding after a lost link or a reported fault.
+               set_mode (or both buttons) re-arms once the link
+               is back. Only the homed modes fault, a fault
+               while Unhomed holds the motors and stays Unhomed
+    SafeMode   MAX_FAULTS faults since the last homing, or one
+               while homing. Only homing again gets out
+
+Stop is accepted everywhere, Teleop and Script drop into Hold
+and Homing back to Unhomed. Heartbeats are accepted everywhere
+and only feed the watchdog. Anything else a mode does not
+accept is refused with CommandRejected and nothing moves.
+
+Entering Homing sends the arm home first, a home pose the
+solver refuses (or a failed write) leaves the mode and the
+solver as they were.
+Entering Idle, Hold, Fault or SafeMode holds the motors (and
+so does a stopped Homing dropping back to Unhomed),
+finishing Homing clears the fault count and leaving Fault
+clears the fault. Entering Teleop or Script re-arms the
+watchdog, so it fails while the link is down. transition()
+refuses anything can_transition() does not allow, so nothing
+can skip homing. A write the driver fails (SpiError) is
+returned as it is, and last_commanded only changes once every
+joint was written.
+
+With velocity teleop (set_velocity_teleop) jogs in Teleop only
+set the target speeds and move the gripper, update() moves the
+arm a step every cycle and returns what the motors were sent.
+Heartbeats count as centered sticks, and leaving Teleop or a
+rejected step stops it dead.
+
+The gripper is checked for a grasp (or a stall) every cycle
+while it is moving, in Teleop and Script the eased back spool
+target is written straight away.
+
+Homing, grasp detection and restore() all need encoder
+feedback, which only the simulator gives so far. On the SPI
+motor controllers (RobotDriver::read_feedback() is None)
+homing finishes as soon as the home pose is sent, the
+gripper is only ever Sent (gripper.rs) and a saved state is
+always Unconfirmed, so the arm starts Unhomed.
+is_homing_confirmed() tells a homing the encoders settled on
+from one that was only sent, main.rs says which at start up
+and in the RPC faults.
+
+Mode changes, faults, unconfirmed homing and grasps are not
+printed here, they are queued as ControllerEvents for the
+binary to log (take_events()).
+
+Everything takes the current time (like CommandWatchdog) and
+the driver is generic, so the whole machine is tested below
+with a mock driver.
+
+*/
+
+// external imports
+use std::fmt;
+use std::time::{Duration, Instant};
+use arm_protocol::status::ArmMode;
+use serde::{Deserialize, Serialize};
+
+// internal imports
+use crate::arm_errors::RoboticArmError;
+use crate::commands::arm_command::{ArmCommand, ControlMode};
+use crate::networking::data_handler::DataHandler;
+use super::arm_state::{ArmState, Joint, RoboticArmSolver};
+use super::gripper::GripState;
+use super::saved_state::{Reconciliation, SavedState};
+use super::command_watchdog::CommandWatchdog;
+use super::robot_driver::{JointWiring, MotorDriver};
+use super::velocity_teleop::VelocityTeleop;
+
+// faults since the last homing before the arm goes to SafeMode
+pub const MAX_FAULTS: u32 = 3;
+// every joint has to be this close (encoder ticks) for homing to finish
+pub const HOMING_TOLERANCE_TICKS: u16 = 2;
+// homing that takes longer than this is a fault
+pub const HOMING_TIMEOUT: Duration = Duration::from_secs(30);
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OperatingMode
+{
+    Boot,
+    Unhomed,
+    Homing,
+    Idle,
+    Teleop,
+    Script,
+    Hold,
+    Fault,
+    SafeMode,
+}
+
+impl OperatingMode
+{
+    pub fn can_transition(&self, to: OperatingMode) -> bool
+    {
+        use OperatingMode::*;
+        matches!((*self, to),
+            (Boot, Unhomed | Idle)
+            | (Unhomed, Homing)
+            | (Homing, Idle | Unhomed | SafeMode)
+            | (Idle | Teleop | Script | Hold, Idle | Teleop | Script | Hold | Homing)
+            | (Idle | Teleop | Script | Hold, Fault)
+            | (Fault, Idle | Teleop | Script | Hold | SafeMode)
+            | (SafeMode, Homing))
+    }
+
+    pub fn accepts(&self, command: &ArmCommand) -> bool
+    {
+        use OperatingMode::*;
+        match (*self, command)
+        {
+            (_, ArmCommand::Heartbeat | ArmCommand::Stop) => true,
+            (Unhomed | Idle | Teleop | Script | Hold | SafeMode, ArmCommand::Home) => true,
+            (Teleop, ArmCommand::Jog(_)) => true,
+            // both buttons homes, arms or re-arms
+            (Unhomed | Idle | Hold | Fault, ArmCommand::Jog(data)) => data.both_buttons_pressed(),
+            (Teleop | Script, ArmCommand::Gripper { .. }) => true,
+            (Script, ArmCommand::MoveToPose { .. } | ArmCommand::MoveJoints { .. }) => true,
+            (Idle | Teleop | Script | Hold | Fault, ArmCommand::SetMode { .. }) => true,
+            _ => false,
+        }
+    }
+}
+
+// what the controller did on its own, for the binary to log
+#[derive(Clone, Debug, PartialEq)]
+pub enum ControllerEvent
+{
+    ModeChanged { from: OperatingMode, to: OperatingMode },
+    Fault(String),
+    // homing finished without encoder feedback to confirm it
+    HomingUnconfirmed,
+    Grip { state: GripState, position: Option<u16> },
+}
+
+impl fmt::Display for ControllerEvent
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            ControllerEvent::ModeChanged { from, to } => write!(f, "Mode {from:?} -> {to:?}"),
+            ControllerEvent::Fault(reason) => write!(f, "Fault: {reason}"),
+            ControllerEvent::HomingUnconfirmed => write!(f, "No encoder feedback, homing is not confirmed"),
+            ControllerEvent::Grip { state, position } => write!(f, "Gripper {state:?} at {position:?}"),
+        }
+    }
+}
+
+impl From<ControlMode> for OperatingMode
+{
+    fn from(mode: ControlMode) -> OperatingMode
+    {
+        match mode
+        {
+            ControlMode::Idle => OperatingMode::Idle,
+            ControlMode::Teleop => OperatingMode::Teleop,
+            ControlMode::Script => OperatingMode::Script,
+            ControlMode::Hold => OperatingMode::Hold,
+        }
+    }
+}
+
+impl From<OperatingMode> for ArmMode
+{
+    fn from(mode: OperatingMode) -> ArmMode
+    {
+        match mode
+        {
+            OperatingMode::Boot => ArmMode::Boot,
+            OperatingMode::Unhomed => ArmMode::Unhomed,
+            OperatingMode::Homing => ArmMode::Homing,
+            OperatingMode::Idle => ArmMode::Idle,
+            OperatingMode::Teleop => ArmMode::Teleop,
+            OperatingMode::Script => ArmMode::Script,
+            OperatingMode::Hold => ArmMode::Hold,
+            OperatingMode::Fault => ArmMode::Fault,
+            OperatingMode::SafeMode => ArmMode::SafeMode,
+        }
+    }
+}
+
+
+pub struct ArmController<D: MotorDriver>
+{
+    mode: OperatingMode,
+    robotic_arm: RoboticArmSolver,
+    driver: D,
+    wiring: JointWiring,
+    // only sources that send heartbeats get one
+    watchdog: Option<CommandWatchdog>,
+    // jogs are position increments without one
+    teleop: Option<VelocityTeleop>,
+    fault: Option<String>,
+    fault_count: u32,
+    homing_started: Option<Instant>,
+    // the encoders settled at home, not just sent there
+    homing_confirmed: bool,
+    last_commanded: Option<ArmState>,
+    // for the binary to log, see take_events()
+    events: Vec<ControllerEvent>,
+}
+
+impl<D: MotorDriver> ArmController<D>
+{
+    pub fn new(robotic_arm: RoboticArmSolver, driver: D, wiring: JointWiring) -> ArmController<D>
+    {
+        ArmController
+        {
+            mode: OperatingMode::Boot,
+            robotic_arm,
+            driver,
+            wiring,
+            watchdog: None,
+            teleop: None,
+            fault: None,
+            fault_count: 0,
+            homing_started: None,
+            homing_confirmed: false,
+            last_commanded: None,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn set_watchdog(&mut self, watchdog: CommandWatchdog)
+    {
+        self.watchdog = Some(watchdog);
+    }
+
+    pub fn set_velocity_teleop(&mut self, teleop: VelocityTeleop)
+    {
+        self.teleop = Some(teleop);
+    }
+
+    pub fn start(&mut self, now: Instant) -> Result<(), RoboticArmError>
+    {
+        self.transition(OperatingMode::Unhomed, now)
+    }
+
+    pub fn restore(&mut self, saved: &SavedState, now: Instant) -> Result<Reconciliation, RoboticArmError>
+    {
+        // instead of start(), pick up from the last run once the encoders have had their say
+        let reconciliation = saved.reconcile(self.driver.read_feedback(), self.robotic_arm.get_joint_map());
+        let delta = match reconciliation
+        {
+            Reconciliation::Confirmed | Reconciliation::Unconfirmed => {
+                self.robotic_arm.restore(saved.commanded, Some((saved.joint_angles, saved.pose)))?;
+                saved.commanded
+            },
+            Reconciliation::Moved { feedback } => {
+                self.robotic_arm.restore(feedback, None)?;
+                feedback
+            },
+        };
+        self.last_commanded = Some(delta);
+
+        let homed = saved.homed && reconciliation == Reconciliation::Confirmed;
+        self.homing_confirmed = homed;
+        self.transition(if homed { OperatingMode::Idle } else { OperatingMode::Unhomed }, now)?;
+        Ok(reconciliation)
+    }
+
+    pub fn handle(&mut self, command: ArmCommand, now: Instant) -> Result<Option<ArmState>, RoboticArmError>
+    {
+        // returns what the motors were sent, if anything
+        if let Some(watchdog) = &mut self.watchdog
+        {
+            watchdog.feed(now);
+        }
+        if !self.mode.accepts(&command)
+        {
+            return Err(RoboticArmError::CommandRejected { mode: self.mode, command });
+        }
+
+        match command
+        {
+            ArmCommand::Heartbeat => {
+                if let Some(teleop) = &mut self.teleop
+                {
+                    teleop.set_input(DataHandler::heartbeat(), now);
+                }
+                Ok(None)
+            },
+            ArmCommand::Stop => {
+                match self.mode
+                {
+                    OperatingMode::Teleop | OperatingMode::Script => self.transition(OperatingMode::Hold, now)?,
+                    OperatingMode::Homing => self.transition(OperatingMode::Unhomed, now)?,
+                    _ => {
+                        self.driver.hold(&self.wiring)?;
+                    },
+                }
+                Ok(None)
+            },
+            ArmCommand::SetMode { mode } => {
+                self.transition(mode.into(), now)?;
+                Ok(None)
+            },
+            ArmCommand::Home => {
+                self.transition(OperatingMode::Homing, now)?;
+                Ok(self.last_commanded)
+            },
+            // both buttons outside teleop
+            ArmCommand::Jog(_) if self.mode == OperatingMode::Unhomed => {
+                self.transition(OperatingMode::Homing, now)?;
+                Ok(self.last_commanded)
+            },
+            ArmCommand::Jog(_) if self.mode != OperatingMode::Teleop => {
+                self.transition(OperatingMode::Teleop, now)?;
+                Ok(None)
+            },
+            ArmCommand::Jog(data) if self.teleop.is_some() => {
+                // the arm itself moves in update()
+                if let Some(teleop) = &mut self.teleop
+                {
+                    teleop.set_input(data, now);
+                }
+                if self.robotic_arm.jog_gripper(data)
+                {
+                    return self.write_motors().map(Some);
+                }
+                Ok(None)
+            },
+            _ => self.move_arm(command),
+        }
+    }
+
+    pub fn update(&mut self, now: Instant) -> Result<Option<ArmState>, RoboticArmError>
+    {
+        // called every control cycle, whether a command came in or not
+        // returns what the motors were sent, if velocity teleop moved the arm
+        self.driver.update();
+
+        // only the moving modes need the operator, the rest are holding already
+        let tripped = self.watchdog.as_mut().is_some_and(|watchdog| watchdog.check(now));
+        if tripped && matches!(self.mode, OperatingMode::Teleop | OperatingMode::Script)
+        {
+            let silence = self.watchdog.map(|watchdog| watchdog.silence(now)).unwrap_or_default();
+            self.report_fault(format!("No commands for {silence:?}"), now)?;
+        }
+
+        if self.mode == OperatingMode::Homing
+        {
+            let started = self.homing_started.unwrap_or(now);
+            if self.homing_settled()
+            {
+                // without feedback the arm was only sent home
+                self.homing_confirmed = self.driver.read_feedback().is_some();
+                if !self.homing_confirmed
+                {
+                    self.events.push(ControllerEvent::HomingUnconfirmed);
+                }
+                self.fault_count = 0;
+                self.fault = None;
+                self.transition(OperatingMode::Idle, now)?;
+            } else if now.saturating_duration_since(started) > HOMING_TIMEOUT
+            {
+                self.report_fault(format!("Homing did not finish in {HOMING_TIMEOUT:?}"), now)?;
+            }
+        }
+
+        if self.robotic_arm.get_gripper().get_state() == GripState::Moving
+        {
+            self.check_grip(now)?;
+        }
+
+        if self.mode != OperatingMode::Teleop
+        {
+            return Ok(None);
+        }
+        let Some(teleop) = &mut self.teleop else {
+            return Ok(None);
+        };
+        let step = teleop.step(now);
+        if step.is_zero()
+        {
+            return Ok(None);
+        }
+        let result = self.robotic_arm.apply_jog_step(step);
+        if result.is_err()
+        {
+            // do not keep pushing into the limit
+            teleop.stop();
+        }
+        let delta = self.write_motors()?;
+        result.map(|_| Some(delta))
+    }
+
+    pub fn report_fault(&mut self, reason: String, now: Instant) -> Result<(), RoboticArmError>
+    {
+        // Fault first, SafeMode if faults keep coming or homing failed
+        self.events.push(ControllerEvent::Fault(reason.clone()));
+        self.fault = Some(reason);
+        self.fault_count += 1;
+
+        if matches!(self.mode, OperatingMode::Boot | OperatingMode::SafeMode)
+        {
+            return Ok(());
+        }
+        // leaving Fault would skip homing, so the arm stays where it has to home from
+        if self.mode == OperatingMode::Unhomed
+        {
+            self.driver.hold(&self.wiring)?;
+            return Ok(());
+        }
+        if !matches!(self.mode, OperatingMode::Homing | OperatingMode::Fault)
+        {
+            self.transition(OperatingMode::Fault, now)?;
+        }
+        if self.mode == OperatingMode::Homing || self.fault_count >= MAX_FAULTS
+        {
+            self.transition(OperatingMode::SafeMode, now)?;
+        }
+        Ok(())
+    }
+
+    pub fn transition(&mut self, to: OperatingMode, now: Instant) -> Result<(), RoboticArmError>
+    {
+        let from = self.mode;
+        if from == to
+        {
+            return Ok(());
+        }
+        if !from.can_transition(to)
+        {
+            return Err(RoboticArmError::IllegalTransition { from, to });
+        }
+        // moving again needs the operator to be there
+        if matches!(to, OperatingMode::Teleop | OperatingMode::Script)
+        {
+            if let Some(watchdog) = &mut self.watchdog
+            {
+                watchdog.rearm(now)?;
+            }
+        }
+        // the home move has to reach the motors before the arm counts as homing,
+        // a home pose the solver or the bus refuses leaves the mode as it was
+        if to == OperatingMode::Homing
+        {
+            let before = self.robotic_arm.clone();
+            self.robotic_arm.home()?;
+            if let Err(e) = self.write_motors()
+            {
+                // the solver stays with what the motors were last sent
+                self.robotic_arm = before;
+                return Err(e);
+            }
+        }
+
+        // exit
+        if from == OperatingMode::Teleop
+        {
+            if let Some(teleop) = &mut self.teleop
+            {
+                teleop.stop();
+            }
+        }
+        if from == OperatingMode::Fault && to != OperatingMode::SafeMode
+        {
+            self.fault = None;
+        }
+        if from == OperatingMode::Homing
+        {
+            self.homing_started = None;
+        }
+
+        self.mode = to;
+        self.events.push(ControllerEvent::ModeChanged { from, to });
+
+        // entry
+        match to
+        {
+            OperatingMode::Homing => {
+                self.homing_started = Some(now);
+                self.homing_confirmed = false;
+            },
+            OperatingMode::Idle | OperatingMode::Hold | OperatingMode::Fault | OperatingMode::SafeMode => {
+                self.driver.hold(&self.wiring)?;
+            },
+            // a stopped homing stops where it is instead of driving on to the home pose
+            OperatingMode::Unhomed if from == OperatingMode::Homing => {
+                self.driver.hold(&self.wiring)?;
+            },
+            OperatingMode::Boot | OperatingMode::Unhomed | OperatingMode::Teleop | OperatingMode::Script => (),
+        }
+        Ok(())
+    }
+
+    fn move_arm(&mut self, command: ArmCommand) -> Result<Option<ArmState>, RoboticArmError>
+    {
+        // the motors get whatever the solver holds, a rejected pose leaves it where it was
+        let result = self.robotic_arm.apply_command(command);
+        let delta = self.write_motors()?;
+        result.map(|_| Some(delta))
+    }
+
+    fn write_motors(&mut self) -> Result<ArmState, RoboticArmError>
+    {
+        // only what reached the motor controllers counts as commanded
+        let delta = self.robotic_arm.get_delta_joints();
+        self.driver.write_arm_state(delta, &self.wiring)?;
+        self.last_commanded = Some(delta);
+        Ok(delta)
+    }
+
+    fn check_grip(&mut self, now: Instant) -> Result<(), RoboticArmError>
+    {
+        // spool positions are relative to start up like the rest, and the spool starts at 0
+        let position = self.driver.read_feedback().map(|feedback| feedback.spool);
+        let current = self.driver.read_current(Joint::Spool);
+        let target = self.robotic_arm.get_gripper().get_target();
+        let state = self.robotic_arm.check_grip(position, current, now);
+        if matches!(state, GripState::Grasped | GripState::Stalled)
+        {
+            self.events.push(ControllerEvent::Grip { state, position });
+        }
+        let moving = matches!(self.mode, OperatingMode::Teleop | OperatingMode::Script);
+        if moving && self.robotic_arm.get_gripper().get_target() != target
+        {
+            self.write_motors()?;
+        }
+        Ok(())
+    }
+
+    fn homing_settled(&mut self) -> bool
+    {
+        // without feedback there is nothing to wait for
+        let joint_map = self.robotic_arm.get_joint_map();
+        match (self.driver.read_feedback(), self.last_commanded)
+        {
+            (Some(feedback), Some(target)) => Joint::ALL.iter()
+                .all(|joint| joint_map.tick_distance(*joint, feedback.get_joint(*joint), target.get_joint(*joint)) <= HOMING_TOLERANCE_TICKS),
+            _ => true,
+        }
+    }
+
+    pub fn link_lost(&self, now: Instant) -> bool
+    {
+        self.watchdog.is_some_and(|watchdog| !watchdog.link_alive(now))
+    }
+
+    pub fn is_homed(&self) -> bool
+    {
+        // a fault does not lose the position, SafeMode does
+        matches!(self.mode, OperatingMode::Idle | OperatingMode::Teleop | OperatingMode::Script | OperatingMode::Hold | OperatingMode::Fault)
+    }
+
+    pub fn is_homing_confirmed(&self) -> bool
+    {
+        self.is_homed() && self.homing_confirmed
+    }
+
+    pub fn get_saved_state(&mut self) -> SavedState
+    {
+        // what StateStore keeps for the next run
+        SavedState
+        {
+            commanded: self.last_commanded.unwrap_or_else(|| self.robotic_arm.get_delta_joints()),
+            confirmed: self.driver.read_feedback(),
+            homed: self.is_homed(),
+            pose: self.robotic_arm.get_end_effector_position(),
+            joint_angles: self.robotic_arm.get_joint_angles(),
+        }
+    }
+
+    pub fn get_mode(&self) -> OperatingMode
+    {
+        self.mode
+    }
+
+    pub fn get_fault(&self) -> Option<&str>
+    {
+        self.fault.as_deref()
+    }
+
+    pub fn get_fault_count(&self) -> u32
+    {
+        self.fault_count
+    }
+
+    pub fn get_arm(&self) -> &RoboticArmSolver
+    {
+        &self.robotic_arm
+    }
+
+    pub fn get_driver(&self) -> &D
+    {
+        &self.driver
+    }
+
+    pub fn get_driver_mut(&mut self) -> &mut D
+    {
+        &mut self.driver
+    }
+
+    pub fn get_watchdog(&self) -> Option<&CommandWatchdog>
+    {
+        self.watchdog.as_ref()
+    }
+
+    pub fn get_teleop(&self) -> Option<&VelocityTeleop>
+    {
+        self.teleop.as_ref()
+    }
+
+    pub fn take_events(&mut self) -> Vec<ControllerEvent>
+    {
+        // everything that happened since the last call, oldest first
+        std::mem::take(&mut self.events)
+    }
+
+    pub fn get_last_commanded(&self) -> Option<ArmState>
+    {
+        self.last_commanded
+    }
+}
+
+
+
+
+// ------------------------------- unit tests -------------------------------------
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::robotics::arm_state::JointLimits;
+    use crate::robotics::gripper::{GripperCommand, GripperPreset};
+    use crate::robotics::robot_driver::mock::{test_arm, MockDriver};
+    use crate::robotics::velocity_teleop::TeleopConfig;
+
+    const TIMEOUT: Duration = Duration::from_millis(500);
+
+    fn both_buttons() -> ArmCommand
+    {
+        // buttons are active low
+        ArmCommand::Jog(DataHandler::new(0, 0, 0, 0, 0, 0))
+    }
+
+    fn test_controller(driver: MockDriver) -> ArmController<MockDriver>
+    {
+        ArmController::new(test_arm(), driver, JointWiring::default())
+    }
+
+    fn homed_controller(now: Instant) -> ArmController<MockDriver>
+    {
+        let mut controller = test_controller(MockDriver::default());
+        controller.start(now).unwrap();
+        controller.handle(ArmCommand::Home, now).unwrap();
+        controller.update(now).unwrap();
+        controller
+    }
+
+    #[test]
+    fn test_transition_table()
+    {
+        // homing can not be skipped, SafeMode only leads back to homing
+        use OperatingMode::*;
+        assert!(Boot.can_transition(Unhomed));
+        assert!(!Boot.can_transition(Teleop));
+        assert!(!Unhomed.can_transition(Teleop));
+        assert!(!Unhomed.can_transition(Idle));
+        assert!(Idle.can_transition(Script));
+        assert!(Teleop.can_transition(Fault));
+        assert!(!Unhomed.can_transition(Fault));
+        assert!(!Fault.can_transition(Homing));
+        assert!(Fault.can_transition(SafeMode));
+        assert!(!Teleop.can_transition(SafeMode));
+        assert!(SafeMode.can_transition(Homing));
+        assert!(!SafeMode.can_transition(Idle));
+
+        let mut controller = test_controller(MockDriver::default());
+        assert!(matches!(controller.transition(Teleop, Instant::now()), Err(RoboticArmError::IllegalTransition { from: Boot, to: Teleop })));
+        assert_eq!(controller.get_mode(), Boot);
+    }
+
+    #[test]
+    fn test_boot_to_teleop()
+    {
+        // joystick only: both buttons homes, then both buttons arms
+        let now = Instant::now();
+        let mut controller = test_controller(MockDriver::default());
+        controller.start(now).unwrap();
+        assert_eq!(controller.get_mode(), OperatingMode::Unhomed);
+
+        let jog = ArmCommand::Jog(DataHandler::new(-1, 0, 0, 0, 1, 1));
+        assert_eq!(controller.handle(jog, now).unwrap_err(), RoboticArmError::CommandRejected { mode: OperatingMode::Unhomed, command: jog });
+        assert_eq!(controller.get_driver().writes(), 0);
+
+        assert!(controller.handle(both_buttons(), now).unwrap().is_some());
+        assert_eq!(controller.get_mode(), OperatingMode::Homing);
+        controller.update(now).unwrap();
+        assert_eq!(controller.get_mode(), OperatingMode::Idle);
+
+        controller.handle(both_buttons(), now).unwrap();
+        assert_eq!(controller.get_mode(), OperatingMode::Teleop);
+        assert!(controller.handle(jog, now).unwrap().is_some());
+        assert_eq!(controller.get_arm().get_end_effector_position(), [11.0, 6.0, 0.5]);
+    }
+
+    #[test]
+    fn test_commands_per_mode()
+    {
+        let now = Instant::now();
+        let mut controller = homed_controller(now);
+        let pose = ArmCommand::MoveToPose { x: 11.0, y: 6.0, si: 0.5 };
+
+        // poses are for scripts, jogs for teleop
+        assert!(controller.handle(pose, now).is_err());
+        controller.handle(ArmCommand::SetMode { mode: ControlMode::Script }, now).unwrap();
+        assert!(controller.handle(pose, now).is_ok());
+        assert!(controller.handle(ArmCommand::Jog(DataHandler::new(1, 0, 0, 0, 1, 1)), now).is_err());
+        assert!(controller.handle(ArmCommand::Gripper(GripperCommand::Position(1.0)), now).is_ok());
+
+        // an unreachable pose is the solver's error, the mode stays
+        let far = ArmCommand::MoveToPose { x: 40.0, y: 0.0, si: 0.0 };
+        assert!(matches!(controller.handle(far, now), Err(RoboticArmError::KinematicJointsNotUpdated { .. })));
+        assert_eq!(controller.get_mode(), OperatingMode::Script);
+
+        // stop holds, entering hold holds the motors
+        let holds = controller.get_driver().holds;
+        controller.handle(ArmCommand::Stop, now).unwrap();
+        assert_eq!(controller.get_mode(), OperatingMode::Hold);
+        assert_eq!(controller.get_driver().holds, holds + 1);
+        assert!(controller.handle(pose, now).is_err());
+
+        // stop while homing holds too, and homing does not finish
+        controller.get_driver_mut().feedback = Some(ArmState::from_array([100, 0, 0, 0, 0]));
+        controller.handle(ArmCommand::Home, now).unwrap();
+        assert_eq!(controller.get_mode(), OperatingMode::Homing);
+        let holds = controller.get_driver().holds;
+        controller.handle(ArmCommand::Stop, now).unwrap();
+        assert_eq!(controller.get_mode(), OperatingMode::Unhomed);
+        assert_eq!(controller.get_driver().holds, holds + 1);
+        controller.get_driver_mut().feedback = controller.get_last_commanded();
+        controller.update(now).unwrap();
+        assert_eq!(controller.get_mode(), OperatingMode::Unhomed);
+    }
+
+    #[test]
+    fn test_link_loss_faults_and_rearms()
+    {
+        let start = Instant::now();
+        let mut controller = homed_controller(start);
+        controller.set_watchdog(CommandWatchdog::new(TIMEOUT, start));
+        controller.handle(both_buttons(), start).unwrap();
+
+        let lost = start + 2 * TIMEOUT;
+        controller.update(lost).unwrap();
+        assert_eq!(controller.get_mode(), OperatingMode::Fault);
+        assert!(controller.get_fault().unwrap().contains("No commands"));
+        assert!(controller.link_lost(lost));
+
+        // the link coming back is not enough, the operator has to re-arm
+        controller.handle(ArmCommand::Heartbeat, lost).unwrap();
+        assert_eq!(controller.get_mode(), OperatingMode::Fault);
+        controller.handle(both_buttons(), lost).unwrap();
+        assert_eq!(controller.get_mode(), OperatingMode::Teleop);
+        assert_eq!(controller.get_fault(), None);
+    }
+
+    #[test]
+    fn test_repeated_faults_go_to_safe_mode()
+    {
+        let now = Instant::now();
+        let mut controller = homed_controller(now);
+
+        for _ in 0..MAX_FAULTS - 1
+        {
+            controller.handle(ArmCommand::SetMode { mode: ControlMode::Teleop }, now).unwrap();
+            controller.report_fault("motor controller not answering".into(), now).unwrap();
+            assert_eq!(controller.get_mode(), OperatingMode::Fault);
+        }
+        controller.report_fault("motor controller not answering".into(), now).unwrap();
+        assert_eq!(controller.get_mode(), OperatingMode::SafeMode);
+
+        // only homing again gets out
+        assert!(controller.handle(ArmCommand::SetMode { mode: ControlMode::Teleop }, now).is_err());
+        controller.handle(ArmCommand::Home, now).unwrap();
+        controller.update(now).unwrap();
+        assert_eq!(controller.get_mode(), OperatingMode::Idle);
+        assert_eq!(controller.get_fault_count(), 0);
+    }
+
+    #[test]
+    fn test_homing_without_feedback_is_not_confirmed()
+    {
+        // the SPI motor controllers have no readback, the arm is only sent home
+        use OperatingMode::*;
+        let now = Instant::now();
+        let mut controller = homed_controller(now);
+        assert_eq!(controller.get_mode(), OperatingMode::Idle);
+        assert!(!controller.is_homing_confirmed());
+        assert_eq!(controller.take_events(), vec![
+            ControllerEvent::ModeChanged { from: Boot, to: Unhomed },
+            ControllerEvent::ModeChanged { from: Unhomed, to: Homing },
+            ControllerEvent::HomingUnconfirmed,
+            ControllerEvent::ModeChanged { from: Homing, to: Idle },
+        ]);
+        assert!(controller.take_events().is_empty());
+
+        // with feedback homing waits for the encoders to get there
+        let mut controller = test_controller(MockDriver { feedback: Some(ArmState::from_array([0; 5])), ..MockDriver::default() });
+        controller.start(now).unwrap();
+        controller.handle(ArmCommand::Home, now).unwrap();
+        controller.get_driver_mut().feedback = controller.get_last_commanded();
+        controller.update(now).unwrap();
+        assert_eq!(controller.get_mode(), OperatingMode::Idle);
+        assert!(controller.is_homing_confirmed());
+    }
+
+    #[test]
+    fn test_fault_while_unhomed_does_not_skip_homing()
+    {
+        // a fault before homing used to be a way out of Unhomed
+        let now = Instant::now();
+        let mut controller = test_controller(MockDriver::default());
+        controller.start(now).unwrap();
+        controller.report_fault("motor controller not answering".into(), now).unwrap();
+        assert_eq!(controller.get_mode(), OperatingMode::Unhomed);
+        assert_eq!(controller.get_driver().holds, 1);
+
+        assert!(controller.handle(ArmCommand::SetMode { mode: ControlMode::Script }, now).is_err());
+        assert!(controller.handle(ArmCommand::MoveToPose { x: 11.0, y: 6.0, si: 0.5 }, now).is_err());
+        assert!(!controller.is_homed());
+        assert!(!controller.get_saved_state().homed);
+        assert_eq!(controller.get_driver().writes(), 0);
+
+        // homing clears it
+        controller.handle(ArmCommand::Home, now).unwrap();
+        controller.update(now).unwrap();
+        assert_eq!(controller.get_mode(), OperatingMode::Idle);
+        assert_eq!(controller.get_fault(), None);
+    }
+
+    #[test]
+    fn test_write_errors_reach_the_caller()
+    {
+        // a move the motors never got is not what was commanded
+        let now = Instant::now();
+        let mut controller = homed_controller(now);
+        controller.handle(ArmCommand::SetMode { mode: ControlMode::Script }, now).unwrap();
+        let commanded = controller.get_last_commanded();
+
+        controller.get_driver_mut().failing = true;
+        let error = controller.handle(ArmCommand::MoveToPose { x: 11.0, y: 6.0, si: 0.5 }, now).unwrap_err();
+        assert!(matches!(error, RoboticArmError::SpiError { mac_number: 1, .. }));
+        assert!(error.report().ends_with("bus down"));
+        assert_eq!(controller.get_last_commanded(), commanded);
+
+        // nor is a home pose, the solver stays where the motors are
+        controller.get_driver_mut().failing = false;
+        controller.handle(ArmCommand::MoveToPose { x: 11.0, y: 6.0, si: 0.5 }, now).unwrap();
+        let commanded = controller.get_last_commanded();
+        controller.get_driver_mut().failing = true;
+        assert!(controller.handle(ArmCommand::Home, now).is_err());
+        assert_eq!(controller.get_mode(), OperatingMode::Script);
+        assert_eq!(controller.get_last_commanded(), commanded);
+        assert_eq!(Some(controller.get_arm().get_delta_joints()), commanded);
+    }
+
+    #[test]
+    fn test_home_outside_limits_stays_unhomed()
+    {
+        // a home pose the joint limits refuse never starts homing, so it can not finish either
+        let now = Instant::now();
+        let mut arm = test_arm();
+        let [shoulder, _, _] = arm.get_last_joint_angles().unwrap();
+        arm.set_joint_limits(JointLimits { shoulder: [shoulder + 0.1, shoulder + 0.2], ..JointLimits::default() });
+        let mut controller = ArmController::new(arm, MockDriver::default(), JointWiring::default());
+        controller.start(now).unwrap();
+
+        assert!(matches!(controller.handle(ArmCommand::Home, now), Err(RoboticArmError::JointLimitExceeded { joint: Joint::Shoulder, .. })));
+        controller.update(now).unwrap();
+        assert_eq!(controller.get_mode(), OperatingMode::Unhomed);
+        assert!(!controller.is_homed());
+        assert_eq!(controller.get_driver().writes(), 0);
+        assert!(controller.handle(both_buttons(), now).is_err());
+        assert_eq!(controller.get_mode(), OperatingMode::Unhomed);
+    }
+
+    #[test]
+    fn test_homing_waits_for_feedback()
+    {
+        // the encoders are somewhere else and never get there
+        let now = Instant::now();
+        let driver = MockDriver { feedback: Some(ArmState::from_array([100, 0, 0, 0, 0])), ..MockDriver::default() };
+        let mut controller = test_controller(driver);
+        controller.start(now).unwrap();
+        controller.handle(ArmCommand::Home, now).unwrap();
+
+        controller.update(now + Duration::from_secs(1)).unwrap();
+        assert_eq!(controller.get_mode(), OperatingMode::Homing);
+        controller.update(now + HOMING_TIMEOUT + Duration::from_secs(1)).unwrap();
+        assert_eq!(controller.get_mode(), OperatingMode::SafeMode);
+
+        // once they do, homing finishes, the elbow a tick below zero is there too
+        controller.get_driver_mut().feedback = Some(ArmState::from_array([1, 4999, 0, 0, 0]));
+        controller.handle(ArmCommand::Home, now).unwrap();
+        controller.update(now).unwrap();
+        assert_eq!(controller.get_mode(), OperatingMode::Idle);
+    }
+
+    #[test]
+    fn test_velocity_teleop_moves_in_update()
+    {
+        let start = Instant::now();
+        let mut controller = homed_controller(start);
+        let config = TeleopConfig { max_linear_speed: 1.0, max_linear_acceleration: 100.0, expo: 0.0, ..TeleopConfig::default() };
+        controller.set_velocity_teleop(VelocityTeleop::new(config));
+        controller.handle(both_buttons(), start).unwrap();
+
+        // the jog only sets the speed, nothing moves until update
+        let jog = ArmCommand::Jog(DataHandler::new(-8, 0, 0, 0, 1, 1));
+        assert!(controller.handle(jog, start).unwrap().is_none());
+        assert!(controller.update(start).unwrap().is_none());
+        let later = start + Duration::from_millis(100);
+        assert!(controller.update(later).unwrap().is_some());
+        let [x, _, _] = controller.get_arm().get_end_effector_position();
+        assert!(x < 12.0 && x > 11.8);
+
+        // leaving teleop stops it dead
+        controller.handle(ArmCommand::Stop, later).unwrap();
+        assert_eq!(controller.get_teleop().unwrap().get_velocity(), [0.0; 4]);
+        assert!(controller.update(later + Duration::from_millis(100)).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_grasp_eases_spool()
+    {
+        let start = Instant::now();
+        let mut controller = homed_controller(start);
+        controller.handle(ArmCommand::SetMode { mode: ControlMode::Script }, start).unwrap();
+        controller.handle(ArmCommand::Gripper(GripperCommand::Preset(GripperPreset::Open)), start).unwrap();
+        for spool in [2000, 4000, 4999]
+        {
+            // less than half a turn a cycle, or the reading is taken the other way round
+            controller.get_driver_mut().feedback = Some(ArmState::from_array([0, 0, 0, 0, spool]));
+            controller.update(start).unwrap();
+        }
+        assert_eq!(controller.get_arm().get_gripper().get_state(), GripState::Reached);
+
+        // closing on something, the spool stops at 2000
+        controller.handle(ArmCommand::Gripper(GripperCommand::Preset(GripperPreset::Closed)), start).unwrap();
+        for spool in [3000, 2000]
+        {
+            controller.get_driver_mut().feedback = Some(ArmState::from_array([0, 0, 0, 0, spool]));
+            controller.update(start).unwrap();
+        }
+        let writes = controller.get_driver().writes();
+        controller.update(start + Duration::from_secs(1)).unwrap();
+        assert_eq!(controller.get_arm().get_gripper().get_state(), GripState::Grasped);
+        assert_eq!(controller.get_last_commanded().unwrap().spool, 1960);
+        assert!(controller.get_driver().writes() > writes);
+    }
+
+    #[test]
+    fn test_restore_after_restart()
+    {
+        let now = Instant::now();
+        let mut before = homed_controller(now);
+        before.handle(ArmCommand::SetMode { mode: ControlMode::Script }, now).unwrap();
+        before.handle(ArmCommand::MoveToPose { x: 11.0, y: 6.0, si: 0.5 }, now).unwrap();
+        let saved = before.get_saved_state();
+        assert!(saved.homed);
+
+        // the encoders agree, no homing needed
+        let driver = MockDriver { feedback: Some(saved.commanded), ..MockDriver::default() };
+        let mut controller = test_controller(driver);
+        assert_eq!(controller.restore(&saved, now).unwrap(), Reconciliation::Confirmed);
+        assert_eq!(controller.get_mode(), OperatingMode::Idle);
+        assert!(controller.is_homing_confirmed());
+        assert_eq!(controller.get_arm().get_end_effector_position(), [11.0, 6.0, 0.5]);
+
+        // the motor controllers restarted too, back at their zero and unhomed
+        let zero = ArmState::from_array([0; 5]);
+        let mut controller = test_controller(MockDriver { feedback: Some(zero), ..MockDriver::default() });
+        assert_eq!(controller.restore(&saved, now).unwrap(), Reconciliation::Moved { feedback: zero });
+        assert_eq!(controller.get_mode(), OperatingMode::Unhomed);
+        assert_eq!(controller.get_arm().get_delta_joints(), zero);
+        assert_eq!(controller.get_last_commanded(), Some(zero));
+    }
+}
@@ -15,8 +15,10 @@ use std::f64::consts::PI;
 
 // internal imports
 use crate::arm_errors::RoboticArmError;
+use super::arm_state::Joint;
 
 // contain joint angles, linkage lengths, and up/down elbow solver
+#[derive(Copy, Clone, Debug)]
 pub struct InverseKinematicSolver
 {
 	// linkage lengths
@@ -46,7 +48,47 @@ impl InverseKinematicSolver
 		self.link1 + self.link2 + self.link3
 	}
 
+	pub fn link_lengths(&self) -> [f64; 3]
+	{
+		[self.link1, self.link2, self.link3]
+	}
+
+	pub fn find_joint_positions(&self, joint_angles: [f64; 3]) -> [[f64; 2]; 4]
+	{
+		// forward kinematics, joint angles are relative to the previous link
+		// (same convention as find_joint_angles) so they are summed to get the
+		// absolute angle of each link
+		let [theta1, theta2, theta3] = joint_angles;
+		let phi1 = theta1;
+		let phi2 = phi1 + theta2;
+		let phi3 = phi2 + theta3;
+
+		// base, elbow, wrist, end effector
+		let x1 = self.link1 * phi1.cos();
+		let y1 = self.link1 * phi1.sin();
+		let x2 = x1 + self.link2 * phi2.cos();
+		let y2 = y1 + self.link2 * phi2.sin();
+		let x3 = x2 + self.link3 * phi3.cos();
+		let y3 = y2 + self.link3 * phi3.sin();
+
+		[[0.0, 0.0], [x1, y1], [x2, y2], [x3, y3]]
+	}
+
+	pub fn find_end_effector_position(&self, joint_angles: [f64; 3]) -> [f64; 3]
+	{
+		// returns [x, y, si] of the end effector
+		let positions = self.find_joint_positions(joint_angles);
+		let si = joint_angles.iter().sum::<f64>();
+
+		[positions[3][0], positions[3][1], si]
+	}
+
 	pub fn find_joint_angles(&self, x3: f64, y3: f64, si: f64) -> Result<[f64; 3], RoboticArmError>
+	{
+		self.find_joint_angles_with_elbow(x3, y3, si, self.up)
+	}
+
+	pub fn find_joint_angles_with_elbow(&self, x3: f64, y3: f64, si: f64, up: bool) -> Result<[f64; 3], RoboticArmError>
 	{
 
 		// ----------------------------- theta 2 ----------------------------------------
@@ -61,10 +103,10 @@ impl InverseKinematicSolver
 
 		if theta2.is_nan() 
 		{
-			return Err(RoboticArmError::Singularity("Singularity in theta 2".into()));
+			return Err(RoboticArmError::Singularity { joint: Joint::Elbow, pose: [x3, y3, si] });
 		}
 		// elbow down position
-		if self.up
+		if up
 		{
 			theta2 *= -1.0;
 		}
@@ -75,11 +117,7 @@ impl InverseKinematicSolver
 
 		// ---------------------------------- theta 1 --------------------------------------
 		// correct for quadrants
-		if x2 < 0.0 && y2 >= 0.0 
-		{
-			gamma += PI;
-		} 
-		else if x2 < 0.0 && y2 < 0.0 
+		if x2 < 0.0 
 		{
 			gamma += PI;
 		} 
@@ -160,7 +198,54 @@ mod tests
 				panic!();
 			},
 			Err(e) => assert_eq!(e, 
-				RoboticArmError::Singularity("Singularity in theta 2".into())),
+				RoboticArmError::Singularity { joint: Joint::Elbow, pose: [21.0, -0.5, 0.0] }),
 		}
 	}
-}
\ No newline at end of file
+
+	#[test]
+	fn test_elbow_up_reaches_same_pose()
+	{
+		// both elbow solutions put the end effector in the same place
+		let arm = InverseKinematicSolver::new(10.0, 7.0, 3.0);
+
+		let down = arm.find_joint_angles_with_elbow(-7.76, 6.9, 2.625, false).unwrap();
+		let up = arm.find_joint_angles_with_elbow(-7.76, 6.9, 2.625, true).unwrap();
+		let [x, y, _] = arm.find_end_effector_position(up);
+
+		assert_near!(up[1], -down[1], 1e-9);
+		assert_near!(x, -7.76, 0.001);
+		assert_near!(y, 6.9, 0.001);
+	}
+
+	#[test]
+	fn test_forward_kinematics_round_trip()
+	{
+		// joint angles from the IK solver should put the end effector
+		// back where it was requested
+		let arm = InverseKinematicSolver::new(10.0, 7.0, 3.0);
+
+		let joint_angles = arm.find_joint_angles(-7.76, 6.9, 2.625).unwrap();
+		let [x, y, si] = arm.find_end_effector_position(joint_angles);
+
+		assert_near!(x, -7.76, 0.001);
+		assert_near!(y, 6.9, 0.001);
+		assert_near!(si, 2.625, 0.001);
+	}
+
+	#[test]
+	fn test_left_quadrants()
+	{
+		// the wrist is left of the base, above it and then below it
+		let arm = InverseKinematicSolver::new(10.0, 7.0, 3.0);
+
+		for (x, y) in [(-8.0, 6.0), (-8.0, -6.0)]
+		{
+			let joint_angles = arm.find_joint_angles(x, y, PI).unwrap();
+			let [x_found, y_found, _] = arm.find_end_effector_position(joint_angles);
+
+			assert_near!(x_found, x, 0.001);
+			assert_near!(y_found, y, 0.001);
+			assert!((0.0..2.0 * PI).contains(&joint_angles[0]));
+		}
+	}
+}
@@ -5,47 +5,403 @@ William Albertini
 This module handles the movement of the robotic arm. It uses the 
 inverse kinematics solver (InverseKinematicSolver) to update
 joint positions and map them to encoder values. It also keeps
-track of the initial state of the arm, and provides the change in
-joint angles for any move. This is needed for the motor controllers,
-which use relative position, not absolute position.
+track of the initial state of the arm, and provides the encoder
+ticks the motor controllers are sent for any move
+(get_delta_joints), counted from where their encoders read zero.
+
+AngleToEncoderMap has a JointEncoder for each joint:
+
+    ticks_per_revolution   of the shaft the encoder is on
+    gear_ratio             shaft turns per joint turn
+    direction              -1 if the encoder counts the other way
+    zero_offset            joint angle (radians) at encoder zero
+    backlash               play in the gearing (radians)
+
+Conversions round to the nearest tick and wrap within one turn
+of the joint, ticks_to_angle() gives the angle within half a
+turn of the zero offset. Without a zero offset the encoder reads
+zero at the starting pose (the motor controllers zero where they
+power up), with one (fitted by encoder_calibration.rs) the ticks
+sent are absolute and the arm is only at the starting pose once
+it has been homed there. The motor controllers only take
+positions from 0 up (13 bits), so a joint sent below its zero
+is sent the same angle one turn up, the wire format is not
+changed here.
+
+Besides joystick jogs (update_from_data_handler) the arm can be
+sent straight to a pose (move_to_pose), to a set of joint angles
+(move_joints), back to where it started (home), and the
+gripper sent to a position or preset (command_gripper, the
+spool stays between closed and open, see gripper.rs).
+
+Joystick packets flagged joint_jog move the shoulder, elbow and
+wrist directly (jog_joints), which works at singularities where
+IK does not. Each joint stops at its limit and the end effector
+pose is brought back in line with forward kinematics.
+
+With JogFrame::Tool the x stick pushes along the gripper's
+approach direction (si) and y across it, instead of along the
+base axes. The cross axis is turned by the roll as well, only
+the part of it left in the arm's plane moves the arm (rolled a
+quarter turn, y points out of the plane and does nothing).
+
+Velocity teleop (velocity_teleop.rs) works out how far to go
+each control cycle and hands it over as a JogStep
+(apply_jog_step), the gripper buttons still go through
+jog_gripper once per packet.
+
+After a restart restore() puts the solver back where the
+motor controllers were left (see saved_state.rs) instead of
+the starting pose.
+
+How far a joystick count moves the arm is set by JogGains,
+and JointLimits keeps the kinematic joints inside their
+mechanical range (a pose that needs a joint past its limit is
+refused like an unreachable one). Both come from the config
+file (arm_config.rs).
 
 */
 
 use std::f64::consts::PI;
-use std::ops::Sub;
+use std::fmt;
+use std::time::Instant;
+use serde::de::{self, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use crate::networking::data_handler::DataHandler;
+use crate::commands::arm_command::ArmCommand;
 use crate::arm_errors::RoboticArmError;
 use super::arm_kinematics::InverseKinematicSolver;
+use super::gripper::{GripState, Gripper, GripperCommand, GripperConfig};
+use super::velocity_teleop::JogStep;
+
+
+// joints driven by the motor controllers
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Joint
+{
+    Shoulder,
+    Elbow,
+    Wrist,
+    Roll,
+    Spool,
+}
+
+impl Joint
+{
+    pub const ALL: [Joint; 5] = [Joint::Shoulder, Joint::Elbow, Joint::Wrist, Joint::Roll, Joint::Spool];
+}
 
+// one joint's encoder, see the top of the file
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(remote = "Self", default, deny_unknown_fields)]
+pub struct JointEncoder
+{
+    // ticks per revolution of the shaft the encoder is on
+    pub ticks_per_revolution: u16,
+    // encoder shaft turns per joint turn
+    pub gear_ratio: f64,
+    // 1, or -1 if the encoder counts down as the joint angle goes up
+    pub direction: i8,
+    // joint angle (radians) where the encoder reads zero, unset it is the starting pose
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub zero_offset: Option<f64>,
+    // play in the gearing (radians at the joint)
+    pub backlash: f64,
+}
 
+impl Default for JointEncoder
+{
+    fn default() -> JointEncoder
+    {
+        JointEncoder::new(5000)
+    }
+}
 
+impl JointEncoder
+{
+    pub fn new(ticks_per_revolution: u16) -> JointEncoder
+    {
+        // encoder straight on the joint, counting up with it
+        JointEncoder { ticks_per_revolution, gear_ratio: 1.0, direction: 1, zero_offset: None, backlash: 0.0 }
+    }
+
+    pub fn get_span(&self) -> u16
+    {
+        // encoder ticks in one turn of the joint
+        (self.ticks_per_revolution as f64 * self.gear_ratio).round().clamp(1.0, u16::MAX as f64) as u16
+    }
+
+    pub fn ticks_per_radian(&self) -> f64
+    {
+        // of the joint, negative when the encoder counts the other way
+        self.direction.signum() as f64 * self.ticks_per_revolution as f64 * self.gear_ratio / (2.0 * PI)
+    }
+
+    pub fn angle_to_ticks(&self, angle: f64) -> u16
+    {
+        // rounded to the nearest tick, wrapped into one turn of the joint
+        let ticks = ((angle - self.zero_offset.unwrap_or(0.0)) * self.ticks_per_radian()).round() as i64;
+        ticks.rem_euclid(self.get_span() as i64) as u16
+    }
+
+    pub fn ticks_to_angle(&self, ticks: u16) -> f64
+    {
+        // the angle within half a turn of the zero offset
+        let span = self.get_span() as i64;
+        let mut ticks = ticks as i64 % span;
+        if ticks > span / 2
+        {
+            ticks -= span;
+        }
+        self.zero_offset.unwrap_or(0.0) + ticks as f64 / self.ticks_per_radian()
+    }
+
+    pub fn tick_difference(&self, ticks: u16, from: u16) -> i32
+    {
+        // signed ticks from one reading to another the short way round
+        wrapped_difference(ticks, from, self.get_span())
+    }
+
+    pub fn backlash_correction(&self, motion: f64) -> f64
+    {
+        // radians to add to a target approached in the direction of motion,
+        // half the play, so the middle of the play is where the joint is
+        if motion == 0.0 { 0.0 } else { motion.signum() * self.backlash / 2.0 }
+    }
+}
+
+impl Serialize for JointEncoder
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    {
+        JointEncoder::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for JointEncoder
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<JointEncoder, D::Error>
+    {
+        // either just the ticks per revolution (shoulder = 5000) or the whole table
+        struct EncoderVisitor;
+
+        impl<'de> Visitor<'de> for EncoderVisitor
+        {
+            type Value = JointEncoder;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result
+            {
+                formatter.write_str("ticks per revolution or an encoder table")
+            }
+
+            fn visit_i64<E: de::Error>(self, ticks: i64) -> Result<JointEncoder, E>
+            {
+                u16::try_from(ticks).map(JointEncoder::new)
+                    .map_err(|_| E::invalid_value(de::Unexpected::Signed(ticks), &"ticks per revolution up to 65535"))
+            }
+
+            fn visit_u64<E: de::Error>(self, ticks: u64) -> Result<JointEncoder, E>
+            {
+                u16::try_from(ticks).map(JointEncoder::new)
+                    .map_err(|_| E::invalid_value(de::Unexpected::Unsigned(ticks), &"ticks per revolution up to 65535"))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<JointEncoder, A::Error>
+            {
+                JointEncoder::deserialize(de::value::MapAccessDeserializer::new(map))
+            }
+        }
+
+        deserializer.deserialize_any(EncoderVisitor)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct AngleToEncoderMap
 {
-    shoulder: u16,
-    elbow: u16,
-    wrist: u16,
-    roll: u16,
-    spool: u16,
+    shoulder: JointEncoder,
+    elbow: JointEncoder,
+    wrist: JointEncoder,
+    roll: JointEncoder,
+    spool: JointEncoder,
 }
 
 impl AngleToEncoderMap
 {
     pub fn new(shoulder: u16, elbow: u16, wrist: u16, roll: u16, spool: u16) -> AngleToEncoderMap
     {
+        // ticks per revolution only, straight on the joints and zeroed at the starting pose
         AngleToEncoderMap
         {
-            shoulder,
-            elbow,
-            wrist,
-            roll,
-            spool
+            shoulder: JointEncoder::new(shoulder),
+            elbow: JointEncoder::new(elbow),
+            wrist: JointEncoder::new(wrist),
+            roll: JointEncoder::new(roll),
+            spool: JointEncoder::new(spool),
+        }
+    }
+
+    pub fn get_encoder(&self, joint: Joint) -> &JointEncoder
+    {
+        match joint
+        {
+            Joint::Shoulder => &self.shoulder,
+            Joint::Elbow => &self.elbow,
+            Joint::Wrist => &self.wrist,
+            Joint::Roll => &self.roll,
+            Joint::Spool => &self.spool,
         }
     }
+
+    pub fn set_encoder(&mut self, joint: Joint, encoder: JointEncoder)
+    {
+        match joint
+        {
+            Joint::Shoulder => self.shoulder = encoder,
+            Joint::Elbow => self.elbow = encoder,
+            Joint::Wrist => self.wrist = encoder,
+            Joint::Roll => self.roll = encoder,
+            Joint::Spool => self.spool = encoder,
+        }
+    }
+
+    pub fn with_zero_at(&self, joint_angles: [f64; 3]) -> AngleToEncoderMap
+    {
+        // shoulder, elbow, wrist without a zero offset read zero at these angles
+        let mut joint_map = *self;
+        for (joint, angle) in [Joint::Shoulder, Joint::Elbow, Joint::Wrist].into_iter().zip(joint_angles)
+        {
+            let mut encoder = *joint_map.get_encoder(joint);
+            encoder.zero_offset = encoder.zero_offset.or(Some(angle));
+            joint_map.set_encoder(joint, encoder);
+        }
+        joint_map
+    }
+
+    pub fn ticks_per_revolution(&self, joint: Joint) -> u16
+    {
+        // per turn of the joint, gearing included
+        self.get_encoder(joint).get_span()
+    }
+
+    pub fn ticks_to_angle(&self, joint: Joint, ticks: u16) -> f64
+    {
+        // encoder ticks back to radians
+        self.get_encoder(joint).ticks_to_angle(ticks)
+    }
+
+    pub fn angle_to_ticks(&self, joint: Joint, angle: f64) -> u16
+    {
+        // radians to encoder ticks
+        self.get_encoder(joint).angle_to_ticks(angle)
+    }
+
+    pub fn tick_distance(&self, joint: Joint, ticks: u16, from: u16) -> u16
+    {
+        // how far apart two readings are, one tick below zero is one tick from it
+        self.get_encoder(joint).tick_difference(ticks, from).unsigned_abs() as u16
+    }
+}
+
+pub fn wrapped_difference(ticks: u16, from: u16, span: u16) -> i32
+{
+    // ticks - from on an encoder that wraps every span ticks, between -span/2 and span/2
+    let span = span.max(1) as i32;
+    let difference = (ticks as i32 - from as i32).rem_euclid(span);
+    if difference > span / 2 { difference - span } else { difference }
+}
+
+// which way the x and y sticks push the end effector
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JogFrame
+{
+    // x and y along the base axes
+    Base,
+    // x along the gripper, y across it
+    Tool,
+}
+
+// how much one joystick count moves each axis
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JogGains
+{
+    pub frame: JogFrame,
+    // mm per count on x and y
+    pub linear: f64,
+    // radians per count of pitch
+    pub pitch: f64,
+    // encoder ticks per count of roll
+    pub roll: i32,
+    // encoder ticks per packet while a gripper button is held
+    pub spool: i32,
+    // radians per count in joint jog
+    pub joint: f64,
+}
+
+impl Default for JogGains
+{
+    fn default() -> JogGains
+    {
+        JogGains { frame: JogFrame::Base, linear: 1.0, pitch: 0.01, roll: 1, spool: 8, joint: 0.01 }
+    }
+}
+
+// [min, max] radians for each joint the solver moves, unlimited by default
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JointLimits
+{
+    pub shoulder: [f64; 2],
+    pub elbow: [f64; 2],
+    pub wrist: [f64; 2],
+}
+
+impl JointLimits
+{
+    pub fn as_array(&self) -> [[f64; 2]; 3]
+    {
+        [self.shoulder, self.elbow, self.wrist]
+    }
+
+    pub fn check(&self, joint_angles: [f64; 3]) -> Result<(), RoboticArmError>
+    {
+        let joints = [Joint::Shoulder, Joint::Elbow, Joint::Wrist];
+        for ((joint, limits), angle) in joints.into_iter().zip(self.as_array()).zip(joint_angles)
+        {
+            if angle < limits[0] || angle > limits[1]
+            {
+                return Err(RoboticArmError::JointLimitExceeded { joint, angle, limits });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn clamp(&self, joint_angles: [f64; 3]) -> [f64; 3]
+    {
+        let mut clamped = joint_angles;
+        for (angle, [min, max]) in clamped.iter_mut().zip(self.as_array())
+        {
+            *angle = angle.clamp(min, max);
+        }
+        clamped
+    }
+}
+
+impl Default for JointLimits
+{
+    fn default() -> JointLimits
+    {
+        let unlimited = [f64::NEG_INFINITY, f64::INFINITY];
+        JointLimits { shoulder: unlimited, elbow: unlimited, wrist: unlimited }
+    }
 }
 
 // struct to keep track of motor positions
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ArmState
 {
     pub shoulder: u16,
@@ -62,7 +418,18 @@ impl ArmState
         ArmState{shoulder, elbow, wrist, roll, spool}
     }
 
-    fn update(&mut self, shoulder: u16, elbow: u16, wrist: u16, roll: u16, spool: u16) 
+    pub fn from_array(values: [u16; 5]) -> ArmState
+    {
+        // values are in the same order as Joint::ALL
+        ArmState::new(values[0], values[1], values[2], values[3], values[4])
+    }
+
+    pub fn as_array(&self) -> [u16; 5]
+    {
+        [self.shoulder, self.elbow, self.wrist, self.roll, self.spool]
+    }
+
+    pub fn update(&mut self, shoulder: u16, elbow: u16, wrist: u16, roll: u16, spool: u16) 
     {
         self.shoulder = shoulder;
         self.elbow = elbow;
@@ -79,26 +446,21 @@ impl ArmState
         self.elbow = elbow;
         self.wrist = wrist;
     }
-}
 
-impl Sub for ArmState
-{
-    type Output = Self;
-
-    // allows for the comparison of the motor states
-    fn sub(self, state: ArmState) -> Self::Output
+    pub fn get_joint(&self, joint: Joint) -> u16
     {
-
-        ArmState::new(
-            self.shoulder - state.shoulder,
-            self.elbow - state.elbow,
-            self.wrist - state.wrist,
-            self.roll - state.roll,
-            self.spool - state.spool,
-        )
+        match joint
+        {
+            Joint::Shoulder => self.shoulder,
+            Joint::Elbow => self.elbow,
+            Joint::Wrist => self.wrist,
+            Joint::Roll => self.roll,
+            Joint::Spool => self.spool,
+        }
     }
 }
 
+#[derive(Clone)]
 pub struct RoboticArmSolver
 {
     x: f64,
@@ -108,6 +470,19 @@ pub struct RoboticArmSolver
     updated_state: ArmState,
     solver: InverseKinematicSolver,
     joint_map: AngleToEncoderMap,
+    jog_gains: JogGains,
+    joint_limits: JointLimits,
+    // [x, y, si] the arm started at, home() goes back here
+    home: [f64; 3],
+    // shoulder, elbow, wrist (radians) the arm is at now
+    joint_angles: [f64; 3],
+    // last requested [x, y, si] and what the solver made of it
+    last_target: [f64; 3],
+    last_joint_angles: Option<[f64; 3]>,
+    // part of a roll tick left over from velocity jogs
+    roll_residual: f64,
+    // owns the spool target, updated_state.spool follows it
+    gripper: Gripper,
 }
 
 
@@ -127,14 +502,18 @@ impl RoboticArmSolver {
         // find initial joint values
         let [theta1, theta2, theta3] = solver.find_joint_angles(starting_x, starting_y, starting_si)?;
 
+        // encoders without a calibrated zero offset read zero here
+        let joint_map = joint_map.with_zero_at([theta1, theta2, theta3]);
+
         // convert the joint angles (radians) to encoder ticks
-        let init_shoulder = (theta1 * joint_map.shoulder as f64 / 2.0 / PI) as u16;
-        let init_elbow = (theta2 * joint_map.elbow as f64 / 2.0 / PI) as u16;
-        let init_wrist = (theta3 * joint_map.wrist as f64 / 2.0 / PI) as u16;
+        let init_shoulder = joint_map.angle_to_ticks(Joint::Shoulder, theta1);
+        let init_elbow = joint_map.angle_to_ticks(Joint::Elbow, theta2);
+        let init_wrist = joint_map.angle_to_ticks(Joint::Wrist, theta3);
 
         // create init and updated ArmState
         let initial_state = ArmState::new(init_shoulder, init_elbow, init_wrist, 0, 0);
-        let updated_state = initial_state.clone();
+        let updated_state = initial_state;
+        let spool_ticks = joint_map.ticks_per_revolution(Joint::Spool);
 
         Ok(RoboticArmSolver{ 
             x: starting_x,
@@ -144,88 +523,323 @@ impl RoboticArmSolver {
             updated_state,
             solver,
             joint_map,
+            jog_gains: JogGains::default(),
+            joint_limits: JointLimits::default(),
+            home: [starting_x, starting_y, starting_si],
+            joint_angles: [theta1, theta2, theta3],
+            last_target: [starting_x, starting_y, starting_si],
+            last_joint_angles: Some([theta1, theta2, theta3]),
+            roll_residual: 0.0,
+            // fully open is one turn of the spool until the config says otherwise
+            gripper: Gripper::new(GripperConfig { open: spool_ticks - 1, ..GripperConfig::default() }, spool_ticks, 0),
         })
     }
 
     pub fn update_from_data_handler(&mut self, data: DataHandler) -> Result<(), RoboticArmError>
     {
         // close fingers depending on buttons
+        self.jog_gripper(data);
+
+        // update roll
+        self.updated_state.roll = self.add_value_wrap(self.updated_state.roll, data.roll as i64 * self.jog_gains.roll as i64, self.joint_map.ticks_per_revolution(Joint::Roll));
+
+        if data.joint_jog
+        {
+            return self.jog_joints([data.x, data.y, data.pitch]);
+        }
+
+        // try to update state of arm
+        let [delta_x, delta_y] = self.jog_to_base(data.x as f64 * self.jog_gains.linear, data.y as f64 * self.jog_gains.linear);
+        let new_x = delta_x + self.x;
+        let new_y = delta_y + self.y;
+        let new_pitch = data.pitch as f64 * self.jog_gains.pitch + self.si;
+
+        self.move_to_pose(new_x, new_y, new_pitch)
+    }
+
+    pub fn jog_gripper(&mut self, data: DataHandler) -> bool
+    {
+        // open/close the gripper from the buttons, true if the spool moved
+        let spool = self.updated_state.spool;
         if data.both_buttons_pressed()
         {
-            // **** IMPLEMENT A GO HOME ROUTINE
+            // homing and arming, up to the control loop
 
-        // if only button 2 is pressed, increment spool
+        // if only button 2 is pressed, open
         } else if (data.button1 == 1) && (data.button2 == 0)
         {
-            self.updated_state.spool = self.add_value_wrap(self.updated_state.spool, 8, self.joint_map.spool);
-        // if only button 1 is pressed, decrement spool
+            self.updated_state.spool = self.gripper.jog(self.jog_gains.spool);
+        // if only button 1 is pressed, close
         } else if (data.button1 == 0) && (data.button2 == 1)
         {
-            self.updated_state.spool = self.add_value_wrap(self.updated_state.spool, -8, self.joint_map.spool);
+            self.updated_state.spool = self.gripper.jog(-self.jog_gains.spool);
         }
+        self.updated_state.spool != spool
+    }
 
-        // update roll
-        self.updated_state.roll = self.add_value_wrap(self.updated_state.roll, data.roll as i32, self.joint_map.roll);
+    pub fn apply_jog_step(&mut self, step: JogStep) -> Result<(), RoboticArmError>
+    {
+        // velocity teleop, the step is already scaled by the elapsed time
+        // roll steps are fractions of a tick, the remainder carries over
+        self.roll_residual += step.roll * self.joint_map.get_encoder(Joint::Roll).ticks_per_radian();
+        let ticks = self.roll_residual.trunc();
+        self.roll_residual -= ticks;
+        self.updated_state.roll = self.add_value_wrap(self.updated_state.roll, ticks as i64, self.joint_map.ticks_per_revolution(Joint::Roll));
 
-        // try to update state of arm
-        let new_x = data.x as f64 + self.x;
-        let new_y = data.y as f64 + self.y;
-        let new_pitch = data.pitch as f64 / 100.0 + self.si;
+        if step.joint_jog
+        {
+            let mut joint_angles = self.joint_angles;
+            for (angle, delta) in joint_angles.iter_mut().zip(step.axes)
+            {
+                *angle += delta;
+            }
+            return self.move_joints(self.joint_limits.clamp(joint_angles));
+        }
+
+        let [x, y, pitch] = step.axes;
+        let [delta_x, delta_y] = self.jog_to_base(x, y);
+        self.move_to_pose(self.x + delta_x, self.y + delta_y, self.si + pitch)
+    }
+
+    pub fn jog_to_base(&self, x: f64, y: f64) -> [f64; 2]
+    {
+        // stick deltas (mm) in the jog frame to [x, y] in the base frame
+        match self.jog_gains.frame
+        {
+            JogFrame::Base => [x, y],
+            JogFrame::Tool => {
+                // across the gripper, only what roll leaves in the plane
+                let roll = self.joint_map.ticks_to_angle(Joint::Roll, self.updated_state.roll);
+                let across = y * roll.cos();
+                let (sin, cos) = self.si.sin_cos();
+                [x * cos - across * sin, x * sin + across * cos]
+            },
+        }
+    }
+
+    pub fn apply_command(&mut self, command: ArmCommand) -> Result<(), RoboticArmError>
+    {
+        // stopping and modes are up to the control loop, heartbeats do nothing
+        match command
+        {
+            ArmCommand::Jog(data) => self.update_from_data_handler(data),
+            ArmCommand::MoveToPose { x, y, si } => self.move_to_pose(x, y, si),
+            ArmCommand::MoveJoints { joint_angles } => self.move_joints(joint_angles),
+            ArmCommand::Gripper(command) => {
+                self.command_gripper(command);
+                Ok(())
+            },
+            ArmCommand::Home => self.home(),
+            ArmCommand::Heartbeat | ArmCommand::Stop | ArmCommand::SetMode { .. } => Ok(()),
+        }
+    }
+
+    pub fn move_to_pose(&mut self, x: f64, y: f64, si: f64) -> Result<(), RoboticArmError>
+    {
+        let kinematics_result = self.solver.find_joint_angles(x, y, si)
+            .and_then(|joint_angles| self.joint_limits.check(joint_angles).map(|_| joint_angles));
+        self.last_target = [x, y, si];
+        self.last_joint_angles = kinematics_result.as_ref().ok().copied();
 
-        let kinematics_result = self.solver.find_joint_angles(new_x, new_y, new_pitch);
-        
         match kinematics_result
         {
             Ok(joint_angles) => {
                 // update new end effector positions
-                self.x = new_x;
-                self.y = new_y;
-                self.si = new_pitch;
-
-                // convert motor positions to u16 motor values (these will be sent directly to motor controllers)
-                let shoulder_position = (joint_angles[0] * self.joint_map.shoulder as f64/ 2.0 / PI) as u16;
-                let elbow_position = (joint_angles[1] * self.joint_map.elbow as f64 / 2.0 / PI) as u16;
-                let wrist_position = (joint_angles[2] * self.joint_map.wrist as f64 / 2.0 / PI) as u16;
-                
-                // update updated state
-                self.updated_state.update_kinematic_joints(shoulder_position, elbow_position, wrist_position);
-
+                self.x = x;
+                self.y = y;
+                self.si = si;
+                self.set_kinematic_joints(joint_angles);
                 Ok(())
-
             }
-            Err(e) => return Err(RoboticArmError::KinematicJointsNotUpdated("Joints not updated due to singularity".into())),
+            Err(e @ RoboticArmError::JointLimitExceeded { .. }) => Err(e),
+            // keep why the solver could not get there
+            Err(e) => Err(RoboticArmError::KinematicJointsNotUpdated { pose: [x, y, si], source: Box::new(e) }),
         }
     }
 
-    pub fn get_delta_joints(&self) -> ArmState
+    pub fn move_joints(&mut self, joint_angles: [f64; 3]) -> Result<(), RoboticArmError>
     {
-        // return the overall change in the joints
-        self.updated_state - self.initial_state
+        // shoulder, elbow, wrist in radians, the pose follows from forward kinematics
+        if joint_angles.iter().any(|angle| !angle.is_finite())
+        {
+            return Err(RoboticArmError::InvalidTrajectory(format!("Joint angles {joint_angles:?} are not finite")));
+        }
+        self.joint_limits.check(joint_angles)?;
+        let [x, y, si] = self.solver.find_end_effector_position(joint_angles);
+        self.last_target = [x, y, si];
+        self.last_joint_angles = Some(joint_angles);
+        self.x = x;
+        self.y = y;
+        self.si = si;
+        self.set_kinematic_joints(joint_angles);
+        Ok(())
     }
 
-    fn add_value_wrap(&self, curr_value: u16, add: i32, map_value: u16) -> u16
+    pub fn jog_joints(&mut self, counts: [i16; 3]) -> Result<(), RoboticArmError>
     {
-        // this function keeps the motor position between 0-max_encoder_value
-        if add >= 0
+        // shoulder, elbow, wrist counts from the joystick, each joint stops at its limit
+        let mut joint_angles = self.joint_angles;
+        for (angle, count) in joint_angles.iter_mut().zip(counts)
         {
-            
-            let updated_value = curr_value + add as u16;
-            if updated_value >= map_value
-            {
-                return updated_value - map_value
-            } else {
-                return updated_value
-            }
-        } else {
-            let updated_value = (curr_value as i32) + add;
+            *angle += count as f64 * self.jog_gains.joint;
+        }
+        self.move_joints(self.joint_limits.clamp(joint_angles))
+    }
 
-            if updated_value < 0
-            {
-                return (map_value as i32 + updated_value) as u16
-            } else {
-                return updated_value as u16
-            }
+    pub fn home(&mut self) -> Result<(), RoboticArmError>
+    {
+        // back to the starting pose with the roll undone, the gripper is left alone
+        let [x, y, si] = self.home;
+        self.move_to_pose(x, y, si)?;
+        self.updated_state.roll = self.initial_state.roll;
+        Ok(())
+    }
+
+    pub fn restore(&mut self, delta: ArmState, saved: Option<([f64; 3], [f64; 3])>) -> Result<(), RoboticArmError>
+    {
+        // delta is what the motor controllers were sent, like get_delta_joints()
+        // saved is the joint angles and pose that went with it, without it they follow from the ticks
+        let (joint_angles, pose) = saved.unwrap_or_else(|| {
+            let joint_angles = [Joint::Shoulder, Joint::Elbow, Joint::Wrist].map(|joint| self.joint_map.ticks_to_angle(joint, delta.get_joint(joint)));
+            (joint_angles, self.solver.find_end_effector_position(joint_angles))
+        });
+        if joint_angles.iter().chain(pose.iter()).any(|value| !value.is_finite())
+        {
+            return Err(RoboticArmError::InvalidTrajectory(format!("Saved joint angles {joint_angles:?} and pose {pose:?} are not finite")));
         }
+
+        // the arm is there whatever the limits say, refusing would not move it
+        let [x, y, si] = pose;
+        self.x = x;
+        self.y = y;
+        self.si = si;
+        self.joint_angles = joint_angles;
+        self.last_target = pose;
+        self.last_joint_angles = Some(joint_angles);
+        self.roll_residual = 0.0;
+        self.updated_state = delta;
+        self.gripper = Gripper::new(self.gripper.get_config(), self.joint_map.ticks_per_revolution(Joint::Spool), delta.spool);
+        Ok(())
+    }
+
+    pub fn command_gripper(&mut self, command: GripperCommand)
+    {
+        self.updated_state.spool = self.gripper.command(command);
+    }
+
+    pub fn check_grip(&mut self, position: Option<u16>, current: Option<f64>, now: Instant) -> GripState
+    {
+        // spool feedback from the motor controller, a grasp eases the target back to the squeeze
+        let state = self.gripper.check(position, current, now);
+        self.updated_state.spool = self.gripper.get_target();
+        state
+    }
+
+    fn set_kinematic_joints(&mut self, joint_angles: [f64; 3])
+    {
+        self.joint_angles = joint_angles;
+
+        // convert motor positions to u16 motor values (these will be sent directly to motor controllers)
+        let shoulder_position = self.joint_map.angle_to_ticks(Joint::Shoulder, joint_angles[0]);
+        let elbow_position = self.joint_map.angle_to_ticks(Joint::Elbow, joint_angles[1]);
+        let wrist_position = self.joint_map.angle_to_ticks(Joint::Wrist, joint_angles[2]);
+
+        // update updated state
+        self.updated_state.update_kinematic_joints(shoulder_position, elbow_position, wrist_position);
+    }
+
+    pub fn set_jog_gains(&mut self, jog_gains: JogGains)
+    {
+        self.jog_gains = jog_gains;
+    }
+
+    pub fn set_gripper_config(&mut self, config: GripperConfig)
+    {
+        self.gripper = Gripper::new(config, self.joint_map.ticks_per_revolution(Joint::Spool), self.updated_state.spool);
+        self.updated_state.spool = self.gripper.get_target();
+    }
+
+    pub fn set_joint_limits(&mut self, joint_limits: JointLimits)
+    {
+        self.joint_limits = joint_limits;
+    }
+
+    pub fn get_jog_gains(&self) -> JogGains
+    {
+        self.jog_gains
+    }
+
+    pub fn get_gripper(&self) -> &Gripper
+    {
+        &self.gripper
+    }
+
+    pub fn get_joint_limits(&self) -> JointLimits
+    {
+        self.joint_limits
+    }
+
+    pub fn get_home_position(&self) -> [f64; 3]
+    {
+        self.home
+    }
+
+    pub fn get_end_effector_position(&self) -> [f64; 3]
+    {
+        // current [x, y, si] of the end effector
+        [self.x, self.y, self.si]
+    }
+
+    pub fn get_last_target(&self) -> [f64; 3]
+    {
+        // [x, y, si] asked for by the last update, whether it was reachable or not
+        self.last_target
+    }
+
+    pub fn get_joint_angles(&self) -> [f64; 3]
+    {
+        // joint angles the arm is at, whatever happened to the last target
+        self.joint_angles
+    }
+
+    pub fn get_last_joint_angles(&self) -> Option<[f64; 3]>
+    {
+        // joint angles found for the last target, None if the solver rejected it
+        self.last_joint_angles
+    }
+
+    pub fn get_solver(&self) -> &InverseKinematicSolver
+    {
+        &self.solver
+    }
+
+    pub fn get_joint_map(&self) -> &AngleToEncoderMap
+    {
+        &self.joint_map
+    }
+
+    pub fn get_initial_state(&self) -> ArmState
+    {
+        self.initial_state
+    }
+
+    pub fn get_updated_state(&self) -> ArmState
+    {
+        self.updated_state
+    }
+
+    pub fn get_delta_joints(&self) -> ArmState
+    {
+        // what the motor controllers are sent, ticks from where their encoders
+        // read zero (the starting pose, unless the zero offsets are calibrated)
+        self.updated_state
+    }
+
+    fn add_value_wrap(&self, curr_value: u16, add: i64, map_value: u16) -> u16
+    {
+        // this function keeps the motor position between 0-max_encoder_value
+        // however many turns the step is, without overflowing u16
+        (curr_value as i64 + add).rem_euclid(map_value as i64) as u16
     }
 }
 
@@ -264,12 +878,64 @@ mod tests
                                                                                     0.0, map);
         if let Err(e) = robotic_arm
         {
-            assert_eq!(e, RoboticArmError::Singularity("Singularity in theta 2".into()));
+            assert_eq!(e, RoboticArmError::Singularity { joint: Joint::Elbow, pose: [19.0, 0.0, 0.0] });
         } else {
-            assert!(false, "Function call should have errored out");
+            panic!("Function call should have errored out");
+        }
+    }
+
+    #[test]
+    fn test_encoder_conversions()
+    {
+        // rounded, and below zero wraps instead of saturating at 0
+        let tick = 2.0 * PI / 5000.0;
+        let map = AngleToEncoderMap::new(5000, 5000, 5000, 5000, 5000);
+        assert_eq!(map.angle_to_ticks(Joint::Shoulder, 2.6 * tick), 3);
+        assert_eq!(map.angle_to_ticks(Joint::Shoulder, -10.0 * tick), 4990);
+        assert!((map.ticks_to_angle(Joint::Shoulder, 4990) + 10.0 * tick).abs() < 1e-12);
+        assert_eq!(map.tick_distance(Joint::Shoulder, 4999, 0), 1);
+        assert_eq!(map.tick_distance(Joint::Shoulder, 2, 4998), 4);
+        assert_eq!(wrapped_difference(4999, 0, 5000), -1);
+        assert_eq!(wrapped_difference(2600, 0, 5000), -2400);
+
+        // geared 4 to 1, counting backwards from 0.3 rad
+        let encoder = JointEncoder { gear_ratio: 4.0, direction: -1, zero_offset: Some(0.3), backlash: 0.02, ..JointEncoder::new(1000) };
+        assert_eq!(encoder.get_span(), 4000);
+        assert_eq!(encoder.angle_to_ticks(0.4), 4000 - 64);
+        assert!((encoder.ticks_to_angle(encoder.angle_to_ticks(0.4)) - 0.4).abs() < 2.0 * PI / 4000.0);
+
+        // the play is taken up on the side the joint is moving towards
+        assert!((encoder.backlash_correction(1.0) - 0.01).abs() < 1e-12);
+        assert!((encoder.backlash_correction(-1.0) + 0.01).abs() < 1e-12);
+        assert_eq!(encoder.backlash_correction(0.0), 0.0);
+    }
+
+    #[test]
+    fn test_move_below_encoder_zero()
+    {
+        // joints going below where they started wrap round and read back as the same angle
+        let map = AngleToEncoderMap::new(5000, 5000, 5000, 5000, 5000);
+        let mut robotic_arm = RoboticArmSolver::try_new_from_ef_position(10.0, 5.0, 3.0, 12.0, 6.0, 0.5, map).unwrap();
+        let start = robotic_arm.get_joint_angles();
+        robotic_arm.move_to_pose(13.0, 6.0, 0.5).unwrap();
+
+        let joint_map = robotic_arm.get_joint_map();
+        let delta = robotic_arm.get_delta_joints();
+        let joint_angles = robotic_arm.get_joint_angles();
+        assert!(joint_angles.iter().zip(start).any(|(angle, start)| *angle < start));
+        for (joint, angle) in [Joint::Shoulder, Joint::Elbow, Joint::Wrist].into_iter().zip(joint_angles)
+        {
+            assert!((joint_map.ticks_to_angle(joint, delta.get_joint(joint)) - angle).abs() < 2.0 * PI / 5000.0);
         }
     }
 
+    fn singularity_refusal(robotic_arm: &RoboticArmSolver) -> RoboticArmError
+    {
+        // a move to the last target refused because the elbow can not reach it
+        let pose = robotic_arm.get_last_target();
+        RoboticArmError::KinematicJointsNotUpdated { pose, source: Box::new(RoboticArmError::Singularity { joint: Joint::Elbow, pose }) }
+    }
+
     #[test]
     fn test_moving_ef_out_of_workspace()
     {
@@ -286,9 +952,9 @@ mod tests
         // data should make EF move out of workspace
         if let Err(e) = update_status
         {
-            assert_eq!(e, RoboticArmError::KinematicJointsNotUpdated("Joints not updated due to singularity".into()));
+            assert_eq!(e, singularity_refusal(&robotic_arm));
         } else {
-            assert!(false, "EF was able to move out of workspace")
+            panic!("EF was able to move out of workspace")
         }
 
         let joint_state = robotic_arm.get_delta_joints();
@@ -309,15 +975,15 @@ mod tests
                                                                     18.0, 0.0, 
                                                                     0.0, map).unwrap();
 
-        let mut update_status: Result<(), RoboticArmError> = robotic_arm.update_from_data_handler(data.clone());
-        update_status = robotic_arm.update_from_data_handler(data);
+        let _ = robotic_arm.update_from_data_handler(data);
+        let update_status: Result<(), RoboticArmError> = robotic_arm.update_from_data_handler(data);
         
         // data should make EF move out of workspace
         if let Err(e) = update_status
         {
-            assert_eq!(e, RoboticArmError::KinematicJointsNotUpdated("Joints not updated due to singularity".into()));
+            assert_eq!(e, singularity_refusal(&robotic_arm));
         } else {
-            assert!(false, "EF was able to move out of workspace")
+            panic!("EF was able to move out of workspace")
         }
 
         let joint_state = robotic_arm.get_delta_joints();
@@ -345,9 +1011,9 @@ mod tests
         // data should make EF move out of workspace
         if let Err(e) = update_status
         {
-            assert_eq!(e, RoboticArmError::KinematicJointsNotUpdated("Joints not updated due to singularity".into()));
+            assert_eq!(e, singularity_refusal(&robotic_arm));
         } else {
-            assert!(false, "EF was able to move out of workspace")
+            panic!("EF was able to move out of workspace")
         }
 
         // Arm state should be updated
@@ -412,4 +1078,210 @@ mod tests
 
         assert_eq!(robotic_arm.add_value_wrap(2, -2, 5000), 0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_multi_turn_wrap()
+    {
+        // steps of more than a turn wrap all the way, sums past u16::MAX do not overflow
+        let map = AngleToEncoderMap::new(5000, 5000, 5000, 5000, 5000);
+        let robotic_arm = RoboticArmSolver::try_new_from_ef_position(10.0, 
+            5.0, 
+            3.0, 
+            18.0, 0.0, 
+            0.0, map).expect("Contructor did not work");
+
+        assert_eq!(robotic_arm.add_value_wrap(10, 3 * 5000 + 5, 5000), 15);
+        assert_eq!(robotic_arm.add_value_wrap(10, -2 * 5000 - 11, 5000), 4999);
+        assert_eq!(robotic_arm.add_value_wrap(8000, 60000, 8192), 2464);
+        assert_eq!(robotic_arm.add_value_wrap(0, i16::MIN as i64 * i32::MAX as i64, 8192), 0);
+    }
+
+    #[test]
+    fn test_move_to_pose_and_home()
+    {
+        let map = AngleToEncoderMap::new(5000, 5000, 5000, 5000, 5000);
+        let mut robotic_arm = RoboticArmSolver::try_new_from_ef_position(10.0, 5.0, 3.0, 12.0, 6.0, 0.5, map).unwrap();
+
+        robotic_arm.move_to_pose(10.0, 4.0, 0.2).unwrap();
+        assert_eq!(robotic_arm.get_end_effector_position(), [10.0, 4.0, 0.2]);
+        assert_ne!(robotic_arm.get_updated_state(), robotic_arm.get_initial_state());
+
+        // out of reach leaves the arm where it was
+        assert!(robotic_arm.move_to_pose(30.0, 0.0, 0.0).is_err());
+        assert_eq!(robotic_arm.get_end_effector_position(), [10.0, 4.0, 0.2]);
+
+        robotic_arm.home().unwrap();
+        assert_eq!(robotic_arm.get_end_effector_position(), robotic_arm.get_home_position());
+        assert_eq!(robotic_arm.get_updated_state(), robotic_arm.get_initial_state());
+    }
+
+    #[test]
+    fn test_move_joints()
+    {
+        // joint moves go through forward kinematics, and back again with IK
+        let map = AngleToEncoderMap::new(5000, 5000, 5000, 5000, 5000);
+        let mut robotic_arm = RoboticArmSolver::try_new_from_ef_position(10.0, 5.0, 3.0, 12.0, 6.0, 0.5, map).unwrap();
+        let joint_angles = robotic_arm.get_last_joint_angles().unwrap();
+        robotic_arm.move_to_pose(10.0, 4.0, 0.2).unwrap();
+
+        robotic_arm.move_joints(joint_angles).unwrap();
+        let [x, y, si] = robotic_arm.get_end_effector_position();
+        assert!((x - 12.0).abs() < 1e-9 && (y - 6.0).abs() < 1e-9 && (si - 0.5).abs() < 1e-9);
+        assert_eq!(robotic_arm.get_delta_joints(), ArmState::new(0, 0, 0, 0, 0));
+        assert!(robotic_arm.move_joints([f64::NAN, 0.0, 0.0]).is_err());
+    }
+
+    #[test]
+    fn test_command_gripper()
+    {
+        let map = AngleToEncoderMap::new(5000, 5000, 5000, 5000, 5000);
+        let mut robotic_arm = RoboticArmSolver::try_new_from_ef_position(10.0, 5.0, 3.0, 12.0, 6.0, 0.5, map).unwrap();
+
+        robotic_arm.command_gripper(GripperCommand::Position(1.0));
+        assert_eq!(robotic_arm.get_updated_state().spool, 4999);
+        robotic_arm.command_gripper(GripperCommand::Position(-3.0));
+        assert_eq!(robotic_arm.get_updated_state().spool, 0);
+
+        // closing past closed does not wrap round to open
+        let close = DataHandler::new(0, 0, 0, 0, 0, 1);
+        assert!(!robotic_arm.jog_gripper(close));
+        assert_eq!(robotic_arm.get_updated_state().spool, 0);
+    }
+
+    #[test]
+    fn test_apply_command()
+    {
+        // every command ends up in the same place as calling the solver directly
+        let map = AngleToEncoderMap::new(5000, 5000, 5000, 5000, 5000);
+        let mut robotic_arm = RoboticArmSolver::try_new_from_ef_position(10.0, 5.0, 3.0, 12.0, 6.0, 0.5, map).unwrap();
+
+        robotic_arm.apply_command(ArmCommand::MoveToPose { x: 10.0, y: 4.0, si: 0.2 }).unwrap();
+        assert_eq!(robotic_arm.get_end_effector_position(), [10.0, 4.0, 0.2]);
+        robotic_arm.apply_command(ArmCommand::Jog(DataHandler::new(1, 0, 0, 0, 1, 1))).unwrap();
+        assert_eq!(robotic_arm.get_end_effector_position(), [11.0, 4.0, 0.2]);
+        robotic_arm.apply_command(ArmCommand::Stop).unwrap();
+        robotic_arm.apply_command(ArmCommand::Home).unwrap();
+        assert_eq!(robotic_arm.get_end_effector_position(), [12.0, 6.0, 0.5]);
+        assert!(robotic_arm.apply_command(ArmCommand::MoveToPose { x: 40.0, y: 0.0, si: 0.0 }).is_err());
+    }
+
+    #[test]
+    fn test_jog_gains()
+    {
+        // half a mm per count, pitch a tenth of a radian per count
+        let map = AngleToEncoderMap::new(5000, 5000, 5000, 5000, 5000);
+        let mut robotic_arm = RoboticArmSolver::try_new_from_ef_position(10.0, 5.0, 3.0, 12.0, 6.0, 0.5, map).unwrap();
+        robotic_arm.set_jog_gains(JogGains { linear: 0.5, pitch: 0.1, roll: 3, ..JogGains::default() });
+
+        robotic_arm.update_from_data_handler(DataHandler::new(-2, 0, 2, 1, 1, 1)).unwrap();
+        let [x, y, si] = robotic_arm.get_end_effector_position();
+        assert_eq!([x, y], [11.0, 6.0]);
+        assert!((si - 0.6).abs() < 1e-9);
+        assert_eq!(robotic_arm.get_updated_state().roll, 6);
+    }
+
+    #[test]
+    fn test_joint_limits()
+    {
+        // a reachable pose that needs the shoulder past its limit is refused
+        let map = AngleToEncoderMap::new(5000, 5000, 5000, 5000, 5000);
+        let mut robotic_arm = RoboticArmSolver::try_new_from_ef_position(10.0, 5.0, 3.0, 12.0, 6.0, 0.5, map).unwrap();
+        let [shoulder, _, _] = robotic_arm.get_last_joint_angles().unwrap();
+        robotic_arm.set_joint_limits(JointLimits { shoulder: [shoulder - 0.01, shoulder + 0.01], ..JointLimits::default() });
+
+        let result = robotic_arm.move_to_pose(0.0, 12.0, 1.5);
+        assert!(matches!(result, Err(RoboticArmError::JointLimitExceeded { joint: Joint::Shoulder, limits, .. }) if limits == [shoulder - 0.01, shoulder + 0.01]));
+        assert_eq!(robotic_arm.get_end_effector_position(), [12.0, 6.0, 0.5]);
+        assert_eq!(robotic_arm.get_last_joint_angles(), None);
+        assert!(robotic_arm.move_joints([shoulder + 0.5, 0.0, 0.0]).is_err());
+    }
+
+    #[test]
+    fn test_joint_jog()
+    {
+        // joints move directly and the pose follows by forward kinematics
+        let map = AngleToEncoderMap::new(5000, 5000, 5000, 5000, 5000);
+        let mut robotic_arm = RoboticArmSolver::try_new_from_ef_position(10.0, 5.0, 3.0, 12.0, 6.0, 0.5, map).unwrap();
+        let [shoulder, elbow, wrist] = robotic_arm.get_joint_angles();
+        let mut data = DataHandler::new(2, -1, 0, 0, 1, 1);
+        data.joint_jog = true;
+
+        robotic_arm.update_from_data_handler(data).unwrap();
+        let joint_angles = robotic_arm.get_joint_angles();
+        assert!((joint_angles[0] - (shoulder + 0.02)).abs() < 1e-12);
+        assert!((joint_angles[1] - (elbow - 0.01)).abs() < 1e-12);
+        assert_eq!(joint_angles[2], wrist);
+        assert_eq!(robotic_arm.get_end_effector_position(), robotic_arm.get_solver().find_end_effector_position(joint_angles));
+
+        // each joint stops at its limit
+        robotic_arm.set_joint_limits(JointLimits { shoulder: [-1.0, joint_angles[0] + 0.01], ..JointLimits::default() });
+        robotic_arm.jog_joints([5, 0, 0]).unwrap();
+        assert_eq!(robotic_arm.get_joint_angles()[0], joint_angles[0] + 0.01);
+    }
+
+    #[test]
+    fn test_joint_jog_out_of_singularity()
+    {
+        // fully stretched out, a cartesian jog can not move but a joint jog can
+        let map = AngleToEncoderMap::new(5000, 5000, 5000, 5000, 5000);
+        let mut robotic_arm = RoboticArmSolver::try_new_from_ef_position(10.0, 5.0, 3.0, 18.0, 0.0, 0.0, map).unwrap();
+        assert!(robotic_arm.update_from_data_handler(DataHandler::new(1, 0, 0, 0, 1, 1)).is_err());
+
+        let mut data = DataHandler::new(0, 3, 0, 0, 1, 1);
+        data.joint_jog = true;
+        robotic_arm.update_from_data_handler(data).unwrap();
+        let [x, _, _] = robotic_arm.get_end_effector_position();
+        assert!(x < 18.0);
+        assert!(robotic_arm.move_to_pose(x - 0.5, 0.0, 0.0).is_ok());
+    }
+
+    #[test]
+    fn test_tool_frame_jog()
+    {
+        // pointing straight up (si = pi/2), forward on the stick is +y in the base frame
+        let map = AngleToEncoderMap::new(5000, 5000, 5000, 4000, 5000);
+        let mut robotic_arm = RoboticArmSolver::try_new_from_ef_position(10.0, 5.0, 3.0, 12.0, 6.0, PI / 2.0, map).unwrap();
+        robotic_arm.set_jog_gains(JogGains { frame: JogFrame::Tool, ..JogGains::default() });
+
+        let [x, y] = robotic_arm.jog_to_base(1.0, 0.0);
+        assert!(x.abs() < 1e-12 && (y - 1.0).abs() < 1e-12);
+        let [x, y] = robotic_arm.jog_to_base(0.0, 1.0);
+        assert!((x + 1.0).abs() < 1e-12 && y.abs() < 1e-12);
+
+        robotic_arm.update_from_data_handler(DataHandler::new(-1, 0, 0, 0, 1, 1)).unwrap();
+        let [x, y, _] = robotic_arm.get_end_effector_position();
+        assert!((x - 12.0).abs() < 1e-9 && (y - 5.0).abs() < 1e-9);
+
+        // rolled a quarter turn, across the gripper is out of the plane
+        robotic_arm.update_from_data_handler(DataHandler::new(0, 0, 1000, 0, 1, 1)).unwrap();
+        let [x, y] = robotic_arm.jog_to_base(0.0, 1.0);
+        assert!(x.abs() < 1e-12 && y.abs() < 1e-12);
+
+        // the base frame ignores all of it
+        robotic_arm.set_jog_gains(JogGains::default());
+        assert_eq!(robotic_arm.jog_to_base(1.0, 2.0), [1.0, 2.0]);
+    }
+
+    #[test]
+    fn test_restore()
+    {
+        // back where the last run left the motor controllers
+        let map = AngleToEncoderMap::new(5000, 5000, 5000, 5000, 5000);
+        let mut robotic_arm = RoboticArmSolver::try_new_from_ef_position(10.0, 5.0, 3.0, 12.0, 6.0, 0.5, map).unwrap();
+        let mut moved = robotic_arm.clone();
+        moved.move_to_pose(11.0, 6.0, 0.5).unwrap();
+        moved.command_gripper(GripperCommand::Position(0.5));
+
+        let saved = (moved.get_joint_angles(), moved.get_end_effector_position());
+        robotic_arm.restore(moved.get_delta_joints(), Some(saved)).unwrap();
+        assert_eq!(robotic_arm.get_updated_state(), moved.get_updated_state());
+        assert_eq!(robotic_arm.get_end_effector_position(), [11.0, 6.0, 0.5]);
+        assert_eq!(robotic_arm.get_gripper().get_target(), 2500);
+
+        // from the ticks alone, the pose follows from them
+        robotic_arm.restore(moved.get_delta_joints(), None).unwrap();
+        assert_eq!(robotic_arm.get_updated_state(), moved.get_updated_state());
+        let pose = robotic_arm.get_solver().find_end_effector_position(robotic_arm.get_joint_angles());
+        assert_eq!(robotic_arm.get_end_effector_position(), pose);
+    }
+}
@@ -0,0 +1,172 @@
+/*
+William Albertini
+
+CommandWatchdog keeps track of whether the operator is
+still there. Every packet from the controller (joystick
+input or heartbeat) feeds it. If nothing arrives for the
+timeout, check() trips it: the control loop commands the
+motors to hold and ignores joystick input until the
+watchdog is re-armed.
+
+Re-arming is deliberate, it is not enough for packets to
+start arriving again. rearm() only succeeds while the link
+is alive (a packet inside the timeout), so the operator
+has to be back before the arm will move. trip() puts the
+watchdog in the tripped state on request (an operator
+asking for hold).
+
+All methods take the current time so the watchdog can be
+tested without sleeping.
+
+*/
+
+// external imports
+use std::time::{Duration, Instant};
+
+// internal imports
+use crate::arm_errors::RoboticArmError;
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WatchdogState
+{
+    // commands are accepted
+    Armed,
+    // link was lost, holding until re-armed
+    Tripped,
+}
+
+
+#[derive(Copy, Clone, Debug)]
+pub struct CommandWatchdog
+{
+    timeout: Duration,
+    last_packet: Instant,
+    state: WatchdogState,
+}
+
+
+impl CommandWatchdog
+{
+    pub fn new(timeout: Duration, now: Instant) -> CommandWatchdog
+    {
+        // the clock starts at boot, a controller that never shows up trips it too
+        CommandWatchdog { timeout, last_packet: now, state: WatchdogState::Armed }
+    }
+
+    pub fn feed(&mut self, now: Instant)
+    {
+        self.last_packet = now;
+    }
+
+    pub fn check(&mut self, now: Instant) -> bool
+    {
+        // true only on the cycle the watchdog trips
+        if self.state == WatchdogState::Armed && !self.link_alive(now)
+        {
+            self.state = WatchdogState::Tripped;
+            return true;
+        }
+        false
+    }
+
+    pub fn trip(&mut self)
+    {
+        self.state = WatchdogState::Tripped;
+    }
+
+    pub fn rearm(&mut self, now: Instant) -> Result<(), RoboticArmError>
+    {
+        if !self.link_alive(now)
+        {
+            return Err(RoboticArmError::NetworkError { context: format!("No packets for {:?}, can not re-arm", self.silence(now)), source: None });
+        }
+        self.state = WatchdogState::Armed;
+        Ok(())
+    }
+
+    pub fn link_alive(&self, now: Instant) -> bool
+    {
+        self.silence(now) <= self.timeout
+    }
+
+    pub fn silence(&self, now: Instant) -> Duration
+    {
+        now.saturating_duration_since(self.last_packet)
+    }
+
+    pub fn is_armed(&self) -> bool
+    {
+        self.state == WatchdogState::Armed
+    }
+
+    pub fn get_state(&self) -> WatchdogState
+    {
+        self.state
+    }
+
+    pub fn get_timeout(&self) -> Duration
+    {
+        self.timeout
+    }
+}
+
+
+
+
+// ------------------------------- unit tests -------------------------------------
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(500);
+
+    #[test]
+    fn test_fed_watchdog_stays_armed()
+    {
+        let start = Instant::now();
+        let mut watchdog = CommandWatchdog::new(TIMEOUT, start);
+        for i in 1..20
+        {
+            let now = start + Duration::from_millis(100 * i);
+            watchdog.feed(now);
+            assert!(!watchdog.check(now));
+        }
+        assert!(watchdog.is_armed());
+    }
+
+    #[test]
+    fn test_trips_once_after_timeout()
+    {
+        let start = Instant::now();
+        let mut watchdog = CommandWatchdog::new(TIMEOUT, start);
+
+        assert!(!watchdog.check(start + TIMEOUT));
+        assert!(watchdog.check(start + TIMEOUT + Duration::from_millis(1)));
+        // already tripped, nothing new to report
+        assert!(!watchdog.check(start + 2 * TIMEOUT));
+        assert_eq!(watchdog.get_state(), WatchdogState::Tripped);
+    }
+
+    #[test]
+    fn test_packets_alone_do_not_rearm()
+    {
+        let start = Instant::now();
+        let mut watchdog = CommandWatchdog::new(TIMEOUT, start);
+        let later = start + 2 * TIMEOUT;
+        watchdog.check(later);
+
+        // the link coming back is not enough
+        watchdog.feed(later);
+        assert!(!watchdog.check(later));
+        assert!(!watchdog.is_armed());
+
+        // re-arming needs the link to be alive
+        let much_later = later + 2 * TIMEOUT;
+        assert!(watchdog.rearm(much_later).is_err());
+        watchdog.feed(much_later);
+        assert!(watchdog.rearm(much_later).is_ok());
+        assert!(watchdog.is_armed());
+    }
+}
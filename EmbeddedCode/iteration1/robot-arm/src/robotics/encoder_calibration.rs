@@ -0,0 +1,219 @@
+/*
+William Albertini
+
+Fits the encoder zero offsets (JointEncoder::zero_offset in
+arm_state.rs) from poses the arm was measured at. Each
+CalibrationSample is the [x, y, si] the end effector was
+measured at (a jig, or a tape measure and a protractor) and
+the encoder ticks read back there. The solver gives the joint
+angles for the pose, and with the gear ratio and direction
+from the map each sample says where that joint's encoder zero
+is. The offset is the mean over the samples (taken round the
+circle, so offsets near half a turn do not average out to
+zero), and the rms residual says how well the samples agree.
+A large one is a bad measurement, or a wrong gear ratio or
+direction.
+
+The solver picks the same elbow branch as it does when
+driving the arm, so the samples have to be taken with the arm
+in that configuration. Samples are read from a TOML file by
+the calibrate_encoders binary:
+
+    [[sample]]
+    pose = [1500.0, 200.0, 0.0]
+    ticks = { shoulder = 120, elbow = 4870, wrist = 35, roll = 0, spool = 0 }
+
+*/
+
+// external imports
+use std::f64::consts::PI;
+use std::fs;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+
+// internal imports
+use crate::arm_errors::RoboticArmError;
+use super::arm_kinematics::InverseKinematicSolver;
+use super::arm_state::{AngleToEncoderMap, ArmState, Joint};
+
+const KINEMATIC_JOINTS: [Joint; 3] = [Joint::Shoulder, Joint::Elbow, Joint::Wrist];
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CalibrationSample
+{
+    // measured [x, y, si] of the end effector
+    pub pose: [f64; 3],
+    // encoder readback at that pose
+    pub ticks: ArmState,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SampleFile
+{
+    sample: Vec<CalibrationSample>,
+}
+
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CalibrationFit
+{
+    // the map that was fitted with its zero offsets set
+    pub joint_map: AngleToEncoderMap,
+    // shoulder, elbow, wrist (radians)
+    pub zero_offsets: [f64; 3],
+    pub residuals: [f64; 3],
+}
+
+
+pub fn load_samples(path: &Path) -> Result<Vec<CalibrationSample>, RoboticArmError>
+{
+    let text = fs::read_to_string(path)
+        .map_err(|e| RoboticArmError::file(format!("Could not read {}", path.display()), e))?;
+    toml::from_str::<SampleFile>(&text)
+        .map(|file| file.sample)
+        .map_err(|e| RoboticArmError::file(format!("Bad calibration samples {}", path.display()), e))
+}
+
+pub fn fit_zero_offsets(solver: &InverseKinematicSolver,
+                        joint_map: &AngleToEncoderMap,
+                        samples: &[CalibrationSample]) -> Result<CalibrationFit, RoboticArmError>
+{
+    if samples.is_empty()
+    {
+        return Err(RoboticArmError::ConfigError { context: "No calibration samples to fit the encoders to".into(), source: None });
+    }
+
+    // for every sample and joint, the joint angle where the encoder would read zero
+    let mut candidates = Vec::with_capacity(samples.len());
+    for (number, sample) in samples.iter().enumerate()
+    {
+        let [x, y, si] = sample.pose;
+        let joint_angles = solver.find_joint_angles(x, y, si)
+            .map_err(|e| RoboticArmError::config(format!("Calibration sample {} at {:?} is not reachable", number + 1, sample.pose), e))?;
+        candidates.push(std::array::from_fn::<f64, 3, _>(|index| {
+            let joint = KINEMATIC_JOINTS[index];
+            let mut encoder = *joint_map.get_encoder(joint);
+            encoder.zero_offset = Some(0.0);
+            joint_angles[index] - encoder.ticks_to_angle(sample.ticks.get_joint(joint))
+        }));
+    }
+
+    let mut fitted_map = *joint_map;
+    let mut zero_offsets = [0.0; 3];
+    let mut residuals = [0.0; 3];
+    for (index, joint) in KINEMATIC_JOINTS.into_iter().enumerate()
+    {
+        // mean round the circle, then how far each sample is from it
+        let (sin, cos) = candidates.iter()
+            .map(|candidate| candidate[index].sin_cos())
+            .fold((0.0, 0.0), |(sin, cos), (s, c)| (sin + s, cos + c));
+        let offset = sin.atan2(cos);
+        let squares: f64 = candidates.iter()
+            .map(|candidate| wrap_angle(candidate[index] - offset).powi(2))
+            .sum();
+
+        zero_offsets[index] = offset;
+        residuals[index] = (squares / candidates.len() as f64).sqrt();
+        let mut encoder = *joint_map.get_encoder(joint);
+        encoder.zero_offset = Some(offset);
+        fitted_map.set_encoder(joint, encoder);
+    }
+
+    Ok(CalibrationFit { joint_map: fitted_map, zero_offsets, residuals })
+}
+
+fn wrap_angle(angle: f64) -> f64
+{
+    // into -pi to pi
+    (angle + PI).rem_euclid(2.0 * PI) - PI
+}
+
+
+
+
+// ------------------------------- unit tests -------------------------------------
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::robotics::arm_state::JointEncoder;
+
+    const POSES: [[f64; 3]; 4] = [[12.0, 6.0, 0.5], [10.0, 4.0, 0.2], [8.0, 9.0, 1.0], [14.0, 2.0, -0.3]];
+
+    fn true_map() -> AngleToEncoderMap
+    {
+        // geared, one joint counting backwards, offsets either side of zero and near half a turn
+        let mut joint_map = AngleToEncoderMap::new(5000, 5000, 5000, 5000, 5000);
+        joint_map.set_encoder(Joint::Shoulder, JointEncoder { gear_ratio: 1.5, zero_offset: Some(0.2), ..JointEncoder::new(4000) });
+        joint_map.set_encoder(Joint::Elbow, JointEncoder { direction: -1, zero_offset: Some(-0.4), ..JointEncoder::new(5000) });
+        joint_map.set_encoder(Joint::Wrist, JointEncoder { zero_offset: Some(3.1), ..JointEncoder::new(5000) });
+        joint_map
+    }
+
+    fn samples(solver: &InverseKinematicSolver) -> Vec<CalibrationSample>
+    {
+        let joint_map = true_map();
+        POSES.iter().map(|pose| {
+            let joint_angles = solver.find_joint_angles(pose[0], pose[1], pose[2]).unwrap();
+            let ticks = std::array::from_fn(|index| match index
+            {
+                0..=2 => joint_map.angle_to_ticks(KINEMATIC_JOINTS[index], joint_angles[index]),
+                _ => 0,
+            });
+            CalibrationSample { pose: *pose, ticks: ArmState::from_array(ticks) }
+        }).collect()
+    }
+
+    #[test]
+    fn test_fits_offsets()
+    {
+        // the map to fit only knows the gearing and direction
+        let solver = InverseKinematicSolver::new(10.0, 5.0, 3.0);
+        let mut joint_map = true_map();
+        for joint in KINEMATIC_JOINTS
+        {
+            joint_map.set_encoder(joint, JointEncoder { zero_offset: None, ..*joint_map.get_encoder(joint) });
+        }
+
+        let fit = fit_zero_offsets(&solver, &joint_map, &samples(&solver)).unwrap();
+        for (fitted, expected) in fit.zero_offsets.iter().zip([0.2, -0.4, 3.1])
+        {
+            assert!(wrap_angle(fitted - expected).abs() < 1e-3, "fitted {fitted}, expected {expected}");
+        }
+        assert!(fit.residuals.iter().all(|residual| *residual < 1e-3));
+
+        // and the fitted map reads the samples back as their poses
+        for sample in samples(&solver)
+        {
+            let joint_angles = KINEMATIC_JOINTS.map(|joint| fit.joint_map.ticks_to_angle(joint, sample.ticks.get_joint(joint)));
+            let [x, y, si] = solver.find_end_effector_position(joint_angles);
+            assert!((x - sample.pose[0]).abs() < 0.05 && (y - sample.pose[1]).abs() < 0.05 && wrap_angle(si - sample.pose[2]).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_bad_sample_shows_in_residual()
+    {
+        let solver = InverseKinematicSolver::new(10.0, 5.0, 3.0);
+        let mut samples = samples(&solver);
+        samples[1].ticks.elbow = samples[1].ticks.elbow.wrapping_add(400);
+
+        let fit = fit_zero_offsets(&solver, &true_map(), &samples).unwrap();
+        assert!(fit.residuals[1] > 0.1);
+        assert!(fit.residuals[0] < 1e-3);
+    }
+
+    #[test]
+    fn test_unusable_samples()
+    {
+        let solver = InverseKinematicSolver::new(10.0, 5.0, 3.0);
+        assert!(fit_zero_offsets(&solver, &true_map(), &[]).is_err());
+
+        let far = CalibrationSample { pose: [40.0, 0.0, 0.0], ticks: ArmState::from_array([0; 5]) };
+        let error = fit_zero_offsets(&solver, &true_map(), &[far]).err().unwrap();
+        assert!(error.to_string().contains("sample 1"));
+    }
+}
@@ -0,0 +1,380 @@
+/*
+William Albertini
+
+Gripper is the tendon gripper on the spool motor. The spool
+is only ever sent between the closed and open positions (encoder
+ticks, either way round), it never wraps like the roll does,
+a wrapped tendon gripper would go from closed to wide open in
+one step.
+
+Commands (GripperCommand) are separate from the jog axes:
+
+    {"gripper": {"position": 0.5}}     0 closed to 1 open
+    {"gripper": {"preset": "pinch"}}   open, closed or pinch
+
+and the joystick buttons nudge the spool by the jog gain
+while held (jog()).
+
+Grasp detection (check()) uses feedback from the motor
+controller. The spool encoder wraps like the others, so each
+reading is taken the short way round from the last one
+(position()): one tick past closed at 0 is -1, not 4999.
+That needs a reading every control cycle, the spool moves
+far less than half a turn in one.
+While the spool is short of its target it is
+Moving, until either
+
+    - the motor current reaches grasp_current (amps), or
+    - the encoder has not moved more than stall_ticks in
+      stall_ms
+
+and then it is Grasped if it was closing (something is in
+the fingers) or Stalled if it was opening. On a grasp the
+target is pulled back to squeeze ticks past where the
+fingers stopped, so the motor holds the object with a set
+grip instead of driving flat out at the closed position.
+
+Only the simulator has feedback so far, the SPI motor
+controllers (RobotDriver) have no readback. Without it there
+is nothing to detect: the spool is only Sent, never Reached,
+Grasped or Stalled, so nothing downstream takes an
+unconfirmed grip for a real one.
+
+*/
+
+// external imports
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+
+// internal imports
+use super::arm_state::wrapped_difference;
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GripperPreset
+{
+    Open,
+    Closed,
+    Pinch,
+}
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GripperCommand
+{
+    // 0 closed, 1 open
+    Position(f64),
+    Preset(GripperPreset),
+}
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GripState
+{
+    // on the way to the target
+    Moving,
+    // at the target, nothing in the way
+    Reached,
+    // stopped short while closing, holding something
+    Grasped,
+    // stopped short while opening
+    Stalled,
+    // sent to the target, no feedback to say where it got to
+    Sent,
+}
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GripperConfig
+{
+    // spool ticks, open can be above or below closed
+    pub open: u16,
+    pub closed: u16,
+    pub pinch: u16,
+    // this close to the target (ticks) is there
+    pub tolerance: u16,
+    // moving less than stall_ticks in stall_ms is a stall
+    pub stall_ticks: u16,
+    pub stall_ms: u64,
+    // motor current (amps) that means the fingers are on something, inf never does
+    pub grasp_current: f64,
+    // ticks past the contact point to hold a grasp with
+    pub squeeze: u16,
+}
+
+impl Default for GripperConfig
+{
+    fn default() -> GripperConfig
+    {
+        // a 5000 tick spool, closed at start up and one turn to fully open
+        GripperConfig
+        {
+            open: 4999,
+            closed: 0,
+            pinch: 1500,
+            tolerance: 4,
+            stall_ticks: 2,
+            stall_ms: 250,
+            grasp_current: f64::INFINITY,
+            squeeze: 40,
+        }
+    }
+}
+
+impl GripperConfig
+{
+    pub fn get_preset(&self, preset: GripperPreset) -> u16
+    {
+        match preset
+        {
+            GripperPreset::Open => self.open,
+            GripperPreset::Closed => self.closed,
+            GripperPreset::Pinch => self.pinch,
+        }
+    }
+
+    pub fn clamp(&self, ticks: i32) -> u16
+    {
+        // anything outside closed..open is stopped at the end
+        let (min, max) = (self.open.min(self.closed), self.open.max(self.closed));
+        ticks.clamp(min as i32, max as i32) as u16
+    }
+}
+
+
+#[derive(Clone, Debug)]
+pub struct Gripper
+{
+    config: GripperConfig,
+    // encoder ticks in one turn of the spool
+    span: u16,
+    // spool ticks the motor is sent to
+    target: u16,
+    state: GripState,
+    // last reading, unwrapped so it can go below 0 or past a turn
+    position: i32,
+    // where the spool last moved and when, for stall detection
+    last_motion: Option<(i32, Instant)>,
+}
+
+impl Gripper
+{
+    pub fn new(config: GripperConfig, span: u16, position: u16) -> Gripper
+    {
+        // position is the spool now, pulled inside the range if it is not
+        Gripper
+        {
+            config,
+            span,
+            target: config.clamp(position as i32),
+            state: GripState::Reached,
+            position: position as i32,
+            last_motion: None,
+        }
+    }
+
+    pub fn command(&mut self, command: GripperCommand) -> u16
+    {
+        let target = match command
+        {
+            GripperCommand::Position(position) => {
+                let [closed, open] = [self.config.closed, self.config.open].map(f64::from);
+                let position = if position.is_nan() { 0.0 } else { position.clamp(0.0, 1.0) };
+                (closed + position * (open - closed)).round() as i32
+            },
+            GripperCommand::Preset(preset) => self.config.get_preset(preset) as i32,
+        };
+        self.set_target(target)
+    }
+
+    pub fn jog(&mut self, ticks: i32) -> u16
+    {
+        // positive ticks open, whichever way round the spool is
+        let towards_open = if self.config.open >= self.config.closed { ticks } else { -ticks };
+        self.set_target(self.target as i32 + towards_open)
+    }
+
+    pub fn check(&mut self, position: Option<u16>, current: Option<f64>, now: Instant) -> GripState
+    {
+        // position and current of the spool motor, returns the state (also get_state())
+        let position = position.map(|reading| self.position(reading));
+        if matches!(self.state, GripState::Grasped | GripState::Stalled | GripState::Reached | GripState::Sent)
+        {
+            return self.state;
+        }
+        let Some(position) = position else {
+            self.state = GripState::Sent;
+            return self.state;
+        };
+        let target = self.target as i32;
+        if position.abs_diff(target) <= self.config.tolerance as u32
+        {
+            self.state = GripState::Reached;
+            return self.state;
+        }
+
+        let (last_position, last_time) = *self.last_motion.get_or_insert((position, now));
+        let moved = position.abs_diff(last_position) > self.config.stall_ticks as u32;
+        if moved
+        {
+            self.last_motion = Some((position, now));
+        }
+        let stalled = !moved && now.saturating_duration_since(last_time) >= Duration::from_millis(self.config.stall_ms);
+        let overloaded = current.is_some_and(|current| current >= self.config.grasp_current);
+        if !stalled && !overloaded
+        {
+            return self.state;
+        }
+
+        let closed = self.config.closed as i32;
+        let closing = target.abs_diff(closed) < position.abs_diff(closed);
+        if closing
+        {
+            // hold with a set squeeze instead of driving on to the closed position
+            let squeeze = self.config.squeeze as i32;
+            let towards_closed = if closed >= position { squeeze } else { -squeeze };
+            self.target = self.config.clamp(position + towards_closed);
+            self.state = GripState::Grasped;
+        } else {
+            self.target = self.config.clamp(position);
+            self.state = GripState::Stalled;
+        }
+        self.state
+    }
+
+    fn position(&mut self, reading: u16) -> i32
+    {
+        // the short way round from the last reading, readings come every cycle
+        self.position += wrapped_difference(reading, self.position.rem_euclid(self.span.max(1) as i32) as u16, self.span);
+        self.position
+    }
+
+    fn set_target(&mut self, target: i32) -> u16
+    {
+        let target = self.config.clamp(target);
+        if target != self.target
+        {
+            self.target = target;
+            self.state = GripState::Moving;
+            self.last_motion = None;
+        }
+        self.target
+    }
+
+    pub fn get_target(&self) -> u16
+    {
+        self.target
+    }
+
+    pub fn get_state(&self) -> GripState
+    {
+        self.state
+    }
+
+    pub fn get_config(&self) -> GripperConfig
+    {
+        self.config
+    }
+}
+
+
+
+
+// ------------------------------- unit tests -------------------------------------
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_bounded_and_presets()
+    {
+        // the spool stops at the ends instead of wrapping
+        let mut gripper = Gripper::new(GripperConfig::default(), 5000, 0);
+        assert_eq!(gripper.jog(-8), 0);
+        assert_eq!(gripper.jog(8), 8);
+        assert_eq!(gripper.command(GripperCommand::Position(2.0)), 4999);
+        assert_eq!(gripper.jog(8), 4999);
+        assert_eq!(gripper.command(GripperCommand::Preset(GripperPreset::Pinch)), 1500);
+        assert_eq!(gripper.command(GripperCommand::Preset(GripperPreset::Closed)), 0);
+    }
+
+    #[test]
+    fn test_reversed_spool()
+    {
+        // opening winds the spool down
+        let config = GripperConfig { open: 100, closed: 3000, pinch: 2000, ..GripperConfig::default() };
+        let mut gripper = Gripper::new(config, 5000, 0);
+        assert_eq!(gripper.get_target(), 100);
+        assert_eq!(gripper.jog(8), 100);
+        assert_eq!(gripper.jog(-8), 108);
+        assert_eq!(gripper.command(GripperCommand::Position(0.5)), 1550);
+    }
+
+    #[test]
+    fn test_grasp_from_stall()
+    {
+        let start = Instant::now();
+        let mut gripper = Gripper::new(GripperConfig::default(), 5000, 4999);
+        gripper.command(GripperCommand::Preset(GripperPreset::Closed));
+
+        assert_eq!(gripper.check(Some(3000), None, start), GripState::Moving);
+        assert_eq!(gripper.check(Some(2000), None, start + Duration::from_millis(100)), GripState::Moving);
+        // fingers stop on the object
+        assert_eq!(gripper.check(Some(1999), None, start + Duration::from_millis(200)), GripState::Moving);
+        assert_eq!(gripper.check(Some(1999), None, start + Duration::from_millis(400)), GripState::Grasped);
+        assert_eq!(gripper.get_target(), 1959);
+
+        // a new command starts over
+        gripper.command(GripperCommand::Preset(GripperPreset::Open));
+        assert_eq!(gripper.get_state(), GripState::Moving);
+        assert_eq!(gripper.check(Some(3500), None, start), GripState::Moving);
+        assert_eq!(gripper.check(Some(4997), None, start), GripState::Reached);
+    }
+
+    #[test]
+    fn test_grasp_from_current()
+    {
+        let now = Instant::now();
+        let config = GripperConfig { grasp_current: 1.5, ..GripperConfig::default() };
+        let mut gripper = Gripper::new(config, 5000, 4999);
+        gripper.command(GripperCommand::Preset(GripperPreset::Closed));
+        assert_eq!(gripper.check(Some(3000), Some(0.4), now), GripState::Moving);
+        assert_eq!(gripper.check(Some(2500), Some(1.8), now), GripState::Grasped);
+
+        // opening into something is a stall, not a grasp
+        gripper.command(GripperCommand::Preset(GripperPreset::Open));
+        assert_eq!(gripper.check(Some(3000), Some(2.0), now), GripState::Stalled);
+        assert_eq!(gripper.get_target(), 3000);
+    }
+
+    #[test]
+    fn test_no_feedback_is_only_sent()
+    {
+        // without readback the gripper can not claim to have got there
+        let now = Instant::now();
+        let mut gripper = Gripper::new(GripperConfig::default(), 5000, 4999);
+        gripper.command(GripperCommand::Preset(GripperPreset::Closed));
+        assert_eq!(gripper.check(None, None, now), GripState::Sent);
+        assert_eq!(gripper.check(None, None, now + Duration::from_secs(1)), GripState::Sent);
+        assert_eq!(gripper.get_target(), 0);
+    }
+
+    #[test]
+    fn test_reading_below_closed()
+    {
+        // a tick past closed reads 4999, that is there, not a stall near open
+        let start = Instant::now();
+        let mut gripper = Gripper::new(GripperConfig { tolerance: 2, ..GripperConfig::default() }, 5000, 4999);
+        gripper.command(GripperCommand::Preset(GripperPreset::Closed));
+        assert_eq!(gripper.check(Some(3000), None, start), GripState::Moving);
+        assert_eq!(gripper.check(Some(1000), None, start), GripState::Moving);
+        assert_eq!(gripper.check(Some(10), None, start + Duration::from_millis(100)), GripState::Moving);
+        assert_eq!(gripper.check(Some(4999), None, start + Duration::from_millis(200)), GripState::Reached);
+        assert_eq!(gripper.get_target(), 0);
+    }
+}
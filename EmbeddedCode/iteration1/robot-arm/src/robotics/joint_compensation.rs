@@ -0,0 +1,376 @@
+/*
+William Albertini
+
+CompensatedDriver sits between the solver and the motor driver
+(it wraps any MotorDriver, main.rs wraps the SPI one) and
+corrects what is written for slack in the joints, so the end
+effector lands where the solver sent it instead of short of it.
+
+Backlash. Each joint remembers which way it last moved, and the
+motor is sent take_up x half the encoder's backlash
+(JointEncoder::backlash) past the target in that direction. On
+a reversal the motor winds through the play before the joint
+moves. Holding still keeps the last direction's take-up, the
+joint is still resting against that side of the play.
+
+Compliance. Tendons and gear trains stretch under load. With a
+ComplianceConfig the static torque on the shoulder, elbow and
+wrist is estimated from the link masses (taken at the middle of
+each link), the payload at the end effector and gravity (-y),
+and each motor is sent torque / stiffness further round to make
+up for the sag. In orbit (gravity 0) there is nothing to do.
+
+Feedback is read back with the same correction taken off, so
+the controller compares it with what it commanded (homing,
+restore) as if there was no compensation. Holding sends the
+motors to where they are and forgets the directions.
+
+Corrections are whole encoder ticks. The roll wraps like it
+always does, the spool is kept inside its turn instead (see
+gripper.rs).
+
+*/
+
+// external imports
+use serde::{Deserialize, Serialize};
+
+// internal imports
+use crate::arm_errors::RoboticArmError;
+use super::arm_kinematics::InverseKinematicSolver;
+use super::arm_state::{AngleToEncoderMap, ArmState, Joint, RoboticArmSolver};
+use super::robot_driver::{JointWiring, MotorDriver};
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ComplianceConfig
+{
+    // kg of the shoulder, elbow and wrist links, and of what the gripper holds
+    pub link_masses: [f64; 3],
+    pub payload: f64,
+    // in link length units per s^2 (9810 with lengths in mm)
+    pub gravity: f64,
+    // torque per radian of sag at each joint, kg (link length)^2 / s^2 like the simulator
+    pub stiffness: [f64; 3],
+}
+
+impl Default for ComplianceConfig
+{
+    fn default() -> ComplianceConfig
+    {
+        // the simulator's links, rigid until the stiffness is measured
+        ComplianceConfig
+        {
+            link_masses: [0.30, 0.20, 0.15],
+            payload: 0.0,
+            gravity: 9810.0,
+            stiffness: [f64::INFINITY; 3],
+        }
+    }
+}
+
+impl ComplianceConfig
+{
+    pub fn find_sag(&self, solver: &InverseKinematicSolver, joint_angles: [f64; 3]) -> [f64; 3]
+    {
+        // radians each joint gives under its static load, negative is drooping clockwise
+        let positions = solver.find_joint_positions(joint_angles);
+        let mut sag = [0.0; 3];
+        for (joint, sag) in sag.iter_mut().enumerate()
+        {
+            // weight beyond the joint, times how far out it hangs
+            let pivot = positions[joint][0];
+            let mut moment = self.payload * (positions[3][0] - pivot);
+            for link in joint..3
+            {
+                let middle = (positions[link][0] + positions[link + 1][0]) / 2.0;
+                moment += self.link_masses[link] * (middle - pivot);
+            }
+            *sag = -self.gravity * moment / self.stiffness[joint];
+        }
+        sag
+    }
+}
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompensationConfig
+{
+    // fraction of each encoder's backlash taken up, 0 turns it off
+    pub take_up: f64,
+    // None, no sag compensation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compliance: Option<ComplianceConfig>,
+}
+
+impl Default for CompensationConfig
+{
+    fn default() -> CompensationConfig
+    {
+        CompensationConfig { take_up: 1.0, compliance: None }
+    }
+}
+
+
+pub struct CompensatedDriver<D: MotorDriver>
+{
+    driver: D,
+    config: CompensationConfig,
+    solver: InverseKinematicSolver,
+    joint_map: AngleToEncoderMap,
+    // last state written before compensation
+    last_target: Option<ArmState>,
+    // which way each joint last moved (joint angle, not ticks), 0 before it has
+    directions: [f64; 5],
+    // ticks added to each joint on the last write
+    offsets: [i32; 5],
+}
+
+impl<D: MotorDriver> CompensatedDriver<D>
+{
+    pub fn new(driver: D, arm: &RoboticArmSolver, config: CompensationConfig) -> CompensatedDriver<D>
+    {
+        // the solver's encoder map, zeroed where it was constructed
+        CompensatedDriver
+        {
+            driver,
+            config,
+            solver: *arm.get_solver(),
+            joint_map: *arm.get_joint_map(),
+            last_target: None,
+            directions: [0.0; 5],
+            offsets: [0; 5],
+        }
+    }
+
+    pub fn compensate(&mut self, state: ArmState) -> ArmState
+    {
+        // what the motors are sent for the joints to end up at state
+        let joint_angles = [Joint::Shoulder, Joint::Elbow, Joint::Wrist].map(|joint| self.joint_map.ticks_to_angle(joint, state.get_joint(joint)));
+        let sag = self.config.compliance.map_or([0.0; 3], |compliance| compliance.find_sag(&self.solver, joint_angles));
+
+        let mut compensated = [0; 5];
+        for (index, joint) in Joint::ALL.into_iter().enumerate()
+        {
+            let encoder = self.joint_map.get_encoder(joint);
+            let span = encoder.get_span() as i32;
+            let target = state.get_joint(joint) as i32;
+            if let Some(last) = self.last_target
+            {
+                // the short way round, in joint angle
+                let moved = encoder.tick_difference(state.get_joint(joint), last.get_joint(joint));
+                if moved != 0
+                {
+                    self.directions[index] = (moved.signum() * encoder.direction.signum() as i32) as f64;
+                }
+            }
+
+            let mut correction = encoder.backlash_correction(self.directions[index]) * self.config.take_up;
+            if let Some(sag) = sag.get(index)
+            {
+                correction -= sag;
+            }
+            let ticks = target + (correction * encoder.ticks_per_radian()).round() as i32;
+            let ticks = if joint == Joint::Spool { ticks.clamp(0, span - 1) } else { ticks.rem_euclid(span) };
+            compensated[index] = ticks as u16;
+            self.offsets[index] = ticks - target;
+        }
+        self.last_target = Some(state);
+        ArmState::from_array(compensated)
+    }
+
+    fn remove_offsets(&self, feedback: ArmState) -> ArmState
+    {
+        // motor side ticks back to where the joint is
+        let feedback = feedback.as_array();
+        ArmState::from_array(std::array::from_fn(|index| {
+            let span = self.joint_map.ticks_per_revolution(Joint::ALL[index]) as i32;
+            (feedback[index] as i32 - self.offsets[index]).rem_euclid(span) as u16
+        }))
+    }
+
+    pub fn set_payload(&mut self, payload: f64)
+    {
+        // kg in the gripper, only used with compliance
+        if let Some(compliance) = &mut self.config.compliance
+        {
+            compliance.payload = payload;
+        }
+    }
+
+    pub fn get_offsets(&self) -> [i32; 5]
+    {
+        self.offsets
+    }
+
+    pub fn get_config(&self) -> CompensationConfig
+    {
+        self.config
+    }
+
+    pub fn get_driver(&self) -> &D
+    {
+        &self.driver
+    }
+
+    pub fn get_driver_mut(&mut self) -> &mut D
+    {
+        &mut self.driver
+    }
+}
+
+impl<D: MotorDriver> MotorDriver for CompensatedDriver<D>
+{
+    fn write_mac(&mut self, data: u16, motor: u8, mac_number: u8) -> Result<(), RoboticArmError>
+    {
+        // single writes do not say which joint they are, they go straight through
+        self.driver.write_mac(data, motor, mac_number)
+    }
+
+    fn update(&mut self)
+    {
+        self.driver.update()
+    }
+
+    fn read_feedback(&mut self) -> Option<ArmState>
+    {
+        let feedback = self.driver.read_feedback()?;
+        Some(self.remove_offsets(feedback))
+    }
+
+    fn read_current(&mut self, joint: Joint) -> Option<f64>
+    {
+        self.driver.read_current(joint)
+    }
+
+    fn hold(&mut self, wiring: &JointWiring) -> Result<Option<ArmState>, RoboticArmError>
+    {
+        // held where the motors are, there is nothing to take up any more
+        let Some(held) = self.driver.hold(wiring)? else {
+            return Ok(None);
+        };
+        self.last_target = None;
+        self.directions = [0.0; 5];
+        self.offsets = [0; 5];
+        Ok(Some(held))
+    }
+
+    fn write_arm_state(&mut self, state: ArmState, wiring: &JointWiring) -> Result<(), RoboticArmError>
+    {
+        let compensated = self.compensate(state);
+        self.driver.write_arm_state(compensated, wiring)
+    }
+}
+
+
+
+
+// ------------------------------- unit tests -------------------------------------
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::f64::consts::PI;
+    use crate::robotics::arm_state::JointEncoder;
+    use crate::robotics::robot_driver::mock::{test_arm_with, MockDriver};
+
+    fn geared_arm() -> RoboticArmSolver
+    {
+        // 0.02 rad of play in the elbow and the roll, 5000 ticks a turn
+        let mut map = AngleToEncoderMap::new(5000, 5000, 5000, 5000, 5000);
+        for joint in [Joint::Elbow, Joint::Roll]
+        {
+            map.set_encoder(joint, JointEncoder { backlash: 0.02, ..JointEncoder::new(5000) });
+        }
+        test_arm_with(map)
+    }
+
+    fn state(elbow: u16, roll: u16) -> ArmState
+    {
+        ArmState::from_array([0, elbow, 0, roll, 0])
+    }
+
+    #[test]
+    fn test_take_up_on_reversal()
+    {
+        // half of 0.02 rad is 8 ticks, added the way the joint is going
+        let wiring = JointWiring::default();
+        let mut driver = CompensatedDriver::new(MockDriver::default(), &geared_arm(), CompensationConfig::default());
+        driver.write_arm_state(state(100, 0), &wiring).unwrap();
+        assert_eq!(driver.get_driver().last_written(&wiring, Joint::Elbow), 100);
+
+        driver.write_arm_state(state(200, 0), &wiring).unwrap();
+        assert_eq!(driver.get_driver().last_written(&wiring, Joint::Elbow), 208);
+        // holding still stays against the same side
+        driver.write_arm_state(state(200, 0), &wiring).unwrap();
+        assert_eq!(driver.get_driver().last_written(&wiring, Joint::Elbow), 208);
+        // reversing winds through the play first
+        driver.write_arm_state(state(150, 0), &wiring).unwrap();
+        assert_eq!(driver.get_driver().last_written(&wiring, Joint::Elbow), 142);
+        // joints without play are left alone
+        assert_eq!(driver.get_driver().last_written(&wiring, Joint::Shoulder), 0);
+    }
+
+    #[test]
+    fn test_roll_wraps_and_spool_does_not()
+    {
+        let wiring = JointWiring::default();
+        let mut driver = CompensatedDriver::new(MockDriver::default(), &geared_arm(), CompensationConfig::default());
+        driver.write_arm_state(state(0, 2), &wiring).unwrap();
+        driver.write_arm_state(state(0, 4998), &wiring).unwrap();
+        assert_eq!(driver.get_driver().last_written(&wiring, Joint::Roll), 4990);
+        driver.write_arm_state(state(0, 3), &wiring).unwrap();
+        assert_eq!(driver.get_driver().last_written(&wiring, Joint::Roll), 11);
+
+        let mut map = *geared_arm().get_joint_map();
+        map.set_encoder(Joint::Spool, JointEncoder { backlash: 0.02, ..JointEncoder::new(5000) });
+        let arm = RoboticArmSolver::try_new_from_ef_position(10.0, 5.0, 3.0, 12.0, 6.0, 0.5, map).unwrap();
+        let mut driver = CompensatedDriver::new(MockDriver::default(), &arm, CompensationConfig::default());
+        driver.write_arm_state(ArmState::from_array([0, 0, 0, 0, 4990]), &wiring).unwrap();
+        driver.write_arm_state(ArmState::from_array([0, 0, 0, 0, 4995]), &wiring).unwrap();
+        assert_eq!(driver.get_driver().last_written(&wiring, Joint::Spool), 4999);
+    }
+
+    #[test]
+    fn test_feedback_and_hold()
+    {
+        // feedback reads as where the joint is, holding forgets the take-up
+        let wiring = JointWiring::default();
+        let mut driver = CompensatedDriver::new(MockDriver::default(), &geared_arm(), CompensationConfig { take_up: 0.5, compliance: None });
+        driver.write_arm_state(state(100, 0), &wiring).unwrap();
+        driver.write_arm_state(state(200, 0), &wiring).unwrap();
+        assert_eq!(driver.get_offsets()[1], 4);
+
+        driver.get_driver_mut().feedback = Some(state(204, 0));
+        assert_eq!(driver.read_feedback(), Some(state(200, 0)));
+        assert_eq!(driver.hold(&wiring).unwrap(), Some(state(204, 0)));
+        assert_eq!(driver.get_driver().last_written(&wiring, Joint::Elbow), 204);
+        driver.write_arm_state(state(204, 0), &wiring).unwrap();
+        assert_eq!(driver.get_driver().last_written(&wiring, Joint::Elbow), 204);
+    }
+
+    #[test]
+    fn test_compliance()
+    {
+        // straight out along x every joint droops, the shoulder most, and is sent up to make up for it
+        let map = AngleToEncoderMap::new(5000, 5000, 5000, 5000, 5000);
+        let arm = RoboticArmSolver::try_new_from_ef_position(10.0, 5.0, 3.0, 18.0, 0.0, 0.0, map).unwrap();
+        let compliance = ComplianceConfig { link_masses: [2.0, 1.0, 0.5], payload: 1.0, gravity: 10.0, stiffness: [50.0 * PI; 3] };
+        let sag = compliance.find_sag(arm.get_solver(), arm.get_joint_angles());
+        assert!(sag[0] < sag[1] && sag[1] < sag[2] && sag[2] < 0.0);
+        // wrist: 0.5 kg at 1.5 and 1 kg at 3, 10 * 3.75 / 50 pi
+        assert!((sag[2] + 10.0 * 3.75 / (50.0 * PI)).abs() < 1e-9);
+
+        let wiring = JointWiring::default();
+        let config = CompensationConfig { take_up: 0.0, compliance: Some(compliance) };
+        let mut driver = CompensatedDriver::new(MockDriver::default(), &arm, config);
+        driver.write_arm_state(ArmState::from_array([0; 5]), &wiring).unwrap();
+        let expected = (-sag[2] * 5000.0 / (2.0 * PI)).round() as u16;
+        assert_eq!(driver.get_driver().last_written(&wiring, Joint::Wrist), expected);
+
+        // in orbit nothing hangs
+        let orbit = ComplianceConfig { gravity: 0.0, ..compliance };
+        assert!(orbit.find_sag(arm.get_solver(), arm.get_joint_angles()).iter().all(|sag| *sag == 0.0));
+    }
+}
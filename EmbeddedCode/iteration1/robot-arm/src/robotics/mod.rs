@@ -1,3 +1,10 @@
 pub mod arm_kinematics;
 pub mod arm_state;
-pub mod robot_driver;
\ No newline at end of file
+pub mod robot_driver;
+pub mod command_watchdog;
+pub mod arm_controller;
+pub mod velocity_teleop;
+pub mod gripper;
+pub mod saved_state;
+pub mod encoder_calibration;
+pub mod joint_compensation;
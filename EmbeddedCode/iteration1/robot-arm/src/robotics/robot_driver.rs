@@ -13,12 +13,168 @@ Bytes are written individually, allowing SS line
 to return high between bytes (trigerring serial transfer
 complete interrupt SPI STC)
 
+MotorDriver is implemented by anything that can take
+motor controller writes (the SPI driver or the simulator),
+so the control loop does not care which one it is talking to.
+
+The bus and clock speed come from the config file
+(RobotDriver::with_spi), RobotDriver::new() is SPI1 at 8 MHz.
+Either returns a SpiError for the motor controller whose
+slave select could not be opened. Writes return a SpiError
+too, with the rppal error as its source, and a mac number
+other than 1 to MAC_COUNT is a ConfigError (the wiring is
+wrong), so a bus fault reaches the control loop instead of
+panicking it.
+
 */
 
 
 
 
-use rppal::spi::{Bus, Mode, Segment, SlaveSelect, Spi};
+use rppal::spi::{Bus, Mode, SlaveSelect, Spi};
+use serde::{Deserialize, Serialize};
+
+// internal imports
+use crate::arm_config::MAC_COUNT;
+use crate::arm_errors::{ErrorSource, RoboticArmError};
+use super::arm_state::{ArmState, Joint};
+
+
+// which motor controller (mac) and motor slot each joint is wired to
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JointWiring
+{
+	pub shoulder: (u8, u8),
+	pub elbow: (u8, u8),
+	pub wrist: (u8, u8),
+	pub roll: (u8, u8),
+	pub spool: (u8, u8),
+}
+
+impl JointWiring
+{
+	pub fn get_joint(&self, joint: Joint) -> (u8, u8)
+	{
+		match joint
+		{
+			Joint::Shoulder => self.shoulder,
+			Joint::Elbow => self.elbow,
+			Joint::Wrist => self.wrist,
+			Joint::Roll => self.roll,
+			Joint::Spool => self.spool,
+		}
+	}
+
+	pub fn find_joint(&self, mac_number: u8, motor: u8) -> Option<Joint>
+	{
+		Joint::ALL.into_iter().find(|joint| self.get_joint(*joint) == (mac_number, motor))
+	}
+}
+
+pub fn check_mac_number(mac_number: u8) -> Result<(), RoboticArmError>
+{
+	if (1..=MAC_COUNT).contains(&mac_number)
+	{
+		return Ok(());
+	}
+	Err(RoboticArmError::ConfigError { context: format!("There is no motor controller {mac_number} (1 to {MAC_COUNT})"), source: None })
+}
+
+impl Default for JointWiring
+{
+	fn default() -> JointWiring
+	{
+		// (mac number, motor) two motors per controller
+		JointWiring
+		{
+			shoulder: (1, 0),
+			elbow: (1, 1),
+			wrist: (2, 0),
+			roll: (2, 1),
+			spool: (3, 0),
+		}
+	}
+}
+
+
+pub trait MotorDriver
+{
+	fn write_mac(&mut self, data: u16, motor: u8, mac_number: u8) -> Result<(), RoboticArmError>;
+
+	// advance anything that runs alongside the driver (the simulator),
+	// hardware drivers have nothing to do here
+	fn update(&mut self) {}
+
+	// encoder positions read back from the motor controllers, if the driver can
+	fn read_feedback(&mut self) -> Option<ArmState>
+	{
+		None
+	}
+
+	// motor current (amps) of a joint, if the motor controller reports it
+	fn read_current(&mut self, _joint: Joint) -> Option<f64>
+	{
+		None
+	}
+
+	// stop the arm where it is. with encoder feedback every joint is sent to
+	// its current position, without it the motor controllers are left on
+	// their last target. returns the state held, if known
+	fn hold(&mut self, wiring: &JointWiring) -> Result<Option<ArmState>, RoboticArmError>
+	{
+		let Some(state) = self.read_feedback() else {
+			return Ok(None);
+		};
+		self.write_arm_state(state, wiring)?;
+		Ok(Some(state))
+	}
+
+	fn write_arm_state(&mut self, state: ArmState, wiring: &JointWiring) -> Result<(), RoboticArmError>
+	{
+		// send every joint to the motor it is wired to, stopping at the first write that fails
+		for joint in Joint::ALL
+		{
+			let (mac_number, motor) = wiring.get_joint(joint);
+			self.write_mac(state.get_joint(joint), motor, mac_number)?;
+		}
+		Ok(())
+	}
+}
+
+// so the control loop can pick a driver at runtime and still hand it to ArmController
+impl<D: MotorDriver + ?Sized> MotorDriver for Box<D>
+{
+	fn write_mac(&mut self, data: u16, motor: u8, mac_number: u8) -> Result<(), RoboticArmError>
+	{
+		(**self).write_mac(data, motor, mac_number)
+	}
+
+	fn update(&mut self)
+	{
+		(**self).update()
+	}
+
+	fn read_feedback(&mut self) -> Option<ArmState>
+	{
+		(**self).read_feedback()
+	}
+
+	fn read_current(&mut self, joint: Joint) -> Option<f64>
+	{
+		(**self).read_current(joint)
+	}
+
+	fn hold(&mut self, wiring: &JointWiring) -> Result<Option<ArmState>, RoboticArmError>
+	{
+		(**self).hold(wiring)
+	}
+
+	fn write_arm_state(&mut self, state: ArmState, wiring: &JointWiring) -> Result<(), RoboticArmError>
+	{
+		(**self).write_arm_state(state, wiring)
+	}
+}
 
 
 pub struct RobotDriver
@@ -31,44 +187,43 @@ pub struct RobotDriver
 
 impl RobotDriver
 {
-	pub fn new() -> RobotDriver
+	pub fn new() -> Result<RobotDriver, RoboticArmError>
 	{
-		let mac1 = Spi::new(Bus::Spi1, SlaveSelect::Ss0, 8_000_000, Mode::Mode0).unwrap();
-		let mac2 = Spi::new(Bus::Spi1, SlaveSelect::Ss1, 8_000_000, Mode::Mode0).unwrap();
-		let mac3 = Spi::new(Bus::Spi1, SlaveSelect::Ss2, 8_000_000, Mode::Mode0).unwrap();
+		RobotDriver::with_spi(Bus::Spi1, 8_000_000)
+	}
 
-		RobotDriver{ mac1, mac2, mac3 }
+	pub fn with_spi(bus: Bus, clock_speed: u32) -> Result<RobotDriver, RoboticArmError>
+	{
+		// one slave select line per motor controller
+		let open = |mac_number: u8, slave_select: SlaveSelect| Spi::new(bus, slave_select, clock_speed, Mode::Mode0)
+			.map_err(|e| RoboticArmError::SpiError { mac_number, source: ErrorSource(e) });
+		let mac1 = open(1, SlaveSelect::Ss0)?;
+		let mac2 = open(2, SlaveSelect::Ss1)?;
+		let mac3 = open(3, SlaveSelect::Ss2)?;
+
+		Ok(RobotDriver{ mac1, mac2, mac3 })
 	}
+}
 
-	pub fn write_mac(&mut self, data: u16, motor: u8, mac_number: u8)
+impl MotorDriver for RobotDriver
+{
+	fn write_mac(&mut self, data: u16, motor: u8, mac_number: u8) -> Result<(), RoboticArmError>
 	{
+		check_mac_number(mac_number)?;
 		let send_data = data_decomposition(data, motor);
-
-		let _ = match mac_number
+		let mac = match mac_number
 		{
-			1 => {
-				self.mac1.write(&[send_data[0]]).unwrap();
-				std::thread::sleep(std::time::Duration::from_millis(2));
-				self.mac1.write(&[send_data[1]])
-				},
-			2 => {
-				self.mac2.write(&[send_data[0]]).unwrap();
-				std::thread::sleep(std::time::Duration::from_millis(2));
-				self.mac2.write(&[send_data[1]])
-				},
-			3 => {
-				self.mac3.write(&[send_data[0]]).unwrap();
-				std::thread::sleep(std::time::Duration::from_millis(2));
-				self.mac3.write(&[send_data[1]])
-				},
-			_ => {
-				println!("wrong motor selected");
-				Ok(0)
-			},
+			1 => &mut self.mac1,
+			2 => &mut self.mac2,
+			_ => &mut self.mac3,
 		};
-		
-	}
+		let spi_error = |e| RoboticArmError::SpiError { mac_number, source: ErrorSource(e) };
 
+		mac.write(&[send_data[0]]).map_err(spi_error)?;
+		std::thread::sleep(std::time::Duration::from_millis(2));
+		mac.write(&[send_data[1]]).map_err(spi_error)?;
+		Ok(())
+	}
 }
 
 fn data_decomposition(data: u16, motor: u8) -> [u8; 2]
@@ -87,6 +242,93 @@ fn data_decomposition(data: u16, motor: u8) -> [u8; 2]
 
 
 
+// a driver and an arm for the controller, mission and compensation tests
+#[cfg(test)]
+pub mod mock
+{
+	use super::*;
+	use crate::robotics::arm_state::{AngleToEncoderMap, RoboticArmSolver};
+
+	// remembers every write, the bus can be taken down and the
+	// encoders can be made to follow whatever was last written
+	#[derive(Default)]
+	pub struct MockDriver
+	{
+		pub written: Vec<((u8, u8), u16)>,
+		pub holds: usize,
+		pub feedback: Option<ArmState>,
+		// feedback tracks the writes (default wiring)
+		pub follows: bool,
+		// the bus is down
+		pub failing: bool,
+	}
+
+	impl MockDriver
+	{
+		pub fn following() -> MockDriver
+		{
+			MockDriver { feedback: Some(ArmState::from_array([0; 5])), follows: true, ..MockDriver::default() }
+		}
+
+		pub fn writes(&self) -> usize
+		{
+			self.written.len()
+		}
+
+		pub fn last_written(&self, wiring: &JointWiring, joint: Joint) -> u16
+		{
+			let slot = wiring.get_joint(joint);
+			self.written.iter().rev().find(|(written, _)| *written == slot).map_or(0, |(_, data)| *data)
+		}
+	}
+
+	impl MotorDriver for MockDriver
+	{
+		fn write_mac(&mut self, data: u16, motor: u8, mac_number: u8) -> Result<(), RoboticArmError>
+		{
+			if self.failing
+			{
+				let source = ErrorSource(rppal::spi::Error::Io(std::io::Error::other("bus down")));
+				return Err(RoboticArmError::SpiError { mac_number, source });
+			}
+			self.written.push(((mac_number, motor), data));
+			if let (true, Some(feedback), Some(joint)) = (self.follows, self.feedback.as_mut(), JointWiring::default().find_joint(mac_number, motor))
+			{
+				let mut values = feedback.as_array();
+				values[Joint::ALL.iter().position(|j| *j == joint).unwrap()] = data;
+				*feedback = ArmState::from_array(values);
+			}
+			Ok(())
+		}
+
+		fn read_feedback(&mut self) -> Option<ArmState>
+		{
+			self.feedback
+		}
+
+		fn hold(&mut self, wiring: &JointWiring) -> Result<Option<ArmState>, RoboticArmError>
+		{
+			// counted, then held the way a real driver does
+			self.holds += 1;
+			let Some(state) = self.read_feedback() else {
+				return Ok(None);
+			};
+			self.write_arm_state(state, wiring)?;
+			Ok(Some(state))
+		}
+	}
+
+	pub fn test_arm_with(map: AngleToEncoderMap) -> RoboticArmSolver
+	{
+		RoboticArmSolver::try_new_from_ef_position(10.0, 5.0, 3.0, 12.0, 6.0, 0.5, map).unwrap()
+	}
+
+	pub fn test_arm() -> RoboticArmSolver
+	{
+		test_arm_with(AngleToEncoderMap::new(5000, 5000, 5000, 5000, 5000))
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -116,6 +358,30 @@ mod tests {
 		assert_eq!([0b11100111 ,0b00001000], send_val);
 	}
 
+	#[test]
+	fn test_wiring_lookup()
+	{
+		// every joint should be found at the slot it is wired to
+		let wiring = JointWiring::default();
+
+		for joint in Joint::ALL
+		{
+			let (mac_number, motor) = wiring.get_joint(joint);
+			assert_eq!(wiring.find_joint(mac_number, motor), Some(joint));
+		}
+		assert_eq!(wiring.find_joint(3, 1), None);
+	}
+
+	#[test]
+	fn test_unknown_mac_number()
+	{
+		// only controllers 1 to 3 are on the bus
+		assert!(check_mac_number(1).is_ok());
+		assert!(check_mac_number(MAC_COUNT).is_ok());
+		assert!(matches!(check_mac_number(0), Err(RoboticArmError::ConfigError { .. })));
+		assert!(check_mac_number(MAC_COUNT + 1).is_err());
+	}
+
 	// #[test]
 	// fn test_data_decomposition_5000_0()
 	// {
@@ -0,0 +1,272 @@
+/*
+William Albertini
+
+SavedState is what the arm needs to pick up where it left off
+after a restart (a Pi reboot mid-mission): the last ArmState
+written to the motor controllers, the last encoder readback,
+whether the arm was homed, and the pose and joint angles it
+was sent to. StateStore keeps it in a json file.
+
+Saving is atomic: the state is written to <file>.tmp, synced,
+and renamed over the file, so a crash or power cut leaves
+either the old state or the new one, never half of each
+(write_atomic(), the packet filter saves its replay counter
+the same way).
+StateStore::update() is called every control cycle and only
+writes when something changed, straight away if the homing
+status did and at most every SAVE_PERIOD otherwise (the SD
+card would not last long being written at the loop rate).
+
+On startup the saved state is checked against the encoders
+(reconcile()):
+
+    Confirmed     readback is within RESTORE_TOLERANCE_TICKS
+                  of the commanded or confirmed state, the
+                  arm is where it was left
+    Unconfirmed   the driver has no readback (the SPI
+                  motor controllers do not yet, only the
+                  simulator does), the saved state is the
+                  best guess but not trusted
+    Moved         readback disagrees (the arm was moved, or
+                  the motor controllers restarted too), the
+                  encoders win
+
+ArmController::restore() puts the solver back there and only
+a Confirmed state that was homed skips homing, so on the SPI
+motor controllers the arm always starts Unhomed.
+
+*/
+
+// external imports
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+
+// internal imports
+use crate::arm_errors::RoboticArmError;
+use super::arm_state::{AngleToEncoderMap, ArmState, Joint};
+
+// how close (encoder ticks) readback has to be to the saved state
+pub const RESTORE_TOLERANCE_TICKS: u16 = 4;
+// most often the state is written when only the position changed
+pub const SAVE_PERIOD: Duration = Duration::from_millis(500);
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SavedState
+{
+    // last state written to the motor controllers, relative to their start up
+    pub commanded: ArmState,
+    // last encoder readback, if the driver has it
+    pub confirmed: Option<ArmState>,
+    pub homed: bool,
+    // [x, y, si] of the end effector and shoulder, elbow, wrist (radians)
+    pub pose: [f64; 3],
+    pub joint_angles: [f64; 3],
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Reconciliation
+{
+    Confirmed,
+    Unconfirmed,
+    Moved { feedback: ArmState },
+}
+
+impl SavedState
+{
+    pub fn reconcile(&self, feedback: Option<ArmState>, joint_map: &AngleToEncoderMap) -> Reconciliation
+    {
+        let Some(feedback) = feedback else {
+            return Reconciliation::Unconfirmed;
+        };
+        let close = |state: ArmState| Joint::ALL.iter()
+            .all(|joint| joint_map.tick_distance(*joint, feedback.get_joint(*joint), state.get_joint(*joint)) <= RESTORE_TOLERANCE_TICKS);
+        if close(self.commanded) || self.confirmed.is_some_and(close)
+        {
+            Reconciliation::Confirmed
+        } else {
+            Reconciliation::Moved { feedback }
+        }
+    }
+}
+
+
+pub struct StateStore
+{
+    path: PathBuf,
+    last_saved: Option<SavedState>,
+    last_write: Option<Instant>,
+}
+
+impl StateStore
+{
+    pub fn new(path: &Path) -> StateStore
+    {
+        StateStore { path: path.to_path_buf(), last_saved: None, last_write: None }
+    }
+
+    pub fn load(&self) -> Result<Option<SavedState>, RoboticArmError>
+    {
+        // None on the first run, when there is no file yet
+        let text = match fs::read_to_string(&self.path)
+        {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(RoboticArmError::file(format!("Could not read {}", self.path.display()), e)),
+        };
+        serde_json::from_str(&text)
+            .map(Some)
+            .map_err(|e| RoboticArmError::file(format!("Bad saved state {}", self.path.display()), e))
+    }
+
+    pub fn save(&mut self, state: &SavedState) -> Result<(), RoboticArmError>
+    {
+        let text = serde_json::to_string_pretty(state)
+            .map_err(|e| RoboticArmError::file("Could not encode saved state", e))?;
+        write_atomic(&self.path, &text)?;
+        self.last_saved = Some(*state);
+        Ok(())
+    }
+
+    pub fn update(&mut self, state: SavedState, now: Instant) -> Result<bool, RoboticArmError>
+    {
+        // true if the state was written
+        let due = match (self.last_saved, self.last_write)
+        {
+            (Some(last), _) if last == state => false,
+            (Some(last), _) if last.homed != state.homed => true,
+            (_, Some(last_write)) => now.saturating_duration_since(last_write) >= SAVE_PERIOD,
+            (_, None) => true,
+        };
+        if !due
+        {
+            return Ok(false);
+        }
+        // a failing disk is tried again next period, not every cycle
+        self.last_write = Some(now);
+        self.save(&state).map(|_| true)
+    }
+
+    pub fn get_path(&self) -> &Path
+    {
+        &self.path
+    }
+}
+
+
+fn temporary_path(path: &Path) -> PathBuf
+{
+    // appended, so arm.json and arm.counter do not share arm.tmp
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+pub fn write_atomic(path: &Path, text: &str) -> Result<(), RoboticArmError>
+{
+    // write the whole file next to the old one, then swap it in
+    let file_error = |e: std::io::Error| RoboticArmError::file(format!("Could not save to {}", path.display()), e);
+    let temporary = temporary_path(path);
+    let mut file = File::create(&temporary).map_err(file_error)?;
+    file.write_all(text.as_bytes()).map_err(file_error)?;
+    file.sync_all().map_err(file_error)?;
+    fs::rename(&temporary, path).map_err(file_error)?;
+
+    // and the rename itself, where the directory can be opened
+    if let Some(directory) = path.parent().filter(|directory| !directory.as_os_str().is_empty())
+    {
+        if let Ok(directory) = File::open(directory)
+        {
+            let _ = directory.sync_all();
+        }
+    }
+    Ok(())
+}
+
+
+
+
+// ------------------------------- unit tests -------------------------------------
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn saved(commanded: [u16; 5], homed: bool) -> SavedState
+    {
+        SavedState
+        {
+            commanded: ArmState::from_array(commanded),
+            confirmed: None,
+            homed,
+            pose: [11.0, 6.0, 0.5],
+            joint_angles: [0.1, 0.2, 0.3],
+        }
+    }
+
+    fn temp_store(name: &str) -> StateStore
+    {
+        let path = std::env::temp_dir().join(format!("robot-arm-{name}-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        StateStore::new(&path)
+    }
+
+    #[test]
+    fn test_save_and_load()
+    {
+        let mut store = temp_store("state");
+        assert_eq!(store.load().unwrap(), None);
+
+        let state = saved([10, 20, 30, 40, 50], true);
+        store.save(&state).unwrap();
+        assert_eq!(store.load().unwrap(), Some(state));
+        assert!(!temporary_path(store.get_path()).exists());
+        assert_eq!(temporary_path(store.get_path()).file_name().unwrap().to_str().unwrap(),
+                   format!("robot-arm-state-{}.json.tmp", std::process::id()));
+        assert_ne!(temporary_path(Path::new("arm.json")), temporary_path(Path::new("arm.counter")));
+
+        fs::write(store.get_path(), "{not json").unwrap();
+        assert!(matches!(store.load(), Err(RoboticArmError::FileError { source: Some(_), .. })));
+        fs::remove_file(store.get_path()).unwrap();
+    }
+
+    #[test]
+    fn test_update_rate_limited()
+    {
+        let start = Instant::now();
+        let mut store = temp_store("state-rate");
+        assert!(store.update(saved([0; 5], false), start).unwrap());
+        // nothing new, then too soon
+        assert!(!store.update(saved([0; 5], false), start).unwrap());
+        assert!(!store.update(saved([5, 0, 0, 0, 0], false), start + Duration::from_millis(100)).unwrap());
+        // homing status does not wait
+        assert!(store.update(saved([5, 0, 0, 0, 0], true), start + Duration::from_millis(200)).unwrap());
+        assert!(store.update(saved([9, 0, 0, 0, 0], true), start + SAVE_PERIOD * 2).unwrap());
+        assert_eq!(store.load().unwrap().unwrap().commanded.shoulder, 9);
+        fs::remove_file(store.get_path()).unwrap();
+    }
+
+    #[test]
+    fn test_reconcile()
+    {
+        let map = AngleToEncoderMap::new(5000, 5000, 5000, 5000, 5000);
+        let mut state = saved([100, 200, 300, 0, 0], true);
+        assert_eq!(state.reconcile(None, &map), Reconciliation::Unconfirmed);
+        assert_eq!(state.reconcile(Some(ArmState::from_array([102, 199, 300, 0, 0])), &map), Reconciliation::Confirmed);
+
+        // still on the way when it was saved, the last readback counts too
+        state.confirmed = Some(ArmState::from_array([50, 200, 300, 0, 0]));
+        assert_eq!(state.reconcile(Some(ArmState::from_array([51, 200, 300, 0, 0])), &map), Reconciliation::Confirmed);
+
+        // the motor controllers restarted as well
+        let zero = ArmState::from_array([0; 5]);
+        assert_eq!(state.reconcile(Some(zero), &map), Reconciliation::Moved { feedback: zero });
+
+        // saved at home, the encoders settled a tick below zero
+        let home = saved([0; 5], true);
+        assert_eq!(home.reconcile(Some(ArmState::from_array([4999, 0, 0, 0, 0])), &map), Reconciliation::Confirmed);
+    }
+}
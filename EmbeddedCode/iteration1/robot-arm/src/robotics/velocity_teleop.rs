@@ -0,0 +1,336 @@
+/*
+William Albertini
+
+Velocity teleop. Instead of every joystick packet moving the
+arm a fixed step (so the speed depends on how often packets
+arrive, and on jitter), the sticks set a target velocity and
+the control loop moves the arm by velocity x elapsed time
+every cycle (step()), whether a packet came in or not.
+
+    x, y      up to max_linear_speed (mm/s), the pair is
+              capped so diagonals are no faster
+    pitch     up to max_angular_speed (rad/s)
+    roll      up to max_angular_speed (rad/s)
+
+In joint jog x, y and pitch are shoulder, elbow and wrist
+rates, all up to max_angular_speed.
+
+The velocity follows the target at no more than the
+acceleration limits, so letting go of the sticks (or a
+heartbeat, or no input for input_timeout_ms) ramps the arm
+down instead of stopping it dead. Stick values go through
+response_curve() first: expo 0 is linear, 1 is cubic, which
+gives fine control near the center and full speed at the
+ends.
+
+*/
+
+// external imports
+use std::time::{Duration, Instant};
+use arm_protocol::joystick::AXIS_MAX;
+use serde::{Deserialize, Serialize};
+
+// internal imports
+use crate::networking::data_handler::DataHandler;
+
+// longest step taken at once, a stalled loop does not jump the arm
+pub const MAX_STEP: Duration = Duration::from_millis(100);
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TeleopMode
+{
+    // sticks set speeds, see above
+    Velocity,
+    // every packet moves the arm by the JogGains
+    Position,
+}
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TeleopConfig
+{
+    pub mode: TeleopMode,
+    // mm/s
+    pub max_linear_speed: f64,
+    // rad/s
+    pub max_angular_speed: f64,
+    // mm/s^2
+    pub max_linear_acceleration: f64,
+    // rad/s^2
+    pub max_angular_acceleration: f64,
+    // 0 linear to 1 cubic
+    pub expo: f64,
+    // the sticks count as centered if nothing new arrives for this long
+    pub input_timeout_ms: u64,
+}
+
+impl Default for TeleopConfig
+{
+    fn default() -> TeleopConfig
+    {
+        // about what full stick did at 10 packets a second in position mode
+        TeleopConfig
+        {
+            mode: TeleopMode::Velocity,
+            max_linear_speed: 80.0,
+            max_angular_speed: 0.8,
+            max_linear_acceleration: 400.0,
+            max_angular_acceleration: 4.0,
+            expo: 0.5,
+            input_timeout_ms: 300,
+        }
+    }
+}
+
+
+// how far to move this cycle
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct JogStep
+{
+    // [x mm, y mm, pitch rad] in the jog frame, or shoulder, elbow, wrist (rad) in joint jog
+    pub axes: [f64; 3],
+    // radians
+    pub roll: f64,
+    pub joint_jog: bool,
+}
+
+impl JogStep
+{
+    pub fn is_zero(&self) -> bool
+    {
+        self.roll == 0.0 && self.axes.iter().all(|axis| *axis == 0.0)
+    }
+}
+
+
+pub fn response_curve(value: f64, expo: f64) -> f64
+{
+    // value from -1 to 1, blend of linear and cubic
+    let value = value.clamp(-1.0, 1.0);
+    (1.0 - expo) * value + expo * value.powi(3)
+}
+
+
+pub struct VelocityTeleop
+{
+    config: TeleopConfig,
+    input: DataHandler,
+    last_input: Option<Instant>,
+    last_step: Option<Instant>,
+    // [x, y, pitch, roll]
+    velocity: [f64; 4],
+}
+
+impl VelocityTeleop
+{
+    pub fn new(config: TeleopConfig) -> VelocityTeleop
+    {
+        VelocityTeleop
+        {
+            config,
+            input: DataHandler::heartbeat(),
+            last_input: None,
+            last_step: None,
+            velocity: [0.0; 4],
+        }
+    }
+
+    pub fn set_input(&mut self, data: DataHandler, now: Instant)
+    {
+        // mm/s and rad/s do not mix, switching jog mode starts from rest
+        if data.joint_jog != self.input.joint_jog && !data.heartbeat
+        {
+            self.velocity = [0.0; 4];
+        }
+        if !data.heartbeat
+        {
+            self.input = data;
+        } else {
+            self.input = DataHandler { joint_jog: self.input.joint_jog, ..DataHandler::heartbeat() };
+        }
+        self.last_input = Some(now);
+    }
+
+    pub fn target_velocity(&self, now: Instant) -> [f64; 4]
+    {
+        let timeout = Duration::from_millis(self.config.input_timeout_ms);
+        let fresh = self.last_input.is_some_and(|last| now.saturating_duration_since(last) <= timeout);
+        if !fresh || self.input.heartbeat
+        {
+            return [0.0; 4];
+        }
+
+        let axis = |value: i16| response_curve(value as f64 / AXIS_MAX as f64, self.config.expo);
+        let angular = self.config.max_angular_speed;
+        let linear = if self.input.joint_jog { angular } else { self.config.max_linear_speed };
+        let mut target = [axis(self.input.x) * linear,
+                          axis(self.input.y) * linear,
+                          axis(self.input.pitch) * angular,
+                          axis(self.input.roll) * angular];
+
+        // diagonals are no faster than straight
+        let speed = target[0].hypot(target[1]);
+        if !self.input.joint_jog && speed > linear
+        {
+            target[0] *= linear / speed;
+            target[1] *= linear / speed;
+        }
+        target
+    }
+
+    pub fn step(&mut self, now: Instant) -> JogStep
+    {
+        // the first step only starts the clock
+        let dt = self.last_step
+            .map_or(Duration::ZERO, |last| now.saturating_duration_since(last).min(MAX_STEP))
+            .as_secs_f64();
+        self.last_step = Some(now);
+
+        let target = self.target_velocity(now);
+        let angular = self.config.max_angular_acceleration;
+        let linear = if self.input.joint_jog { angular } else { self.config.max_linear_acceleration };
+        for ((velocity, target), acceleration) in self.velocity.iter_mut().zip(target).zip([linear, linear, angular, angular])
+        {
+            let change = acceleration * dt;
+            *velocity += (target - *velocity).clamp(-change, change);
+        }
+
+        let [x, y, pitch, roll] = self.velocity.map(|velocity| velocity * dt);
+        JogStep { axes: [x, y, pitch], roll, joint_jog: self.input.joint_jog }
+    }
+
+    pub fn stop(&mut self)
+    {
+        // dead stop, for leaving teleop or a rejected move
+        self.velocity = [0.0; 4];
+        self.last_input = None;
+        self.last_step = None;
+    }
+
+    pub fn get_velocity(&self) -> [f64; 4]
+    {
+        self.velocity
+    }
+
+    pub fn get_input(&self) -> DataHandler
+    {
+        self.input
+    }
+
+    pub fn get_config(&self) -> TeleopConfig
+    {
+        self.config
+    }
+}
+
+
+
+
+// ------------------------------- unit tests -------------------------------------
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn full_x() -> DataHandler
+    {
+        DataHandler::new(AXIS_MAX as i16, 0, 0, 0, 1, 1)
+    }
+
+    fn run(teleop: &mut VelocityTeleop, start: Instant, packet_period: Duration, seconds: u64) -> f64
+    {
+        // 10 ms control loop, x travelled
+        let mut travelled = 0.0;
+        let mut next_packet = start;
+        for cycle in 0..=seconds * 100
+        {
+            let now = start + Duration::from_millis(10 * cycle);
+            if now >= next_packet
+            {
+                teleop.set_input(full_x(), now);
+                next_packet += packet_period;
+            }
+            travelled += teleop.step(now).axes[0];
+        }
+        travelled
+    }
+
+    #[test]
+    fn test_response_curve()
+    {
+        assert_eq!(response_curve(1.0, 0.5), 1.0);
+        assert_eq!(response_curve(-1.0, 0.5), -1.0);
+        assert_eq!(response_curve(0.5, 0.0), 0.5);
+        // near the center the cubic end is much finer
+        assert!(response_curve(0.25, 1.0) < 0.02);
+        assert_eq!(response_curve(3.0, 0.2), 1.0);
+    }
+
+    #[test]
+    fn test_speed_does_not_depend_on_packet_rate()
+    {
+        let start = Instant::now();
+        let slow = run(&mut VelocityTeleop::new(TeleopConfig::default()), start, Duration::from_millis(200), 2);
+        let fast = run(&mut VelocityTeleop::new(TeleopConfig::default()), start, Duration::from_millis(50), 2);
+
+        assert!((slow - fast).abs() < 1e-9);
+        // 0.2 s to get up to 80 mm/s, then 1.8 s flat out
+        assert!((fast - (0.5 * 80.0 * 0.2 + 80.0 * 1.8)).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_acceleration_limited()
+    {
+        let start = Instant::now();
+        let mut teleop = VelocityTeleop::new(TeleopConfig::default());
+        teleop.set_input(full_x(), start);
+        teleop.step(start);
+        teleop.step(start + Duration::from_millis(50));
+        assert!((teleop.get_velocity()[0] - 20.0).abs() < 1e-9);
+
+        // letting go ramps down too
+        let mut now = start + Duration::from_millis(50);
+        for _ in 0..3
+        {
+            now += Duration::from_millis(100);
+            teleop.set_input(full_x(), now);
+            teleop.step(now);
+        }
+        assert_eq!(teleop.get_velocity()[0], 80.0);
+        teleop.set_input(DataHandler::heartbeat(), now);
+        now += Duration::from_millis(100);
+        teleop.step(now);
+        assert!((teleop.get_velocity()[0] - 40.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_stale_input_stops()
+    {
+        // the controller went quiet, the sticks count as centered
+        let start = Instant::now();
+        let mut teleop = VelocityTeleop::new(TeleopConfig::default());
+        teleop.set_input(full_x(), start);
+        assert_eq!(teleop.target_velocity(start)[0], 80.0);
+        assert_eq!(teleop.target_velocity(start + Duration::from_millis(400)), [0.0; 4]);
+    }
+
+    #[test]
+    fn test_diagonal_capped_and_joint_jog()
+    {
+        let now = Instant::now();
+        let mut teleop = VelocityTeleop::new(TeleopConfig::default());
+        teleop.set_input(DataHandler::new(8, 8, 0, 0, 1, 1), now);
+        let [x, y, _, _] = teleop.target_velocity(now);
+        assert!((x.hypot(y) - 80.0).abs() < 1e-9);
+
+        // joint jog runs at angular speeds
+        let mut data = DataHandler::new(8, 0, 0, 0, 1, 1);
+        data.joint_jog = true;
+        teleop.set_input(data, now);
+        assert_eq!(teleop.target_velocity(now)[0], 0.8);
+        assert!(teleop.step(now).joint_jog);
+    }
+}
@@ -0,0 +1,449 @@
+/*
+William Albertini
+
+This module integrates the planar dynamics of the three
+link arm (shoulder, elbow, wrist). It replaces the python
+slider simulations with something the control loop can
+actually drive. Links are modelled as rigid bodies with a
+mass, a moment of inertia about their center of mass, and
+a center of mass some distance along the link. The
+equations of motion are built from the link jacobians:
+
+    M(q) q'' + C(q, q') + G(q) = tau - b q'
+
+Joint angles use the same convention as the inverse
+kinematic solver (each angle is relative to the previous
+link), so the result of find_joint_angles can be fed
+directly in as a starting state. Lengths are in whatever
+unit the solver uses, inertia must be given in
+mass * length^2 of that same unit.
+
+*/
+
+// the matrix math reads better with plain indices
+#![allow(clippy::needless_range_loop)]
+
+// internal imports
+use crate::robotics::arm_kinematics::InverseKinematicSolver;
+
+
+// step used to numerically differentiate the mass matrix
+const DIFFERENTIATION_STEP: f64 = 1e-6;
+
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LinkProperties
+{
+    pub mass: f64,
+    // moment of inertia about the center of mass
+    pub inertia: f64,
+    // distance from the joint to the center of mass
+    pub center_of_mass: f64,
+}
+
+impl LinkProperties
+{
+    pub fn new(mass: f64, inertia: f64, center_of_mass: f64) -> LinkProperties
+    {
+        LinkProperties { mass, inertia, center_of_mass }
+    }
+
+    pub fn uniform_rod(mass: f64, length: f64) -> LinkProperties
+    {
+        // thin rod, center of mass at the middle of the link
+        LinkProperties::new(mass, mass * length * length / 12.0, length / 2.0)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MotorProperties
+{
+    // largest torque the motor (after gearing) can apply
+    pub max_torque: f64,
+    // viscous friction in the joint
+    pub damping: f64,
+}
+
+impl MotorProperties
+{
+    pub fn new(max_torque: f64, damping: f64) -> MotorProperties
+    {
+        MotorProperties { max_torque, damping }
+    }
+}
+
+
+#[derive(Clone)]
+pub struct ArmDynamics
+{
+    lengths: [f64; 3],
+    links: [LinkProperties; 3],
+    motors: [MotorProperties; 3],
+    // gravity acts in -y, set to zero for orbit
+    gravity: f64,
+    // joint angles and velocities (relative, radians)
+    q: [f64; 3],
+    qd: [f64; 3],
+}
+
+
+impl ArmDynamics
+{
+    pub fn new(solver: &InverseKinematicSolver,
+               links: [LinkProperties; 3],
+               motors: [MotorProperties; 3],
+               gravity: f64,
+               joint_angles: [f64; 3]) -> ArmDynamics
+    {
+        ArmDynamics {
+            lengths: solver.link_lengths(),
+            links,
+            motors,
+            gravity,
+            q: joint_angles,
+            qd: [0.0; 3],
+        }
+    }
+
+    pub fn get_joint_angles(&self) -> [f64; 3]
+    {
+        self.q
+    }
+
+    pub fn get_joint_velocities(&self) -> [f64; 3]
+    {
+        self.qd
+    }
+
+    pub fn get_links(&self) -> &[LinkProperties; 3]
+    {
+        &self.links
+    }
+
+    pub fn get_lengths(&self) -> [f64; 3]
+    {
+        self.lengths
+    }
+
+    pub fn set_state(&mut self, joint_angles: [f64; 3], joint_velocities: [f64; 3])
+    {
+        self.q = joint_angles;
+        self.qd = joint_velocities;
+    }
+
+    pub fn clamp_torques(&self, torques: [f64; 3]) -> [f64; 3]
+    {
+        // motors can not apply more than their rated torque
+        let mut clamped = torques;
+        for (torque, motor) in clamped.iter_mut().zip(self.motors.iter())
+        {
+            *torque = torque.clamp(-motor.max_torque, motor.max_torque);
+        }
+        clamped
+    }
+
+    pub fn step(&mut self, torques: [f64; 3], dt: f64) -> [f64; 3]
+    {
+        // semi-implicit euler, velocities first so the integration stays stable
+        let qdd = self.joint_accelerations(torques);
+        for i in 0..3
+        {
+            self.qd[i] += qdd[i] * dt;
+            self.q[i] += self.qd[i] * dt;
+        }
+        qdd
+    }
+
+    pub fn joint_accelerations(&self, torques: [f64; 3]) -> [f64; 3]
+    {
+        // solve M q'' = tau - b q' - C - G
+        let torques = self.clamp_torques(torques);
+        let mass_matrix = self.mass_matrix(self.q);
+        let coriolis = self.coriolis_forces();
+        let gravity = self.gravity_forces(self.q);
+
+        let mut rhs = [0.0; 3];
+        for i in 0..3
+        {
+            rhs[i] = torques[i] - self.motors[i].damping * self.qd[i] - coriolis[i] - gravity[i];
+        }
+
+        solve_3x3(mass_matrix, rhs)
+    }
+
+    pub fn mass_matrix(&self, q: [f64; 3]) -> [[f64; 3]; 3]
+    {
+        let mut mass_matrix = [[0.0; 3]; 3];
+
+        for link in 0..3
+        {
+            let jacobian = self.center_of_mass_jacobian(q, link);
+            let properties = self.links[link];
+
+            for i in 0..3
+            {
+                for j in 0..3
+                {
+                    // translational part
+                    mass_matrix[i][j] += properties.mass
+                        * (jacobian[i][0] * jacobian[j][0] + jacobian[i][1] * jacobian[j][1]);
+                    // rotational part, link rotates with every joint before it
+                    if i <= link && j <= link
+                    {
+                        mass_matrix[i][j] += properties.inertia;
+                    }
+                }
+            }
+        }
+        mass_matrix
+    }
+
+    pub fn kinetic_energy(&self) -> f64
+    {
+        let mass_matrix = self.mass_matrix(self.q);
+        let mut energy = 0.0;
+        for i in 0..3
+        {
+            for j in 0..3
+            {
+                energy += 0.5 * self.qd[i] * mass_matrix[i][j] * self.qd[j];
+            }
+        }
+        energy
+    }
+
+    pub fn potential_energy(&self) -> f64
+    {
+        let centers = self.centers_of_mass(self.q);
+        centers.iter()
+            .zip(self.links.iter())
+            .map(|(center, link)| link.mass * self.gravity * center[1])
+            .sum()
+    }
+
+    pub fn centers_of_mass(&self, q: [f64; 3]) -> [[f64; 2]; 3]
+    {
+        let phi = absolute_angles(q);
+        let mut centers = [[0.0; 2]; 3];
+        let mut joint = [0.0, 0.0];
+
+        for link in 0..3
+        {
+            let com = self.links[link].center_of_mass;
+            centers[link] = [joint[0] + com * phi[link].cos(), joint[1] + com * phi[link].sin()];
+            joint = [joint[0] + self.lengths[link] * phi[link].cos(),
+                     joint[1] + self.lengths[link] * phi[link].sin()];
+        }
+        centers
+    }
+
+    pub fn center_of_mass_jacobian(&self, q: [f64; 3], link: usize) -> [[f64; 2]; 3]
+    {
+        // row j is the derivative of the link's center of mass with respect to joint j
+        let phi = absolute_angles(q);
+        let mut jacobian = [[0.0; 2]; 3];
+
+        for (j, row) in jacobian.iter_mut().enumerate().take(link + 1)
+        {
+            // every link from joint j up to (but not including) this one contributes its full length
+            for k in j..link
+            {
+                row[0] -= self.lengths[k] * phi[k].sin();
+                row[1] += self.lengths[k] * phi[k].cos();
+            }
+            let com = self.links[link].center_of_mass;
+            row[0] -= com * phi[link].sin();
+            row[1] += com * phi[link].cos();
+        }
+        jacobian
+    }
+
+    fn coriolis_forces(&self) -> [f64; 3]
+    {
+        // christoffel symbols from a numerical derivative of the mass matrix
+        let mut derivatives = [[[0.0; 3]; 3]; 3];
+        for (k, derivative) in derivatives.iter_mut().enumerate()
+        {
+            let mut q_plus = self.q;
+            let mut q_minus = self.q;
+            q_plus[k] += DIFFERENTIATION_STEP;
+            q_minus[k] -= DIFFERENTIATION_STEP;
+            let m_plus = self.mass_matrix(q_plus);
+            let m_minus = self.mass_matrix(q_minus);
+
+            for i in 0..3
+            {
+                for j in 0..3
+                {
+                    derivative[i][j] = (m_plus[i][j] - m_minus[i][j]) / (2.0 * DIFFERENTIATION_STEP);
+                }
+            }
+        }
+
+        let mut forces = [0.0; 3];
+        for (i, force) in forces.iter_mut().enumerate()
+        {
+            for j in 0..3
+            {
+                for k in 0..3
+                {
+                    // dM_ij/dq_k - 1/2 dM_jk/dq_i
+                    *force += (derivatives[k][i][j] - 0.5 * derivatives[i][j][k]) * self.qd[j] * self.qd[k];
+                }
+            }
+        }
+        forces
+    }
+
+    fn gravity_forces(&self, q: [f64; 3]) -> [f64; 3]
+    {
+        let mut forces = [0.0; 3];
+        for link in 0..3
+        {
+            let jacobian = self.center_of_mass_jacobian(q, link);
+            for (force, row) in forces.iter_mut().zip(jacobian.iter())
+            {
+                *force += self.links[link].mass * self.gravity * row[1];
+            }
+        }
+        forces
+    }
+}
+
+
+pub fn absolute_angles(q: [f64; 3]) -> [f64; 3]
+{
+    // convert relative joint angles to angles measured from the x axis
+    [q[0], q[0] + q[1], q[0] + q[1] + q[2]]
+}
+
+pub fn solve_3x3(matrix: [[f64; 3]; 3], rhs: [f64; 3]) -> [f64; 3]
+{
+    // gaussian elimination with partial pivoting, the mass matrix is
+    // always positive definite so this never hits a zero pivot
+    let mut a = matrix;
+    let mut b = rhs;
+
+    for col in 0..3
+    {
+        let pivot = (col..3)
+            .max_by(|r1, r2| a[*r1][col].abs().total_cmp(&a[*r2][col].abs()))
+            .unwrap_or(col);
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+
+        for row in (col + 1)..3
+        {
+            let factor = a[row][col] / a[col][col];
+            for k in col..3
+            {
+                a[row][k] -= factor * a[col][k];
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+
+    let mut x = [0.0; 3];
+    for row in (0..3).rev()
+    {
+        let mut sum = b[row];
+        for k in (row + 1)..3
+        {
+            sum -= a[row][k] * x[k];
+        }
+        x[row] = sum / a[row][row];
+    }
+    x
+}
+
+
+
+
+// ------------------------------- unit tests -------------------------------------
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use all_asserts::assert_near;
+
+    fn test_arm(gravity: f64, damping: f64) -> ArmDynamics
+    {
+        let solver = InverseKinematicSolver::new(1.0, 0.5, 0.3);
+        let links = [LinkProperties::uniform_rod(2.0, 1.0),
+                     LinkProperties::uniform_rod(1.0, 0.5),
+                     LinkProperties::uniform_rod(0.5, 0.3)];
+        let motors = [MotorProperties::new(50.0, damping); 3];
+
+        ArmDynamics::new(&solver, links, motors, gravity, [0.3, 0.4, -0.2])
+    }
+
+    #[test]
+    fn test_at_rest_without_gravity()
+    {
+        // no torque and no gravity, the arm should not move
+        let mut arm = test_arm(0.0, 0.0);
+        for _ in 0..1000
+        {
+            arm.step([0.0; 3], 0.001);
+        }
+
+        assert_eq!(arm.get_joint_angles(), [0.3, 0.4, -0.2]);
+    }
+
+    #[test]
+    fn test_energy_conserved()
+    {
+        // an undamped arm swinging under gravity should keep its total energy
+        let mut arm = test_arm(9.81, 0.0);
+        let initial_energy = arm.kinetic_energy() + arm.potential_energy();
+
+        for _ in 0..2000
+        {
+            arm.step([0.0; 3], 0.0001);
+        }
+        let final_energy = arm.kinetic_energy() + arm.potential_energy();
+
+        assert!(arm.kinetic_energy() > 0.1, "arm should have started swinging");
+        assert_near!(final_energy, initial_energy, 0.01 * initial_energy.abs());
+    }
+
+    #[test]
+    fn test_single_link_acceleration()
+    {
+        // with the outer links massless, the shoulder behaves like a rod about its end
+        // (they still need some inertia, or the mass matrix is singular)
+        let solver = InverseKinematicSolver::new(1.0, 0.5, 0.3);
+        let links = [LinkProperties::uniform_rod(3.0, 1.0),
+                     LinkProperties::new(0.0, 0.01, 0.25),
+                     LinkProperties::new(0.0, 0.01, 0.15)];
+        let motors = [MotorProperties::new(10.0, 0.0); 3];
+        let arm = ArmDynamics::new(&solver, links, motors, 0.0, [0.0; 3]);
+
+        // rod about its end, I = m l^2 / 3 = 1, the free outer links keep their orientation
+        let qdd = arm.joint_accelerations([1.0, 0.0, 0.0]);
+        assert!(qdd.iter().all(|acceleration| acceleration.is_finite()));
+        assert_near!(qdd[0], 1.0, 1e-9);
+    }
+
+    #[test]
+    fn test_torque_limited()
+    {
+        // commanding more than the motor can give is the same as commanding the limit
+        let arm = test_arm(0.0, 0.0);
+
+        let limited = arm.joint_accelerations([1000.0, 0.0, 0.0]);
+        let at_limit = arm.joint_accelerations([50.0, 0.0, 0.0]);
+
+        assert_eq!(limited, at_limit);
+    }
+
+    #[test]
+    fn test_solve_3x3()
+    {
+        let x = solve_3x3([[0.0, 2.0, 1.0], [1.0, 1.0, 0.0], [3.0, 0.0, 1.0]], [5.0, 3.0, 6.0]);
+
+        assert_near!(x[0], 1.4, 1e-9);
+        assert_near!(x[1], 1.6, 1e-9);
+        assert_near!(x[2], 1.8, 1e-9);
+    }
+}
@@ -0,0 +1,398 @@
+/*
+William Albertini
+
+Microgravity model of the arm on a free floating CubeSat
+bus. Nothing holds the bus still in orbit, so whenever the
+arm moves the bus translates and rotates the other way to
+keep the linear and angular momentum of the whole system
+at zero (the arm is assumed to start from rest).
+
+Joint motion is treated as prescribed (the motor
+controllers run stiff position loops), and the bus
+velocity is solved from momentum conservation at every
+step. Two things come out of a planned move:
+
+- the attitude disturbance if the bus is left free
+  floating (how far and how fast it rotates)
+- the reaction torque and momentum the attitude control
+  system would have to absorb to hold the bus still
+
+Only the planar shoulder/elbow/wrist links are modelled,
+roll and spool are light enough to ignore. Units follow
+the arm model (ArmDynamics), so the bus inertia must use
+the same length unit as the link lengths.
+
+*/
+
+// the momentum sums read better with plain indices
+#![allow(clippy::needless_range_loop)]
+
+// internal imports
+use super::arm_dynamics::{solve_3x3, ArmDynamics};
+use super::joint_trajectory::JointTrajectory;
+
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SpacecraftProperties
+{
+    pub mass: f64,
+    // moment of inertia of the bus about its center of mass
+    pub inertia: f64,
+    // where the shoulder is mounted, measured from the bus center of mass
+    pub mount_offset: [f64; 2],
+}
+
+impl SpacecraftProperties
+{
+    pub fn new(mass: f64, inertia: f64, mount_offset: [f64; 2]) -> SpacecraftProperties
+    {
+        SpacecraftProperties { mass, inertia, mount_offset }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AttitudeControlLimits
+{
+    // most torque the attitude control system can apply
+    pub max_torque: f64,
+    // most momentum it can store (reaction wheel capacity)
+    pub max_momentum: f64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ReactionReport
+{
+    // bus rotation (radians) if left free floating
+    pub final_attitude_change: f64,
+    pub peak_attitude_change: f64,
+    pub peak_base_rate: f64,
+    // what the attitude control system sees if it holds the bus still
+    pub peak_reaction_torque: f64,
+    pub peak_reaction_momentum: f64,
+}
+
+impl ReactionReport
+{
+    pub fn within_budget(&self, limits: &AttitudeControlLimits) -> bool
+    {
+        self.peak_reaction_torque <= limits.max_torque && self.peak_reaction_momentum <= limits.max_momentum
+    }
+}
+
+
+#[derive(Clone)]
+pub struct FreeFloatingArm
+{
+    // only used for link geometry and mass properties
+    model: ArmDynamics,
+    spacecraft: SpacecraftProperties,
+    // bus [x, y, attitude] and its rate of change
+    base_pose: [f64; 3],
+    base_velocity: [f64; 3],
+    joint_angles: [f64; 3],
+}
+
+
+impl FreeFloatingArm
+{
+    pub fn new(model: ArmDynamics, spacecraft: SpacecraftProperties) -> FreeFloatingArm
+    {
+        let joint_angles = model.get_joint_angles();
+        FreeFloatingArm {
+            model,
+            spacecraft,
+            base_pose: [0.0; 3],
+            base_velocity: [0.0; 3],
+            joint_angles,
+        }
+    }
+
+    pub fn get_base_pose(&self) -> [f64; 3]
+    {
+        self.base_pose
+    }
+
+    pub fn get_base_velocity(&self) -> [f64; 3]
+    {
+        self.base_velocity
+    }
+
+    pub fn get_joint_angles(&self) -> [f64; 3]
+    {
+        self.joint_angles
+    }
+
+    pub fn reset(&mut self, joint_angles: [f64; 3])
+    {
+        // bus back at the origin and at rest
+        self.base_pose = [0.0; 3];
+        self.base_velocity = [0.0; 3];
+        self.joint_angles = joint_angles;
+    }
+
+    pub fn move_joints(&mut self, joint_angles: [f64; 3], dt: f64)
+    {
+        // evaluate at the middle of the step so the integration is second order
+        let mut joint_velocities = [0.0; 3];
+        let mut midpoint = [0.0; 3];
+        for i in 0..3
+        {
+            joint_velocities[i] = (joint_angles[i] - self.joint_angles[i]) / dt;
+            midpoint[i] = 0.5 * (joint_angles[i] + self.joint_angles[i]);
+        }
+
+        // half step the bus, solve its velocity there and take the full step
+        let mut half_pose = self.base_pose;
+        for i in 0..3
+        {
+            half_pose[i] += 0.5 * dt * self.base_velocity[i];
+        }
+        self.base_velocity = self.base_velocity_for(half_pose, midpoint, joint_velocities);
+        for i in 0..3
+        {
+            self.base_pose[i] += dt * self.base_velocity[i];
+        }
+        self.joint_angles = joint_angles;
+    }
+
+    pub fn base_velocity_for(&self, base_pose: [f64; 3], joint_angles: [f64; 3], joint_velocities: [f64; 3]) -> [f64; 3]
+    {
+        // momentum is linear in the velocities, so build it up one unit
+        // velocity at a time and solve for the bus motion that cancels the arm
+        let arm_only = self.momentum(base_pose, [0.0; 3], joint_angles, joint_velocities);
+        let mut columns = [[0.0; 3]; 3];
+        for (axis, column) in columns.iter_mut().enumerate()
+        {
+            let mut unit = [0.0; 3];
+            unit[axis] = 1.0;
+            *column = self.momentum(base_pose, unit, joint_angles, [0.0; 3]);
+        }
+
+        let matrix = [[columns[0][0], columns[1][0], columns[2][0]],
+                      [columns[0][1], columns[1][1], columns[2][1]],
+                      [columns[0][2], columns[1][2], columns[2][2]]];
+        solve_3x3(matrix, [-arm_only[0], -arm_only[1], -arm_only[2]])
+    }
+
+    pub fn momentum(&self, base_pose: [f64; 3], base_velocity: [f64; 3], joint_angles: [f64; 3], joint_velocities: [f64; 3]) -> [f64; 3]
+    {
+        // [linear x, linear y, angular] momentum of the bus plus arm, about the inertial origin
+        let [bx, by, attitude] = base_pose;
+        let [vx, vy, rate] = base_velocity;
+        let (sin, cos) = attitude.sin_cos();
+        let rotate = |v: [f64; 2]| [cos * v[0] - sin * v[1], sin * v[0] + cos * v[1]];
+
+        let mut linear = [self.spacecraft.mass * vx, self.spacecraft.mass * vy];
+        let mut angular = self.spacecraft.inertia * rate + self.spacecraft.mass * (bx * vy - by * vx);
+
+        let centers = self.model.centers_of_mass(joint_angles);
+        let links = self.model.get_links();
+        let mut link_rate = rate;
+
+        for link in 0..3
+        {
+            // center of mass position relative to the bus, in the inertial frame
+            let offset = rotate([self.spacecraft.mount_offset[0] + centers[link][0],
+                                 self.spacecraft.mount_offset[1] + centers[link][1]]);
+            let position = [bx + offset[0], by + offset[1]];
+
+            // bus translation + bus rotation + joint motion
+            let jacobian = self.model.center_of_mass_jacobian(joint_angles, link);
+            let mut arm_velocity = [0.0; 2];
+            for j in 0..3
+            {
+                arm_velocity[0] += jacobian[j][0] * joint_velocities[j];
+                arm_velocity[1] += jacobian[j][1] * joint_velocities[j];
+            }
+            let arm_velocity = rotate(arm_velocity);
+            let velocity = [vx - rate * offset[1] + arm_velocity[0],
+                            vy + rate * offset[0] + arm_velocity[1]];
+
+            link_rate += joint_velocities[link];
+            let mass = links[link].mass;
+            linear[0] += mass * velocity[0];
+            linear[1] += mass * velocity[1];
+            angular += mass * (position[0] * velocity[1] - position[1] * velocity[0]) + links[link].inertia * link_rate;
+        }
+
+        [linear[0], linear[1], angular]
+    }
+
+    pub fn system_center_of_mass(&self) -> [f64; 2]
+    {
+        // bus plus arm, this never moves when the system starts at rest
+        let [bx, by, attitude] = self.base_pose;
+        let (sin, cos) = attitude.sin_cos();
+        let links = self.model.get_links();
+        let mut total_mass = self.spacecraft.mass;
+        let mut weighted = [self.spacecraft.mass * bx, self.spacecraft.mass * by];
+
+        for (center, link) in self.model.centers_of_mass(self.joint_angles).iter().zip(links.iter())
+        {
+            let offset = [self.spacecraft.mount_offset[0] + center[0], self.spacecraft.mount_offset[1] + center[1]];
+            weighted[0] += link.mass * (bx + cos * offset[0] - sin * offset[1]);
+            weighted[1] += link.mass * (by + sin * offset[0] + cos * offset[1]);
+            total_mass += link.mass;
+        }
+
+        [weighted[0] / total_mass, weighted[1] / total_mass]
+    }
+
+    pub fn held_base_momentum(&self, joint_angles: [f64; 3], joint_velocities: [f64; 3]) -> f64
+    {
+        // angular momentum of the arm about the bus center of mass with the bus held still,
+        // this is what the attitude control system has to soak up
+        self.momentum([0.0; 3], [0.0; 3], joint_angles, joint_velocities)[2]
+    }
+
+    pub fn analyze(&self, trajectory: &JointTrajectory) -> ReactionReport
+    {
+        // fly the trajectory once free floating and once with the bus held
+        let mut free = self.clone();
+        free.reset(trajectory.start());
+
+        let mut report = ReactionReport {
+            final_attitude_change: 0.0,
+            peak_attitude_change: 0.0,
+            peak_base_rate: 0.0,
+            peak_reaction_torque: 0.0,
+            peak_reaction_momentum: 0.0,
+        };
+        let mut previous_momentum = 0.0;
+
+        for pair in trajectory.samples().windows(2)
+        {
+            let (t0, q0) = pair[0];
+            let (t1, q1) = pair[1];
+            let dt = t1 - t0;
+
+            // free floating bus
+            free.move_joints(q1, dt);
+            let attitude = free.base_pose[2];
+            report.peak_attitude_change = report.peak_attitude_change.max(attitude.abs());
+            report.peak_base_rate = report.peak_base_rate.max(free.base_velocity[2].abs());
+
+            // held bus, torque is the rate of change of the arm's momentum
+            let mut midpoint = [0.0; 3];
+            let mut joint_velocities = [0.0; 3];
+            for i in 0..3
+            {
+                midpoint[i] = 0.5 * (q0[i] + q1[i]);
+                joint_velocities[i] = (q1[i] - q0[i]) / dt;
+            }
+            let held_momentum = self.held_base_momentum(midpoint, joint_velocities);
+            report.peak_reaction_momentum = report.peak_reaction_momentum.max(held_momentum.abs());
+            report.peak_reaction_torque = report.peak_reaction_torque.max(((held_momentum - previous_momentum) / dt).abs());
+            previous_momentum = held_momentum;
+        }
+
+        report.final_attitude_change = free.base_pose[2];
+        report
+    }
+}
+
+
+
+
+// ------------------------------- unit tests -------------------------------------
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use all_asserts::assert_near;
+    use crate::robotics::arm_kinematics::InverseKinematicSolver;
+    use crate::simulation::arm_dynamics::{LinkProperties, MotorProperties};
+
+    fn test_model(links: [LinkProperties; 3], joint_angles: [f64; 3]) -> ArmDynamics
+    {
+        let solver = InverseKinematicSolver::new(1.0, 0.5, 0.3);
+        let motors = [MotorProperties::new(10.0, 0.0); 3];
+        ArmDynamics::new(&solver, links, motors, 0.0, joint_angles)
+    }
+
+    fn rods() -> [LinkProperties; 3]
+    {
+        [LinkProperties::uniform_rod(2.0, 1.0),
+         LinkProperties::uniform_rod(1.0, 0.5),
+         LinkProperties::uniform_rod(0.5, 0.3)]
+    }
+
+    #[test]
+    fn test_no_motion_no_disturbance()
+    {
+        let arm = FreeFloatingArm::new(test_model(rods(), [0.2, 0.3, 0.1]), SpacecraftProperties::new(12.0, 0.5, [0.1, 0.0]));
+        let trajectory = JointTrajectory::interpolated([0.2, 0.3, 0.1], [0.2, 0.3, 0.1], 1.0, 10);
+        let report = arm.analyze(&trajectory);
+
+        assert_eq!(report.final_attitude_change, 0.0);
+        assert_eq!(report.peak_reaction_torque, 0.0);
+    }
+
+    #[test]
+    fn test_spinning_disc_on_bus_center()
+    {
+        // a shoulder link with its mass on the joint is a reaction wheel, the bus
+        // turns back by the ratio of the inertias: I_bus dtheta + I_link (dtheta + dq) = 0
+        let links = [LinkProperties::new(1.0, 0.2, 0.0),
+                     LinkProperties::new(0.0, 0.0, 0.0),
+                     LinkProperties::new(0.0, 0.0, 0.0)];
+        let arm = FreeFloatingArm::new(test_model(links, [0.0; 3]), SpacecraftProperties::new(10.0, 0.8, [0.0, 0.0]));
+        let trajectory = JointTrajectory::interpolated([0.0; 3], [1.0, 0.0, 0.0], 2.0, 200);
+        let report = arm.analyze(&trajectory);
+
+        assert_near!(report.final_attitude_change, -0.2 / (0.8 + 0.2), 1e-6);
+    }
+
+    #[test]
+    fn test_center_of_mass_fixed()
+    {
+        // with no linear momentum the system center of mass can not move
+        let mut arm = FreeFloatingArm::new(test_model(rods(), [0.2, 0.3, 0.1]), SpacecraftProperties::new(12.0, 0.5, [0.1, 0.05]));
+        let start = arm.system_center_of_mass();
+        let trajectory = JointTrajectory::interpolated([0.2, 0.3, 0.1], [1.2, -0.4, 0.6], 1.0, 1000);
+        for pair in trajectory.samples().windows(2)
+        {
+            arm.move_joints(pair[1].1, pair[1].0 - pair[0].0);
+        }
+        let end = arm.system_center_of_mass();
+
+        assert_near!(end[0], start[0], 1e-5);
+        assert_near!(end[1], start[1], 1e-5);
+        assert!(arm.get_base_pose()[2].abs() > 1e-3, "bus should have rotated");
+    }
+
+    #[test]
+    fn test_heavy_bus_barely_moves()
+    {
+        let links = rods();
+        let light = FreeFloatingArm::new(test_model(links, [0.2, 0.3, 0.1]), SpacecraftProperties::new(12.0, 0.5, [0.1, 0.0]));
+        let heavy = FreeFloatingArm::new(test_model(links, [0.2, 0.3, 0.1]), SpacecraftProperties::new(1.0e6, 1.0e6, [0.1, 0.0]));
+        let trajectory = JointTrajectory::interpolated([0.2, 0.3, 0.1], [1.2, -0.4, 0.6], 1.0, 200);
+
+        let light_report = light.analyze(&trajectory);
+        let heavy_report = heavy.analyze(&trajectory);
+
+        assert!(heavy_report.final_attitude_change.abs() < 1e-5);
+        assert!(light_report.final_attitude_change.abs() > 1e-2);
+        // holding the bus still needs the same torque no matter how heavy it is
+        assert_near!(heavy_report.peak_reaction_torque, light_report.peak_reaction_torque, 1e-9);
+    }
+
+    #[test]
+    fn test_faster_move_needs_more_torque()
+    {
+        let arm = FreeFloatingArm::new(test_model(rods(), [0.2, 0.3, 0.1]), SpacecraftProperties::new(12.0, 0.5, [0.1, 0.0]));
+        let slow = JointTrajectory::interpolated([0.2, 0.3, 0.1], [1.2, -0.4, 0.6], 4.0, 400);
+        let fast = slow.time_scaled(0.5);
+
+        let slow_report = arm.analyze(&slow);
+        let fast_report = arm.analyze(&fast);
+        let limits = AttitudeControlLimits { max_torque: slow_report.peak_reaction_torque * 1.5, max_momentum: 10.0 };
+
+        // twice as fast is four times the torque and the same path through attitude
+        assert_near!(fast_report.peak_reaction_torque / slow_report.peak_reaction_torque, 4.0, 0.1);
+        assert_near!(fast_report.final_attitude_change, slow_report.final_attitude_change, 1e-6);
+        assert!(slow_report.within_budget(&limits));
+        assert!(!fast_report.within_budget(&limits));
+    }
+}
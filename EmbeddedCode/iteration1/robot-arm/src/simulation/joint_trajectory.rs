@@ -0,0 +1,160 @@
+/*
+William Albertini
+
+A JointTrajectory is a time stamped list of shoulder, elbow
+and wrist angles (same convention as the inverse kinematic
+solver). It is the common currency between anything that
+plans a move and anything that evaluates one, like the
+free floating base model.
+
+Interpolated moves use a quintic time scaling so the joints
+start and stop with zero velocity and acceleration.
+
+*/
+
+// internal imports
+use crate::arm_errors::RoboticArmError;
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct JointTrajectory
+{
+    // (time in seconds, joint angles in radians), times always increase
+    samples: Vec<(f64, [f64; 3])>,
+}
+
+
+impl JointTrajectory
+{
+    pub fn try_from_samples(samples: Vec<(f64, [f64; 3])>) -> Result<JointTrajectory, RoboticArmError>
+    {
+        // a trajectory needs a start and an end, and time can only go forward
+        if samples.len() < 2
+        {
+            return Err(RoboticArmError::InvalidTrajectory("Trajectory needs at least two samples".into()));
+        }
+        if samples.windows(2).any(|pair| pair[1].0 <= pair[0].0)
+        {
+            return Err(RoboticArmError::InvalidTrajectory("Trajectory times must increase".into()));
+        }
+
+        Ok(JointTrajectory { samples })
+    }
+
+    pub fn interpolated(start: [f64; 3], end: [f64; 3], duration: f64, steps: usize) -> JointTrajectory
+    {
+        // straight line in joint space with smooth start and stop
+        let steps = steps.max(1);
+        let samples = (0..=steps)
+            .map(|step| {
+                let tau = step as f64 / steps as f64;
+                let s = smooth_step(tau);
+                let mut angles = [0.0; 3];
+                for (angle, (a, b)) in angles.iter_mut().zip(start.iter().zip(end.iter()))
+                {
+                    *angle = a + (b - a) * s;
+                }
+                (tau * duration, angles)
+            })
+            .collect();
+
+        JointTrajectory { samples }
+    }
+
+    pub fn through_waypoints(waypoints: &[[f64; 3]], duration: f64, steps: usize) -> JointTrajectory
+    {
+        // one smooth segment between each pair of waypoints, each segment
+        // gets an equal share of the time
+        let segments = waypoints.len().saturating_sub(1).max(1);
+        let segment_duration = duration / segments as f64;
+        let segment_steps = (steps / segments).max(1);
+        let mut samples: Vec<(f64, [f64; 3])> = vec![(0.0, waypoints.first().copied().unwrap_or([0.0; 3]))];
+
+        for (index, pair) in waypoints.windows(2).enumerate()
+        {
+            let segment = JointTrajectory::interpolated(pair[0], pair[1], segment_duration, segment_steps);
+            let offset = index as f64 * segment_duration;
+            samples.extend(segment.samples.into_iter().skip(1).map(|(time, angles)| (time + offset, angles)));
+        }
+
+        JointTrajectory { samples }
+    }
+
+    pub fn samples(&self) -> &[(f64, [f64; 3])]
+    {
+        &self.samples
+    }
+
+    pub fn duration(&self) -> f64
+    {
+        self.samples[self.samples.len() - 1].0 - self.samples[0].0
+    }
+
+    pub fn start(&self) -> [f64; 3]
+    {
+        self.samples[0].1
+    }
+
+    pub fn end(&self) -> [f64; 3]
+    {
+        self.samples[self.samples.len() - 1].1
+    }
+
+    pub fn time_scaled(&self, factor: f64) -> JointTrajectory
+    {
+        // same path, factor > 1 makes the move slower
+        JointTrajectory {
+            samples: self.samples.iter().map(|(time, angles)| (time * factor, *angles)).collect(),
+        }
+    }
+}
+
+
+fn smooth_step(tau: f64) -> f64
+{
+    // quintic with zero velocity and acceleration at both ends
+    let tau = tau.clamp(0.0, 1.0);
+    tau * tau * tau * (10.0 - 15.0 * tau + 6.0 * tau * tau)
+}
+
+
+
+
+// ------------------------------- unit tests -------------------------------------
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use all_asserts::assert_near;
+
+    #[test]
+    fn test_interpolated_end_points()
+    {
+        let trajectory = JointTrajectory::interpolated([0.0, 1.0, 2.0], [1.0, 0.0, 2.0], 4.0, 40);
+
+        assert_eq!(trajectory.start(), [0.0, 1.0, 2.0]);
+        assert_eq!(trajectory.end(), [1.0, 0.0, 2.0]);
+        assert_eq!(trajectory.samples().len(), 41);
+        assert_near!(trajectory.duration(), 4.0, 1e-12);
+    }
+
+    #[test]
+    fn test_waypoints_visited()
+    {
+        let waypoints = [[0.0; 3], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]];
+        let trajectory = JointTrajectory::through_waypoints(&waypoints, 2.0, 20);
+
+        // the middle waypoint is reached half way through
+        let middle = trajectory.samples().iter().find(|(time, _)| (time - 1.0).abs() < 1e-9).unwrap();
+        assert_eq!(middle.1, [1.0, 0.0, 0.0]);
+        assert_eq!(trajectory.end(), [1.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn test_bad_samples_rejected()
+    {
+        let result = JointTrajectory::try_from_samples(vec![(0.0, [0.0; 3]), (0.0, [1.0; 3])]);
+
+        assert_eq!(result, Err(RoboticArmError::InvalidTrajectory("Trajectory times must increase".into())));
+    }
+}
@@ -0,0 +1,5 @@
+pub mod arm_dynamics;
+pub mod simulated_arm;
+pub mod joint_trajectory;
+pub mod free_floating;
+pub mod trajectory_planner;
@@ -0,0 +1,369 @@
+/*
+William Albertini
+
+SimulatedArm stands in for the motor controllers. It takes
+the same writes as the SPI driver (MotorDriver), decodes
+them the way the Atmega328p does, and drives each joint
+towards its target with a torque limited position loop.
+The shoulder, elbow and wrist are integrated with
+ArmDynamics, roll and spool are not part of the planar
+model so they are treated as rate limited actuators.
+
+Positions written to the simulator go through the solver's
+encoder map, so like the real motor controllers they are
+relative to where the arm was at start up unless the zero
+offsets are calibrated.
+update() advances the simulation by the wall clock time
+since the last call (multiplied by the time scale, at
+most MAX_UPDATE_TIME), so the control loop in main.rs can
+run against it in real time or faster.
+
+With set_free_floating() the arm is mounted on a free
+floating bus (FreeFloatingArm) and the bus attitude is
+tracked as the joints move.
+
+*/
+
+// external imports
+use std::f64::consts::PI;
+use std::time::Instant;
+
+// internal imports
+use crate::arm_errors::RoboticArmError;
+use crate::robotics::arm_kinematics::InverseKinematicSolver;
+use crate::robotics::arm_state::{AngleToEncoderMap, ArmState, Joint, RoboticArmSolver};
+use crate::robotics::robot_driver::{check_mac_number, JointWiring, MotorDriver};
+use super::arm_dynamics::{ArmDynamics, LinkProperties, MotorProperties};
+use super::free_floating::{FreeFloatingArm, SpacecraftProperties};
+
+
+// integration step of the dynamics (seconds)
+const DEFAULT_TIME_STEP: f64 = 0.0005;
+// the motor controllers only decode 13 bits of position
+const POSITION_MASK: u16 = 0x1FFF;
+// most simulated time one update() runs (seconds), a large
+// time scale runs as fast as it can instead of stalling the loop
+pub const MAX_UPDATE_TIME: f64 = 1.0;
+
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ServoGains
+{
+    pub kp: f64,
+    pub kd: f64,
+}
+
+
+pub struct SimulatedArm
+{
+    dynamics: ArmDynamics,
+    solver: InverseKinematicSolver,
+    joint_map: AngleToEncoderMap,
+    wiring: JointWiring,
+    gains: [ServoGains; 3],
+    // commanded angles (radians)
+    targets: [f64; 5],
+    // roll and spool positions and their top speed (rad/s)
+    wrist_angles: [f64; 2],
+    wrist_rate: f64,
+    // microgravity bus, None when the base is bolted down
+    base: Option<FreeFloatingArm>,
+    time_step: f64,
+    time_scale: f64,
+    sim_time: f64,
+    last_update: Option<Instant>,
+}
+
+
+impl SimulatedArm
+{
+    pub fn try_new(arm: &RoboticArmSolver,
+                   links: [LinkProperties; 3],
+                   motors: [MotorProperties; 3],
+                   gravity: f64,
+                   servo_bandwidth: f64,
+                   wrist_rate: f64,
+                   wiring: JointWiring) -> Result<SimulatedArm, RoboticArmError>
+    {
+        // start the simulation wherever the solver thinks the arm is
+        let solver = *arm.get_solver();
+        let [x, y, si] = arm.get_end_effector_position();
+        let joint_angles = solver.find_joint_angles(x, y, si)?;
+        let dynamics = ArmDynamics::new(&solver, links, motors, gravity, joint_angles);
+
+        // critically damped position loop on each joint, tuned from the
+        // inertia each motor sees in the starting pose
+        let mass_matrix = dynamics.mass_matrix(joint_angles);
+        let gains = [0, 1, 2].map(|i| {
+            let inertia = mass_matrix[i][i];
+            ServoGains {
+                kp: inertia * servo_bandwidth * servo_bandwidth,
+                kd: 2.0 * inertia * servo_bandwidth,
+            }
+        });
+
+        Ok(SimulatedArm {
+            dynamics,
+            solver,
+            joint_map: *arm.get_joint_map(),
+            wiring,
+            gains,
+            targets: [joint_angles[0], joint_angles[1], joint_angles[2], 0.0, 0.0],
+            wrist_angles: [0.0; 2],
+            wrist_rate,
+            base: None,
+            time_step: DEFAULT_TIME_STEP,
+            time_scale: 1.0,
+            sim_time: 0.0,
+            last_update: None,
+        })
+    }
+
+    pub fn set_time_scale(&mut self, time_scale: f64)
+    {
+        // 1.0 is real time, larger values run faster than real time
+        self.time_scale = time_scale;
+    }
+
+    pub fn set_free_floating(&mut self, spacecraft: SpacecraftProperties)
+    {
+        // bus starts at rest wherever the arm currently is
+        self.base = Some(FreeFloatingArm::new(self.dynamics.clone(), spacecraft));
+    }
+
+    pub fn get_base_pose(&self) -> Option<[f64; 3]>
+    {
+        // [x, y, attitude] of the bus, only when free floating
+        self.base.as_ref().map(|base| base.get_base_pose())
+    }
+
+    pub fn get_sim_time(&self) -> f64
+    {
+        self.sim_time
+    }
+
+    pub fn get_dynamics(&self) -> &ArmDynamics
+    {
+        &self.dynamics
+    }
+
+    pub fn get_joint_angles(&self) -> [f64; 5]
+    {
+        let q = self.dynamics.get_joint_angles();
+        [q[0], q[1], q[2], self.wrist_angles[0], self.wrist_angles[1]]
+    }
+
+    pub fn get_target_angles(&self) -> [f64; 5]
+    {
+        self.targets
+    }
+
+    pub fn get_end_effector_position(&self) -> [f64; 3]
+    {
+        self.solver.find_end_effector_position(self.dynamics.get_joint_angles())
+    }
+
+    pub fn get_encoder_positions(&self) -> ArmState
+    {
+        // what the motor controllers would read back from their encoders
+        let angles = self.get_joint_angles();
+        ArmState::from_array(std::array::from_fn(|i| self.joint_map.angle_to_ticks(Joint::ALL[i], angles[i])))
+    }
+
+    pub fn advance(&mut self, seconds: f64)
+    {
+        // integrate in fixed steps, any remainder is carried into the last step
+        let mut remaining = seconds;
+        while remaining > 0.0
+        {
+            let dt = remaining.min(self.time_step);
+            self.step(dt);
+            remaining -= dt;
+        }
+    }
+
+    fn step(&mut self, dt: f64)
+    {
+        // motor controller position loops
+        let q = self.dynamics.get_joint_angles();
+        let qd = self.dynamics.get_joint_velocities();
+        let mut torques = [0.0; 3];
+        for i in 0..3
+        {
+            torques[i] = self.gains[i].kp * (self.targets[i] - q[i]) - self.gains[i].kd * qd[i];
+        }
+        self.dynamics.step(torques, dt);
+
+        // whatever the joints just did, the bus reacts to
+        if let Some(base) = &mut self.base
+        {
+            base.move_joints(self.dynamics.get_joint_angles(), dt);
+        }
+
+        // roll and spool move straight to their target at a fixed rate
+        let max_move = self.wrist_rate * dt;
+        for i in 0..2
+        {
+            let error = self.targets[i + 3] - self.wrist_angles[i];
+            self.wrist_angles[i] += error.clamp(-max_move, max_move);
+        }
+
+        self.sim_time += dt;
+    }
+}
+
+
+impl MotorDriver for SimulatedArm
+{
+    fn write_mac(&mut self, data: u16, motor: u8, mac_number: u8) -> Result<(), RoboticArmError>
+    {
+        // unwired slots are ignored, same as a motor controller with nothing plugged in
+        check_mac_number(mac_number)?;
+        let Some(joint) = self.wiring.find_joint(mac_number, motor) else {
+            return Ok(());
+        };
+        let index = Joint::ALL.iter().position(|j| *j == joint).unwrap_or(0);
+
+        // a turn either way reads the same, go round the other way only if it is clearly shorter
+        let angle = self.joint_map.ticks_to_angle(joint, data & POSITION_MASK);
+        let last = self.targets[index];
+        let unwrapped = angle - 2.0 * PI * ((angle - last) / (2.0 * PI)).round();
+        self.targets[index] = if (unwrapped - last).abs() < (angle - last).abs() - 1e-9 { unwrapped } else { angle };
+        Ok(())
+    }
+
+    fn update(&mut self)
+    {
+        let now = Instant::now();
+        if let Some(last_update) = self.last_update
+        {
+            let elapsed = now.duration_since(last_update).as_secs_f64();
+            self.advance((elapsed * self.time_scale).min(MAX_UPDATE_TIME));
+        }
+        self.last_update = Some(now);
+    }
+
+    fn read_feedback(&mut self) -> Option<ArmState>
+    {
+        Some(self.get_encoder_positions())
+    }
+}
+
+
+
+
+// ------------------------------- unit tests -------------------------------------
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use all_asserts::assert_near;
+
+    fn test_arm() -> SimulatedArm
+    {
+        let map = AngleToEncoderMap::new(5000, 5000, 5000, 5000, 5000);
+        let arm = RoboticArmSolver::try_new_from_ef_position(10.0, 5.0, 3.0, 12.0, 6.0, 0.5, map).unwrap();
+        let links = [LinkProperties::uniform_rod(2.0, 10.0),
+                     LinkProperties::uniform_rod(1.0, 5.0),
+                     LinkProperties::uniform_rod(0.5, 3.0)];
+        let motors = [MotorProperties::new(500.0, 0.1); 3];
+
+        SimulatedArm::try_new(&arm, links, motors, 0.0, 20.0, 2.0, JointWiring::default()).unwrap()
+    }
+
+    #[test]
+    fn test_starts_at_solver_position()
+    {
+        let sim = test_arm();
+        let [x, y, si] = sim.get_end_effector_position();
+
+        assert_near!(x, 12.0, 1e-6);
+        assert_near!(y, 6.0, 1e-6);
+        assert_near!(si, 0.5, 1e-6);
+        assert_eq!(sim.get_encoder_positions(), ArmState::from_array([0; 5]));
+    }
+
+    #[test]
+    fn test_joint_reaches_target()
+    {
+        // 250 ticks of 5000 is a twentieth of a turn on the elbow
+        let mut sim = test_arm();
+        let start = sim.get_joint_angles()[1];
+        let (mac_number, motor) = JointWiring::default().elbow;
+        sim.write_mac(250, motor, mac_number).unwrap();
+        sim.advance(2.0);
+
+        let angles = sim.get_joint_angles();
+        assert_near!(angles[1] - start, 2.0 * PI / 20.0, 1e-3);
+        assert_eq!(sim.get_encoder_positions().elbow, 250);
+        assert_near!(sim.get_sim_time(), 2.0, 1e-9);
+    }
+
+    #[test]
+    fn test_update_time_capped()
+    {
+        // a huge time scale still only runs MAX_UPDATE_TIME per update
+        let mut sim = test_arm();
+        sim.set_time_scale(1e9);
+        sim.update();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        sim.update();
+
+        assert_near!(sim.get_sim_time(), MAX_UPDATE_TIME, 1e-9);
+    }
+
+    #[test]
+    fn test_roll_rate_limited()
+    {
+        // a half turn of roll at 2 rad/s takes longer than half a second
+        let mut sim = test_arm();
+        let (mac_number, motor) = JointWiring::default().roll;
+        sim.write_mac(2500, motor, mac_number).unwrap();
+        sim.advance(0.5);
+
+        assert_near!(sim.get_joint_angles()[3], 1.0, 1e-9);
+    }
+
+    #[test]
+    fn test_free_floating_bus_reacts()
+    {
+        // swinging the shoulder one way turns the bus the other way
+        let mut sim = test_arm();
+        sim.set_free_floating(SpacecraftProperties::new(12.0, 50.0, [0.0, 0.0]));
+        let (mac_number, motor) = JointWiring::default().shoulder;
+        sim.write_mac(250, motor, mac_number).unwrap();
+        sim.advance(2.0);
+
+        let [_, _, attitude] = sim.get_base_pose().unwrap();
+        assert!(attitude < -1e-3, "bus attitude was {attitude}");
+    }
+
+    #[test]
+    fn test_unwired_motor_ignored()
+    {
+        let mut sim = test_arm();
+        let targets = sim.get_target_angles();
+        sim.write_mac(100, 1, 3).unwrap();
+
+        assert_eq!(sim.get_target_angles(), targets);
+        // a controller that is not on the bus is a wiring mistake
+        assert!(sim.write_mac(100, 0, 4).is_err());
+    }
+
+    #[test]
+    fn test_hold_stops_where_it_is()
+    {
+        // cut a move short, the arm stays where it was when hold was called
+        let mut sim = test_arm();
+        let wiring = JointWiring::default();
+        let (mac_number, motor) = wiring.shoulder;
+        sim.write_mac(500, motor, mac_number).unwrap();
+        sim.advance(0.05);
+
+        let held = sim.hold(&wiring).unwrap().unwrap();
+        sim.advance(2.0);
+
+        assert!(held.shoulder > 0 && held.shoulder < 500, "held at {}", held.shoulder);
+        assert!(sim.get_encoder_positions().shoulder.abs_diff(held.shoulder) <= 2);
+    }
+}
@@ -0,0 +1,293 @@
+/*
+William Albertini
+
+Reaction minimizing planner for free floating operation.
+A reach to the same end effector pose can be flown many
+ways, and each one hands a different amount of angular
+momentum to the CubeSat bus. The planner tries:
+
+- elbow down and elbow up solutions for the goal
+- joint goals unwrapped to the nearest equivalent angle
+  (the solver returns theta1 in 0-2PI, so a straight
+  line between two solutions can swing most of a turn)
+- moving all joints together, the wrist first, or the
+  shoulder and elbow first
+- the shortest duration that stays inside the attitude
+  control limits, and the longest allowed duration
+
+Every candidate is flown through FreeFloatingArm::analyze
+and scored with cost(). Candidates that exceed the
+attitude control torque or momentum are thrown out.
+
+*/
+
+// external imports
+use std::f64::consts::PI;
+
+// internal imports
+use crate::arm_errors::RoboticArmError;
+use crate::robotics::arm_kinematics::InverseKinematicSolver;
+use super::free_floating::{AttitudeControlLimits, FreeFloatingArm, ReactionReport};
+use super::joint_trajectory::JointTrajectory;
+
+
+// shortest move the planner will hand out (seconds)
+const MIN_DURATION: f64 = 0.1;
+
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PlannerSettings
+{
+    pub limits: AttitudeControlLimits,
+    // longest a single reach is allowed to take (seconds)
+    pub max_duration: f64,
+    // cost of one radian of bus rotation if it were left free floating
+    pub attitude_weight: f64,
+    // cost of one second of move time, without it the slowest plan always wins
+    pub duration_weight: f64,
+    // samples per trajectory
+    pub steps: usize,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PathShape
+{
+    // every joint moves at once
+    Direct,
+    // wrist first, then shoulder and elbow
+    DistalFirst,
+    // shoulder and elbow first, then wrist
+    ProximalFirst,
+}
+
+impl PathShape
+{
+    pub const ALL: [PathShape; 3] = [PathShape::Direct, PathShape::DistalFirst, PathShape::ProximalFirst];
+
+    pub fn waypoints(&self, start: [f64; 3], goal: [f64; 3]) -> Vec<[f64; 3]>
+    {
+        match self
+        {
+            PathShape::Direct => vec![start, goal],
+            PathShape::DistalFirst => vec![start, [start[0], start[1], goal[2]], goal],
+            PathShape::ProximalFirst => vec![start, [goal[0], goal[1], start[2]], goal],
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlannedMove
+{
+    pub trajectory: JointTrajectory,
+    pub report: ReactionReport,
+    pub cost: f64,
+    pub elbow_up: bool,
+    pub shape: PathShape,
+}
+
+
+pub struct ReactionPlanner
+{
+    solver: InverseKinematicSolver,
+    arm: FreeFloatingArm,
+    settings: PlannerSettings,
+}
+
+
+impl ReactionPlanner
+{
+    pub fn new(solver: InverseKinematicSolver, arm: FreeFloatingArm, settings: PlannerSettings) -> ReactionPlanner
+    {
+        ReactionPlanner { solver, arm, settings }
+    }
+
+    pub fn cost(&self, report: &ReactionReport, duration: f64) -> f64
+    {
+        // momentum the bus has to soak up (as a fraction of what the wheels can hold),
+        // plus how far the bus would turn, plus how long the move takes
+        report.peak_reaction_momentum / self.settings.limits.max_momentum
+            + self.settings.attitude_weight * report.final_attitude_change.abs()
+            + self.settings.duration_weight * duration
+    }
+
+    pub fn naive_plan(&self, start: [f64; 3], goal: [f64; 3]) -> Result<JointTrajectory, RoboticArmError>
+    {
+        // straight line from the start joints to whatever the solver returns
+        let goal_joints = self.solver.find_joint_angles(goal[0], goal[1], goal[2])?;
+        Ok(JointTrajectory::interpolated(start, goal_joints, self.settings.max_duration, self.settings.steps))
+    }
+
+    pub fn plan(&self, start: [f64; 3], goal: [f64; 3]) -> Result<PlannedMove, RoboticArmError>
+    {
+        let mut best: Option<PlannedMove> = None;
+
+        for elbow_up in [false, true]
+        {
+            // an unreachable elbow solution just means one less candidate
+            let Ok(goal_joints) = self.solver.find_joint_angles_with_elbow(goal[0], goal[1], goal[2], elbow_up) else {
+                continue;
+            };
+            let goal_joints = unwrap_near(start, goal_joints);
+
+            for shape in PathShape::ALL
+            {
+                for candidate in self.candidates(start, goal_joints, elbow_up, shape)
+                {
+                    if best.as_ref().is_none_or(|best| candidate.cost < best.cost)
+                    {
+                        best = Some(candidate);
+                    }
+                }
+            }
+        }
+
+        best.ok_or(RoboticArmError::ReactionBudgetExceeded("No plan stays inside attitude control limits".into()))
+    }
+
+    fn candidates(&self, start: [f64; 3], goal: [f64; 3], elbow_up: bool, shape: PathShape) -> Vec<PlannedMove>
+    {
+        // fly the path once over one second, momentum scales with 1/T and torque
+        // with 1/T^2 so the shortest duration inside the limits falls straight out
+        let reference = JointTrajectory::through_waypoints(&shape.waypoints(start, goal), 1.0, self.settings.steps);
+        let report = self.arm.analyze(&reference);
+        let limits = &self.settings.limits;
+        let shortest = (report.peak_reaction_momentum / limits.max_momentum)
+            .max((report.peak_reaction_torque / limits.max_torque).sqrt())
+            .max(MIN_DURATION);
+
+        if shortest > self.settings.max_duration
+        {
+            return Vec::new();
+        }
+
+        [shortest, self.settings.max_duration]
+            .into_iter()
+            .map(|duration| {
+                let trajectory = reference.time_scaled(duration);
+                let report = self.arm.analyze(&trajectory);
+                PlannedMove {
+                    cost: self.cost(&report, duration),
+                    trajectory,
+                    report,
+                    elbow_up,
+                    shape,
+                }
+            })
+            .filter(|candidate| candidate.report.within_budget(limits))
+            .collect()
+    }
+}
+
+
+fn unwrap_near(start: [f64; 3], goal: [f64; 3]) -> [f64; 3]
+{
+    // move each goal angle by whole turns so it is as close to the start as possible
+    let mut unwrapped = goal;
+    for (angle, reference) in unwrapped.iter_mut().zip(start.iter())
+    {
+        *angle += 2.0 * PI * ((reference - *angle) / (2.0 * PI)).round();
+    }
+    unwrapped
+}
+
+
+
+
+// ------------------------------- unit tests -------------------------------------
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use all_asserts::assert_near;
+    use crate::simulation::arm_dynamics::{ArmDynamics, LinkProperties, MotorProperties};
+    use crate::simulation::free_floating::SpacecraftProperties;
+
+    fn test_planner(limits: AttitudeControlLimits) -> (ReactionPlanner, InverseKinematicSolver)
+    {
+        // same geometry as main.rs (mm), 6U bus
+        let solver = InverseKinematicSolver::new(1000.0, 500.0, 300.0);
+        let links = [LinkProperties::uniform_rod(0.30, 1000.0),
+                     LinkProperties::uniform_rod(0.20, 500.0),
+                     LinkProperties::uniform_rod(0.15, 300.0)];
+        let motors = [MotorProperties::new(1.0e6, 0.0); 3];
+        let model = ArmDynamics::new(&solver, links, motors, 0.0, [0.0; 3]);
+        let arm = FreeFloatingArm::new(model, SpacecraftProperties::new(12.0, 1.0e5, [170.0, 0.0]));
+        let settings = PlannerSettings {
+            limits,
+            max_duration: 10.0,
+            attitude_weight: 1.0,
+            duration_weight: 0.01,
+            steps: 200,
+        };
+
+        (ReactionPlanner::new(solver, arm, settings), solver)
+    }
+
+    #[test]
+    fn test_unwrap_near()
+    {
+        let unwrapped = unwrap_near([0.1, -3.0, 0.0], [2.0 * PI - 0.1, 3.0, 4.0 * PI]);
+
+        assert_near!(unwrapped[0], -0.1, 1e-12);
+        assert_near!(unwrapped[1], 3.0 - 2.0 * PI, 1e-12);
+        assert_near!(unwrapped[2], 0.0, 1e-12);
+    }
+
+    #[test]
+    fn test_plan_beats_naive_straight_line()
+    {
+        // reach from below the x axis up and over it, the solver puts the start
+        // shoulder angle near 2PI and the goal just above 0 so the naive plan
+        // swings the shoulder most of a turn the wrong way
+        let unlimited = AttitudeControlLimits { max_torque: f64::MAX, max_momentum: 1.0e7 };
+        let (planner, solver) = test_planner(unlimited);
+        let start = solver.find_joint_angles(1200.0, -300.0, 0.0).unwrap();
+        let goal = [900.0, 900.0, 0.8];
+
+        let naive = planner.naive_plan(start, goal).unwrap();
+        let naive_report = planner.arm.analyze(&naive);
+        let naive_cost = planner.cost(&naive_report, naive.duration());
+        let planned = planner.plan(start, goal).unwrap();
+
+        assert!(planned.cost < naive_cost, "planned {} vs naive {}", planned.cost, naive_cost);
+        assert!(planned.report.peak_reaction_momentum < naive_report.peak_reaction_momentum);
+        assert!(planned.report.final_attitude_change.abs() < naive_report.final_attitude_change.abs());
+
+        // the planned move still ends up at the goal
+        let [x, y, si] = solver.find_end_effector_position(planned.trajectory.end());
+        assert_near!(x, 900.0, 1e-6);
+        assert_near!(y, 900.0, 1e-6);
+        assert_near!(si.sin(), 0.8_f64.sin(), 1e-9);
+    }
+
+    #[test]
+    fn test_plan_stays_inside_limits()
+    {
+        // tighten the limits until the naive plan no longer fits
+        let (loose, solver) = test_planner(AttitudeControlLimits { max_torque: f64::MAX, max_momentum: f64::MAX });
+        let start = solver.find_joint_angles(1200.0, -300.0, 0.0).unwrap();
+        let goal = [900.0, 900.0, 0.8];
+        let naive_report = loose.arm.analyze(&loose.naive_plan(start, goal).unwrap());
+
+        let limits = AttitudeControlLimits {
+            max_torque: naive_report.peak_reaction_torque / 2.0,
+            max_momentum: naive_report.peak_reaction_momentum / 2.0,
+        };
+        let (planner, _) = test_planner(limits);
+        let planned = planner.plan(start, goal).unwrap();
+
+        assert!(!naive_report.within_budget(&limits));
+        assert!(planned.report.within_budget(&limits));
+        assert!(planned.trajectory.duration() <= 10.0);
+    }
+
+    #[test]
+    fn test_impossible_limits()
+    {
+        let (planner, solver) = test_planner(AttitudeControlLimits { max_torque: 1e-9, max_momentum: 1e-9 });
+        let start = solver.find_joint_angles(1200.0, -300.0, 0.0).unwrap();
+
+        assert_eq!(planner.plan(start, [900.0, 900.0, 0.8]),
+                   Err(RoboticArmError::ReactionBudgetExceeded("No plan stays inside attitude control limits".into())));
+    }
+}
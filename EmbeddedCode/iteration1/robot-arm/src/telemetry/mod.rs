@@ -0,0 +1,2 @@
+pub mod telemetry_recorder;
+pub mod telemetry_reader;
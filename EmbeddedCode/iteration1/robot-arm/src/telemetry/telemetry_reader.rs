@@ -0,0 +1,209 @@
+/*
+William Albertini
+
+Reads back the logs written by TelemetryRecorder. A
+whole log directory is read oldest file first, so the
+entries come out in the order they were recorded.
+
+TelemetryQuery picks entries out by time window and
+whether the IK solver rejected the target. Entries can
+be exported as CSV (one row per cycle, for spreadsheets
+and plotting) or as ArmState json lines that the
+session_viewer can play back.
+
+*/
+
+// external imports
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::path::Path;
+
+// internal imports
+use crate::arm_errors::RoboticArmError;
+use crate::networking::data_handler::RawDatagram;
+use super::telemetry_recorder::{log_files, IkOutcome, TelemetryEntry};
+
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct TelemetryQuery
+{
+    // inclusive window (milliseconds since the unix epoch)
+    pub from_ms: Option<u64>,
+    pub to_ms: Option<u64>,
+    // only cycles where the IK solver rejected the target
+    pub errors_only: bool,
+}
+
+impl TelemetryQuery
+{
+    pub fn matches(&self, entry: &TelemetryEntry) -> bool
+    {
+        self.from_ms.is_none_or(|from| entry.timestamp_ms >= from)
+            && self.to_ms.is_none_or(|to| entry.timestamp_ms <= to)
+            && (!self.errors_only || matches!(entry.ik, Some(IkOutcome::Rejected { .. })))
+    }
+
+    pub fn apply(&self, entries: Vec<TelemetryEntry>) -> Vec<TelemetryEntry>
+    {
+        entries.into_iter().filter(|entry| self.matches(entry)).collect()
+    }
+}
+
+
+pub fn read_log(path: &Path) -> Result<Vec<TelemetryEntry>, RoboticArmError>
+{
+    let contents = fs::read_to_string(path)
+        .map_err(|e| RoboticArmError::file(format!("Could not read {}", path.display()), e))?;
+
+    // one json TelemetryEntry per line, blank lines are skipped
+    contents.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(number, line)| {
+            let mut entry: TelemetryEntry = serde_json::from_str(line)
+                .map_err(|e| RoboticArmError::file(format!("Bad telemetry entry in {} line {}", path.display(), number + 1), e))?;
+            // the raw datagram is logged once, put it back on the DataHandler
+            entry.data.raw = RawDatagram::new(&entry.raw_bytes);
+            Ok(entry)
+        })
+        .collect()
+}
+
+pub fn read_log_directory(directory: &Path, name: &str) -> Result<Vec<TelemetryEntry>, RoboticArmError>
+{
+    let files = log_files(directory, name);
+    if files.is_empty()
+    {
+        return Err(RoboticArmError::FileError { context: format!("No {name} logs in {}", directory.display()), source: None });
+    }
+
+    let mut entries = Vec::new();
+    for file in files
+    {
+        entries.extend(read_log(&file)?);
+    }
+    Ok(entries)
+}
+
+pub fn export_csv(entries: &[TelemetryEntry]) -> String
+{
+    let mut csv = String::from("timestamp_ms,raw_bytes,x,y,roll,pitch,button1,button2,target_x,target_y,target_si,\
+ik_ok,theta1,theta2,theta3,ik_error,shoulder,elbow,wrist,roll_ticks,spool,\
+feedback_shoulder,feedback_elbow,feedback_wrist,feedback_roll,feedback_spool\n");
+
+    for entry in entries
+    {
+        let raw: String = entry.raw_bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+        let data = &entry.data;
+        let [tx, ty, tsi] = entry.target;
+        let _ = write!(csv, "{},{raw},{},{},{},{},{},{},{tx},{ty},{tsi},",
+            entry.timestamp_ms, data.x, data.y, data.roll, data.pitch, data.button1, data.button2);
+
+        match &entry.ik
+        {
+            Some(IkOutcome::Solved { joint_angles: [t1, t2, t3] }) => { let _ = write!(csv, "1,{t1},{t2},{t3},,"); }
+            // quote the reason, it may hold commas
+            Some(IkOutcome::Rejected { reason }) => { let _ = write!(csv, "0,,,,\"{}\",", reason.replace('"', "\"\"")); }
+            // nothing was asked this cycle
+            None => csv.push_str(",,,,,"),
+        }
+
+        let state = entry.commanded.as_array().map(|ticks| ticks.to_string()).join(",");
+        let feedback = match entry.feedback
+        {
+            Some(feedback) => feedback.as_array().map(|ticks| ticks.to_string()).join(","),
+            None => ",,,,".to_string(),
+        };
+        let _ = writeln!(csv, "{state},{feedback}");
+    }
+    csv
+}
+
+pub fn export_states(entries: &[TelemetryEntry]) -> Result<String, RoboticArmError>
+{
+    // ArmState json lines, the format session_viewer reads
+    let mut states = String::new();
+    for entry in entries
+    {
+        let line = serde_json::to_string(&entry.commanded)
+            .map_err(|e| RoboticArmError::file("Could not encode ArmState", e))?;
+        states.push_str(&line);
+        states.push('\n');
+    }
+    Ok(states)
+}
+
+
+
+
+// ------------------------------- unit tests -------------------------------------
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::networking::data_handler::DataHandler;
+    use crate::robotics::arm_state::ArmState;
+    use crate::visualization::arm_render::read_state_log;
+
+    fn entry_at(timestamp_ms: u64, ik: IkOutcome) -> TelemetryEntry
+    {
+        let mut entry = TelemetryEntry::new(DataHandler::new(1, -2, 0, 0, 1, 1),
+                                            [10.0, 20.0, 0.5],
+                                            ik,
+                                            ArmState::from_array([5, 4, 3, 2, 1]),
+                                            Some(ArmState::from_array([5, 4, 3, 2, 0])));
+        entry.timestamp_ms = timestamp_ms;
+        entry.raw_bytes = vec![0x12, 0xab];
+        entry
+    }
+
+    fn rejected() -> IkOutcome
+    {
+        IkOutcome::Rejected { reason: "Singularity, \"unreachable\"".into() }
+    }
+
+    #[test]
+    fn test_query_filters()
+    {
+        let solved = IkOutcome::Solved { joint_angles: [0.0; 3] };
+        let entries = vec![entry_at(100, solved.clone()), entry_at(200, rejected()), entry_at(300, solved)];
+
+        let window = TelemetryQuery { from_ms: Some(150), to_ms: Some(300), errors_only: false };
+        let stamps: Vec<u64> = window.apply(entries.clone()).iter().map(|entry| entry.timestamp_ms).collect();
+        assert_eq!(stamps, vec![200, 300]);
+
+        let errors = TelemetryQuery { errors_only: true, ..TelemetryQuery::default() };
+        let stamps: Vec<u64> = errors.apply(entries).iter().map(|entry| entry.timestamp_ms).collect();
+        assert_eq!(stamps, vec![200]);
+    }
+
+    #[test]
+    fn test_export_csv()
+    {
+        let entries = vec![entry_at(100, IkOutcome::Solved { joint_angles: [0.5, 0.25, 0.0] }), entry_at(200, rejected())];
+        let csv = export_csv(&entries);
+        let rows: Vec<&str> = csv.lines().collect();
+        let columns = rows[0].split(',').count();
+
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[1], "100,12ab,1,-2,0,0,1,1,10,20,0.5,1,0.5,0.25,0,,5,4,3,2,1,5,4,3,2,0");
+        assert_eq!(rows[1].split(',').count(), columns);
+        assert!(rows[2].starts_with("200,12ab,1,-2,0,0,1,1,10,20,0.5,0,,,,\"Singularity, \"\"unreachable\"\"\",5,4,3,2,1"));
+
+        // a cycle nothing was asked of has the IK columns empty
+        let idle = TelemetryEntry::for_cycle([10.0, 20.0, 0.5], ArmState::from_array([5, 4, 3, 2, 1]), None);
+        let csv = export_csv(&[idle]);
+        let row = csv.lines().nth(1).unwrap();
+        assert!(row.ends_with(",10,20,0.5,,,,,,5,4,3,2,1,,,,,"));
+        assert_eq!(row.split(',').count(), columns);
+    }
+
+    #[test]
+    fn test_export_states_reads_back()
+    {
+        let entries = vec![entry_at(100, rejected())];
+        let states = read_state_log(&export_states(&entries).unwrap()).unwrap();
+
+        assert_eq!(states, vec![ArmState::from_array([5, 4, 3, 2, 1])]);
+    }
+}
@@ -0,0 +1,326 @@
+/*
+William Albertini
+
+TelemetryRecorder writes one json line (TelemetryEntry)
+per control cycle. Each entry holds everything needed to
+work out afterwards why the arm did what it did: when the
+cycle ran, the raw datagram, the decoded DataHandler (or
+the ArmCommand, for commands that are not joystick input),
+the end effector target, what the IK solver made of it, the
+ArmState the solver commanded (before any backlash and sag
+compensation in the driver) and any encoder feedback.
+Cycles where nothing was asked of the arm are logged too
+(for_cycle()), with no IK outcome, so homing and the
+simulator settling show up between commands.
+
+Logs rotate by size. The live file is <name>.jsonl, and
+when it grows past the size limit it becomes <name>.1.jsonl
+(the old .1 becomes .2 and so on). Only max_files rotated
+files are kept, the oldest is deleted. Every entry is
+flushed as it is written so a crash loses at most the
+cycle in progress.
+
+*/
+
+// external imports
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+
+// internal imports
+use crate::arm_errors::RoboticArmError;
+use crate::commands::arm_command::ArmCommand;
+use crate::networking::data_handler::DataHandler;
+use crate::robotics::arm_state::ArmState;
+
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum IkOutcome
+{
+    Solved { joint_angles: [f64; 3] },
+    Rejected { reason: String },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TelemetryEntry
+{
+    // milliseconds since the unix epoch
+    pub timestamp_ms: u64,
+    pub raw_bytes: Vec<u8>,
+    pub data: DataHandler,
+    // requested [x, y, si]
+    pub target: [f64; 3],
+    // None on a cycle nothing was asked of the arm
+    #[serde(default)]
+    pub ik: Option<IkOutcome>,
+    // state the solver commanded, before the driver compensates it
+    #[serde(alias = "arm_state")]
+    pub commanded: ArmState,
+    // encoder readback, None if the driver can not read back
+    pub feedback: Option<ArmState>,
+    // anything other than a jog, data is a heartbeat then
+    #[serde(default)]
+    pub command: Option<ArmCommand>,
+}
+
+impl TelemetryEntry
+{
+    pub fn new(data: DataHandler,
+               target: [f64; 3],
+               ik: IkOutcome,
+               commanded: ArmState,
+               feedback: Option<ArmState>) -> TelemetryEntry
+    {
+        TelemetryEntry {
+            timestamp_ms: now_ms(),
+            raw_bytes: data.raw.as_slice().to_vec(),
+            data,
+            target,
+            ik: Some(ik),
+            commanded,
+            feedback,
+            command: None,
+        }
+    }
+
+    pub fn for_cycle(target: [f64; 3], commanded: ArmState, feedback: Option<ArmState>) -> TelemetryEntry
+    {
+        // a cycle without a command or a move, the arm may still be settling
+        TelemetryEntry {
+            ik: None,
+            ..TelemetryEntry::new(DataHandler::heartbeat(), target, IkOutcome::Solved { joint_angles: [0.0; 3] }, commanded, feedback)
+        }
+    }
+
+    pub fn for_command(command: ArmCommand,
+                       target: [f64; 3],
+                       ik: IkOutcome,
+                       commanded: ArmState,
+                       feedback: Option<ArmState>) -> TelemetryEntry
+    {
+        match command
+        {
+            ArmCommand::Jog(data) => TelemetryEntry::new(data, target, ik, commanded, feedback),
+            _ => TelemetryEntry {
+                command: Some(command),
+                ..TelemetryEntry::new(DataHandler::heartbeat(), target, ik, commanded, feedback)
+            },
+        }
+    }
+}
+
+
+pub struct TelemetryRecorder
+{
+    directory: PathBuf,
+    name: String,
+    // rotate once the live file is bigger than this (bytes)
+    max_bytes: u64,
+    // rotated files kept on disk
+    max_files: usize,
+    writer: BufWriter<File>,
+    bytes_written: u64,
+}
+
+
+impl TelemetryRecorder
+{
+    pub fn try_new(directory: &Path, name: &str, max_bytes: u64, max_files: usize) -> Result<TelemetryRecorder, RoboticArmError>
+    {
+        fs::create_dir_all(directory)
+            .map_err(|e| RoboticArmError::file(format!("Could not create {}", directory.display()), e))?;
+
+        // pick up where an existing live file left off
+        let live = live_path(directory, name);
+        let writer = open_append(&live)?;
+        let bytes_written = fs::metadata(&live).map(|metadata| metadata.len()).unwrap_or(0);
+
+        Ok(TelemetryRecorder {
+            directory: directory.to_path_buf(),
+            name: name.to_string(),
+            max_bytes,
+            max_files,
+            writer,
+            bytes_written,
+        })
+    }
+
+    pub fn record(&mut self, entry: &TelemetryEntry) -> Result<(), RoboticArmError>
+    {
+        let mut line = serde_json::to_string(entry)
+            .map_err(|e| RoboticArmError::file("Could not encode telemetry", e))?;
+        line.push('\n');
+
+        if self.bytes_written > 0 && self.bytes_written + line.len() as u64 > self.max_bytes
+        {
+            self.rotate()?;
+        }
+
+        self.writer.write_all(line.as_bytes())
+            .and_then(|_| self.writer.flush())
+            .map_err(|e| RoboticArmError::file("Could not write telemetry", e))?;
+        self.bytes_written += line.len() as u64;
+        Ok(())
+    }
+
+    pub fn files(&self) -> Vec<PathBuf>
+    {
+        // oldest first, live file last
+        log_files(&self.directory, &self.name)
+    }
+
+    fn rotate(&mut self) -> Result<(), RoboticArmError>
+    {
+        let _ = self.writer.flush();
+
+        // shift every rotated file up one, the oldest falls off the end
+        let oldest = rotated_path(&self.directory, &self.name, self.max_files);
+        if oldest.exists()
+        {
+            fs::remove_file(&oldest)
+                .map_err(|e| RoboticArmError::file(format!("Could not remove {}", oldest.display()), e))?;
+        }
+        for index in (1..self.max_files).rev()
+        {
+            let from = rotated_path(&self.directory, &self.name, index);
+            if from.exists()
+            {
+                let to = rotated_path(&self.directory, &self.name, index + 1);
+                fs::rename(&from, &to)
+                    .map_err(|e| RoboticArmError::file(format!("Could not rotate {}", from.display()), e))?;
+            }
+        }
+
+        let live = live_path(&self.directory, &self.name);
+        if self.max_files > 0
+        {
+            fs::rename(&live, rotated_path(&self.directory, &self.name, 1))
+                .map_err(|e| RoboticArmError::file(format!("Could not rotate {}", live.display()), e))?;
+        } else {
+            fs::remove_file(&live)
+                .map_err(|e| RoboticArmError::file(format!("Could not remove {}", live.display()), e))?;
+        }
+
+        self.writer = open_append(&live)?;
+        self.bytes_written = 0;
+        Ok(())
+    }
+}
+
+
+pub fn live_path(directory: &Path, name: &str) -> PathBuf
+{
+    directory.join(format!("{name}.jsonl"))
+}
+
+pub fn rotated_path(directory: &Path, name: &str, index: usize) -> PathBuf
+{
+    directory.join(format!("{name}.{index}.jsonl"))
+}
+
+pub fn log_files(directory: &Path, name: &str) -> Vec<PathBuf>
+{
+    // rotated files from the highest index down (oldest first), then the live file
+    let mut files = Vec::new();
+    let mut index = 1;
+    while rotated_path(directory, name, index).exists()
+    {
+        index += 1;
+    }
+    for index in (1..index).rev()
+    {
+        files.push(rotated_path(directory, name, index));
+    }
+    let live = live_path(directory, name);
+    if live.exists()
+    {
+        files.push(live);
+    }
+    files
+}
+
+pub fn now_ms() -> u64
+{
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_millis() as u64).unwrap_or(0)
+}
+
+fn open_append(path: &Path) -> Result<BufWriter<File>, RoboticArmError>
+{
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map(BufWriter::new)
+        .map_err(|e| RoboticArmError::file(format!("Could not open {}", path.display()), e))
+}
+
+
+
+
+// ------------------------------- unit tests -------------------------------------
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::telemetry::telemetry_reader::read_log;
+
+    fn test_directory(name: &str) -> PathBuf
+    {
+        let directory = std::env::temp_dir().join(format!("robot-arm-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    fn test_entry(x: i16) -> TelemetryEntry
+    {
+        TelemetryEntry::new(DataHandler::new(x, 0, 0, 0, 1, 1),
+                            [1.0, 2.0, 0.0],
+                            IkOutcome::Solved { joint_angles: [0.1, 0.2, 0.3] },
+                            ArmState::from_array([1, 2, 3, 4, 5]),
+                            None)
+    }
+
+    #[test]
+    fn test_entries_round_trip()
+    {
+        let directory = test_directory("round-trip");
+        let mut recorder = TelemetryRecorder::try_new(&directory, "telemetry", 1 << 20, 3).unwrap();
+        let entry = test_entry(4);
+        recorder.record(&entry).unwrap();
+        recorder.record(&test_entry(5)).unwrap();
+
+        let idle = TelemetryEntry::for_cycle([1.0, 2.0, 0.0], ArmState::from_array([1, 2, 3, 4, 5]), None);
+        recorder.record(&idle).unwrap();
+
+        let entries = read_log(&live_path(&directory, "telemetry")).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0], entry);
+        assert_eq!(entries[1].data.x, 5);
+        assert_eq!(entries[2].ik, None);
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn test_rotation_keeps_max_files()
+    {
+        // every entry is bigger than the limit, so each one lands in its own file
+        let directory = test_directory("rotation");
+        let mut recorder = TelemetryRecorder::try_new(&directory, "telemetry", 10, 2).unwrap();
+        for x in 0..5
+        {
+            recorder.record(&test_entry(x)).unwrap();
+        }
+
+        let files = recorder.files();
+        assert_eq!(files, vec![rotated_path(&directory, "telemetry", 2),
+                               rotated_path(&directory, "telemetry", 1),
+                               live_path(&directory, "telemetry")]);
+
+        // the oldest two entries were dropped, the rest are in order
+        let xs: Vec<i16> = files.iter().flat_map(|file| read_log(file).unwrap()).map(|entry| entry.data.x).collect();
+        assert_eq!(xs, vec![2, 3, 4]);
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
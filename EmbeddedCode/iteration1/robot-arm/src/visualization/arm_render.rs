@@ -0,0 +1,309 @@
+/*
+William Albertini
+
+This module draws the planar arm so a session can be
+reviewed after the fact without the python sims. An
+ArmState (ticks like get_delta_joints(), from where the
+encoders read zero) is turned back into joint angles with
+the encoder map, and the joint positions come from the
+forward kinematics in InverseKinematicSolver. Logged states
+are relative to the starting pose unless the encoders are
+calibrated, so from_arm() takes the map the arm was built
+with, zeroed there (ArmConfig::build_solver()).
+
+Each frame shows the base, the three links, the joints,
+the workspace boundary (full reach of the arm) and,
+if one is given, the requested end effector target.
+Frames can be drawn as SVG (one file per frame) or as
+ASCII for a terminal. Up on the page is +y in both.
+
+*/
+
+// external imports
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::path::Path;
+
+// internal imports
+use crate::arm_errors::RoboticArmError;
+use crate::robotics::arm_kinematics::InverseKinematicSolver;
+use crate::robotics::arm_state::{AngleToEncoderMap, ArmState, Joint, RoboticArmSolver};
+
+
+// svg canvas size (pixels)
+const SVG_SIZE: f64 = 600.0;
+// leave some room around the workspace boundary
+const MARGIN: f64 = 1.1;
+
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ArmFrame
+{
+    // base, elbow, wrist, end effector
+    pub joints: [[f64; 2]; 4],
+    // requested [x, y, si], if there was one
+    pub target: Option<[f64; 3]>,
+}
+
+
+pub struct ArmRenderer
+{
+    solver: InverseKinematicSolver,
+    joint_map: AngleToEncoderMap,
+}
+
+
+impl ArmRenderer
+{
+    pub fn new(solver: InverseKinematicSolver, joint_map: AngleToEncoderMap) -> ArmRenderer
+    {
+        ArmRenderer { solver, joint_map }
+    }
+
+    pub fn from_arm(robotic_arm: &RoboticArmSolver) -> ArmRenderer
+    {
+        // the geometry and the encoder map zeroed at the starting pose
+        ArmRenderer::new(*robotic_arm.get_solver(), *robotic_arm.get_joint_map())
+    }
+
+    pub fn frame_from_angles(&self, joint_angles: [f64; 3], target: Option<[f64; 3]>) -> ArmFrame
+    {
+        ArmFrame {
+            joints: self.solver.find_joint_positions(joint_angles),
+            target,
+        }
+    }
+
+    pub fn frame_from_state(&self, state: &ArmState, target: Option<[f64; 3]>) -> ArmFrame
+    {
+        // only the kinematic joints matter in the plane
+        let joint_angles = [
+            self.joint_map.ticks_to_angle(Joint::Shoulder, state.shoulder),
+            self.joint_map.ticks_to_angle(Joint::Elbow, state.elbow),
+            self.joint_map.ticks_to_angle(Joint::Wrist, state.wrist),
+        ];
+        self.frame_from_angles(joint_angles, target)
+    }
+
+    pub fn render_svg(&self, frame: &ArmFrame) -> String
+    {
+        let reach = self.solver.max_end_effector_distance();
+        let scale = SVG_SIZE / (2.0 * reach * MARGIN);
+        let center = SVG_SIZE / 2.0;
+        // flip y so +y is up the page
+        let to_svg = |point: [f64; 2]| (center + point[0] * scale, center - point[1] * scale);
+
+        let mut svg = String::new();
+        let _ = writeln!(svg, "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{SVG_SIZE}\" height=\"{SVG_SIZE}\" viewBox=\"0 0 {SVG_SIZE} {SVG_SIZE}\">");
+        let _ = writeln!(svg, "<rect width=\"100%\" height=\"100%\" fill=\"white\"/>");
+
+        // workspace boundary
+        let _ = writeln!(svg, "<circle class=\"workspace\" cx=\"{center:.1}\" cy=\"{center:.1}\" r=\"{:.1}\" fill=\"none\" stroke=\"grey\" stroke-dasharray=\"6 4\"/>", reach * scale);
+
+        // links
+        for pair in frame.joints.windows(2)
+        {
+            let (x1, y1) = to_svg(pair[0]);
+            let (x2, y2) = to_svg(pair[1]);
+            let _ = writeln!(svg, "<line class=\"link\" x1=\"{x1:.1}\" y1=\"{y1:.1}\" x2=\"{x2:.1}\" y2=\"{y2:.1}\" stroke=\"steelblue\" stroke-width=\"6\" stroke-linecap=\"round\"/>");
+        }
+
+        // joints, the base is drawn square
+        let (bx, by) = to_svg(frame.joints[0]);
+        let _ = writeln!(svg, "<rect class=\"base\" x=\"{:.1}\" y=\"{:.1}\" width=\"16\" height=\"16\" fill=\"black\"/>", bx - 8.0, by - 8.0);
+        for joint in &frame.joints[1..]
+        {
+            let (x, y) = to_svg(*joint);
+            let _ = writeln!(svg, "<circle class=\"joint\" cx=\"{x:.1}\" cy=\"{y:.1}\" r=\"5\" fill=\"black\"/>");
+        }
+
+        // target cross
+        if let Some([tx, ty, _]) = frame.target
+        {
+            let (x, y) = to_svg([tx, ty]);
+            let _ = writeln!(svg, "<path class=\"target\" d=\"M {:.1} {:.1} L {:.1} {:.1} M {:.1} {:.1} L {:.1} {:.1}\" stroke=\"red\" stroke-width=\"2\"/>",
+                x - 8.0, y - 8.0, x + 8.0, y + 8.0, x - 8.0, y + 8.0, x + 8.0, y - 8.0);
+        }
+
+        svg.push_str("</svg>\n");
+        svg
+    }
+
+    pub fn render_ascii(&self, frame: &ArmFrame, width: usize, height: usize) -> String
+    {
+        let reach = self.solver.max_end_effector_distance();
+        let mut grid = vec![vec![' '; width]; height];
+        // x and y are scaled separately, terminal cells are about twice as tall
+        // as they are wide so width should be about twice height
+        let scale_x = (width as f64 - 1.0) / (2.0 * reach * MARGIN);
+        let scale_y = (height as f64 - 1.0) / (2.0 * reach * MARGIN);
+        let to_cell = |point: [f64; 2]| -> Option<(usize, usize)> {
+            let column = ((width as f64 - 1.0) / 2.0 + point[0] * scale_x).round();
+            let row = ((height as f64 - 1.0) / 2.0 - point[1] * scale_y).round();
+            if column < 0.0 || row < 0.0 || column >= width as f64 || row >= height as f64
+            {
+                return None;
+            }
+            Some((row as usize, column as usize))
+        };
+        let mut plot = |point: [f64; 2], mark: char| {
+            if let Some((row, column)) = to_cell(point)
+            {
+                grid[row][column] = mark;
+            }
+        };
+
+        // workspace boundary
+        let boundary_points = 4 * (width + height);
+        for i in 0..boundary_points
+        {
+            let angle = i as f64 * std::f64::consts::TAU / boundary_points as f64;
+            plot([reach * angle.cos(), reach * angle.sin()], '.');
+        }
+
+        // links, sampled finely enough to leave no gaps
+        let samples = 2 * (width + height);
+        for pair in frame.joints.windows(2)
+        {
+            for i in 0..=samples
+            {
+                let t = i as f64 / samples as f64;
+                plot([pair[0][0] + t * (pair[1][0] - pair[0][0]), pair[0][1] + t * (pair[1][1] - pair[0][1])], '#');
+            }
+        }
+
+        for joint in &frame.joints[1..3]
+        {
+            plot(*joint, 'o');
+        }
+        plot(frame.joints[3], '@');
+        plot(frame.joints[0], '+');
+        if let Some([tx, ty, _]) = frame.target
+        {
+            plot([tx, ty], 'X');
+        }
+
+        let mut text = String::with_capacity((width + 1) * height);
+        for row in grid
+        {
+            text.extend(row.iter());
+            // keep trailing whitespace out of the terminal
+            while text.ends_with(' ')
+            {
+                text.pop();
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    pub fn write_svg_frames(&self, frames: &[ArmFrame], directory: &Path) -> Result<usize, RoboticArmError>
+    {
+        // frame_0000.svg, frame_0001.svg, ...
+        fs::create_dir_all(directory)
+            .map_err(|e| RoboticArmError::file(format!("Could not create {}", directory.display()), e))?;
+
+        for (index, frame) in frames.iter().enumerate()
+        {
+            let path = directory.join(format!("frame_{index:04}.svg"));
+            fs::write(&path, self.render_svg(frame))
+                .map_err(|e| RoboticArmError::file(format!("Could not write {}", path.display()), e))?;
+        }
+        Ok(frames.len())
+    }
+}
+
+
+pub fn read_state_log(contents: &str) -> Result<Vec<ArmState>, RoboticArmError>
+{
+    // one json ArmState per line, blank lines are skipped
+    contents.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(number, line)| {
+            serde_json::from_str(line)
+                .map_err(|e| RoboticArmError::file(format!("Bad ArmState on line {}", number + 1), e))
+        })
+        .collect()
+}
+
+
+
+
+// ------------------------------- unit tests -------------------------------------
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use all_asserts::assert_near;
+
+    fn test_renderer() -> ArmRenderer
+    {
+        let map = AngleToEncoderMap::new(4000, 4000, 4000, 4000, 4000);
+        ArmRenderer::new(InverseKinematicSolver::new(10.0, 5.0, 3.0), map)
+    }
+
+    #[test]
+    fn test_frame_from_state()
+    {
+        // a quarter turn on the shoulder points the arm straight up
+        let renderer = test_renderer();
+        let frame = renderer.frame_from_state(&ArmState::from_array([1000, 0, 0, 0, 0]), None);
+
+        assert_near!(frame.joints[3][0], 0.0, 1e-9);
+        assert_near!(frame.joints[3][1], 18.0, 1e-9);
+    }
+
+    #[test]
+    fn test_frame_from_logged_state()
+    {
+        // logged ticks count from the starting pose, all zero is where the arm started
+        let map = AngleToEncoderMap::new(4000, 4000, 4000, 4000, 4000);
+        let robotic_arm = RoboticArmSolver::try_new_from_ef_position(10.0, 5.0, 3.0, 12.0, 6.0, 0.5, map).unwrap();
+        let renderer = ArmRenderer::from_arm(&robotic_arm);
+        let frame = renderer.frame_from_state(&ArmState::from_array([0; 5]), None);
+
+        assert_near!(frame.joints[3][0], 12.0, 1e-9);
+        assert_near!(frame.joints[3][1], 6.0, 1e-9);
+    }
+
+    #[test]
+    fn test_svg_elements()
+    {
+        let renderer = test_renderer();
+        let frame = renderer.frame_from_angles([0.3, 0.5, -0.2], Some([5.0, 5.0, 0.0]));
+        let svg = renderer.render_svg(&frame);
+
+        assert_eq!(svg.matches("class=\"link\"").count(), 3);
+        assert_eq!(svg.matches("class=\"joint\"").count(), 3);
+        assert_eq!(svg.matches("class=\"workspace\"").count(), 1);
+        assert_eq!(svg.matches("class=\"target\"").count(), 1);
+        assert!(svg.trim_end().ends_with("</svg>"));
+    }
+
+    #[test]
+    fn test_ascii_straight_arm()
+    {
+        // arm straight out along +x, the end effector sits on the middle row
+        let renderer = test_renderer();
+        let frame = renderer.frame_from_angles([0.0; 3], None);
+        let text = renderer.render_ascii(&frame, 41, 21);
+        let rows: Vec<&str> = text.lines().collect();
+
+        assert_eq!(rows.len(), 21);
+        assert_eq!(rows[10].chars().nth(20), Some('+'));
+        assert!(rows[10].contains('@'));
+        assert!(rows[10].contains('#'));
+        assert!(rows[0].trim().is_empty() || rows[0].contains('.'));
+    }
+
+    #[test]
+    fn test_read_state_log()
+    {
+        let log = "{\"shoulder\":1,\"elbow\":2,\"wrist\":3,\"roll\":4,\"spool\":5}\n\n{\"shoulder\":0,\"elbow\":0,\"wrist\":0,\"roll\":0,\"spool\":0}\n";
+        let states = read_state_log(log).unwrap();
+
+        assert_eq!(states, vec![ArmState::from_array([1, 2, 3, 4, 5]), ArmState::from_array([0; 5])]);
+        assert!(read_state_log("not json").is_err());
+    }
+}
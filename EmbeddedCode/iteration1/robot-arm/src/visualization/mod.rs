@@ -0,0 +1 @@
+pub mod arm_render;
\ No newline at end of file